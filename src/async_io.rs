@@ -0,0 +1,248 @@
+//! Reading and writing CARv1 over async IO.
+//!
+//! The implementation is written once against the small [`AsyncSource`] and [`AsyncSink`]
+//! traits and [`CarV1Decoder`], and exposed for Tokio (feature `tokio`) and for
+//! `futures::io` runtimes such as async-std and smol (feature `futures-io`).
+
+use std::future::poll_fn;
+use std::io;
+use std::task::{Context, Poll};
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::block::{write_car_v1_block, CarV1Decoder};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::CarResult;
+
+const READ_CHUNK: usize = 64 * 1024;
+
+/// An async byte source, implemented for the IO traits of each supported runtime.
+pub trait AsyncSource {
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+}
+
+/// An async byte sink, implemented for the IO traits of each supported runtime.
+pub trait AsyncSink {
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_flush_sink(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// Reads a whole CARv1 from `r`.
+pub async fn read_car_v1<R: AsyncSource>(mut r: R) -> CarResult<CarV1> {
+    let mut decoder = CarV1Decoder::new();
+    let mut blocks = vec![];
+    let mut buf = vec![0u8; READ_CHUNK];
+    loop {
+        let read = poll_fn(|cx| r.poll_read_chunk(cx, &mut buf)).await?;
+        if read == 0 {
+            break;
+        }
+        decoder.push(&buf[..read]);
+        while let Some(block) = decoder.next_block()? {
+            blocks.push(block);
+        }
+    }
+    decoder.finish()?;
+    let header = decoder.header().cloned().expect("finish checks the header");
+    Ok(CarV1::new(header, blocks))
+}
+
+/// Writes `car` to `w` and flushes it.
+pub async fn write_car_v1<W: AsyncSink>(car: &CarV1, w: W) -> CarResult<()> {
+    let mut writer = AsyncCarWriter::new(w, car.header.roots.clone()).await?;
+    for block in &car.blocks {
+        writer.write_block(block).await?;
+    }
+    writer.finish().await?;
+    Ok(())
+}
+
+/// Streams a CARv1 to `w` one block at a time, e.g. as the body of a response.
+pub struct AsyncCarWriter<W> {
+    w: W,
+}
+
+impl<W: AsyncSink> AsyncCarWriter<W> {
+    /// Writes the header of an archive with `roots` to `w`.
+    pub async fn new(mut w: W, roots: Vec<Cid>) -> CarResult<Self> {
+        let mut header = vec![];
+        CarHeaderV1 { roots }.write_to(&mut header)?;
+        write_all(&mut w, &header).await?;
+        Ok(Self { w })
+    }
+
+    /// Writes the section of `block`. Blocks are not checked against their CIDs.
+    pub async fn write_block(&mut self, block: &Block<DefaultParams>) -> CarResult<()> {
+        let mut frame = vec![];
+        write_car_v1_block(&mut frame, block)?;
+        write_all(&mut self.w, &frame).await
+    }
+
+    /// Flushes the archive and returns the writer.
+    pub async fn finish(mut self) -> CarResult<W> {
+        poll_fn(|cx| self.w.poll_flush_sink(cx)).await?;
+        Ok(self.w)
+    }
+}
+
+async fn write_all<W: AsyncSink>(w: &mut W, mut buf: &[u8]) -> CarResult<()> {
+    while !buf.is_empty() {
+        let written = poll_fn(|cx| w.poll_write_chunk(cx, buf)).await?;
+        if written == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+        }
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+/// Adapters for `tokio::io`.
+#[cfg(feature = "tokio")]
+pub mod tokio {
+    use super::*;
+    use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use std::pin::Pin;
+
+    /// Wraps a Tokio reader or writer as an [`AsyncSource`] or [`AsyncSink`].
+    pub struct Compat<T>(pub T);
+
+    impl<R: AsyncRead + Unpin> AsyncSource for Compat<R> {
+        fn poll_read_chunk(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            std::task::ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncSink for Compat<W> {
+        fn poll_write_chunk(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush_sink(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+    }
+
+    pub async fn read_car_v1<R: AsyncRead + Unpin>(r: R) -> CarResult<CarV1> {
+        super::read_car_v1(Compat(r)).await
+    }
+
+    pub async fn write_car_v1<W: AsyncWrite + Unpin>(car: &CarV1, w: W) -> CarResult<()> {
+        super::write_car_v1(car, Compat(w)).await
+    }
+
+    /// An [`super::AsyncCarWriter`] over a Tokio writer, created with
+    /// `AsyncCarWriter::new(Compat(w), roots)`.
+    pub type AsyncCarWriter<W> = super::AsyncCarWriter<Compat<W>>;
+}
+
+/// Adapters for `futures::io`.
+#[cfg(feature = "futures-io")]
+pub mod futures {
+    use super::*;
+    use ::futures_io::{AsyncRead, AsyncWrite};
+    use std::pin::Pin;
+
+    /// Wraps a `futures::io` reader or writer as an [`AsyncSource`] or [`AsyncSink`].
+    pub struct Compat<T>(pub T);
+
+    impl<R: AsyncRead + Unpin> AsyncSource for Compat<R> {
+        fn poll_read_chunk(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncSink for Compat<W> {
+        fn poll_write_chunk(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush_sink(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+    }
+
+    pub async fn read_car_v1<R: AsyncRead + Unpin>(r: R) -> CarResult<CarV1> {
+        super::read_car_v1(Compat(r)).await
+    }
+
+    pub async fn write_car_v1<W: AsyncWrite + Unpin>(car: &CarV1, w: W) -> CarResult<()> {
+        super::write_car_v1(car, Compat(w)).await
+    }
+
+    /// An [`super::AsyncCarWriter`] over a `futures::io` writer, created with
+    /// `AsyncCarWriter::new(Compat(w), roots)`.
+    pub type AsyncCarWriter<W> = super::AsyncCarWriter<Compat<W>>;
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "futures-io")))]
+mod tests {
+    use crate::test_utils::diamond;
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn it_round_trips_over_tokio_io() {
+        let car = diamond();
+        let mut bytes = vec![];
+        super::tokio::write_car_v1(&car, &mut bytes).await.unwrap();
+
+        let mut expected = vec![];
+        car.write_to(&mut expected).unwrap();
+        assert_eq!(bytes, expected);
+        let read = super::tokio::read_car_v1(&bytes[..]).await.unwrap();
+        assert_eq!(read.blocks, car.blocks);
+        assert!(super::tokio::read_car_v1(&bytes[..bytes.len() - 1])
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn it_streams_blocks_one_at_a_time() {
+        use super::tokio::{AsyncCarWriter, Compat};
+        let car = diamond();
+        let mut writer = AsyncCarWriter::new(Compat(vec![]), car.header.roots.clone())
+            .await
+            .unwrap();
+        for block in &car.blocks {
+            writer.write_block(block).await.unwrap();
+        }
+        let Compat(bytes) = writer.finish().await.unwrap();
+
+        let mut expected = vec![];
+        car.write_to(&mut expected).unwrap();
+        assert_eq!(bytes, expected);
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[tokio::test]
+    async fn it_round_trips_over_futures_io() {
+        let car = diamond();
+        let mut bytes = vec![];
+        super::futures::write_car_v1(&car, &mut bytes)
+            .await
+            .unwrap();
+
+        let read = super::futures::read_car_v1(&bytes[..]).await.unwrap();
+        assert_eq!(read.header.roots, car.header.roots);
+        assert_eq!(read.blocks, car.blocks);
+    }
+}
@@ -0,0 +1,243 @@
+//! Attributable archives: a signed authorship block naming who produced the roots and what
+//! they allow, stored alongside the DAG as a non-root block.
+//!
+//! The block is a dag-cbor map with `type` set to [`AUTHORSHIP_TYPE`], the `issuer` as an
+//! ed25519 `did:key`, the archive `roots`, a list of `permissions`, and a `signature` by the
+//! issuer over the dag-cbor encoding of the other fields.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use libipld::cbor::DagCborCodec;
+use libipld::cid::multibase::{self, Base};
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::multihash::Code;
+use libipld::{Block, DefaultParams, Ipld};
+
+use crate::params;
+use crate::v1::CarV1;
+use crate::{CarError, CarResult, ContentArchive};
+
+/// The `type` of authorship blocks.
+pub const AUTHORSHIP_TYPE: &str = "racecar/authorship/v1";
+
+/// The multicodec prefix of an ed25519 public key in a `did:key`.
+const ED25519_PUB: [u8; 2] = [0xed, 0x01];
+
+/// A verified authorship block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorship {
+    pub issuer: String,
+    pub roots: Vec<Cid>,
+    pub permissions: Vec<String>,
+}
+
+/// The `did:key` of an ed25519 public key.
+pub fn did_key(key: &VerifyingKey) -> String {
+    let mut bytes = ED25519_PUB.to_vec();
+    bytes.extend_from_slice(key.as_bytes());
+    format!("did:key:{}", multibase::encode(Base::Base58Btc, bytes))
+}
+
+/// Parses an ed25519 `did:key`.
+pub fn parse_did_key(did: &str) -> CarResult<VerifyingKey> {
+    let invalid = || CarError::InvalidAuthorship(format!("unsupported issuer {}", did));
+    let (base, bytes) = did
+        .strip_prefix("did:key:")
+        .and_then(|key| multibase::decode(key).ok())
+        .ok_or_else(invalid)?;
+    match bytes.strip_prefix(&ED25519_PUB[..]) {
+        Some(key) if base == Base::Base58Btc => <[u8; 32]>::try_from(key)
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// Signs the roots of `car` with `key` and adds the authorship block to its blocks, returning
+/// the block's CID.
+pub fn attach_authorship(
+    car: &mut CarV1,
+    key: &SigningKey,
+    permissions: &[&str],
+) -> CarResult<Cid> {
+    let authorship = Authorship {
+        issuer: did_key(&key.verifying_key()),
+        roots: car.header.roots.clone(),
+        permissions: permissions.iter().map(|p| p.to_string()).collect(),
+    };
+    let mut node = authorship.payload();
+    let signature = key.sign(&DagCborCodec.encode(&Ipld::Map(node.clone()))?);
+    node.insert(
+        "signature".to_string(),
+        Ipld::Bytes(signature.to_bytes().to_vec()),
+    );
+    let block = params::encode(DagCborCodec, Code::Sha2_256, &Ipld::Map(node))?;
+    let cid = *block.cid();
+    car.blocks.push(block);
+    Ok(cid)
+}
+
+/// Finds the authorship block of `car`, if any, and checks its signature and that it covers
+/// the archive's roots.
+pub fn verify_authorship(car: &CarV1) -> CarResult<Option<Authorship>> {
+    let mut found = None;
+    for block in &car.blocks {
+        if let Some(authorship) = Authorship::from_block(block)? {
+            if found.is_some() {
+                return Err(CarError::InvalidAuthorship(
+                    "more than one authorship block".to_string(),
+                ));
+            }
+            if authorship.roots != car.header.roots {
+                return Err(CarError::InvalidAuthorship(
+                    "signed roots differ from the header".to_string(),
+                ));
+            }
+            found = Some(authorship);
+        }
+    }
+    Ok(found)
+}
+
+/// Reads an archive and verifies its authorship block, if any.
+pub fn read_attributed<R: Read + Seek>(r: R) -> CarResult<(ContentArchive, Option<Authorship>)> {
+    let archive = ContentArchive::read_bytes(r)?;
+    let authorship = verify_authorship(archive.car_v1())?;
+    Ok((archive, authorship))
+}
+
+impl Authorship {
+    fn payload(&self) -> BTreeMap<String, Ipld> {
+        let mut node = BTreeMap::new();
+        node.insert(
+            "type".to_string(),
+            Ipld::String(AUTHORSHIP_TYPE.to_string()),
+        );
+        node.insert("issuer".to_string(), Ipld::String(self.issuer.clone()));
+        node.insert(
+            "roots".to_string(),
+            Ipld::List(self.roots.iter().copied().map(Ipld::Link).collect()),
+        );
+        node.insert(
+            "permissions".to_string(),
+            Ipld::List(self.permissions.iter().cloned().map(Ipld::String).collect()),
+        );
+        node
+    }
+
+    /// Decodes and verifies `block` if it is an authorship block.
+    fn from_block(block: &Block<DefaultParams>) -> CarResult<Option<Self>> {
+        if block.cid().codec() != u64::from(DagCborCodec) {
+            return Ok(None);
+        }
+        let mut node = match DagCborCodec.decode::<Ipld>(block.data()) {
+            Ok(Ipld::Map(node)) => node,
+            _ => return Ok(None),
+        };
+        match node.get("type") {
+            Some(Ipld::String(kind)) if kind == AUTHORSHIP_TYPE => {}
+            _ => return Ok(None),
+        }
+        let invalid = |field: &str| CarError::InvalidAuthorship(format!("malformed {}", field));
+
+        let signature = match node.remove("signature") {
+            Some(Ipld::Bytes(signature)) => {
+                Signature::from_slice(&signature).map_err(|_| invalid("signature"))?
+            }
+            _ => return Err(invalid("signature")),
+        };
+        let issuer = match node.get("issuer") {
+            Some(Ipld::String(issuer)) => issuer.clone(),
+            _ => return Err(invalid("issuer")),
+        };
+        let roots = match node.get("roots") {
+            Some(Ipld::List(roots)) => roots
+                .iter()
+                .map(|root| match root {
+                    Ipld::Link(cid) => Ok(*cid),
+                    _ => Err(invalid("roots")),
+                })
+                .collect::<CarResult<_>>()?,
+            _ => return Err(invalid("roots")),
+        };
+        let permissions = match node.get("permissions") {
+            Some(Ipld::List(permissions)) => permissions
+                .iter()
+                .map(|permission| match permission {
+                    Ipld::String(permission) => Ok(permission.clone()),
+                    _ => Err(invalid("permissions")),
+                })
+                .collect::<CarResult<_>>()?,
+            _ => return Err(invalid("permissions")),
+        };
+        let authorship = Self {
+            issuer,
+            roots,
+            permissions,
+        };
+        if node != authorship.payload() {
+            return Err(invalid("fields"));
+        }
+
+        parse_did_key(&authorship.issuer)?
+            .verify(&DagCborCodec.encode(&Ipld::Map(node))?, &signature)
+            .map_err(|err| CarError::InvalidAuthorship(err.to_string()))?;
+        Ok(Some(authorship))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cbor, diamond};
+    use std::io::Cursor;
+
+    #[test]
+    fn it_round_trips_did_keys() {
+        let key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let did = did_key(&key);
+        assert!(did.starts_with("did:key:z6Mk"));
+        assert_eq!(parse_did_key(&did).unwrap(), key);
+        assert!(parse_did_key("did:web:example.com").is_err());
+    }
+
+    #[test]
+    fn it_attaches_and_verifies_authorship() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut car = diamond();
+        assert_eq!(verify_authorship(&car).unwrap(), None);
+        attach_authorship(&mut car, &key, &["publish"]).unwrap();
+
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let (_, authorship) = read_attributed(Cursor::new(bytes)).unwrap();
+        let authorship = authorship.unwrap();
+        assert_eq!(authorship.issuer, did_key(&key.verifying_key()));
+        assert_eq!(authorship.roots, car.header.roots);
+        assert_eq!(authorship.permissions, vec!["publish".to_string()]);
+
+        let mut moved = car.clone();
+        moved.header.roots = vec![*car.blocks[0].cid()];
+        assert!(matches!(
+            verify_authorship(&moved),
+            Err(CarError::InvalidAuthorship(_))
+        ));
+
+        // Widening the permissions invalidates the signature.
+        let block = car.blocks.pop().unwrap();
+        let mut node = match block.decode::<DagCborCodec, Ipld>().unwrap() {
+            Ipld::Map(node) => node,
+            _ => unreachable!(),
+        };
+        node.insert(
+            "permissions".to_string(),
+            Ipld::List(vec![Ipld::String("admin".to_string())]),
+        );
+        car.blocks.push(cbor(&Ipld::Map(node)));
+        assert!(verify_authorship(&car).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+#[path = "../json.rs"]
+mod json;
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use notify::{RecursiveMode, Watcher};
+use rust_racecar::chunker::Buzhash;
+use rust_racecar::diff::{delta, diff};
+use rust_racecar::pack::{ChunkCache, Packer, SymlinkPolicy, DEFAULT_CHUNK_SIZE};
+use rust_racecar::prelude::CarV2Index;
+use rust_racecar::query::{Match, Query};
+use rust_racecar::raw::{layout, write_layout, Region};
+use rust_racecar::repair::{repair, truncate_to_valid, Recovery};
+use rust_racecar::repo::write_named;
+use rust_racecar::server::CarServer;
+use rust_racecar::split::write_split;
+use rust_racecar::stats::{archive_stats, write_stats};
+use rust_racecar::unixfs::extract;
+use rust_racecar::ContentArchive;
+
+/// Inspect, transform and serve Content Archive (CAR) files.
+#[derive(Debug, Parser)]
+#[command(name = "racecar", version)]
+struct Cli {
+    /// How to print results: as text, or as JSON for scripts.
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    /// One JSON value per result, errors included, as `{"error": "..."}` on stderr.
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print a completion script for `shell`, e.g. `racecar completions bash >
+    /// /etc/bash_completion.d/racecar`.
+    Completions { shell: Shell },
+    /// Print the byte layout of an archive: its pragma, headers, sections and index.
+    Debug { file: PathBuf },
+    /// Compare the blocks and roots of two archives, e.g. successive snapshots of a dataset.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Write the archive of the blocks only `new` has, rooted at its roots.
+        #[arg(long)]
+        emit_patch: Option<PathBuf>,
+    },
+    /// Write the UnixFS files and directories below the roots of an archive into the output
+    /// directory, checking every block.
+    Extract {
+        file: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Index the sections of a CARv1, or of the payload of a CARv2, into a detached
+    /// `MultihashIndexSorted`, as `car index` does.
+    Index {
+        file: PathBuf,
+        /// Where to write the index, `<file>.idx` by default.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Pack files and directories as UnixFS into `<CID>.car` files in the output directory,
+    /// with a root for each.
+    Pack {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
+        chunk_size: usize,
+        /// Cut content-defined chunks with buzhash instead of ones of `chunk_size` bytes.
+        #[arg(long)]
+        buzhash: bool,
+        /// Chunk cache to load and update, so unchanged files are not read again across runs.
+        #[arg(long)]
+        cache: Option<PathBuf>,
+        /// Keep running and write a delta archive, holding only new blocks, on every change.
+        #[arg(long)]
+        watch: bool,
+        /// Leave out what the `.gitignore`-style rules in files of this name match, in every
+        /// directory packed.
+        #[arg(long = "ignore-file", default_values = [".gitignore", ".racecarignore"])]
+        ignore_files: Vec<String>,
+        /// Pack everything, reading no ignore files.
+        #[arg(long)]
+        no_ignore: bool,
+        /// Pack what symlinks point to, the default.
+        #[arg(long, conflicts_with = "preserve_symlinks")]
+        follow_symlinks: bool,
+        /// Pack symlinks as UnixFS symlinks holding their targets.
+        #[arg(long)]
+        preserve_symlinks: bool,
+    },
+    /// Print the nodes below the roots of an archive matching a query, e.g.
+    /// `**/name == 'config.json'`, one per line with their block and path.
+    Query { file: PathBuf, query: String },
+    /// Recover what a lenient read can from a damaged archive, past padding, non-minimal
+    /// varints, duplicate blocks and a truncated last section, printing what was read past.
+    Repair {
+        file: PathBuf,
+        /// Write the recovered blocks here as a CARv1.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Serve an archive over HTTP at `/ipfs/{cid}?format=raw|car`, reading blocks through its
+    /// index as they are asked for.
+    Serve {
+        file: PathBuf,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// Split an archive into `<CID>.car` shards of at most `max_size` bytes in the output
+    /// directory, with a manifest of what each holds.
+    Split {
+        file: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long)]
+        max_size: u64,
+    },
+    /// Print the number and size of the blocks of an archive, how deep its DAGs are and how
+    /// much of them is shared.
+    Stats {
+        file: PathBuf,
+        /// Also print histograms of block sizes, codecs and depths, which JSON always has.
+        #[arg(long)]
+        histogram: bool,
+    },
+    /// Keep the sections of an archive up to the first that is cut short, malformed or fails to
+    /// verify, printing where it stopped.
+    TruncateToValid {
+        file: PathBuf,
+        /// Write the kept blocks here as a CARv1.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    let Cli { format, command } = Cli::parse();
+    match run(command, format) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            print_error(&*err, format);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints `err` to stderr, as `{"error": "..."}` under `--format json`.
+fn print_error(err: &dyn Error, format: Format) {
+    match format {
+        Format::Text => eprintln!("racecar: {}", err),
+        Format::Json => eprintln!("{{\"error\":{}}}", json::string(&err.to_string())),
+    }
+}
+
+fn run(command: Command, format: Format) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "racecar", &mut io::stdout());
+            Ok(())
+        }
+        Command::Debug { file } => {
+            let bytes = fs::read(file)?;
+            if format == Format::Text {
+                return Ok(write_layout(&bytes, io::stdout().lock())?);
+            }
+            let regions: Vec<String> = layout(&bytes).iter().map(Region::to_json).collect();
+            println!("[{}]", regions.join(","));
+            Ok(())
+        }
+        Command::Diff {
+            old,
+            new,
+            emit_patch,
+        } => {
+            let old = ContentArchive::read_bytes(BufReader::new(File::open(old)?))?;
+            let new = ContentArchive::read_bytes(BufReader::new(File::open(new)?))?;
+            let changes = diff(old.car_v1(), new.car_v1());
+            if format == Format::Json {
+                println!("{}", changes.to_json());
+            } else {
+                println!("{}", changes);
+            }
+            if let Some(patch) = emit_patch {
+                delta(old.car_v1(), new.car_v1()).write_to(BufWriter::new(File::create(patch)?))?;
+            }
+            Ok(())
+        }
+        Command::Extract { file, output } => {
+            let archive = ContentArchive::read_bytes(BufReader::new(File::open(file)?))?;
+            extract(archive.car_v1(), &output)?;
+            let roots = &archive.car_v1().header.roots;
+            match format {
+                Format::Text => {
+                    println!("extracted {} roots into {}", roots.len(), output.display())
+                }
+                Format::Json => println!(
+                    "{{\"roots\":{},\"output\":{}}}",
+                    json::cids(roots),
+                    json_path(&output)
+                ),
+            }
+            Ok(())
+        }
+        Command::Index { file, output } => {
+            let index = CarV2Index::generate_from_reader(BufReader::new(File::open(&file)?))?;
+            let output = output.unwrap_or_else(|| {
+                let mut name = file.into_os_string();
+                name.push(".idx");
+                name.into()
+            });
+            let mut w = BufWriter::new(File::create(&output)?);
+            index.write_to(&mut w)?;
+            w.flush()?;
+            let sections = index.len();
+            match format {
+                Format::Text => println!("indexed {} sections into {}", sections, output.display()),
+                Format::Json => println!(
+                    "{{\"sections\":{},\"output\":{}}}",
+                    sections,
+                    json_path(&output)
+                ),
+            }
+            Ok(())
+        }
+        Command::Pack {
+            paths,
+            output,
+            chunk_size,
+            buzhash,
+            cache,
+            watch,
+            ignore_files,
+            no_ignore,
+            follow_symlinks,
+            preserve_symlinks,
+        } => {
+            fs::create_dir_all(&output)?;
+            if watch {
+                let output = fs::canonicalize(&output)?;
+                for path in &paths {
+                    if output.starts_with(fs::canonicalize(path)?) {
+                        return Err("the output directory must not be inside a watched path".into());
+                    }
+                }
+            }
+            let mut packer = Packer::new().with_chunk_size(chunk_size);
+            if !no_ignore {
+                for name in ignore_files {
+                    packer = packer.with_ignore_file(name);
+                }
+            }
+            packer = packer.with_symlinks(match (follow_symlinks, preserve_symlinks) {
+                (true, _) => SymlinkPolicy::Follow,
+                (_, true) => SymlinkPolicy::Preserve,
+                _ => SymlinkPolicy::default(),
+            });
+            if buzhash {
+                packer = packer.with_chunker(Buzhash::default());
+            }
+            if let Some(cache) = &cache {
+                packer = packer.with_cache(ChunkCache::load(cache)?);
+            }
+            pack(&mut packer, &paths, &output, cache.as_deref(), format)?;
+            if watch {
+                watch_and_pack(&mut packer, &paths, &output, cache.as_deref(), format)?;
+            }
+            Ok(())
+        }
+        Command::Query { file, query } => {
+            let query: Query = query.parse()?;
+            let archive = ContentArchive::read_bytes(BufReader::new(File::open(file)?))?;
+            let car = archive.car_v1();
+            let mut matches = vec![];
+            for root in &car.header.roots {
+                matches.extend(query.select(car, root)?);
+            }
+            match format {
+                Format::Text => matches.iter().for_each(|found| println!("{}", found)),
+                Format::Json => {
+                    let matches = matches.iter().map(Match::to_json);
+                    println!("[{}]", matches.collect::<Result<Vec<_>, _>>()?.join(","));
+                }
+            }
+            Ok(())
+        }
+        Command::Repair { file, output } => {
+            let recovery = repair(BufReader::new(File::open(file)?))?;
+            report(&recovery, output.as_deref(), format)
+        }
+        Command::Serve { file, port, host } => {
+            let server = CarServer::open(&file)?;
+            let listener = TcpListener::bind((host.as_str(), port))?;
+            let url = format!("http://{}", listener.local_addr()?);
+            match format {
+                Format::Text => eprintln!("serving {} on {}", file.display(), url),
+                Format::Json => println!(
+                    "{{\"file\":{},\"url\":{}}}",
+                    json_path(&file),
+                    json::string(&url)
+                ),
+            }
+            Ok(server.serve(listener)?)
+        }
+        Command::Split {
+            file,
+            output,
+            max_size,
+        } => {
+            let archive = ContentArchive::read_bytes(BufReader::new(File::open(file)?))?;
+            let manifest = write_split(archive.car_v1(), max_size, &output)?;
+            match format {
+                Format::Text => {
+                    for shard in &manifest.shards {
+                        println!(
+                            "{}.car {} bytes, {} blocks",
+                            shard.cid,
+                            shard.size,
+                            shard.blocks.len()
+                        );
+                    }
+                }
+                Format::Json => println!("{}", manifest.to_json()),
+            }
+            Ok(())
+        }
+        Command::Stats { file, histogram } => {
+            let archive = ContentArchive::read_bytes(BufReader::new(File::open(file)?))?;
+            let stats = archive_stats(archive.car_v1())?;
+            if format == Format::Json {
+                println!("{}", stats.to_json());
+                return Ok(());
+            }
+            Ok(write_stats(&stats, histogram, io::stdout().lock())?)
+        }
+        Command::TruncateToValid { file, output } => {
+            let recovery = truncate_to_valid(BufReader::new(File::open(file)?))?;
+            report(&recovery, output.as_deref(), format)
+        }
+    }
+}
+
+/// Prints what was recovered and writes it to `output`, if any.
+fn report(
+    recovery: &Recovery,
+    output: Option<&Path>,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    if format == Format::Json {
+        println!("{}", recovery.to_json());
+    } else {
+        for anomaly in &recovery.anomalies {
+            println!("{}", anomaly);
+        }
+        if let Some(err) = &recovery.stopped {
+            println!("stopped at: {}", err);
+        }
+        println!(
+            "recovered {} blocks, dropped {} bytes",
+            recovery.car.blocks.len(),
+            recovery.dropped_bytes
+        );
+    }
+    if let Some(output) = output {
+        let mut w = BufWriter::new(File::create(output)?);
+        recovery.write_to(&mut w)?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+fn pack(
+    packer: &mut Packer,
+    paths: &[PathBuf],
+    output: &Path,
+    cache: Option<&Path>,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    let car = packer.pack_all(paths)?;
+    let archive = match car.blocks.is_empty() {
+        true => None,
+        false => Some(write_named(&car, output)?),
+    };
+    // Only once the archive holding them is written may the cache claim the chunks.
+    if let (Some(cache), Some(chunks)) = (cache, packer.cache()) {
+        chunks.save(cache)?;
+    }
+    match (format, archive) {
+        (Format::Text, None) => {}
+        (Format::Text, Some(archive)) => {
+            for (root, path) in car.header.roots.iter().zip(paths) {
+                println!("{} {}", root, path.display());
+            }
+            println!("{} new blocks in {}.car", car.blocks.len(), archive);
+        }
+        // Printed after every pack, one line each when watching.
+        (Format::Json, archive) => {
+            let roots: Vec<String> = car
+                .header
+                .roots
+                .iter()
+                .zip(paths)
+                .map(|(root, path)| {
+                    format!("{{\"cid\":\"{}\",\"path\":{}}}", root, json_path(path))
+                })
+                .collect();
+            let archive = archive.map_or("null".to_string(), |cid| format!("\"{}.car\"", cid));
+            println!(
+                "{{\"roots\":[{}],\"blocks\":{},\"archive\":{}}}",
+                roots.join(","),
+                car.blocks.len(),
+                archive
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Repacks `paths` after every burst of filesystem events below them.
+fn watch_and_pack(
+    packer: &mut Packer,
+    paths: &[PathBuf],
+    output: &Path,
+    cache: Option<&Path>,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in paths {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+        match format {
+            Format::Text => eprintln!("watching {}", path.display()),
+            Format::Json => println!("{{\"watching\":{}}}", json_path(path)),
+        }
+    }
+    while let Ok(event) = rx.recv() {
+        event?;
+        // Coalesce the events of one save, which editors often spread over several writes.
+        while rx.recv_timeout(Duration::from_millis(200)).is_ok() {}
+        if let Err(err) = pack(packer, paths, output, cache, format) {
+            print_error(&*err, format);
+        }
+    }
+    Ok(())
+}
+
+fn json_path(path: &Path) -> String {
+    json::string(&path.to_string_lossy())
+}
@@ -0,0 +1,959 @@
+//! The crate's own block type, [`CarBlock`], and reading and writing archives at the framing
+//! level with it: sections are split into CIDs and bytes, without decoding or verifying them.
+//! The section and header framing the rest of the crate reads and writes libipld blocks with is
+//! here too.
+
+#[cfg(feature = "ipld")]
+use std::io::Cursor;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use cid::Cid;
+#[cfg(feature = "ipld")]
+use libipld::cbor::DagCborCodec;
+#[cfg(feature = "ipld")]
+use libipld::prelude::Codec;
+#[cfg(feature = "ipld")]
+use libipld::store::StoreParams;
+#[cfg(feature = "ipld")]
+use libipld::{Block, DefaultParams};
+
+#[cfg(feature = "ipld")]
+use crate::v1::{self, CarHeaderV1};
+use crate::{
+    cbor, read_length_prefixed, write_varint, CarError, CarResult, CountingReader,
+    CHARACTERISTICS_LENGTH, DEFAULT_MAX_SECTION_SIZE, HEADER_LENGTH,
+};
+#[cfg(feature = "ipld")]
+use crate::{HashPolicy, ReadOptions, ReadReport};
+
+/// A CID and the bytes of its block, as framed in an archive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CarBlock {
+    pub cid: Cid,
+    pub data: Vec<u8>,
+}
+
+impl CarBlock {
+    pub fn new(cid: Cid, data: Vec<u8>) -> Self {
+        Self { cid, data }
+    }
+
+    /// Converts to a libipld block, checking the data against the CID as
+    /// [`crate::HashPolicy::default`] does. A block that does not match fails with
+    /// [`CarError::HashMismatch`] at offset 0, as it is not placed in an archive; readers that
+    /// know where its section is verify it with [`crate::HashPolicy::block`] instead.
+    #[cfg(feature = "ipld")]
+    pub fn into_block(self) -> CarResult<Block<DefaultParams>> {
+        Ok(crate::HashPolicy::default()
+            .block(self.cid, self.data, 0)?
+            .0)
+    }
+
+    #[cfg(feature = "ipld")]
+    pub fn into_block_unchecked(self) -> Block<DefaultParams> {
+        Block::new_unchecked(self.cid, self.data)
+    }
+
+    /// Writes the `varint | CID | data` section of the block.
+    pub fn write_to<W: Write>(&self, mut w: W) -> CarResult<()> {
+        let cid = self.cid.to_bytes();
+        write_varint(&mut w, (cid.len() + self.data.len()) as u64)?;
+        w.write_all(&cid)?;
+        w.write_all(&self.data)?;
+        Ok(())
+    }
+}
+
+impl From<(Cid, Vec<u8>)> for CarBlock {
+    fn from((cid, data): (Cid, Vec<u8>)) -> Self {
+        Self { cid, data }
+    }
+}
+
+impl From<CarBlock> for (Cid, Vec<u8>) {
+    fn from(block: CarBlock) -> Self {
+        (block.cid, block.data)
+    }
+}
+
+#[cfg(feature = "ipld")]
+impl From<Block<DefaultParams>> for CarBlock {
+    fn from(block: Block<DefaultParams>) -> Self {
+        let (cid, data) = block.into_inner();
+        Self { cid, data }
+    }
+}
+
+/// Where a block's section is in an archive, e.g. for building an external index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockLocation {
+    /// The offset of the section from the start of the archive, which for a CARv2 is before
+    /// its header rather than its payload.
+    pub offset: u64,
+    /// The length of the whole `varint | CID | data` section.
+    pub length: u64,
+}
+
+/// Reads the blocks of a CARv1, or of the CARv1 payload of a CARv2, one section at a time.
+///
+/// Blocks are left as they were framed: nothing is hashed and no codec or size limit of a
+/// libipld `StoreParams` applies, and a block stored twice is read twice. Together with
+/// [`write_car_blocks`] this copies or transports sections that blocks could not hold.
+#[derive(Debug)]
+pub struct CarBlockReader<R> {
+    r: CountingReader<R>,
+    roots: Vec<Cid>,
+    /// Where the first section starts.
+    start: u64,
+    /// Where the payload starts, which section offsets in an index are counted from.
+    payload: u64,
+    /// Where the payload ends, for a CARv2.
+    end: Option<u64>,
+    max_section_size: u64,
+    section_timeout: Option<Duration>,
+}
+
+impl<R: Read> CarBlockReader<R> {
+    /// Reads the header of an archive, skipping to the payload of a CARv2.
+    pub fn new(r: R) -> CarResult<Self> {
+        let mut r = CountingReader::new(r);
+        let mut end = None;
+        let mut payload = 0;
+        let roots = match read_header(&mut r)? {
+            Header::V1(roots) => roots,
+            Header::V2 => {
+                let mut buf = [0; HEADER_LENGTH];
+                r.read_exact(&mut buf)?;
+                let header = parse_v2_header(buf)?;
+                let skip = header
+                    .data_offset
+                    .checked_sub(r.position())
+                    .ok_or(CarError::InvalidFormat)?;
+                io::copy(&mut (&mut r).take(skip), &mut io::sink())?;
+                end = Some(header.data_range().end);
+                payload = header.data_offset;
+                match read_header(&mut r)? {
+                    Header::V1(roots) => roots,
+                    Header::V2 => return Err(CarError::InvalidFormat),
+                }
+            }
+        };
+        let start = r.position();
+        Ok(Self {
+            r,
+            roots,
+            start,
+            payload,
+            end,
+            max_section_size: DEFAULT_MAX_SECTION_SIZE,
+            section_timeout: None,
+        })
+    }
+
+    /// Fails with [`CarError::SectionTooLarge`] on a section longer than `max` bytes, from its
+    /// length alone, before any of it is read or buffered. [`DEFAULT_MAX_SECTION_SIZE`] by
+    /// default, and `u64::MAX` for no limit.
+    pub fn with_max_section_size(mut self, max: u64) -> Self {
+        self.max_section_size = max;
+        self
+    }
+
+    /// Fails with [`CarError::SectionTimedOut`] if reading a section, from the first byte of its
+    /// length, takes longer than `timeout`, so that a peer trickling bytes cannot hold the reader
+    /// indefinitely.
+    ///
+    /// The time is checked after every read of the underlying reader, so a read that never
+    /// returns still needs a timeout of its own, such as [`std::net::TcpStream::set_read_timeout`].
+    pub fn with_section_timeout(mut self, timeout: Duration) -> Self {
+        self.section_timeout = Some(timeout);
+        self
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// The offset from the start of the archive of the next section.
+    pub fn position(&self) -> u64 {
+        self.r.position()
+    }
+
+    /// Where the payload starts: 0 for a CARv1, and the data offset of a CARv2.
+    pub(crate) fn payload(&self) -> u64 {
+        self.payload
+    }
+
+    /// Where the payload ends, for a CARv2.
+    pub(crate) fn end(&self) -> Option<u64> {
+        self.end
+    }
+
+    /// Reads the next block, along with where its section is in the archive.
+    pub fn next_block_with_location(&mut self) -> CarResult<Option<(CarBlock, BlockLocation)>> {
+        let offset = self.r.position();
+        if self.end.is_some_and(|end| offset >= end) {
+            return Ok(None);
+        }
+        let deadline = self
+            .section_timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut r = GuardedReader {
+            r: &mut self.r,
+            deadline,
+            offset,
+        };
+        let length = match crate::read_varint_lenient(&mut r).map_err(|err| r.error(err))? {
+            Some((length, _)) => length,
+            None => return Ok(None),
+        };
+        if length > self.max_section_size {
+            return Err(CarError::SectionTooLarge {
+                offset,
+                length,
+                limit: self.max_section_size,
+            });
+        }
+        let mut section = read_length_prefixed(&mut r, length).map_err(|err| r.error(err))?;
+        let mut data = &section[..];
+        let cid = Cid::read_bytes(&mut data)?;
+        let cid_length = section.len() - data.len();
+        section.drain(..cid_length);
+        let location = BlockLocation {
+            offset,
+            length: self.r.position() - offset,
+        };
+        Ok(Some((CarBlock::new(cid, section), location)))
+    }
+}
+
+/// Fails reads of the section at `offset` once `deadline` has passed.
+struct GuardedReader<'a, R> {
+    r: &'a mut CountingReader<R>,
+    deadline: Option<Instant>,
+    offset: u64,
+}
+
+impl<R> GuardedReader<'_, R> {
+    fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// The error to fail with after `err`, which may be the reader giving up on the deadline.
+    fn error(&self, err: impl Into<CarError>) -> CarError {
+        match self.timed_out() {
+            true => CarError::SectionTimedOut {
+                offset: self.offset,
+            },
+            false => err.into(),
+        }
+    }
+}
+
+impl<R: Read> Read for GuardedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.timed_out() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.r.read(buf)
+    }
+}
+
+impl<R: Read + Seek> CarBlockReader<R> {
+    /// Moves to the section at `offset`, as [`CarBlockReader::position`] counts it, so that it
+    /// is the next one read. Fails with [`CarError::IndexOutOfBounds`] if it is before the
+    /// first section or past the end of a CARv2 payload; that a section starts there is left
+    /// to the next read to check.
+    pub fn seek_to_offset(&mut self, offset: u64) -> CarResult<()> {
+        if offset < self.start || self.end.is_some_and(|end| offset > end) {
+            return Err(CarError::IndexOutOfBounds(offset));
+        }
+        self.r.seek_to(offset)?;
+        Ok(())
+    }
+
+    /// Moves to the `index`th section, counting from zero, so that it is the next one read,
+    /// and returns its offset. The sections before it are skipped over from the first, reading
+    /// only their lengths. `None`, leaving the reader at the end, if there are fewer sections.
+    pub fn seek_to_block(&mut self, index: usize) -> CarResult<Option<u64>> {
+        self.r.seek_to(self.start)?;
+        let mut skipped = 0;
+        loop {
+            let offset = self.r.position();
+            if self.end.is_some_and(|end| offset >= end) {
+                return Ok(None);
+            }
+            let length = match crate::read_varint_lenient(&mut self.r)? {
+                Some((length, _)) => length,
+                None => return Ok(None),
+            };
+            if skipped == index {
+                self.r.seek_to(offset)?;
+                return Ok(Some(offset));
+            }
+            let next = self.r.position().saturating_add(length);
+            self.r.seek_to(next)?;
+            skipped += 1;
+        }
+    }
+}
+
+/// How many blocks an archive holds, see [`quick_count`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct BlockCount {
+    pub blocks: u64,
+    /// The sum of the data sizes of the blocks, without their CIDs and framing.
+    pub bytes: u64,
+}
+
+/// Counts the blocks of a CARv1, or of the payload of a CARv2, and their data bytes, reading
+/// only the length and CID of each section and seeking past its data. Meant for progress bars
+/// and capacity planning: nothing is decoded or verified, and a block stored twice counts
+/// twice.
+///
+/// Fails with [`io::ErrorKind::UnexpectedEof`] if the last section is cut short, as reading it
+/// would.
+pub fn quick_count<R: Read + Seek>(mut r: R) -> CarResult<BlockCount> {
+    let here = r.stream_position()?;
+    let len = r.seek(io::SeekFrom::End(0))? - here;
+    r.seek(io::SeekFrom::Start(here))?;
+    let mut reader = CarBlockReader::new(r)?;
+    let end = reader.end.map_or(len, |end| end.min(len));
+    let mut count = BlockCount::default();
+    loop {
+        let offset = reader.r.position();
+        if reader.end.is_some_and(|end| offset >= end) {
+            return Ok(count);
+        }
+        let length = match crate::read_varint_lenient(&mut reader.r)? {
+            Some((length, _)) => length,
+            None => return Ok(count),
+        };
+        let data_start = reader.r.position();
+        let next = data_start
+            .checked_add(length)
+            .ok_or(CarError::InvalidFormat)?;
+        if next > end {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Cid::read_bytes(&mut reader.r)?;
+        let data = next
+            .checked_sub(reader.r.position())
+            .ok_or(CarError::InvalidFormat)?;
+        reader.r.seek_to(next)?;
+        count.blocks += 1;
+        count.bytes += data;
+    }
+}
+
+impl<R: Read> Iterator for CarBlockReader<R> {
+    type Item = CarResult<CarBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block_with_location()
+            .map(|block| block.map(|(block, _)| block))
+            .transpose()
+    }
+}
+
+/// Writes a CARv1 with `roots` and `blocks`, framed as they are, e.g. as [`CarBlockReader`] reads
+/// them.
+pub fn write_car_blocks<'a, W, I>(mut w: W, roots: &[Cid], blocks: I) -> CarResult<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a CarBlock>,
+{
+    let header = cbor::encode_header(roots);
+    write_varint(&mut w, header.len() as u64)?;
+    w.write_all(&header)?;
+    for block in blocks {
+        block.write_to(&mut w)?;
+    }
+    Ok(())
+}
+
+/// The header of a CARv1 with only `version: 2`, which starts every CARv2.
+pub const PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x02,
+];
+
+/// The `fully-indexed` bit of the first characteristics byte.
+pub(crate) const FULLY_INDEXED: u8 = 0b1000_0000;
+
+/// An IPLD Content Archive Header Version 2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarHeaderV2 {
+    pub characteristics: [u8; CHARACTERISTICS_LENGTH],
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub index_offset: u64,
+}
+
+impl CarHeaderV2 {
+    /// Whether the `fully-indexed` characteristic is set.
+    pub fn is_fully_indexed(&self) -> bool {
+        self.characteristics[0] & FULLY_INDEXED != 0
+    }
+
+    /// The 40 bytes of the header, as [`parse_v2_header`] reads them.
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[..CHARACTERISTICS_LENGTH].copy_from_slice(&self.characteristics);
+        let offsets = &mut bytes[CHARACTERISTICS_LENGTH..];
+        LittleEndian::write_u64(&mut offsets[0..8], self.data_offset);
+        LittleEndian::write_u64(&mut offsets[8..16], self.data_size);
+        LittleEndian::write_u64(&mut offsets[16..24], self.index_offset);
+        bytes
+    }
+
+    /// Whether the archive has an index, i.e. `index_offset` is not zero.
+    pub fn has_index(&self) -> bool {
+        self.index_offset != 0
+    }
+
+    /// Byte range of the inner CARv1 within the archive. Its end saturates, rather than
+    /// overflowing, for a size that could not fit in any archive.
+    pub fn data_range(&self) -> Range<u64> {
+        self.data_offset..self.data_offset.saturating_add(self.data_size)
+    }
+
+    /// Byte range of the index within an archive of `file_len` bytes, which runs to the end of
+    /// the file. `None` if there is no index or it starts past the end.
+    pub fn index_range(&self, file_len: u64) -> Option<Range<u64>> {
+        (self.has_index() && self.index_offset <= file_len).then_some(self.index_offset..file_len)
+    }
+}
+
+/// Reads the 40 bytes of a CARv2 header, as [`CarHeaderV2::to_bytes`] writes them.
+pub fn parse_v2_header(header: [u8; HEADER_LENGTH]) -> CarResult<CarHeaderV2> {
+    Ok(CarHeaderV2 {
+        characteristics: header[0..CHARACTERISTICS_LENGTH].try_into()?,
+        data_offset: LittleEndian::read_u64(
+            &header[CHARACTERISTICS_LENGTH..CHARACTERISTICS_LENGTH + 8],
+        ),
+        data_size: LittleEndian::read_u64(
+            &header[CHARACTERISTICS_LENGTH + 8..CHARACTERISTICS_LENGTH + 16],
+        ),
+        index_offset: LittleEndian::read_u64(&header[CHARACTERISTICS_LENGTH + 16..HEADER_LENGTH]),
+    })
+}
+
+/// Reads the blocks of the sections in `r` up to its end, such as those after the header of a
+/// CARv1, verifying them against their CIDs.
+#[cfg(feature = "ipld")]
+pub fn read_car_v1_data<R: Read>(r: R) -> CarResult<Vec<Block<DefaultParams>>> {
+    read_car_v1_data_with_options(r, &ReadOptions::default())
+}
+
+/// Like [`read_car_v1_data`], following `options`.
+#[cfg(feature = "ipld")]
+pub fn read_car_v1_data_with_options<R: Read>(
+    r: R,
+    options: &ReadOptions,
+) -> CarResult<Vec<Block<DefaultParams>>> {
+    v1::read_sections(
+        &mut CountingReader::new(r),
+        options,
+        &mut ReadReport::default(),
+    )
+}
+
+/// Writes the section of each of `blocks`, as [`write_car_v1_block`] does.
+#[cfg(feature = "ipld")]
+pub fn write_car_v1_data<'a, W, I, S>(mut w: W, blocks: I) -> CarResult<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Block<S>>,
+    S: StoreParams,
+{
+    for block in blocks {
+        write_car_v1_block(&mut w, block)?;
+    }
+    Ok(())
+}
+
+/// Writes a single `varint | CID | data` section.
+#[cfg(feature = "ipld")]
+pub fn write_car_v1_block<W: Write, S: StoreParams>(mut w: W, block: &Block<S>) -> CarResult<()> {
+    let cid_bytes = block.cid().to_bytes();
+    write_varint(&mut w, (cid_bytes.len() + block.data().len()) as u64)?;
+    w.write_all(&cid_bytes)?;
+    w.write_all(block.data())?;
+    Ok(())
+}
+
+/// A push-based CARv1 parser that does no IO itself: bytes are fed with [`CarV1Decoder::push`]
+/// as they arrive and blocks are taken out once complete.
+#[cfg(feature = "ipld")]
+#[derive(Debug, Clone)]
+pub struct CarV1Decoder {
+    buf: Vec<u8>,
+    pos: usize,
+    /// The offset in the CARv1 of the start of `buf`.
+    offset: u64,
+    header: Option<CarHeaderV1>,
+    max_section_size: u64,
+    section_timeout: Option<Duration>,
+    /// When the first byte of the next section arrived, if it has.
+    started: Option<Instant>,
+}
+
+#[cfg(feature = "ipld")]
+impl Default for CarV1Decoder {
+    fn default() -> Self {
+        Self {
+            buf: vec![],
+            pos: 0,
+            offset: 0,
+            header: None,
+            max_section_size: DEFAULT_MAX_SECTION_SIZE,
+            section_timeout: None,
+            started: None,
+        }
+    }
+}
+
+#[cfg(feature = "ipld")]
+impl CarV1Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails with [`CarError::SectionTooLarge`] on a section, the header included, longer than
+    /// `max` bytes, as soon as its length is pushed. [`DEFAULT_MAX_SECTION_SIZE`] by default,
+    /// and `u64::MAX` for no limit.
+    pub fn with_max_section_size(mut self, max: u64) -> Self {
+        self.max_section_size = max;
+        self
+    }
+
+    /// Fails with [`CarError::SectionTimedOut`] if a section is still incomplete `timeout` after
+    /// its first byte was pushed, or after the section before it was taken out if that was
+    /// later.
+    ///
+    /// The time is only checked when a block is asked for, so input that stops arriving
+    /// altogether still needs a timeout of its own.
+    pub fn with_section_timeout(mut self, timeout: Duration) -> Self {
+        self.section_timeout = Some(timeout);
+        self
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.pos > 0 && self.pos >= self.buf.len() / 2 {
+            self.buf.drain(..self.pos);
+            self.offset += self.pos as u64;
+            self.pos = 0;
+        }
+        if self.started.is_none() && !bytes.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The header, once enough bytes were pushed to decode it.
+    pub fn header(&self) -> Option<&CarHeaderV1> {
+        self.header.as_ref()
+    }
+
+    /// Decodes the next block, or returns `None` until more bytes are pushed.
+    pub fn next_block(&mut self) -> CarResult<Option<Block<DefaultParams>>> {
+        if self.header.is_none() {
+            match self.next_section()? {
+                Some(section) => {
+                    let header = CarHeaderV1::from_ipld(DagCborCodec.decode(section)?)?;
+                    self.header = Some(header);
+                }
+                None => return Ok(None),
+            }
+        }
+        let offset = self.offset + self.pos as u64;
+        match self.next_section()? {
+            Some(section) => {
+                let mut section = Cursor::new(section);
+                let cid = Cid::read_bytes(&mut section)?;
+                let data = &section.get_ref()[section.position() as usize..];
+                Ok(Some(
+                    HashPolicy::default().block(cid, data.to_vec(), offset)?.0,
+                ))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that the input ended between two sections, after the header.
+    pub fn finish(&self) -> CarResult<()> {
+        if self.header.is_none() || self.pos < self.buf.len() {
+            return Err(CarError::InvalidFormat);
+        }
+        Ok(())
+    }
+
+    fn next_section(&mut self) -> CarResult<Option<&[u8]>> {
+        let offset = self.offset + self.pos as u64;
+        let (length, rest) = match unsigned_varint::decode::u64(&self.buf[self.pos..]) {
+            Ok((length, rest)) => (length, rest.len()),
+            Err(unsigned_varint::decode::Error::Insufficient) => return self.incomplete(offset),
+            Err(_) => return Err(CarError::InvalidFormat),
+        };
+        if length > self.max_section_size {
+            return Err(CarError::SectionTooLarge {
+                offset,
+                length,
+                limit: self.max_section_size,
+            });
+        }
+        let start = self.buf.len() - rest;
+        if (rest as u64) < length {
+            return self.incomplete(offset);
+        }
+        self.pos = start + length as usize;
+        self.started = (self.pos < self.buf.len()).then(Instant::now);
+        Ok(Some(&self.buf[start..self.pos]))
+    }
+
+    /// No section yet, failing if the one at `offset` has been arriving for too long.
+    fn incomplete(&self, offset: u64) -> CarResult<Option<&[u8]>> {
+        let started = self.started.filter(|_| self.pos < self.buf.len());
+        match (started, self.section_timeout) {
+            (Some(started), Some(timeout)) if started.elapsed() >= timeout => {
+                Err(CarError::SectionTimedOut { offset })
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+enum Header {
+    V1(Vec<Cid>),
+    V2,
+}
+
+fn read_header<R: Read>(r: &mut R) -> CarResult<Header> {
+    let length = unsigned_varint::io::read_u64(&mut *r)?;
+    let buf = read_length_prefixed(&mut *r, length)?;
+    match cbor::decode_header(&buf)? {
+        cbor::Header {
+            version: 1,
+            roots: Some(roots),
+        } => Ok(Header::V1(roots)),
+        cbor::Header { version: 2, .. } => Ok(Header::V2),
+        cbor::Header { version: 1, .. } => Err(CarError::InvalidFormat),
+        cbor::Header { version, .. } => Err(CarError::UnsupportedVersion(version as u8)),
+    }
+}
+
+#[cfg(all(test, feature = "ipld"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::diamond;
+
+    #[test]
+    fn it_derives_header_ranges() {
+        let mut header = CarHeaderV2 {
+            characteristics: [0; CHARACTERISTICS_LENGTH],
+            data_offset: 51,
+            data_size: 448,
+            index_offset: 499,
+        };
+        assert!(header.has_index());
+        assert!(!header.is_fully_indexed());
+        assert_eq!(header.data_range(), 51..499);
+        assert_eq!(header.index_range(600), Some(499..600));
+        assert_eq!(header.index_range(400), None);
+
+        header.index_offset = 0;
+        assert!(!header.has_index());
+        assert_eq!(header.index_range(600), None);
+
+        // The characteristic is the most significant bit of the first byte alone.
+        header.characteristics[0] = FULLY_INDEXED;
+        assert!(header.is_fully_indexed());
+        header.characteristics[0] = !FULLY_INDEXED;
+        header.characteristics[1..].fill(0xff);
+        assert!(!header.is_fully_indexed());
+    }
+
+    #[test]
+    fn it_reads_and_writes_frames() {
+        let car = diamond();
+        let blocks: Vec<CarBlock> = car.blocks.iter().cloned().map(CarBlock::from).collect();
+        let mut bytes = vec![];
+        write_car_blocks(&mut bytes, &car.header.roots, &blocks).unwrap();
+        let mut expected = vec![];
+        car.write_to(&mut expected).unwrap();
+        assert_eq!(bytes, expected);
+
+        let reader = CarBlockReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.roots(), &car.header.roots[..]);
+        let read: Vec<_> = reader.collect::<CarResult<_>>().unwrap();
+        assert_eq!(read, blocks);
+        assert_eq!(read[0].clone().into_block().unwrap(), car.blocks[0]);
+
+        let mut reader = CarBlockReader::new(&bytes[..]).unwrap();
+        let mut end = reader.position();
+        while let Some((block, location)) = reader.next_block_with_location().unwrap() {
+            assert_eq!(location.offset, end);
+            let mut section = vec![];
+            block.write_to(&mut section).unwrap();
+            assert_eq!(
+                bytes[location.offset as usize..][..location.length as usize],
+                section
+            );
+            end += location.length;
+        }
+        assert_eq!(end, bytes.len() as u64);
+
+        let forged = CarBlock::new(read[0].cid, b"forged".to_vec());
+        assert!(forged.clone().into_block().is_err());
+        assert_eq!(forged.into_block_unchecked().data(), b"forged");
+    }
+
+    #[test]
+    fn it_copies_sections_without_validating_them() {
+        use libipld::multihash::{Code, MultihashDigest};
+
+        let car = diamond();
+        // An unknown codec and a hash that does not match, neither of which a block allows.
+        let forged = Cid::new_v1(0x3000_0000, Code::Sha2_256.digest(b"other"));
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        CarBlock::new(forged, b"forged".to_vec())
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let reader = CarBlockReader::new(&bytes[..]).unwrap();
+        let roots = reader.roots().to_vec();
+        let blocks = reader.collect::<CarResult<Vec<_>>>().unwrap();
+        assert_eq!(roots, car.header.roots);
+        assert_eq!(blocks.len(), car.blocks.len() + 1);
+        assert_eq!(blocks[0], car.blocks[0].clone().into());
+        let (cid, data) = blocks[4].clone().into();
+        assert_eq!((cid, data), (forged, b"forged".to_vec()));
+        assert!(blocks[4].clone().into_block().is_err());
+
+        let mut copy = vec![];
+        write_car_blocks(&mut copy, &roots, &blocks).unwrap();
+        assert_eq!(copy, bytes);
+    }
+
+    #[test]
+    fn it_seeks_to_sections() {
+        use std::io::{Cursor, Seek, SeekFrom};
+
+        let car = diamond();
+        // Behind a prefix, to check offsets are counted from where the reader started.
+        let mut bytes = b"prefix".to_vec();
+        car.write_to(&mut bytes).unwrap();
+        let mut r = Cursor::new(&bytes);
+        r.seek(SeekFrom::Start(6)).unwrap();
+        let mut reader = CarBlockReader::new(r).unwrap();
+        let start = reader.position();
+        let locations: Vec<_> = std::iter::from_fn(|| reader.next_block_with_location().unwrap())
+            .map(|(_, location)| location)
+            .collect();
+
+        assert_eq!(reader.seek_to_block(2).unwrap(), Some(locations[2].offset));
+        assert_eq!(reader.next().unwrap().unwrap().cid, *car.blocks[2].cid());
+        assert_eq!(reader.seek_to_block(0).unwrap(), Some(start));
+        assert_eq!(reader.seek_to_block(4).unwrap(), None);
+        assert!(reader.next().is_none());
+
+        reader.seek_to_offset(locations[1].offset).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().cid, *car.blocks[1].cid());
+        assert_eq!(reader.next().unwrap().unwrap().cid, *car.blocks[2].cid());
+        assert!(matches!(
+            reader.seek_to_offset(start - 1),
+            Err(CarError::IndexOutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn it_guards_the_size_and_reading_time_of_sections() {
+        /// Trickles out one byte at a time, slower after `fast` bytes.
+        struct Trickle<'a> {
+            bytes: &'a [u8],
+            fast: usize,
+        }
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.fast == 0 {
+                    std::thread::sleep(Duration::from_millis(20));
+                } else {
+                    self.fast -= 1;
+                }
+                let read = (&self.bytes[..self.bytes.len().min(1)]).read(buf)?;
+                self.bytes = &self.bytes[read..];
+                Ok(read)
+            }
+        }
+
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let mut reader = CarBlockReader::new(&bytes[..])
+            .unwrap()
+            .with_max_section_size(40);
+        let offset = reader.position();
+        // The leaf is small enough, the right node is not.
+        let (_, leaf) = reader.next_block_with_location().unwrap().unwrap();
+        assert!(matches!(
+            reader.next_block_with_location(),
+            Err(CarError::SectionTooLarge { offset: at, limit: 40, .. })
+                if at == offset + leaf.length
+        ));
+
+        let header = CarBlockReader::new(&bytes[..]).unwrap().position() as usize;
+        let trickle = Trickle {
+            bytes: &bytes,
+            fast: header + 10,
+        };
+        let mut reader = CarBlockReader::new(trickle)
+            .unwrap()
+            .with_section_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            reader.next_block_with_location(),
+            Err(CarError::SectionTimedOut { offset }) if offset == header as u64
+        ));
+
+        let reader = CarBlockReader::new(&bytes[..])
+            .unwrap()
+            .with_section_timeout(Duration::from_secs(60));
+        assert_eq!(reader.count(), 4);
+    }
+
+    #[test]
+    fn it_fails_on_lengths_longer_than_the_input() {
+        let mut bytes = vec![];
+        diamond().write_to(&mut bytes).unwrap();
+        // 2^62 - 1 bytes, which are not allocated before they are read.
+        let hostile = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3f, 0x01];
+        let section = CarBlockReader::new(&bytes[..]).unwrap().position() as usize;
+        let mut sections = bytes.clone();
+        sections.truncate(section);
+        sections.extend(hostile);
+        let header = [&hostile[..], &bytes[section..]].concat();
+        assert!(matches!(
+            CarBlockReader::new(&sections[..]).unwrap().next(),
+            Some(Err(CarError::SectionTooLarge { .. }))
+        ));
+        for bytes in [sections, header] {
+            let err = CarBlockReader::new(&bytes[..])
+                .and_then(|reader| reader.with_max_section_size(u64::MAX).next().transpose())
+                .unwrap_err();
+            assert!(
+                matches!(&err, CarError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn it_counts_blocks_without_reading_them() {
+        use std::io::Cursor;
+
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert_eq!(
+            quick_count(Cursor::new(&bytes)).unwrap(),
+            BlockCount {
+                blocks: 4,
+                bytes: car
+                    .blocks
+                    .iter()
+                    .map(|block| block.data().len() as u64)
+                    .sum(),
+            }
+        );
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            quick_count(Cursor::new(truncated)),
+            Err(CarError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_reads_car_v2_payloads() {
+        use crate::ContentArchive;
+        use std::io::Cursor;
+
+        let bytes = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let car = ContentArchive::read_bytes(Cursor::new(&bytes[..]))
+            .unwrap()
+            .into_car_v1();
+        let reader = CarBlockReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.roots(), &car.header.roots[..]);
+        let read: Vec<_> = reader.collect::<CarResult<_>>().unwrap();
+        assert_eq!(read.len(), car.blocks.len());
+        assert!(read.iter().zip(&car.blocks).all(|(a, b)| a.cid == *b.cid()));
+
+        assert_eq!(
+            quick_count(Cursor::new(&bytes[..])).unwrap().blocks,
+            read.len() as u64
+        );
+
+        // Seeks stay within the payload, before the index.
+        let mut reader = CarBlockReader::new(Cursor::new(&bytes[..])).unwrap();
+        assert!(reader.seek_to_block(read.len() - 1).unwrap().is_some());
+        assert_eq!(reader.seek_to_block(read.len()).unwrap(), None);
+        assert!(reader.seek_to_offset(bytes.len() as u64).is_err());
+    }
+
+    #[test]
+    fn it_decodes_pushed_bytes() {
+        let car = crate::test_utils::diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let mut decoder = CarV1Decoder::new();
+        let mut blocks = vec![];
+        assert!(decoder.finish().is_err());
+        for chunk in bytes.chunks(7) {
+            decoder.push(chunk);
+            while let Some(block) = decoder.next_block().unwrap() {
+                blocks.push(block);
+            }
+        }
+        decoder.finish().unwrap();
+        assert_eq!(decoder.header().unwrap().roots, car.header.roots);
+        assert_eq!(blocks, car.blocks);
+
+        decoder.push(&bytes[bytes.len() - 3..]);
+        assert!(decoder.next_block().unwrap().is_none());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn it_guards_pushed_sections() {
+        let car = crate::test_utils::diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let header = CarBlockReader::new(&bytes[..]).unwrap().position() as usize;
+
+        // The header and the leaf are short enough, the right node is not.
+        let mut decoder = CarV1Decoder::new().with_max_section_size(60);
+        decoder.push(&bytes);
+        assert_eq!(decoder.next_block().unwrap().as_ref(), Some(&car.blocks[0]));
+        assert!(matches!(
+            decoder.next_block(),
+            Err(CarError::SectionTooLarge { limit: 60, .. })
+        ));
+
+        let mut decoder = CarV1Decoder::new().with_section_timeout(Duration::from_millis(50));
+        decoder.push(&bytes[..header + 10]);
+        assert!(decoder.next_block().unwrap().is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        decoder.push(&bytes[header + 10..header + 11]);
+        assert!(matches!(
+            decoder.next_block(),
+            Err(CarError::SectionTimedOut { offset }) if offset == header as u64
+        ));
+    }
+}
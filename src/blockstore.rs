@@ -0,0 +1,445 @@
+//! Read-only blockstores over archives, for serving an archive as the storage of a node, see
+//! [`CarBlockStore`], [`CarV2Store`] and [`MultiCarStore`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::block::write_car_v1_block;
+use crate::gateway::BlockFetcher;
+use crate::index::CarV2Index;
+use crate::v1::{CarHeaderV1, IndexedCarV1};
+use crate::v2::CarV2Reader;
+use crate::{CarResult, HashPolicy};
+
+/// A read-only store of the blocks of an archive: a [`BlockFetcher`] that knows the roots of
+/// what it fetches from and hands out verified blocks.
+pub trait CarBlockStore: BlockFetcher {
+    /// The roots of the archive.
+    fn roots(&self) -> &[Cid];
+
+    /// Whether the store has the block `cid`, by fetching it unless the store can tell
+    /// without reading it.
+    fn has(&self, cid: &Cid) -> CarResult<bool> {
+        Ok(self.fetch(cid)?.is_some())
+    }
+
+    /// The block `cid`, verified against its CID as [`HashPolicy::default`] does, or `None` if
+    /// the store does not have it.
+    fn get(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        match self.fetch(cid)? {
+            Some(data) => Ok(Some(HashPolicy::default().block(*cid, data, 0)?.0)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A [`CarBlockStore`] over a CARv2, reading blocks on demand through its index, as
+/// [`CarV2Reader::get_block`] does, so only the index is held in memory. An archive without an
+/// index is indexed when opened.
+///
+/// Lookups from several threads take turns reading the archive.
+#[derive(Debug)]
+pub struct CarV2Store<R> {
+    reader: Mutex<CarV2Reader<R>>,
+    roots: Vec<Cid>,
+}
+
+impl CarV2Store<BufReader<File>> {
+    pub fn open(path: &Path) -> CarResult<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> CarV2Store<R> {
+    pub fn new(r: R) -> CarResult<Self> {
+        Ok(Self::from_reader(CarV2Reader::new(r)?))
+    }
+
+    pub fn from_reader(reader: CarV2Reader<R>) -> Self {
+        Self {
+            roots: reader.roots().to_vec(),
+            reader: Mutex::new(reader),
+        }
+    }
+
+    /// Runs `f` with the index of the archive.
+    pub fn with_index<T>(&self, f: impl FnOnce(&CarV2Index) -> T) -> T {
+        f(self.reader().index())
+    }
+
+    fn reader(&self) -> std::sync::MutexGuard<'_, CarV2Reader<R>> {
+        // A panic while reading leaves nothing half-updated: every read seeks first.
+        self.reader.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<R: Read + Seek> BlockFetcher for CarV2Store<R> {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        Ok(self.get(cid)?.map(|block| block.data().to_vec()))
+    }
+}
+
+impl<R: Read + Seek> CarBlockStore for CarV2Store<R> {
+    fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Whether the index has the multihash of `cid`, which a corrupt index may claim for a
+    /// section that [`CarBlockStore::get`] then finds to be of another.
+    fn has(&self, cid: &Cid) -> CarResult<bool> {
+        Ok(self.with_index(|index| index.lookup(cid).is_some()))
+    }
+
+    fn get(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        self.reader().get_block(cid)
+    }
+}
+
+/// The blocks of an archive in memory, looked up by CID, which were verified when it was read.
+impl CarBlockStore for IndexedCarV1<'_> {
+    fn roots(&self) -> &[Cid] {
+        &self.car().header.roots
+    }
+
+    fn has(&self, cid: &Cid) -> CarResult<bool> {
+        Ok(self.contains(cid))
+    }
+
+    fn get(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        Ok(IndexedCarV1::get(self, cid).cloned())
+    }
+}
+
+/// A [`CarBlockStore`] over several stores, asking each in turn for a block.
+///
+/// With a fallback, blocks none of them has are fetched from it, verified and appended to a
+/// delta CARv1, from which they are served afterwards, so the stores work as a read-through
+/// cache of a gateway. Lookups from several threads may fetch the same block at once, but it
+/// is appended once.
+#[derive(Default)]
+pub struct MultiCarStore {
+    stores: Vec<Box<dyn CarBlockStore + Send + Sync>>,
+    roots: Vec<Cid>,
+    fallback: Option<Fallback>,
+}
+
+struct Fallback {
+    fetcher: Box<dyn BlockFetcher + Send + Sync>,
+    delta: Mutex<Delta>,
+}
+
+/// The archive fetched blocks are appended to, with where the data of each is in it.
+struct Delta {
+    path: PathBuf,
+    file: File,
+    data: HashMap<Cid, (u64, usize)>,
+}
+
+impl fmt::Debug for MultiCarStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiCarStore")
+            .field("stores", &self.stores.len())
+            .field("roots", &self.roots)
+            .field("delta", &self.delta_path())
+            .finish()
+    }
+}
+
+impl MultiCarStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `store`, asked after those added before it. Its roots are added to those of the
+    /// store, each once.
+    pub fn with_store<S: CarBlockStore + Send + Sync + 'static>(mut self, store: S) -> Self {
+        for root in store.roots() {
+            if !self.roots.contains(root) {
+                self.roots.push(*root);
+            }
+        }
+        self.stores.push(Box::new(store));
+        self
+    }
+
+    /// Fetches blocks none of the stores has from `fetcher`, appending them to a CARv1 without
+    /// roots created at `delta`, which replaces any file there.
+    pub fn with_fallback<F: BlockFetcher + Send + Sync + 'static>(
+        mut self,
+        fetcher: F,
+        delta: &Path,
+    ) -> CarResult<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(delta)?;
+        CarHeaderV1 { roots: vec![] }.write_to(&mut file)?;
+        self.fallback = Some(Fallback {
+            fetcher: Box::new(fetcher),
+            delta: Mutex::new(Delta {
+                path: delta.to_path_buf(),
+                file,
+                data: HashMap::new(),
+            }),
+        });
+        Ok(self)
+    }
+
+    /// Where fetched blocks are appended, if there is a fallback.
+    pub fn delta_path(&self) -> Option<PathBuf> {
+        self.fallback
+            .as_ref()
+            .map(|fallback| fallback.delta().path.clone())
+    }
+
+    /// The block `cid` from the delta archive, fetching and appending it if it is not there
+    /// yet.
+    fn fetch_missing(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        let Some(fallback) = &self.fallback else {
+            return Ok(None);
+        };
+        if let Some(block) = fallback.delta().read(cid)? {
+            return Ok(Some(block));
+        }
+        let Some(data) = fallback.fetcher.fetch(cid)? else {
+            return Ok(None);
+        };
+        let block = HashPolicy::default().block(*cid, data, 0)?.0;
+        fallback.delta().append(&block)?;
+        Ok(Some(block))
+    }
+}
+
+impl Fallback {
+    fn delta(&self) -> std::sync::MutexGuard<'_, Delta> {
+        // Blocks are indexed only once fully appended, so a panic leaves nothing half-updated.
+        self.delta.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Delta {
+    fn read(&mut self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        let Some(&(offset, len)) = self.data.get(cid) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(HashPolicy::default().block(*cid, data, offset)?.0))
+    }
+
+    fn append(&mut self, block: &Block<DefaultParams>) -> CarResult<()> {
+        if self.data.contains_key(block.cid()) {
+            return Ok(());
+        }
+        let mut section = vec![];
+        write_car_v1_block(&mut section, block)?;
+        let end = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&section)?;
+        let len = block.data().len();
+        self.data
+            .insert(*block.cid(), (end + (section.len() - len) as u64, len));
+        Ok(())
+    }
+}
+
+impl BlockFetcher for MultiCarStore {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        for store in &self.stores {
+            if let Some(data) = store.fetch(cid)? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(self.fetch_missing(cid)?.map(|block| block.into_inner().1))
+    }
+}
+
+impl CarBlockStore for MultiCarStore {
+    fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Whether one of the stores or the delta archive has the block `cid`, without asking the
+    /// fallback.
+    fn has(&self, cid: &Cid) -> CarResult<bool> {
+        for store in &self.stores {
+            if store.has(cid)? {
+                return Ok(true);
+            }
+        }
+        Ok(self
+            .fallback
+            .as_ref()
+            .is_some_and(|fallback| fallback.delta().data.contains_key(cid)))
+    }
+
+    fn get(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        for store in &self.stores {
+            if let Some(block) = store.get(cid)? {
+                return Ok(Some(block));
+            }
+        }
+        self.fetch_missing(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexKind;
+    use crate::test_utils::{diamond, raw, temp_dir};
+    use crate::v1::CarV1;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn it_serves_blocks_of_indexed_archives() {
+        let car = diamond();
+        let mut bytes = Cursor::new(vec![]);
+        car.clone()
+            .into_v2(IndexKind::MultihashSorted)
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+        let store = CarV2Store::new(Cursor::new(bytes.into_inner())).unwrap();
+        assert_eq!(store.roots(), &car.header.roots[..]);
+        let other = raw(b"other");
+        assert!(!store.has(other.cid()).unwrap());
+        assert_eq!(store.get(other.cid()).unwrap(), None);
+
+        // Any store of the same archive answers the same.
+        let check = |store: &dyn CarBlockStore| {
+            for block in &car.blocks {
+                assert!(store.has(block.cid()).unwrap());
+                assert_eq!(store.get(block.cid()).unwrap().as_ref(), Some(block));
+            }
+        };
+        check(&store);
+        check(&car.indexed());
+        assert_eq!(
+            store.fetch(car.blocks[0].cid()).unwrap().as_deref(),
+            Some(car.blocks[0].data())
+        );
+
+        // Lookups can be shared between threads.
+        std::thread::scope(|scope| {
+            for block in &car.blocks {
+                let store = &store;
+                scope.spawn(move || assert!(store.get(block.cid()).unwrap().is_some()));
+            }
+        });
+    }
+
+    /// Blocks by CID, trusting nothing.
+    struct Blocks(std::collections::HashMap<Cid, Vec<u8>>, Vec<Cid>);
+
+    impl BlockFetcher for Blocks {
+        fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+            self.0.fetch(cid)
+        }
+    }
+
+    impl CarBlockStore for Blocks {
+        fn roots(&self) -> &[Cid] {
+            &self.1
+        }
+    }
+
+    #[test]
+    fn it_verifies_fetched_blocks() {
+        let (block, other) = (raw(b"block"), raw(b"other"));
+        let forged = raw(b"forged");
+        let blocks = [
+            (*block.cid(), block.data().to_vec()),
+            (*forged.cid(), b"not forged".to_vec()),
+        ];
+        let store = Blocks(blocks.into_iter().collect(), vec![*block.cid()]);
+        assert!(store.has(block.cid()).unwrap());
+        assert!(!store.has(other.cid()).unwrap());
+        assert_eq!(store.get(block.cid()).unwrap(), Some(block));
+        assert_eq!(store.get(other.cid()).unwrap(), None);
+        assert!(matches!(
+            store.get(forged.cid()),
+            Err(crate::CarError::HashMismatch { .. })
+        ));
+    }
+
+    /// Blocks by CID, counting how often it is asked.
+    struct Remote(std::collections::HashMap<Cid, Vec<u8>>, Arc<AtomicUsize>);
+
+    impl BlockFetcher for Remote {
+        fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.fetch(cid)
+        }
+    }
+
+    #[test]
+    fn it_fetches_missing_blocks_into_a_delta_archive() {
+        let dir = temp_dir("multi-store");
+        let car = diamond();
+        let (local, remote, forged) = (raw(b"local"), raw(b"remote"), raw(b"forged"));
+        let asked = Arc::new(AtomicUsize::new(0));
+        let fallback = Remote(
+            [
+                (*remote.cid(), remote.data().to_vec()),
+                (*forged.cid(), b"not forged".to_vec()),
+            ]
+            .into_iter()
+            .collect(),
+            asked.clone(),
+        );
+        let mut bytes = Cursor::new(vec![]);
+        car.clone()
+            .into_v2(IndexKind::MultihashSorted)
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+        let blocks = [(*local.cid(), local.data().to_vec())];
+        let store = MultiCarStore::new()
+            .with_store(CarV2Store::new(Cursor::new(bytes.into_inner())).unwrap())
+            .with_store(Blocks(
+                blocks.into_iter().collect(),
+                car.header.roots.clone(),
+            ))
+            .with_fallback(fallback, &dir.join("delta.car"))
+            .unwrap();
+        assert_eq!(store.roots(), &car.header.roots[..]);
+
+        assert_eq!(
+            store.get(car.blocks[0].cid()).unwrap().as_ref(),
+            Some(&car.blocks[0])
+        );
+        assert_eq!(store.get(local.cid()).unwrap(), Some(local));
+        assert!(!store.has(remote.cid()).unwrap());
+        assert_eq!(asked.load(Ordering::Relaxed), 0);
+
+        assert_eq!(store.get(remote.cid()).unwrap(), Some(remote.clone()));
+        assert!(store.has(remote.cid()).unwrap());
+        assert_eq!(
+            store.fetch(remote.cid()).unwrap().as_deref(),
+            Some(remote.data())
+        );
+        assert_eq!(asked.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            store.get(forged.cid()),
+            Err(crate::CarError::HashMismatch { .. })
+        ));
+        assert_eq!(store.get(raw(b"nowhere").cid()).unwrap(), None);
+
+        let delta = std::fs::read(store.delta_path().unwrap()).unwrap();
+        let delta = CarV1::from_reader(&delta[..]).unwrap();
+        assert!(delta.header.roots.is_empty());
+        assert_eq!(delta.blocks, vec![remote]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
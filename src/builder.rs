@@ -0,0 +1,234 @@
+//! Building archives out of linked dag-cbor documents, see [`DagBuilder`], or out of roots and
+//! blocks already at hand, see [`CarBuilder`].
+
+use std::collections::HashSet;
+
+use libipld::cid::Cid;
+use libipld::multihash::Code;
+use libipld::{Block, DefaultParams, Ipld, IpldCodec};
+
+use crate::params;
+use crate::traversal::links;
+use crate::v1::{CarHeaderV1, CarV1};
+#[cfg(feature = "v2")]
+use crate::v2::{CarV2, IndexKind};
+use crate::{CarError, CarResult};
+
+/// Collects dag-cbor nodes, each of which may link to nodes added before it, into an archive
+/// rooted at the last one.
+///
+/// Adding a node twice stores it once, but still makes it the last node.
+#[derive(Debug, Clone)]
+pub struct DagBuilder {
+    car: CarV1,
+    added: HashSet<Cid>,
+    last: Option<Cid>,
+}
+
+impl Default for DagBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DagBuilder {
+    pub fn new() -> Self {
+        Self {
+            car: CarV1::new(CarHeaderV1 { roots: vec![] }, vec![]),
+            added: HashSet::new(),
+            last: None,
+        }
+    }
+
+    /// Encodes `node` as dag-cbor and adds it, returning its CID.
+    ///
+    /// Fails with [`CarError::MissingBlock`] if `node` links to a block that was not added.
+    pub fn add(&mut self, node: &Ipld) -> CarResult<Cid> {
+        self.put(params::encode(IpldCodec::DagCbor, Code::Sha2_256, node)?)
+    }
+
+    /// Adds `data` as a raw block, returning its CID.
+    pub fn add_raw(&mut self, data: &[u8]) -> CarResult<Cid> {
+        self.put(params::encode(
+            IpldCodec::Raw,
+            Code::Sha2_256,
+            &Ipld::Bytes(data.to_vec()),
+        )?)
+    }
+
+    /// The CID of the last node added.
+    pub fn last(&self) -> Option<Cid> {
+        self.last
+    }
+
+    /// The archive of every node added, rooted at the last one; `None` if none was.
+    pub fn finish(mut self) -> Option<CarV1> {
+        self.car.header = CarHeaderV1 {
+            roots: vec![self.last?],
+        };
+        Some(self.car)
+    }
+
+    fn put(&mut self, block: Block<DefaultParams>) -> CarResult<Cid> {
+        if let Some(link) = links(&block)?
+            .into_iter()
+            .find(|link| !self.added.contains(link))
+        {
+            return Err(CarError::MissingBlock(link));
+        }
+        let cid = *block.cid();
+        if self.added.insert(cid) {
+            self.car.put_block(block);
+        }
+        self.last = Some(cid);
+        Ok(cid)
+    }
+}
+
+/// Collects roots and blocks, in any order, into an archive that holds every block once and
+/// every root among its blocks.
+#[derive(Debug, Clone, Default)]
+pub struct CarBuilder {
+    roots: Vec<Cid>,
+    blocks: Vec<Block<DefaultParams>>,
+    added: HashSet<Cid>,
+}
+
+impl CarBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `root` to the header, once however often it is added.
+    pub fn add_root(&mut self, root: Cid) -> &mut Self {
+        if !self.roots.contains(&root) {
+            self.roots.push(root);
+        }
+        self
+    }
+
+    /// Adds `block`, unless a block with its CID was added before, and returns its CID.
+    pub fn add_block(&mut self, block: Block<DefaultParams>) -> Cid {
+        let cid = *block.cid();
+        if self.added.insert(cid) {
+            self.blocks.push(block);
+        }
+        cid
+    }
+
+    /// How many distinct blocks were added.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The archive of the roots and blocks, in the order they were added.
+    ///
+    /// Fails with [`CarError::MissingBlock`] if a root was not added as a block.
+    pub fn build_v1(self) -> CarResult<CarV1> {
+        if let Some(root) = self.roots.iter().find(|root| !self.added.contains(root)) {
+            return Err(CarError::MissingBlock(*root));
+        }
+        Ok(CarV1::new(CarHeaderV1 { roots: self.roots }, self.blocks))
+    }
+
+    /// Like [`CarBuilder::build_v1`], wrapped in a CARv2 with an index of `index` if any.
+    #[cfg(feature = "v2")]
+    pub fn build_v2(self, index: Option<IndexKind>) -> CarResult<CarV2> {
+        CarV2::from_car_v1_with_index(self.build_v1()?, index)
+    }
+}
+
+impl Extend<Block<DefaultParams>> for CarBuilder {
+    fn extend<I: IntoIterator<Item = Block<DefaultParams>>>(&mut self, blocks: I) {
+        for block in blocks {
+            self.add_block(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond, raw};
+    use crate::traversal::{traverse, Visit};
+    use libipld::ipld;
+    use std::ops::ControlFlow;
+
+    #[test]
+    fn it_builds_linked_documents() {
+        let mut builder = DagBuilder::new();
+        assert!(builder.clone().finish().is_none());
+        let photo = builder.add_raw(b"jpeg bytes").unwrap();
+        let alice = builder
+            .add(&ipld!({ "name": "alice", "photo": photo }))
+            .unwrap();
+        let bob = builder.add(&ipld!({ "name": "bob" })).unwrap();
+        let team = builder
+            .add(&ipld!({ "members": [alice, bob, alice] }))
+            .unwrap();
+        assert_eq!(builder.add(&ipld!({ "name": "bob" })).unwrap(), bob);
+        let absent = *raw(b"absent").cid();
+        assert!(matches!(
+            builder.add(&ipld!({ "dangling": absent })),
+            Err(CarError::MissingBlock(cid)) if cid == absent
+        ));
+        assert_eq!(
+            builder
+                .add(&ipld!({ "members": [alice, bob, alice] }))
+                .unwrap(),
+            team
+        );
+
+        let car = builder.finish().unwrap();
+        assert_eq!(car.header.roots, vec![team]);
+        assert_eq!(car.blocks.len(), 4);
+        let mut visited = 0;
+        traverse(&car, &car.header.roots, &mut |_: usize, _: &Cid, _: &_| {
+            visited += 1;
+            ControlFlow::Continue(Visit::Descend)
+        })
+        .unwrap();
+        assert_eq!(visited, 4);
+    }
+
+    #[test]
+    fn it_builds_archives_from_roots_and_blocks() {
+        let car = diamond();
+        let [leaf, right, root, left] = cids(&car)[..] else {
+            unreachable!()
+        };
+        let mut builder = CarBuilder::new();
+        builder.add_root(root).add_root(root);
+        builder.extend(car.blocks.iter().rev().cloned());
+        assert_eq!(builder.add_block(car.blocks[0].clone()), leaf);
+        assert_eq!(builder.len(), 4);
+
+        let built = builder.clone().build_v1().unwrap();
+        assert_eq!(built.header.roots, vec![root]);
+        assert_eq!(cids(&built), vec![left, root, right, leaf]);
+        assert!(built.missing_links().unwrap().is_empty());
+
+        let absent = *raw(b"absent").cid();
+        builder.add_root(absent);
+        assert!(matches!(
+            builder.build_v1(),
+            Err(CarError::MissingBlock(cid)) if cid == absent
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_builds_indexed_car_v2_archives() {
+        let car = diamond();
+        let mut builder = CarBuilder::new();
+        builder.add_root(car.header.roots[0]);
+        builder.extend(car.blocks.clone());
+        let built = builder.build_v2(Some(IndexKind::Sorted)).unwrap();
+        assert!(built.is_fully_indexed());
+        assert_eq!(built.into_v1().blocks, car.blocks);
+    }
+}
@@ -0,0 +1,256 @@
+//! Just enough dag-cbor to read and write archive headers without libipld: maps with text
+//! keys, unsigned integers and lists of links. Other values are skipped when decoding, and
+//! can be measured with [`item_length`].
+
+use cid::Cid;
+
+use crate::{CarError, CarResult};
+
+const CID_TAG: u64 = 42;
+/// How deeply values of unknown fields may nest.
+const MAX_DEPTH: usize = 64;
+
+/// The fields of a header the crate knows about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub version: u64,
+    pub roots: Option<Vec<Cid>>,
+}
+
+/// Decodes a dag-cbor header map, requiring `version` and ignoring fields other than `roots`.
+pub(crate) fn decode_header(buf: &[u8]) -> CarResult<Header> {
+    let mut decoder = Decoder { buf };
+    let (major, entries) = decoder.head()?;
+    if major != 5 {
+        return Err(CarError::InvalidFormat);
+    }
+    let mut version = None;
+    let mut roots = None;
+    for _ in 0..entries {
+        match decoder.text()? {
+            "version" => match decoder.head()? {
+                (0, value) => version = Some(value),
+                _ => return Err(CarError::InvalidFormat),
+            },
+            "roots" => roots = Some(decoder.links()?),
+            _ => decoder.skip(0)?,
+        }
+    }
+    if !decoder.buf.is_empty() {
+        return Err(CarError::InvalidFormat);
+    }
+    Ok(Header {
+        version: version.ok_or(CarError::InvalidFormat)?,
+        roots,
+    })
+}
+
+/// Encodes a version 1 header with `roots`, byte for byte as libipld's dag-cbor codec does.
+pub(crate) fn encode_header(roots: &[Cid]) -> Vec<u8> {
+    let mut buf = vec![];
+    head(&mut buf, 5, 2);
+    text(&mut buf, "roots");
+    head(&mut buf, 4, roots.len() as u64);
+    for root in roots {
+        let cid = root.to_bytes();
+        head(&mut buf, 6, CID_TAG);
+        head(&mut buf, 2, cid.len() as u64 + 1);
+        buf.push(0);
+        buf.extend_from_slice(&cid);
+    }
+    text(&mut buf, "version");
+    head(&mut buf, 0, 1);
+    buf
+}
+
+/// The length of the first item in `buf`, `None` if it is malformed or cut off. Nested items
+/// are counted rather than recursed into, so any depth is measured.
+#[cfg_attr(not(feature = "ipld"), allow(dead_code))]
+pub(crate) fn item_length(buf: &[u8]) -> Option<usize> {
+    let mut decoder = Decoder { buf };
+    let mut pending = 1u64;
+    while pending > 0 {
+        pending -= 1;
+        let items = match decoder.head().ok()? {
+            (2 | 3, length) => {
+                decoder.take(length).ok()?;
+                0
+            }
+            (4, length) => length,
+            (5, entries) => entries.checked_mul(2)?,
+            (6, _) => 1,
+            _ => 0,
+        };
+        pending = pending.checked_add(items)?;
+        // Every item takes at least a byte.
+        if pending > decoder.buf.len() as u64 {
+            return None;
+        }
+    }
+    Some(buf.len() - decoder.buf.len())
+}
+
+fn head(buf: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => buf.push(major | value as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(major | 26);
+            buf.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(major | 27);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn text(buf: &mut Vec<u8>, value: &str) {
+    head(buf, 3, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: u64) -> CarResult<&'a [u8]> {
+        if n > self.buf.len() as u64 {
+            return Err(CarError::InvalidFormat);
+        }
+        let (taken, rest) = self.buf.split_at(n as usize);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    /// Reads the major type and argument of the next item. Indefinite lengths are rejected, as
+    /// dag-cbor does not allow them.
+    fn head(&mut self) -> CarResult<(u8, u64)> {
+        let byte = self.take(1)?[0];
+        let value = match byte & 0x1f {
+            info @ 0..=23 => u64::from(info),
+            info @ 24..=27 => self
+                .take(1 << (info - 24))?
+                .iter()
+                .fold(0, |value, byte| value << 8 | u64::from(*byte)),
+            _ => return Err(CarError::InvalidFormat),
+        };
+        Ok((byte >> 5, value))
+    }
+
+    fn text(&mut self) -> CarResult<&'a str> {
+        match self.head()? {
+            (3, length) => Ok(std::str::from_utf8(self.take(length)?)?),
+            _ => Err(CarError::InvalidFormat),
+        }
+    }
+
+    fn links(&mut self) -> CarResult<Vec<Cid>> {
+        let length = match self.head()? {
+            (4, length) => length,
+            _ => return Err(CarError::InvalidFormat),
+        };
+        (0..length)
+            .map(|_| match (self.head()?, self.head()?) {
+                ((6, CID_TAG), (2, length)) => match self.take(length)? {
+                    [0, cid @ ..] => Ok(Cid::try_from(cid)?),
+                    _ => Err(CarError::InvalidFormat),
+                },
+                _ => Err(CarError::InvalidFormat),
+            })
+            .collect()
+    }
+
+    fn skip(&mut self, depth: usize) -> CarResult<()> {
+        if depth > MAX_DEPTH {
+            return Err(CarError::InvalidFormat);
+        }
+        match self.head()? {
+            (2 | 3, length) => {
+                self.take(length)?;
+            }
+            (4, length) => {
+                for _ in 0..length {
+                    self.skip(depth + 1)?;
+                }
+            }
+            (5, entries) => {
+                for _ in 0..entries {
+                    self.skip(depth + 1)?;
+                    self.skip(depth + 1)?;
+                }
+            }
+            (6, _) => self.skip(depth + 1)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn it_round_trips_headers() {
+        let roots = vec![
+            Cid::from_str("bafyreihyrpefhacm6kkp4ql6j6udakdit7g3dmkzfriqfykhjw6cad5lrm").unwrap(),
+            Cid::from_str("bafkreifw7plhl6mofk6sfvhnfh64qmkq73oeqwl6sloru6rehaoujituke").unwrap(),
+        ];
+        let buf = encode_header(&roots);
+        assert_eq!(&buf[..8], b"\xa2\x65roots\x82");
+        assert_eq!(
+            decode_header(&buf).unwrap(),
+            Header {
+                version: 1,
+                roots: Some(roots),
+            }
+        );
+    }
+
+    #[test]
+    fn it_skips_unknown_fields_and_rejects_malformed_headers() {
+        // {"extra": [{"a": 1.5}, "b"], "version": 2}
+        let mut buf = b"\xa2\x65extra\x82\xa1\x61a\xfb".to_vec();
+        buf.extend_from_slice(&1.5f64.to_be_bytes());
+        buf.extend_from_slice(b"\x61b\x67version\x02");
+        assert_eq!(
+            decode_header(&buf).unwrap(),
+            Header {
+                version: 2,
+                roots: None,
+            }
+        );
+
+        // Missing version, an indefinite-length map, a truncated key and trailing bytes.
+        for buf in [
+            &b"\xa1\x65roots\x80"[..],
+            b"\xbf\x67version\x01\xff",
+            b"\xa1\x67vers",
+            b"\xa1\x67version\x01\x00",
+        ] {
+            assert!(decode_header(buf).is_err());
+        }
+        let nested = [&[0xa1, 0x61, b'a'][..], &[0x81; 100], &[0x00]].concat();
+        assert!(decode_header(&nested).is_err());
+    }
+
+    #[test]
+    fn it_measures_items() {
+        let nested = [&[0x81; 1000][..], &[0xa1, 0x61, b'a', 0xf6]].concat();
+        assert_eq!(item_length(&nested), Some(nested.len()));
+        assert_eq!(
+            item_length(&[&nested[..], b"slack"].concat()),
+            Some(nested.len())
+        );
+        assert_eq!(item_length(&nested[..nested.len() - 1]), None);
+        assert_eq!(item_length(b"\x9b\xff\xff\xff\xff\xff\xff\xff\xff"), None);
+    }
+}
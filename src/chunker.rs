@@ -0,0 +1,247 @@
+//! Strategies for splitting file content into the leaves of a UnixFS file, see [`Chunker`].
+//! The chunker decides how well files deduplicate against each other: fixed-size chunks only
+//! match at the same offsets, content-defined ones also after bytes are inserted or removed.
+
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+/// Splits file content into chunks, read one after the other from the same reader.
+pub trait Chunker: fmt::Debug + Send + Sync {
+    /// Reads the next chunk from `r`, empty only once `r` has ended. Bytes after the chunk are
+    /// left in `r` for the next one.
+    fn next_chunk(&self, r: &mut dyn BufRead) -> io::Result<Vec<u8>>;
+
+    /// The length of every chunk but the last, if it does not depend on the content. Only such
+    /// chunkers can lay out a file from a [`crate::pack::ChunkCache`] without reading it.
+    fn fixed_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Chunks of `size` bytes, as `ipfs add --chunker=size-<size>` cuts them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedSize {
+    size: usize,
+}
+
+impl FixedSize {
+    /// Chunks of `size` bytes, at least one.
+    pub fn new(size: usize) -> Self {
+        Self { size: size.max(1) }
+    }
+}
+
+impl Chunker for FixedSize {
+    fn next_chunk(&self, r: &mut dyn BufRead) -> io::Result<Vec<u8>> {
+        let mut chunk = Vec::with_capacity(self.size);
+        r.take(self.size as u64).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+
+    fn fixed_size(&self) -> Option<usize> {
+        Some(self.size)
+    }
+}
+
+/// The width of the window [`Buzhash`] hashes, as in go-buzhash.
+const WINDOW: usize = 32;
+
+/// Content-defined chunks, cut where a buzhash of the last 32 bytes has its low `mask_bits`
+/// bits zero, so that an edit only moves the boundaries near it. Chunks are between `min_size`
+/// and `max_size` bytes long, but for the last.
+///
+/// The window, the defaults and where chunks are cut are those of `ipfs add
+/// --chunker=buzhash`, but the default `table` is this crate's own: files deduplicate as well
+/// as with go-ipfs, against each other. Giving go-buzhash's `bytehash` table as `table` also
+/// gives its chunks, and so its CIDs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Buzhash {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub mask_bits: u32,
+    /// The value hashed for each byte.
+    pub table: &'static [u32; 256],
+}
+
+impl Default for Buzhash {
+    fn default() -> Self {
+        Self {
+            min_size: 128 * 1024,
+            max_size: 512 * 1024,
+            mask_bits: 17,
+            table: &TABLE,
+        }
+    }
+}
+
+impl fmt::Debug for Buzhash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buzhash")
+            .field("min_size", &self.min_size)
+            .field("max_size", &self.max_size)
+            .field("mask_bits", &self.mask_bits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Chunker for Buzhash {
+    fn next_chunk(&self, r: &mut dyn BufRead) -> io::Result<Vec<u8>> {
+        let min = self.min_size.max(WINDOW);
+        let max = self.max_size.max(min);
+        let mask = 1u32
+            .checked_shl(self.mask_bits)
+            .map_or(u32::MAX, |bit| bit - 1);
+        let table = self.table;
+        let mut chunk = Vec::with_capacity(min);
+        r.take(min as u64).read_to_end(&mut chunk)?;
+        if chunk.len() < min {
+            return Ok(chunk);
+        }
+        let mut hash = chunk[min - WINDOW..].iter().fold(0u32, |hash, &byte| {
+            hash.rotate_left(1) ^ table[byte as usize]
+        });
+        while chunk.len() < max && hash & mask != 0 {
+            let buf = match r.fill_buf() {
+                Ok([]) => break,
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let mut used = 0;
+            for &byte in buf.iter().take(max - chunk.len()) {
+                // The byte leaving the window was rotated once per byte since, a full turn.
+                let out = chunk[chunk.len() - WINDOW];
+                hash = hash.rotate_left(1) ^ table[out as usize] ^ table[byte as usize];
+                chunk.push(byte);
+                used += 1;
+                if hash & mask == 0 {
+                    break;
+                }
+            }
+            r.consume(used);
+        }
+        Ok(chunk)
+    }
+}
+
+/// Random values for each byte, from SplitMix64.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = (z ^ (z >> 31)) as u32;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(chunker: &dyn Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut r = data;
+        let mut chunks = vec![];
+        loop {
+            let chunk = chunker.next_chunk(&mut r).unwrap();
+            if chunk.is_empty() {
+                return chunks;
+            }
+            chunks.push(chunk);
+        }
+    }
+
+    #[test]
+    fn it_cuts_fixed_size_chunks() {
+        let chunks = chunks(&FixedSize::new(4), b"0123456789");
+        assert_eq!(chunks, vec![&b"0123"[..], b"4567", b"89"]);
+        assert_eq!(FixedSize::new(0).fixed_size(), Some(1));
+    }
+
+    #[test]
+    fn it_cuts_chunks_where_the_content_says() {
+        let data: Vec<u8> = (0..100_000u64)
+            .map(|i| (i.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8)
+            .collect();
+        let buzhash = Buzhash {
+            min_size: 256,
+            max_size: 4096,
+            mask_bits: 9,
+            ..Buzhash::default()
+        };
+        let cut = chunks(&buzhash, &data);
+        assert_eq!(cut.concat(), data);
+        assert!(cut.len() > 20);
+        let (last, rest) = cut.split_last().unwrap();
+        assert!(rest.iter().all(|chunk| (256..=4096).contains(&chunk.len())));
+        assert!(last.len() <= 4096);
+        assert_eq!(buzhash.fixed_size(), None);
+
+        // Bytes inserted at the start only change the chunks around them.
+        let shifted = chunks(&buzhash, &[b"inserted".as_slice(), &data].concat());
+        let common = cut.iter().filter(|chunk| shifted.contains(chunk)).count();
+        assert!(common >= cut.len() - 2);
+
+        // Reading through a small buffer cuts the same chunks.
+        let mut r = io::BufReader::with_capacity(7, &data[..]);
+        let mut buffered = vec![];
+        loop {
+            let chunk = buzhash.next_chunk(&mut r).unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            buffered.push(chunk);
+        }
+        assert_eq!(buffered, cut);
+    }
+
+    /// The length of the first chunk of `buf`, as go-buzhash's `NextBytes` cuts it from a buffer
+    /// of `max` bytes.
+    fn go_cut(buzhash: &Buzhash, buf: &[u8]) -> usize {
+        let (min, max) = (buzhash.min_size, buzhash.max_size);
+        let mask = (1 << buzhash.mask_bits) - 1;
+        let buf = &buf[..buf.len().min(max)];
+        if buf.len() < min {
+            return buf.len();
+        }
+        let table = buzhash.table;
+        let mut state = 0u32;
+        for &byte in &buf[min - 32..min] {
+            state = state.rotate_left(1) ^ table[byte as usize];
+        }
+        let mut i = min - 32;
+        while i + 32 < buf.len() {
+            if state & mask == 0 {
+                break;
+            }
+            state = state.rotate_left(1) ^ table[buf[i] as usize] ^ table[buf[i + 32] as usize];
+            i += 1;
+        }
+        i + 32
+    }
+
+    #[test]
+    fn it_cuts_where_go_buzhash_does() {
+        let buzhash = Buzhash {
+            min_size: 1024,
+            max_size: 8192,
+            mask_bits: 10,
+            ..Buzhash::default()
+        };
+        let mut data: Vec<u8> = (0..200_000u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 59) as u8)
+            .collect();
+        data.extend([0; 20_000]);
+        let mut rest = &data[..];
+        for chunk in chunks(&buzhash, &data) {
+            assert_eq!(chunk.len(), go_cut(&buzhash, rest));
+            rest = &rest[chunk.len()..];
+        }
+        assert!(rest.is_empty());
+    }
+}
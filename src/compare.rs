@@ -0,0 +1,147 @@
+//! Comparing an archive with a blockstore holding the same blocks, see [`verify_against`].
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use libipld::cid::Cid;
+
+use crate::block::CarBlockReader;
+use crate::gateway::BlockFetcher;
+use crate::hash::HasherRegistry;
+use crate::CarResult;
+
+/// A source of blocks that can also list the blocks it has.
+pub trait BlockStore: BlockFetcher {
+    fn cids(&self) -> CarResult<Vec<Cid>>;
+}
+
+impl BlockFetcher for HashMap<Cid, Vec<u8>> {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        Ok(self.get(cid).cloned())
+    }
+}
+
+impl BlockStore for HashMap<Cid, Vec<u8>> {
+    fn cids(&self) -> CarResult<Vec<Cid>> {
+        Ok(self.keys().copied().collect())
+    }
+}
+
+/// A block whose data differs between the archive and the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub cid: Cid,
+    /// Whether the archive's data hashes to the CID, `None` if its hash has no implementation.
+    pub archive_valid: Option<bool>,
+    /// Whether the store's data hashes to the CID, `None` if its hash has no implementation.
+    pub store_valid: Option<bool>,
+}
+
+/// The differences found by [`verify_against`], each list in the order the blocks were found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    pub mismatched: Vec<Mismatch>,
+    /// Blocks of the archive the store does not have.
+    pub missing_from_store: Vec<Cid>,
+    /// Blocks of the store the archive does not have, in the order the store lists them.
+    pub missing_from_archive: Vec<Cid>,
+}
+
+impl Comparison {
+    /// Whether the archive and the store hold the same blocks with the same data.
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty()
+            && self.missing_from_store.is_empty()
+            && self.missing_from_archive.is_empty()
+    }
+}
+
+/// Compares every block of the archive in `r` with the data `store` has for its CID, and lists
+/// the blocks only one of them has. Blocks of the archive are read without being verified, so
+/// corrupt ones are compared too; when the data differs, both copies are checked against the
+/// CID to tell which side is corrupt. Blocks stored more than once are compared once.
+pub fn verify_against<R: Read, S: BlockStore + ?Sized>(r: R, store: &S) -> CarResult<Comparison> {
+    let hashers = HasherRegistry::new();
+    let valid = |cid: &Cid, data: &[u8]| match hashers.verify(cid, data) {
+        Ok(true) => Some(true),
+        Ok(false) => None,
+        Err(_) => Some(false),
+    };
+
+    let mut comparison = Comparison::default();
+    let mut seen = HashSet::new();
+    for block in CarBlockReader::new(r)? {
+        let block = block?;
+        if !seen.insert(block.cid) {
+            continue;
+        }
+        match store.fetch(&block.cid)? {
+            Some(data) if data == block.data => {}
+            Some(data) => comparison.mismatched.push(Mismatch {
+                cid: block.cid,
+                archive_valid: valid(&block.cid, &block.data),
+                store_valid: valid(&block.cid, &data),
+            }),
+            None => comparison.missing_from_store.push(block.cid),
+        }
+    }
+    comparison.missing_from_archive = store
+        .cids()?
+        .into_iter()
+        .filter(|cid| !seen.contains(cid))
+        .collect();
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond, raw};
+    use libipld::Block;
+
+    #[test]
+    fn it_compares_archives_with_stores() {
+        let car = diamond();
+        let mut store: HashMap<Cid, Vec<u8>> = car
+            .blocks
+            .iter()
+            .map(|block| (*block.cid(), block.data().to_vec()))
+            .collect();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert!(verify_against(&bytes[..], &store).unwrap().is_clean());
+
+        // The archive's leaf and the store's root are corrupt, the store lacks the left node
+        // and has an extra block.
+        let cids = cids(&car);
+        let (leaf, root, left) = (cids[0], cids[2], cids[3]);
+        let mut corrupt = car.clone();
+        corrupt.blocks[0] = Block::new_unchecked(leaf, b"lead".to_vec());
+        let mut bytes = vec![];
+        corrupt.write_to(&mut bytes).unwrap();
+        store.get_mut(&root).unwrap().push(0);
+        store.remove(&left);
+        let extra = raw(b"extra");
+        store.insert(*extra.cid(), extra.data().to_vec());
+
+        let comparison = verify_against(&bytes[..], &store).unwrap();
+        assert_eq!(
+            comparison.mismatched,
+            vec![
+                Mismatch {
+                    cid: leaf,
+                    archive_valid: Some(false),
+                    store_valid: Some(true),
+                },
+                Mismatch {
+                    cid: root,
+                    archive_valid: Some(true),
+                    store_valid: Some(false),
+                },
+            ]
+        );
+        assert_eq!(comparison.missing_from_store, vec![left]);
+        assert_eq!(comparison.missing_from_archive, vec![*extra.cid()]);
+        assert!(!comparison.is_clean());
+    }
+}
@@ -0,0 +1,223 @@
+//! Copying archives, optionally transforming their blocks on the way.
+
+use std::collections::HashSet;
+#[cfg(feature = "v2")]
+use std::io::Cursor;
+use std::io::{Read, Seek, Write};
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::v1::{CarHeaderV1, CarV1};
+#[cfg(feature = "v2")]
+use crate::v2::CarV2;
+use crate::{CarResult, ContentArchive, ReadOptions, ReadReport};
+
+/// Called with the CID and data of every block. Returning `Some` replaces the block with the
+/// returned one; roots that are replaced are replaced in the header too.
+pub type BlockHook<'a> = dyn Fn(&Cid, &[u8]) -> Option<(Cid, Vec<u8>)> + 'a;
+
+/// Options for [`copy`].
+#[derive(Default, Clone, Copy)]
+pub struct CopyOptions<'a> {
+    pub read: ReadOptions<'a>,
+    pub hook: Option<&'a BlockHook<'a>>,
+}
+
+/// Copies the archive in `r` to `w` as a CARv1, passing every block through `options.hook`.
+pub fn copy<R: Read + Seek, W: Write>(r: R, w: W, options: &CopyOptions) -> CarResult<()> {
+    let car = ContentArchive::read_bytes_with_options(r, &options.read)?.into_car_v1();
+    let car = match options.hook {
+        Some(hook) => transform(&car, hook)?,
+        None => car,
+    };
+    car.write_to(w)
+}
+
+/// Rewrites the archive in `r` to `w` in canonical form: minimal varints, a canonical dag-cbor
+/// header without extra fields, no padding or trailing bytes and every block stored once, in
+/// the order it first appeared. Returns what was dropped or normalized.
+///
+/// A CARv2 is written back as a CARv2 with its payload canonicalized the same way, right after
+/// the header, and its index rebuilt as a sorted index of the same kind right after the
+/// payload. An index of an unknown codec is rebuilt as
+/// [`IndexKind::MultihashSorted`](crate::v2::IndexKind::MultihashSorted).
+#[cfg_attr(
+    not(any(feature = "v1", feature = "v2")),
+    allow(unreachable_code, unused_variables)
+)]
+pub fn canonicalize<R: Read + Seek, W: Write>(r: R, w: W) -> CarResult<ReadReport> {
+    let options = ReadOptions {
+        lenient: true,
+        ..ReadOptions::default()
+    };
+    let (archive, report) = ContentArchive::read_bytes_with_report(r, &options)?;
+    let dedup = |car: CarV1| {
+        let mut seen = HashSet::new();
+        let blocks = car
+            .blocks
+            .into_iter()
+            .filter(|block| seen.insert(*block.cid()))
+            .collect();
+        CarV1::new(car.header, blocks)
+    };
+    match archive {
+        #[cfg(feature = "v1")]
+        ContentArchive::V1(car) => dedup(car).write_to(w)?,
+        #[cfg(feature = "v2")]
+        ContentArchive::V2(car) => {
+            let kind = car
+                .index
+                .as_ref()
+                .map(|index| index.kind().unwrap_or_default());
+            let mut bytes = Cursor::new(vec![]);
+            CarV2::from_car_v1_with_index(dedup(car.car_v1), kind)?.write_to(&mut bytes)?;
+            let mut w = w;
+            w.write_all(bytes.get_ref())?;
+        }
+    }
+    Ok(report)
+}
+
+/// Passes every block of `car` through `hook`, keeping the order of the blocks.
+///
+/// Replacement blocks are checked against their CIDs.
+pub fn transform(car: &CarV1, hook: &BlockHook) -> CarResult<CarV1> {
+    let mut roots = car.header.roots.clone();
+    let mut blocks = Vec::with_capacity(car.blocks.len());
+    for block in &car.blocks {
+        match hook(block.cid(), block.data()) {
+            Some((cid, data)) => {
+                for root in roots.iter_mut().filter(|root| *root == block.cid()) {
+                    *root = cid;
+                }
+                blocks.push(Block::<DefaultParams>::new(cid, data)?);
+            }
+            None => blocks.push(block.clone()),
+        }
+    }
+    Ok(CarV1::new(CarHeaderV1 { roots }, blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "v1")]
+    use crate::test_utils::cids;
+    use crate::test_utils::{diamond, raw};
+    use crate::CarError;
+    #[cfg(feature = "v1")]
+    use std::io::Cursor;
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_copies_archives_through_a_hook() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let mut copied = vec![];
+        copy(Cursor::new(&bytes), &mut copied, &CopyOptions::default()).unwrap();
+        assert_eq!(copied, bytes);
+
+        let leaf = cids(&car)[0];
+        let replacement = raw(b"replaced");
+        let hook = |cid: &Cid, _: &[u8]| {
+            (*cid == leaf).then(|| (*replacement.cid(), replacement.data().to_vec()))
+        };
+        let options = CopyOptions {
+            hook: Some(&hook),
+            ..CopyOptions::default()
+        };
+        let mut copied = vec![];
+        copy(Cursor::new(&bytes), &mut copied, &options).unwrap();
+        let copied = CarV1::from_reader(&copied[..]).unwrap();
+        assert_eq!(copied.blocks[0], replacement);
+        assert_eq!(copied.blocks[1..], car.blocks[1..]);
+        assert_eq!(copied.header.roots, car.header.roots);
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_canonicalizes_archives() {
+        let car = diamond();
+        let mut canonical = vec![];
+        car.write_to(&mut canonical).unwrap();
+
+        let mut irregular = canonical.clone();
+        let mut duplicate = vec![];
+        crate::block::write_car_v1_block(&mut duplicate, &car.blocks[0]).unwrap();
+        irregular.extend(&duplicate);
+        irregular.extend([0, 0]);
+        irregular.extend(&duplicate[..duplicate.len() - 1]);
+
+        let mut out = vec![];
+        let report = canonicalize(Cursor::new(&irregular), &mut out).unwrap();
+        assert_eq!(out, canonical);
+        assert_eq!(report.anomalies.len(), 3);
+        assert!(canonicalize(Cursor::new(&canonical), &mut vec![])
+            .unwrap()
+            .is_clean());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_canonicalizes_car_v2_archives() {
+        use crate::v2::{CarV2, CarV2WriteOptions, IndexKind};
+
+        let car = diamond();
+        let mut canonical = Cursor::new(vec![]);
+        CarV2::from_car_v1_with_index(car.clone(), Some(IndexKind::Sorted))
+            .unwrap()
+            .write_to(&mut canonical)
+            .unwrap();
+        let canonical = canonical.into_inner();
+
+        // Padding around the payload and a duplicate block.
+        let mut blocks = car.blocks.clone();
+        blocks.push(car.blocks[0].clone());
+        let padded = CarV2WriteOptions {
+            data_padding: 7,
+            index_padding: 5,
+        };
+        let mut irregular = Cursor::new(vec![]);
+        CarV2::from_car_v1_with_index(
+            CarV1::new(car.header.clone(), blocks),
+            Some(IndexKind::Sorted),
+        )
+        .unwrap()
+        .write_to_with_options(&mut irregular, &padded)
+        .unwrap();
+        let mut out = vec![];
+        let report = canonicalize(Cursor::new(irregular.into_inner()), &mut out).unwrap();
+        assert_eq!(out, canonical);
+        assert_eq!(report.anomalies.len(), 1);
+
+        let v2 = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/carv2-basic.car"
+        ))
+        .unwrap();
+        let mut out = vec![];
+        canonicalize(Cursor::new(v2), &mut out).unwrap();
+        let mut again = vec![];
+        canonicalize(Cursor::new(&out), &mut again).unwrap();
+        assert_eq!(again, out);
+    }
+
+    #[test]
+    fn it_replaces_roots_and_checks_replacements() {
+        let car = diamond();
+        let root = car.header.roots[0];
+        let replacement = raw(b"new root");
+        let hook = |cid: &Cid, _: &[u8]| {
+            (*cid == root).then(|| (*replacement.cid(), replacement.data().to_vec()))
+        };
+        assert_eq!(
+            transform(&car, &hook).unwrap().header.roots,
+            vec![*replacement.cid()]
+        );
+
+        let corrupt = |cid: &Cid, _: &[u8]| (*cid == root).then(|| (root, b"corrupt".to_vec()));
+        assert!(matches!(transform(&car, &corrupt), Err(CarError::Ipld(_))));
+    }
+}
@@ -0,0 +1,265 @@
+//! Archives opened from a local index alone, with their data read on demand from a remote or
+//! otherwise lazy source, see [`DetachedCar`]. Only the sections of the blocks asked for are
+//! ever read, so an archive of any size can be queried with just its index at hand.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::gateway::{self, BlockFetcher};
+use crate::index::CarV2Index;
+use crate::{CarError, CarResult, Deadline, HashPolicy};
+
+/// How many bytes of a section are read before its length is known: enough for the length and
+/// CID of most sections, and the data of small ones.
+const SECTION_HEAD: u64 = 256;
+
+/// Byte ranges of an archive, fetched on demand.
+pub trait RangeSource {
+    /// The bytes of the archive in `range`, fewer only if the archive ends within it.
+    fn read_range(&self, range: Range<u64>) -> CarResult<Vec<u8>>;
+}
+
+impl<F> RangeSource for F
+where
+    F: Fn(Range<u64>) -> CarResult<Vec<u8>>,
+{
+    fn read_range(&self, range: Range<u64>) -> CarResult<Vec<u8>> {
+        self(range)
+    }
+}
+
+/// An archive served over plain HTTP by a server honouring `Range` requests, such as an object
+/// store behind a plain HTTP endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRangeSource {
+    pub host: String,
+    pub port: u16,
+    /// The path of the archive, from its leading `/`.
+    pub path: String,
+    pub timeout: Option<Duration>,
+}
+
+impl HttpRangeSource {
+    /// Parses an `http://host[:port]/path` URL.
+    pub fn new(url: &str) -> CarResult<Self> {
+        let (host, port, path) = gateway::parse_url(url)?;
+        Ok(Self {
+            host,
+            port,
+            path: if path.is_empty() {
+                "/".to_string()
+            } else {
+                path
+            },
+            timeout: Some(Duration::from_secs(30)),
+        })
+    }
+}
+
+impl RangeSource for HttpRangeSource {
+    /// Fails if the server answers with anything but `206 Partial Content`, or `416` for a
+    /// range past the end, rather than download the whole archive.
+    fn read_range(&self, range: Range<u64>) -> CarResult<Vec<u8>> {
+        if range.is_empty() {
+            return Ok(vec![]);
+        }
+        let headers = format!("Range: bytes={}-{}\r\n", range.start, range.end - 1);
+        let length = range.end - range.start;
+        match gateway::get(
+            &self.host,
+            self.port,
+            self.timeout,
+            Deadline::none(),
+            &self.path,
+            &headers,
+            length,
+        )? {
+            (206, body) => Ok(body),
+            (416, _) => Ok(vec![]),
+            (status, _) => Err(CarError::Gateway(format!(
+                "{} returned {} for bytes {}..{}",
+                self.path, status, range.start, range.end
+            ))),
+        }
+    }
+}
+
+/// An archive known by its index, reading each block's section from `source` when asked for
+/// it. Offsets of the index are counted from the start of the CARv1 payload, which for a CARv2
+/// is set with [`DetachedCar::with_data_offset`].
+#[derive(Debug, Clone)]
+pub struct DetachedCar<S> {
+    index: CarV2Index,
+    source: S,
+    data_offset: u64,
+}
+
+impl<S: RangeSource> DetachedCar<S> {
+    pub fn new(index: CarV2Index, source: S) -> Self {
+        Self {
+            index,
+            source,
+            data_offset: 0,
+        }
+    }
+
+    /// Opens the archive of the detached index `index`, as `car index` writes one.
+    pub fn open(index: &[u8], source: S) -> CarResult<Self> {
+        Ok(Self::new(CarV2Index::from_bytes(index)?, source))
+    }
+
+    /// Reads sections `data_offset` bytes further into the source, where the payload of a
+    /// CARv2 starts.
+    pub fn with_data_offset(mut self, data_offset: u64) -> Self {
+        self.data_offset = data_offset;
+        self
+    }
+
+    pub fn index(&self) -> &CarV2Index {
+        &self.index
+    }
+
+    /// Looks `cid` up in the index and reads its section from the source, in one request when
+    /// the section is short or two otherwise, verifying the data against the CID as
+    /// [`HashPolicy::default`] does. `None` under the same conditions as
+    /// [`crate::v2::CarV2Reader::get_block`].
+    ///
+    /// Fails with [`CarError::IndexOutOfBounds`] if the section would end past the largest
+    /// offset a source can have.
+    pub fn get_block(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        let Some(offset) = self.index.lookup(cid) else {
+            return Ok(None);
+        };
+        let at = |length: u64| {
+            self.data_offset
+                .checked_add(offset)
+                .and_then(|start| start.checked_add(length))
+                .ok_or(CarError::IndexOutOfBounds(offset))
+        };
+        let mut section = self.source.read_range(at(0)?..at(SECTION_HEAD)?)?;
+        let (length, rest) =
+            unsigned_varint::decode::u64(&section).map_err(|_| CarError::InvalidFormat)?;
+        let head = (section.len() - rest.len()) as u64;
+        let end = head.checked_add(length).ok_or(CarError::InvalidFormat)?;
+        if end > section.len() as u64 {
+            let more = self
+                .source
+                .read_range(at(section.len() as u64)?..at(end)?)?;
+            section.extend_from_slice(&more);
+        }
+        if end > section.len() as u64 {
+            return Err(CarError::InvalidFormat);
+        }
+        let mut data = &section[head as usize..end as usize];
+        if Cid::read_bytes(&mut data)?.hash() != cid.hash() {
+            return Ok(None);
+        }
+        Ok(Some(
+            HashPolicy::default().block(*cid, data.to_vec(), offset)?.0,
+        ))
+    }
+}
+
+impl<S: RangeSource> BlockFetcher for DetachedCar<S> {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        Ok(self.get_block(cid)?.map(|block| block.data().to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{layout, Part};
+    use crate::test_utils::{diamond, ok, raw, serve};
+    use std::cell::RefCell;
+
+    #[test]
+    fn it_reads_blocks_through_a_detached_index() {
+        let mut car = diamond();
+        car.put_block(raw(&[7; 1000]));
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let sections: Vec<_> = layout(&bytes)
+            .into_iter()
+            .filter_map(|region| match region.part {
+                Part::Section { cid, .. } => Some((cid, region.range.start)),
+                _ => None,
+            })
+            .collect();
+        let mut idx = vec![];
+        CarV2Index::multihash_sorted(sections.iter().map(|(cid, offset)| (cid, *offset)))
+            .write_to(&mut idx)
+            .unwrap();
+
+        let requests = RefCell::new(vec![]);
+        let source = |range: Range<u64>| {
+            requests.borrow_mut().push(range.clone());
+            let end = (range.end as usize).min(bytes.len());
+            Ok(bytes[range.start as usize..end].to_vec())
+        };
+        let detached = DetachedCar::open(&idx, &source).unwrap();
+        for block in &car.blocks {
+            assert_eq!(
+                detached.get_block(block.cid()).unwrap().as_ref(),
+                Some(block)
+            );
+        }
+        // One request a block, and two for the large one.
+        assert_eq!(requests.borrow().len(), car.blocks.len() + 1);
+        assert_eq!(detached.get_block(raw(b"absent").cid()).unwrap(), None);
+
+        // The same blocks behind a prefix, from a corrupt copy.
+        let mut shifted = [&b"prefix"[..], &bytes].concat();
+        let last = shifted.len() - 1;
+        shifted[last] ^= 1;
+        let source = |range: Range<u64>| {
+            let end = (range.end as usize).min(shifted.len());
+            Ok(shifted[range.start as usize..end].to_vec())
+        };
+        let detached = DetachedCar::open(&idx, source).unwrap().with_data_offset(6);
+        let leaf = &car.blocks[0];
+        assert_eq!(
+            detached.fetch(leaf.cid()).unwrap(),
+            Some(leaf.data().to_vec())
+        );
+        assert!(detached.get_block(car.blocks[4].cid()).is_err());
+
+        // Offsets past the largest a source can have, from a hostile index.
+        let offset = u64::MAX - 100;
+        let mut idx = vec![];
+        CarV2Index::multihash_sorted([(leaf.cid(), offset)])
+            .write_to(&mut idx)
+            .unwrap();
+        let unreachable = |_: Range<u64>| -> CarResult<Vec<u8>> { unreachable!() };
+        for data_offset in [0, 200] {
+            let detached = DetachedCar::open(&idx, unreachable)
+                .unwrap()
+                .with_data_offset(data_offset);
+            assert!(matches!(
+                detached.get_block(leaf.cid()),
+                Err(CarError::IndexOutOfBounds(o)) if o == offset
+            ));
+        }
+    }
+
+    #[test]
+    fn it_reads_ranges_over_http() {
+        let url = serve(vec![
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\nabc".to_vec(),
+            b"HTTP/1.1 206 Partial Content\r\n\r\nabc and more".to_vec(),
+            ok(b"the whole archive"),
+        ]);
+        let source = HttpRangeSource::new(&format!("{}/archive.car", url)).unwrap();
+        assert_eq!(source.path, "/archive.car");
+        assert_eq!(source.read_range(10..13).unwrap(), b"abc");
+        // Only as much of the body as was asked for is read.
+        assert_eq!(source.read_range(10..13).unwrap(), b"abc");
+        assert!(matches!(
+            source.read_range(10..13),
+            Err(CarError::Gateway(_))
+        ));
+        assert_eq!(source.read_range(5..5).unwrap(), Vec::<u8>::new());
+    }
+}
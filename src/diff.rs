@@ -0,0 +1,179 @@
+//! Comparing two archives block by block, e.g. successive snapshots of a dataset, see [`diff`],
+//! and the delta archive that brings one up to date with the other, see [`delta`].
+
+use std::collections::HashSet;
+use std::fmt;
+
+use libipld::cid::Cid;
+
+use crate::json;
+use crate::v1::{CarHeaderV1, CarV1};
+
+/// The differences between an old and a new archive, see [`diff`]. Blocks are told apart by
+/// CID alone, so a block whose data differs under the same CID is not a difference.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CarDiff {
+    /// Roots of the new archive the old one does not have.
+    pub roots_added: Vec<Cid>,
+    /// Roots of the old archive the new one does not have.
+    pub roots_removed: Vec<Cid>,
+    /// Blocks of the new archive the old one does not have, in the order they are stored.
+    pub added: Vec<Cid>,
+    /// Blocks of the old archive the new one does not have, in the order they are stored.
+    pub removed: Vec<Cid>,
+    /// The data sizes of the added and of the removed blocks.
+    pub added_bytes: u64,
+    pub removed_bytes: u64,
+    /// Number of distinct blocks both archives have.
+    pub unchanged: usize,
+}
+
+impl CarDiff {
+    /// Whether both archives have the same roots and blocks.
+    pub fn is_empty(&self) -> bool {
+        self.roots_added.is_empty()
+            && self.roots_removed.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+    }
+
+    /// Renders the differences as a JSON object with the field names of [`CarDiff`].
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"roots_added\":{},\"roots_removed\":{},\"added\":{},\"removed\":{},\
+             \"added_bytes\":{},\"removed_bytes\":{},\"unchanged\":{}}}",
+            json::cids(&self.roots_added),
+            json::cids(&self.roots_removed),
+            json::cids(&self.added),
+            json::cids(&self.removed),
+            self.added_bytes,
+            self.removed_bytes,
+            self.unchanged
+        )
+    }
+}
+
+/// A summary of the differences, e.g. `roots: +1 -1, blocks: +3 (1200 bytes) -2 (800 bytes),
+/// 10 unchanged`.
+impl fmt::Display for CarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "roots: +{} -{}, blocks: +{} ({} bytes) -{} ({} bytes), {} unchanged",
+            self.roots_added.len(),
+            self.roots_removed.len(),
+            self.added.len(),
+            self.added_bytes,
+            self.removed.len(),
+            self.removed_bytes,
+            self.unchanged
+        )
+    }
+}
+
+/// Lists the roots and blocks only one of `old` and `new` has. A block stored more than once
+/// is listed once.
+pub fn diff(old: &CarV1, new: &CarV1) -> CarDiff {
+    let cids =
+        |car: &CarV1| -> HashSet<Cid> { car.blocks.iter().map(|block| *block.cid()).collect() };
+    let (old_cids, new_cids) = (cids(old), cids(new));
+    let only = |car: &CarV1, other: &HashSet<Cid>| {
+        let mut seen = HashSet::new();
+        let mut only = vec![];
+        let mut bytes = 0;
+        for block in &car.blocks {
+            if !other.contains(block.cid()) && seen.insert(*block.cid()) {
+                only.push(*block.cid());
+                bytes += block.data().len() as u64;
+            }
+        }
+        (only, bytes)
+    };
+    let (added, added_bytes) = only(new, &old_cids);
+    let (removed, removed_bytes) = only(old, &new_cids);
+    let roots = |car: &CarV1, other: &CarV1| {
+        car.header
+            .roots
+            .iter()
+            .filter(|root| !other.header.roots.contains(root))
+            .copied()
+            .collect()
+    };
+    CarDiff {
+        roots_added: roots(new, old),
+        roots_removed: roots(old, new),
+        added,
+        removed,
+        added_bytes,
+        removed_bytes,
+        unchanged: old_cids.intersection(&new_cids).count(),
+    }
+}
+
+/// The archive rooted at the roots of `new` holding the blocks `old` does not have, each once,
+/// so that together with `old` it holds every block of `new`.
+pub fn delta(old: &CarV1, new: &CarV1) -> CarV1 {
+    let mut seen: HashSet<Cid> = old.blocks.iter().map(|block| *block.cid()).collect();
+    let blocks = new
+        .blocks
+        .iter()
+        .filter(|block| seen.insert(*block.cid()))
+        .cloned()
+        .collect();
+    CarV1::new(
+        CarHeaderV1 {
+            roots: new.header.roots.clone(),
+        },
+        blocks,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond, raw};
+
+    #[test]
+    fn it_diffs_archives() {
+        let old = diamond();
+        assert!(diff(&old, &old).is_empty());
+
+        // The new snapshot drops the right node, adds a block twice and has a new root.
+        let [_, right, root, _] = cids(&old)[..] else {
+            unreachable!()
+        };
+        let mut new = old.clone();
+        new.blocks.remove(1);
+        let extra = raw(b"extra");
+        new.put_block(extra.clone());
+        new.put_block(extra.clone());
+        new.header.roots = vec![*extra.cid()];
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.roots_added, vec![*extra.cid()]);
+        assert_eq!(changes.roots_removed, vec![root]);
+        assert_eq!(changes.added, vec![*extra.cid()]);
+        assert_eq!(changes.added_bytes, 5);
+        assert_eq!(changes.removed, vec![right]);
+        assert_eq!(changes.removed_bytes, old.blocks[1].data().len() as u64);
+        assert_eq!(changes.unchanged, 3);
+        let removed_bytes = changes.removed_bytes;
+        assert_eq!(
+            changes.to_string(),
+            format!("roots: +1 -1, blocks: +1 (5 bytes) -1 ({removed_bytes} bytes), 3 unchanged")
+        );
+        let extra = extra.cid();
+        assert_eq!(
+            changes.to_json(),
+            format!(
+                "{{\"roots_added\":[\"{extra}\"],\"roots_removed\":[\"{root}\"],\
+                 \"added\":[\"{extra}\"],\"removed\":[\"{right}\"],\"added_bytes\":5,\
+                 \"removed_bytes\":{removed_bytes},\"unchanged\":3}}"
+            )
+        );
+
+        let patch = delta(&old, &new);
+        assert_eq!(patch.header.roots, new.header.roots);
+        assert_eq!(cids(&patch), vec![*extra]);
+    }
+}
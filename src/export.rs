@@ -0,0 +1,850 @@
+//! Export of the DAGs reachable from a set of roots as a CARv1, following the trustless gateway
+//! [CAR response parameters](https://specs.ipfs.tech/http-gateways/trustless-gateway/).
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::str::FromStr;
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::block::write_car_v1_block;
+use crate::gateway::BlockFetcher;
+use crate::selector::Selector;
+use crate::traversal;
+use crate::unixfs::UnixFsNode;
+use crate::v1::{CarHeaderV1, CarV1, CarWriter};
+use crate::{CarError, CarResult, Deadline, HashPolicy};
+
+/// Block order of an exported CAR (`order=`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    /// Depth-first pre-order, following links in the order they appear in each block.
+    #[default]
+    Dfs,
+    /// No guaranteed order; blocks are written in the order the source archive stores them.
+    Unknown,
+}
+
+impl Order {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Order::Dfs => "dfs",
+            Order::Unknown => "unk",
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Order {
+    type Err = CarError;
+
+    fn from_str(s: &str) -> CarResult<Self> {
+        match s {
+            "dfs" => Ok(Order::Dfs),
+            "unk" => Ok(Order::Unknown),
+            _ => Err(CarError::InvalidExportParameter(format!("order={}", s))),
+        }
+    }
+}
+
+/// Options negotiated with a client for a CAR response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExportOptions {
+    pub order: Order,
+    /// Whether a block is written again every time the traversal reaches it (`dups=y`).
+    pub dups: bool,
+    pub deadline: Deadline,
+}
+
+impl ExportOptions {
+    pub fn new(order: Order, dups: bool) -> Self {
+        Self {
+            order,
+            dups,
+            deadline: Deadline::none(),
+        }
+    }
+
+    /// Parses `;`-separated content type parameters, e.g. those of
+    /// `application/vnd.ipld.car; version=1; order=dfs; dups=y`.
+    ///
+    /// Parameters other than `order` and `dups` are ignored; missing ones keep their defaults.
+    pub fn from_params(params: &str) -> CarResult<Self> {
+        let mut options = Self::default();
+        for param in params.split(';') {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            match name {
+                "order" => options.order = value.parse()?,
+                "dups" => {
+                    options.dups = match value {
+                        "y" => true,
+                        "n" => false,
+                        _ => {
+                            return Err(CarError::InvalidExportParameter(format!("dups={}", value)))
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+
+    /// The `Content-Type` of a response exported with these options.
+    pub fn content_type(&self) -> String {
+        format!(
+            "application/vnd.ipld.car; version=1; order={}; dups={}",
+            self.order,
+            if self.dups { "y" } else { "n" }
+        )
+    }
+}
+
+/// Writes a CARv1 with the given roots containing every block of `car` reachable from them.
+pub fn export<W: Write>(
+    car: &CarV1,
+    roots: &[Cid],
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    write_blocks(roots, reachable(car, roots, options)?, w)
+}
+
+/// How many bytes of block data an export holds for one of its roots, see
+/// [`export_with_shares`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootShare {
+    pub root: Cid,
+    /// Data of the blocks only this root reaches.
+    pub exclusive: u64,
+    /// Data of the blocks this root reaches along with others.
+    pub shared: u64,
+}
+
+/// Like [`export`], also telling apart for each distinct root the bytes of the blocks only it
+/// reaches from those of the blocks it shares with other roots. However many roots reach a
+/// block, it is written once unless `options` asks for duplicates.
+pub fn export_with_shares<W: Write>(
+    car: &CarV1,
+    roots: &[Cid],
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<Vec<RootShare>> {
+    let blocks = reachable(car, roots, options)?;
+    let single = ExportOptions {
+        dups: false,
+        ..*options
+    };
+    let mut distinct = vec![];
+    let mut reached_by: HashMap<Cid, usize> = HashMap::new();
+    for root in roots {
+        if distinct.iter().any(|(seen, _)| seen == root) {
+            continue;
+        }
+        let below = reachable(car, &[*root], &single)?;
+        for block in &below {
+            *reached_by.entry(*block.cid()).or_default() += 1;
+        }
+        distinct.push((*root, below));
+    }
+    write_blocks(roots, blocks, w)?;
+    Ok(distinct
+        .into_iter()
+        .map(|(root, below)| {
+            let mut share = RootShare {
+                root,
+                exclusive: 0,
+                shared: 0,
+            };
+            for block in below {
+                let bytes = block.data().len() as u64;
+                match reached_by[block.cid()] {
+                    1 => share.exclusive += bytes,
+                    _ => share.shared += bytes,
+                }
+            }
+            share
+        })
+        .collect())
+}
+
+/// Which leaves (blocks without links) a skeleton export keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafSample {
+    None,
+    /// The `n` largest leaves.
+    Largest(usize),
+    /// Every `n`th leaf in traversal order, starting with the first.
+    EveryNth(usize),
+}
+
+/// Writes a CARv1 with the structure of the DAGs below `roots`: every block that has links,
+/// but only the leaves chosen by `sample`.
+///
+/// The result is deliberately incomplete; it is meant for sharing the shape of a DAG without
+/// its content.
+pub fn export_skeleton<W: Write>(
+    car: &CarV1,
+    roots: &[Cid],
+    sample: LeafSample,
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    let blocks = reachable(car, roots, options)?;
+
+    let mut leaves = vec![];
+    let mut seen = HashSet::new();
+    for block in &blocks {
+        if seen.insert(*block.cid()) && traversal::links(block)?.is_empty() {
+            leaves.push(*block);
+        }
+    }
+    let kept: HashSet<&Cid> = match sample {
+        LeafSample::None => HashSet::new(),
+        LeafSample::Largest(n) => {
+            leaves.sort_by_key(|leaf| Reverse(leaf.data().len()));
+            leaves.iter().take(n).map(|leaf| leaf.cid()).collect()
+        }
+        LeafSample::EveryNth(n) => leaves
+            .iter()
+            .step_by(n.max(1))
+            .map(|leaf| leaf.cid())
+            .collect(),
+    };
+    let leaves: HashSet<&Cid> = leaves.iter().map(|leaf| leaf.cid()).collect();
+
+    let skeleton = blocks
+        .into_iter()
+        .filter(|block| !leaves.contains(block.cid()) || kept.contains(block.cid()));
+    write_blocks(roots, skeleton, w)
+}
+
+/// Writes a CARv1 rooted at `root` containing the blocks `selector` reaches from it.
+pub fn export_selector<W: Write>(
+    car: &CarV1,
+    root: &Cid,
+    selector: &Selector,
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    let blocks = walk(
+        car,
+        vec![(*root, selector.clone())],
+        options,
+        |block, selector| Ok(selector.select_links(&block.ipld()?)),
+    )?;
+    write_blocks(&[*root], blocks, w)
+}
+
+/// An inclusive byte range of a UnixFS file (`entity-bytes=from:to`).
+///
+/// Negative offsets count back from the end of the file; a `to` of `None` (`*`) means the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityBytes {
+    pub from: i64,
+    pub to: Option<i64>,
+}
+
+impl EntityBytes {
+    /// Resolves the range against a file size, `None` if no byte of the file is covered.
+    pub fn resolve(&self, file_size: u64) -> Option<(u64, u64)> {
+        let absolute = |offset: i64| {
+            if offset < 0 {
+                file_size.saturating_sub(offset.unsigned_abs())
+            } else {
+                offset as u64
+            }
+        };
+        let from = absolute(self.from);
+        let to = self
+            .to
+            .map_or(file_size, |to| absolute(to).saturating_add(1))
+            .min(file_size);
+        (from < to).then(|| (from, to - 1))
+    }
+}
+
+impl FromStr for EntityBytes {
+    type Err = CarError;
+
+    fn from_str(s: &str) -> CarResult<Self> {
+        let invalid = || CarError::InvalidExportParameter(format!("entity-bytes={}", s));
+        let (from, to) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            from: from.parse().map_err(|_| invalid())?,
+            to: match to {
+                "*" => None,
+                to => Some(to.parse().map_err(|_| invalid())?),
+            },
+        })
+    }
+}
+
+/// Writes a CARv1 rooted at the UnixFS entity `root` containing only the blocks needed to
+/// read `range` of it: every node on the way down to the leaves that overlap the range.
+///
+/// Entities that are not files are exported as their root block alone.
+pub fn export_entity_bytes<W: Write>(
+    car: &CarV1,
+    root: &Cid,
+    range: EntityBytes,
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    let mut resolved = None;
+    let blocks = walk(car, vec![(*root, 0u64)], options, |block, offset| {
+        entity_children(block, offset, range, &mut resolved)
+    })?;
+    write_blocks(&[*root], blocks, w)
+}
+
+/// Writes a CARv1 with `roots` holding every block of the DAGs below them, in depth-first
+/// pre-order, resolving each block from the first of `sources` that has a copy matching its
+/// CID. Blocks are written as they are resolved, so the DAG is never held in memory.
+///
+/// Fails with [`CarError::MissingBlock`] if no source has a block, or with the error of the
+/// last copy that did not match when every copy found was corrupt.
+pub fn gather<W: Write>(
+    roots: &[Cid],
+    sources: &[&dyn BlockFetcher],
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    let start = roots.iter().map(|root| (*root, ())).collect();
+    gather_walk(roots, start, sources, options, w, |block, _| {
+        Ok(traversal::links(block)?
+            .into_iter()
+            .map(|link| (link, ()))
+            .collect())
+    })
+}
+
+/// Like [`export_entity_bytes`], resolving blocks from `sources` as [`gather`] does.
+pub fn gather_entity_bytes<W: Write>(
+    root: &Cid,
+    range: EntityBytes,
+    sources: &[&dyn BlockFetcher],
+    options: &ExportOptions,
+    w: W,
+) -> CarResult<()> {
+    let mut resolved = None;
+    gather_walk(
+        &[*root],
+        vec![(*root, 0u64)],
+        sources,
+        options,
+        w,
+        |block, offset| entity_children(block, offset, range, &mut resolved),
+    )
+}
+
+/// Depth-first walk from `start` as [`walk`] does, writing each block as it is resolved from
+/// `sources`.
+fn gather_walk<T, F, W>(
+    roots: &[Cid],
+    start: Vec<(Cid, T)>,
+    sources: &[&dyn BlockFetcher],
+    options: &ExportOptions,
+    w: W,
+    mut expand: F,
+) -> CarResult<()>
+where
+    T: Clone + Eq + Hash,
+    F: FnMut(&Block<DefaultParams>, T) -> CarResult<Vec<(Cid, T)>>,
+    W: Write,
+{
+    let policy = HashPolicy::default();
+    let resolve = |cid: &Cid| -> CarResult<Block<DefaultParams>> {
+        let mut corrupt = None;
+        for source in sources {
+            if let Some(data) = source.fetch(cid)? {
+                match policy.block(*cid, data, 0) {
+                    Ok((block, _)) => return Ok(block),
+                    Err(err) => corrupt = Some(err),
+                }
+            }
+        }
+        Err(corrupt.unwrap_or(CarError::MissingBlock(*cid)))
+    };
+
+    let mut writer = CarWriter::new(w, roots.to_vec())?;
+    let mut visited = HashSet::new();
+    let mut emitted = HashSet::new();
+    let mut stack: Vec<(Cid, T)> = start.into_iter().rev().collect();
+    while let Some((cid, state)) = stack.pop() {
+        options.deadline.check()?;
+        if !visited.insert((cid, state.clone())) && !options.dups {
+            continue;
+        }
+        let block = resolve(&cid)?;
+        if emitted.insert(cid) || options.dups {
+            writer.write_block(&block)?;
+        }
+        stack.extend(expand(&block, state)?.into_iter().rev());
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Like [`export`] on a blocking task, sending the header and then every block section as a
+/// frame on a channel holding at most `capacity` frames.
+///
+/// The traversal only advances as frames are received, so a slow client holds it back instead
+/// of the export being buffered. An error is sent as the last item; dropping the receiver stops
+/// the export. Must be called within a Tokio runtime.
+#[cfg(feature = "tokio")]
+pub fn export_channel(
+    car: std::sync::Arc<CarV1>,
+    roots: Vec<Cid>,
+    options: ExportOptions,
+    capacity: usize,
+) -> tokio::sync::mpsc::Receiver<CarResult<Vec<u8>>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = send_frames(&car, &roots, &options, &tx) {
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+    rx
+}
+
+/// Sends the frames of an export until done or the receiver is dropped.
+#[cfg(feature = "tokio")]
+fn send_frames(
+    car: &CarV1,
+    roots: &[Cid],
+    options: &ExportOptions,
+    tx: &tokio::sync::mpsc::Sender<CarResult<Vec<u8>>>,
+) -> CarResult<()> {
+    let send = |frame: Vec<u8>| tx.blocking_send(Ok(frame)).is_ok();
+    let frame = |block: &Block<DefaultParams>| -> CarResult<Vec<u8>> {
+        let mut frame = vec![];
+        write_car_v1_block(&mut frame, block)?;
+        Ok(frame)
+    };
+
+    let mut header = vec![];
+    CarHeaderV1 {
+        roots: roots.to_vec(),
+    }
+    .write_to(&mut header)?;
+    if !send(header) {
+        return Ok(());
+    }
+
+    if options.order == Order::Unknown {
+        for block in reachable(car, roots, options)? {
+            if !send(frame(block)?) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let mut emitted = HashSet::new();
+    let start = roots.iter().map(|root| (*root, ())).collect();
+    traversal::walk(car, start, options.dups, options.deadline, |_, block, _| {
+        if (emitted.insert(*block.cid()) || options.dups) && !send(frame(block)?) {
+            return Ok(ControlFlow::Break(()));
+        }
+        Ok(ControlFlow::Continue(
+            traversal::links(block)?
+                .into_iter()
+                .map(|link| (link, ()))
+                .collect(),
+        ))
+    })
+}
+
+/// The children of the UnixFS node `block`, at `offset` of its file, that overlap `range`,
+/// which is resolved against the size of the file at its root.
+fn entity_children(
+    block: &Block<DefaultParams>,
+    offset: u64,
+    range: EntityBytes,
+    resolved: &mut Option<Option<(u64, u64)>>,
+) -> CarResult<Vec<(Cid, u64)>> {
+    let node = UnixFsNode::from_block(block)?;
+    if !node.is_file() {
+        return Ok(vec![]);
+    }
+    let (from, to) = match *resolved {
+        Some(Some(range)) => range,
+        Some(None) => return Ok(vec![]),
+        None => match *resolved.insert(range.resolve(node.file_size())) {
+            Some(range) => range,
+            None => return Ok(vec![]),
+        },
+    };
+
+    let overflow = || CarError::InvalidUnixFs(format!("blocksizes of {} overflow", block.cid()));
+    let mut child_offset = offset
+        .checked_add(node.data.data.len() as u64)
+        .ok_or_else(overflow)?;
+    let mut children = vec![];
+    for (link, size) in node.links.iter().zip(&node.data.blocksizes) {
+        let end = child_offset.checked_add(*size).ok_or_else(overflow)?;
+        if child_offset <= to && end > from {
+            children.push((link.cid, child_offset));
+        }
+        child_offset = end;
+    }
+    Ok(children)
+}
+
+fn write_blocks<'a, W, I>(roots: &[Cid], blocks: I, mut w: W) -> CarResult<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Block<DefaultParams>>,
+{
+    CarHeaderV1 {
+        roots: roots.to_vec(),
+    }
+    .write_to(&mut w)?;
+    for block in blocks {
+        write_car_v1_block(&mut w, block)?;
+    }
+    Ok(())
+}
+
+fn reachable<'a>(
+    car: &'a CarV1,
+    roots: &[Cid],
+    options: &ExportOptions,
+) -> CarResult<Vec<&'a Block<DefaultParams>>> {
+    let start = roots.iter().map(|root| (*root, ())).collect();
+    walk(car, start, options, |block, _| {
+        Ok(traversal::links(block)?
+            .into_iter()
+            .map(|link| (link, ()))
+            .collect())
+    })
+}
+
+/// Depth-first walk from `start`, where `expand` returns the children to descend into along
+/// with the state they are visited with. Returns the blocks to write, in `options` order.
+fn walk<'a, T, F>(
+    car: &'a CarV1,
+    start: Vec<(Cid, T)>,
+    options: &ExportOptions,
+    mut expand: F,
+) -> CarResult<Vec<&'a Block<DefaultParams>>>
+where
+    T: Clone + Eq + Hash,
+    F: FnMut(&Block<DefaultParams>, T) -> CarResult<Vec<(Cid, T)>>,
+{
+    let repeat = options.dups && options.order == Order::Dfs;
+
+    let mut emitted = HashSet::new();
+    let mut selected = vec![];
+    traversal::walk(car, start, repeat, options.deadline, |_, block, state| {
+        if emitted.insert(*block.cid()) || repeat {
+            selected.push(block);
+        }
+        expand(block, state).map(ControlFlow::Continue)
+    })?;
+
+    if options.order == Order::Unknown {
+        let mut written = HashSet::new();
+        return Ok(car
+            .blocks
+            .iter()
+            .filter(|block| emitted.contains(block.cid()) && written.insert(*block.cid()))
+            .collect());
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::RecursionLimit;
+    use crate::test_utils::{cids, diamond, raw, unixfs_file, unixfs_file_with_blocksizes};
+    use std::io::Cursor;
+
+    #[test]
+    fn it_gathers_dags_from_several_sources() {
+        use std::collections::HashMap;
+
+        let car = diamond();
+        let [leaf, right, root, left] = cids(&car)[..] else {
+            unreachable!()
+        };
+        let store = |cids: &[Cid], corrupt: bool| -> HashMap<Cid, Vec<u8>> {
+            let mut store: HashMap<Cid, Vec<u8>> = car
+                .blocks
+                .iter()
+                .filter(|block| cids.contains(block.cid()))
+                .map(|block| (*block.cid(), block.data().to_vec()))
+                .collect();
+            if corrupt {
+                store.get_mut(&leaf).unwrap().push(0);
+            }
+            store
+        };
+        // The first source has a corrupt leaf, which the second has intact.
+        let first = store(&[root, left, leaf], true);
+        let second = CarV1::new(CarHeaderV1 { roots: vec![] }, car.blocks.clone());
+        let mut out = vec![];
+        gather(
+            &[root],
+            &[&first, &second.indexed()],
+            &ExportOptions::default(),
+            &mut out,
+        )
+        .unwrap();
+        let mut expected = vec![];
+        export(&car, &[root], &ExportOptions::default(), &mut expected).unwrap();
+        assert_eq!(out, expected);
+
+        let err = gather(&[root], &[&first], &ExportOptions::default(), &mut vec![]);
+        assert!(matches!(err, Err(CarError::HashMismatch { .. })));
+        let partial = store(&[root, left, leaf], false);
+        let err = gather(&[root], &[&partial], &ExportOptions::default(), &mut vec![]);
+        assert!(matches!(err, Err(CarError::MissingBlock(cid)) if cid == right));
+    }
+
+    fn exported_cids(car: &CarV1, options: ExportOptions) -> Vec<Cid> {
+        let mut out = vec![];
+        export(car, &car.header.roots, &options, &mut out).unwrap();
+        let exported = CarV1::from_reader(Cursor::new(out)).unwrap();
+        assert_eq!(exported.header.roots, car.header.roots);
+        exported.blocks.iter().map(|block| *block.cid()).collect()
+    }
+
+    #[test]
+    fn it_exports_dfs_with_and_without_dups() {
+        let car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| *car.blocks[i].cid());
+
+        assert_eq!(
+            exported_cids(&car, ExportOptions::new(Order::Dfs, false)),
+            vec![root, left, leaf, right]
+        );
+        assert_eq!(
+            exported_cids(&car, ExportOptions::new(Order::Dfs, true)),
+            vec![root, left, leaf, right, leaf]
+        );
+        assert_eq!(
+            exported_cids(&car, ExportOptions::new(Order::Unknown, true)),
+            vec![leaf, right, root, left]
+        );
+    }
+
+    #[test]
+    fn it_exports_shared_subtrees_once() {
+        let car = diamond();
+        let [leaf, right, _, left] = [0, 1, 2, 3].map(|i| &car.blocks[i]);
+        let len = |block: &Block<DefaultParams>| block.data().len() as u64;
+        let roots = [*left.cid(), *right.cid(), *left.cid()];
+        let mut out = vec![];
+        let shares = export_with_shares(&car, &roots, &ExportOptions::default(), &mut out).unwrap();
+        let exported = CarV1::from_reader(Cursor::new(out)).unwrap();
+        assert_eq!(
+            cids(&exported),
+            vec![*left.cid(), *leaf.cid(), *right.cid()]
+        );
+        assert_eq!(
+            shares,
+            vec![
+                RootShare {
+                    root: *left.cid(),
+                    exclusive: len(left),
+                    shared: len(leaf),
+                },
+                RootShare {
+                    root: *right.cid(),
+                    exclusive: len(right),
+                    shared: len(leaf),
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_exports_through_a_bounded_channel() {
+        let car = std::sync::Arc::new(diamond());
+        let roots = car.header.roots.clone();
+        for options in [
+            ExportOptions::new(Order::Dfs, true),
+            ExportOptions::new(Order::Unknown, false),
+        ] {
+            let mut expected = vec![];
+            export(&car, &roots, &options, &mut expected).unwrap();
+
+            let mut rx = export_channel(car.clone(), roots.clone(), options, 1);
+            let mut frames = vec![];
+            while let Some(frame) = rx.recv().await {
+                frames.push(frame.unwrap());
+            }
+            assert_eq!(frames.len(), 1 + exported_cids(&car, options).len());
+            assert_eq!(frames.concat(), expected);
+        }
+
+        let mut rx = export_channel(
+            car.clone(),
+            vec![*raw(b"missing").cid()],
+            ExportOptions::default(),
+            1,
+        );
+        rx.recv().await.unwrap().unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(Err(CarError::MissingBlock(_)))
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn it_fails_on_missing_blocks() {
+        let mut car = diamond();
+        let leaf = *car.blocks.remove(0).cid();
+        match export(&car, &car.header.roots, &ExportOptions::default(), vec![]) {
+            Err(CarError::MissingBlock(cid)) => assert_eq!(cid, leaf),
+            other => panic!("Expected MissingBlock, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_exports_entity_bytes() {
+        let car = unixfs_file(&[b"aaaa", b"bbbb", b"cccc"]);
+        let cids = cids(&car);
+        let export_range = |range: &str| {
+            let mut out = vec![];
+            let range = range.parse().unwrap();
+            export_entity_bytes(&car, &cids[0], range, &ExportOptions::default(), &mut out)
+                .unwrap();
+            let mut gathered = vec![];
+            let sources: &[&dyn BlockFetcher] = &[&car.indexed()];
+            gather_entity_bytes(
+                &cids[0],
+                range,
+                sources,
+                &ExportOptions::default(),
+                &mut gathered,
+            )
+            .unwrap();
+            assert_eq!(gathered, out);
+            let exported = CarV1::from_reader(Cursor::new(out)).unwrap();
+            exported
+                .blocks
+                .iter()
+                .map(|block| *block.cid())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(export_range("0:*"), cids);
+        assert_eq!(export_range("5:6"), vec![cids[0], cids[2]]);
+        assert_eq!(export_range("3:4"), vec![cids[0], cids[1], cids[2]]);
+        assert_eq!(export_range("-1:*"), vec![cids[0], cids[3]]);
+        assert_eq!(export_range("0:-9"), vec![cids[0], cids[1]]);
+        assert_eq!(export_range("100:*"), vec![cids[0]]);
+        assert!("1-2".parse::<EntityBytes>().is_err());
+
+        let hostile = unixfs_file_with_blocksizes(&[b"aaaa", b"bbbb"], &[u64::MAX, 4]);
+        let range = "0:*".parse().unwrap();
+        let root = hostile.header.roots[0];
+        let options = ExportOptions::default();
+        assert!(matches!(
+            export_entity_bytes(&hostile, &root, range, &options, &mut vec![]),
+            Err(CarError::InvalidUnixFs(_))
+        ));
+    }
+
+    #[test]
+    fn it_exports_selected_blocks() {
+        let car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| *car.blocks[i].cid());
+        let export_with = |selector: &Selector| {
+            let mut out = vec![];
+            export_selector(&car, &root, selector, &ExportOptions::default(), &mut out).unwrap();
+            let exported = CarV1::from_reader(Cursor::new(out)).unwrap();
+            exported
+                .blocks
+                .iter()
+                .map(|block| *block.cid())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            export_with(&Selector::explore_all_recursively()),
+            vec![root, left, leaf, right]
+        );
+        let second_only = Selector::ExploreIndex {
+            index: 1,
+            next: Box::new(Selector::Matcher { subset: None }),
+        };
+        assert_eq!(export_with(&second_only), vec![root, right]);
+        let two_levels = Selector::ExploreRecursive {
+            limit: RecursionLimit::Depth(2),
+            sequence: Box::new(Selector::ExploreAll {
+                next: Box::new(Selector::ExploreRecursiveEdge),
+            }),
+        };
+        assert_eq!(export_with(&two_levels), vec![root, left, right]);
+    }
+
+    #[test]
+    fn it_exports_skeletons() {
+        let mut car = unixfs_file(&[b"a", b"bbb", b"cc", b"dddd"]);
+        car.blocks.push(raw(b"unreachable"));
+        let cids = cids(&car);
+        let export_with = |sample| {
+            let mut out = vec![];
+            export_skeleton(
+                &car,
+                &[cids[0]],
+                sample,
+                &ExportOptions::default(),
+                &mut out,
+            )
+            .unwrap();
+            let exported = CarV1::from_reader(Cursor::new(out)).unwrap();
+            exported
+                .blocks
+                .iter()
+                .map(|block| *block.cid())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(export_with(LeafSample::None), vec![cids[0]]);
+        assert_eq!(
+            export_with(LeafSample::Largest(2)),
+            vec![cids[0], cids[2], cids[4]]
+        );
+        assert_eq!(
+            export_with(LeafSample::EveryNth(3)),
+            vec![cids[0], cids[1], cids[4]]
+        );
+        assert_eq!(export_with(LeafSample::Largest(10)), cids[..5].to_vec());
+    }
+
+    #[test]
+    fn it_negotiates_params() {
+        let options =
+            ExportOptions::from_params("application/vnd.ipld.car; version=1; order=unk; dups=y")
+                .unwrap();
+        assert_eq!(options, ExportOptions::new(Order::Unknown, true));
+        assert_eq!(
+            options.content_type(),
+            "application/vnd.ipld.car; version=1; order=unk; dups=y"
+        );
+        assert_eq!(
+            ExportOptions::from_params("version=1").unwrap(),
+            ExportOptions::default()
+        );
+        assert!(ExportOptions::from_params("dups=maybe").is_err());
+    }
+}
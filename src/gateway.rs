@@ -0,0 +1,375 @@
+//! Fetching blocks from an HTTP gateway, for filling in blocks missing locally.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv6Addr, TcpStream};
+use std::time::Duration;
+
+use libipld::cid::Cid;
+use libipld::store::StoreParams;
+use libipld::DefaultParams;
+
+use crate::{CarError, CarResult, Deadline};
+
+/// How long the status line and headers of a response may be in all.
+const MAX_HEAD_LENGTH: u64 = 64 * 1024;
+
+/// A source of blocks that are not stored locally.
+pub trait BlockFetcher {
+    /// The data of the block `cid`, or `None` if the source does not have it. The data is
+    /// checked against `cid` by the caller.
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>>;
+}
+
+impl<F: BlockFetcher + ?Sized> BlockFetcher for &F {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        (**self).fetch(cid)
+    }
+}
+
+impl BlockFetcher for crate::v1::IndexedCarV1<'_> {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        Ok(self.get(cid).map(|block| block.data().to_vec()))
+    }
+}
+
+/// A trustless gateway reached over plain HTTP, queried with `GET /ipfs/{cid}?format=raw`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpGateway {
+    pub host: String,
+    pub port: u16,
+    /// How long each read and write may wait, 30 seconds by default.
+    pub timeout: Option<Duration>,
+    /// How long a whole fetch may take, however steadily its bytes arrive, past which it fails
+    /// with [`CarError::DeadlineExceeded`]. A minute by default.
+    pub fetch_timeout: Option<Duration>,
+    /// The largest block fetched, past which a response fails without being read further.
+    /// `DefaultParams::MAX_BLOCK_SIZE` by default.
+    pub max_block_size: u64,
+}
+
+impl HttpGateway {
+    /// Parses an `http://host[:port]` URL, the host of which may be an IPv6 address in
+    /// brackets.
+    pub fn new(url: &str) -> CarResult<Self> {
+        let (host, port, path) = parse_url(url)?;
+        if !path.trim_end_matches('/').is_empty() {
+            return Err(CarError::Gateway(format!(
+                "unsupported gateway URL {}",
+                url
+            )));
+        }
+        Ok(Self {
+            host,
+            port,
+            timeout: Some(Duration::from_secs(30)),
+            fetch_timeout: Some(Duration::from_secs(60)),
+            max_block_size: DefaultParams::MAX_BLOCK_SIZE as u64,
+        })
+    }
+}
+
+impl BlockFetcher for HttpGateway {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        let target = format!("/ipfs/{}?format=raw", cid);
+        let headers = "Accept: application/vnd.ipld.raw\r\n";
+        let limit = self.max_block_size.saturating_add(1);
+        let deadline = self.fetch_timeout.map_or(Deadline::none(), Deadline::after);
+        let response = get(
+            &self.host,
+            self.port,
+            self.timeout,
+            deadline,
+            &target,
+            headers,
+            limit,
+        );
+        match response? {
+            (200, body) if body.len() as u64 > self.max_block_size => Err(CarError::Gateway(
+                format!("{} is larger than {} bytes", cid, self.max_block_size),
+            )),
+            (200, body) => Ok(Some(body)),
+            (404 | 410, _) => Ok(None),
+            (status, _) => Err(CarError::Gateway(format!("{} returned {}", cid, status))),
+        }
+    }
+}
+
+/// Splits an `http://host[:port][/path]` URL, the path starting with its `/`. An IPv6 host is
+/// written in brackets, as in `http://[::1]:8080`, and returned without them.
+pub(crate) fn parse_url(url: &str) -> CarResult<(String, u16, String)> {
+    let invalid = || CarError::Gateway(format!("unsupported URL {}", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, ""),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+            if host.parse::<Ipv6Addr>().is_err() {
+                return Err(invalid());
+            }
+            (host, port)
+        }
+        None => match authority.find(':') {
+            Some(at) => authority.split_at(at),
+            None => (authority, ""),
+        },
+    };
+    let port = match port {
+        "" => 80,
+        port => port
+            .strip_prefix(':')
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(invalid)?,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Sends `GET target` with the `\r\n`-terminated `headers`, returning the status and up to
+/// `limit` bytes of the body. Bodies of responses other than 200 and 206 are not read.
+///
+/// Fails with [`CarError::DeadlineExceeded`] if the response is not read by `deadline`.
+pub(crate) fn get(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    deadline: Deadline,
+    target: &str,
+    headers: &str,
+    limit: u64,
+) -> CarResult<(u16, Vec<u8>)> {
+    request(host, port, timeout, deadline, target, headers, limit).map_err(|err| {
+        match deadline.check() {
+            Ok(()) => err,
+            Err(exceeded) => exceeded,
+        }
+    })
+}
+
+fn request(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    deadline: Deadline,
+    target: &str,
+    headers: &str,
+    limit: u64,
+) -> CarResult<(u16, Vec<u8>)> {
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    let authority = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        target, authority, headers
+    )?;
+
+    let mut r = BufReader::new(DeadlineReader {
+        r: stream,
+        deadline,
+    });
+    let mut head = (&mut r).take(MAX_HEAD_LENGTH);
+    let mut status_line = String::new();
+    head.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| CarError::Gateway(format!("malformed status line {:?}", status_line)))?;
+
+    let mut chunked = false;
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Err(CarError::Gateway(format!(
+                "response head ended early or was longer than {} bytes",
+                MAX_HEAD_LENGTH
+            )));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            } else if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse::<u64>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![];
+    if !matches!(status, 200 | 206) {
+        return Ok((status, body));
+    }
+    if chunked {
+        read_chunked(&mut r, &mut body, limit)?;
+    } else {
+        r.take(length.unwrap_or(limit).min(limit))
+            .read_to_end(&mut body)?;
+    }
+    Ok((status, body))
+}
+
+/// Fails reads once `deadline` has passed, so that a peer trickling bytes cannot outlast it.
+pub(crate) struct DeadlineReader<R> {
+    pub(crate) r: R,
+    pub(crate) deadline: Deadline,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.deadline.check().is_err() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.r.read(buf)
+    }
+}
+
+/// Reads the chunks of a body until its end or until `limit` bytes of it are read.
+pub(crate) fn read_chunked<R: BufRead>(r: &mut R, body: &mut Vec<u8>, limit: u64) -> CarResult<()> {
+    while (body.len() as u64) < limit {
+        let mut line = String::new();
+        r.take(MAX_HEAD_LENGTH).read_line(&mut line)?;
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| CarError::Gateway(format!("malformed chunk size {:?}", line)))?;
+        if size == 0 {
+            break;
+        }
+        let left = limit - body.len() as u64;
+        r.take(size.min(left)).read_to_end(body)?;
+        if size > left {
+            break;
+        }
+        let mut crlf = [0u8; 2];
+        r.read_exact(&mut crlf)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ok, raw, serve};
+
+    #[test]
+    fn it_parses_gateway_urls() {
+        let gateway = HttpGateway::new("http://localhost:8080/").unwrap();
+        assert_eq!((gateway.host.as_str(), gateway.port), ("localhost", 8080));
+        assert_eq!(HttpGateway::new("http://example.com").unwrap().port, 80);
+        assert!(HttpGateway::new("https://example.com").is_err());
+        assert!(HttpGateway::new("http://example.com/ipfs").is_err());
+
+        let gateway = HttpGateway::new("http://[::1]:8080").unwrap();
+        assert_eq!((gateway.host.as_str(), gateway.port), ("::1", 8080));
+        assert_eq!(HttpGateway::new("http://[::1]/").unwrap().port, 80);
+        assert!(HttpGateway::new("http://::1:8080").is_err());
+        assert!(HttpGateway::new("http://[::1:8080").is_err());
+        assert!(HttpGateway::new("http://[example.com]:8080").is_err());
+        assert!(HttpGateway::new("http://example.com:").is_err());
+    }
+
+    #[test]
+    fn it_fetches_blocks() {
+        let block = raw(b"remote");
+        let url = serve(vec![
+            ok(block.data()),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nrem\r\n3\r\note\r\n0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let gateway = HttpGateway::new(&url).unwrap();
+        let cid = block.cid();
+        assert_eq!(gateway.fetch(cid).unwrap(), Some(b"remote".to_vec()));
+        assert_eq!(gateway.fetch(cid).unwrap(), Some(b"remote".to_vec()));
+        assert_eq!(gateway.fetch(cid).unwrap(), None);
+        assert!(matches!(gateway.fetch(cid), Err(CarError::Gateway(_))));
+    }
+
+    #[test]
+    fn it_refuses_blocks_over_the_size_limit() {
+        let block = raw(b"remote");
+        let url = serve(vec![
+            ok(block.data()),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nremote\r\n0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nremote".to_vec(),
+        ]);
+        let gateway = HttpGateway {
+            max_block_size: 5,
+            ..HttpGateway::new(&url).unwrap()
+        };
+        for _ in 0..3 {
+            assert!(matches!(
+                gateway.fetch(block.cid()),
+                Err(CarError::Gateway(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn it_gives_up_on_slow_responses() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let response = ok(b"remote");
+            for byte in response {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let gateway = HttpGateway {
+            fetch_timeout: Some(Duration::from_millis(100)),
+            ..HttpGateway::new(&url).unwrap()
+        };
+        assert!(matches!(
+            gateway.fetch(raw(b"remote").cid()),
+            Err(CarError::DeadlineExceeded)
+        ));
+    }
+
+    #[test]
+    fn it_fetches_from_ipv6_gateways() {
+        let Ok(listener) = std::net::TcpListener::bind("[::1]:0") else {
+            // No IPv6 loopback to test against.
+            return;
+        };
+        let block = raw(b"remote");
+        let port = listener.local_addr().unwrap().port();
+        let response = ok(block.data());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut r = BufReader::new(&stream);
+            while !head.ends_with(b"\r\n\r\n") {
+                r.read_until(b'\n', &mut head).unwrap();
+            }
+            stream.write_all(&response).unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let gateway = HttpGateway::new(&format!("http://[::1]:{}", port)).unwrap();
+        assert_eq!(
+            gateway.fetch(block.cid()).unwrap(),
+            Some(b"remote".to_vec())
+        );
+        assert!(server.join().unwrap().contains("\r\nHost: [::1]\r\n"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Serving a [`CarRepo`] as the gRPC `racecar.BlockService` in `proto/block_service.proto`.
+//!
+//! [`BlockServiceServer`] is a plain tower service, so it can be mounted on a
+//! `tonic::transport::Server` or any other HTTP/2 server.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use libipld::cid::Cid;
+use libipld::Block;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::{Code, Request, Response, Status};
+
+use crate::repo::CarRepo;
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::CarError;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub cid: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetResponse {
+    /// `None` if the repository does not have the block.
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HasRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub cid: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HasResponse {
+    #[prost(bool, tag = "1")]
+    pub has: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub cid: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutResponse {}
+
+/// Answers `Get`, `Has` and `Put` from a [`CarRepo`].
+///
+/// Lookups go through [`CarRepo::get_block`], including its fallback, and each `Put` is
+/// published as a one-block archive rooted at the block. Repository IO runs on Tokio's
+/// blocking pool.
+#[derive(Debug, Clone)]
+pub struct BlockServiceServer {
+    repo: Arc<CarRepo>,
+}
+
+impl BlockServiceServer {
+    pub fn new(repo: CarRepo) -> Self {
+        Self {
+            repo: Arc::new(repo),
+        }
+    }
+
+    pub async fn get(&self, request: GetRequest) -> Result<GetResponse, Status> {
+        let cid = Cid::try_from(&request.cid[..]).map_err(invalid_argument)?;
+        let block = self.blocking(move |repo| repo.get_block(&cid)).await?;
+        Ok(GetResponse {
+            data: block.map(|block| block.data().to_vec()),
+        })
+    }
+
+    pub async fn has(&self, request: HasRequest) -> Result<HasResponse, Status> {
+        let cid = Cid::try_from(&request.cid[..]).map_err(invalid_argument)?;
+        let block = self.blocking(move |repo| repo.get_block(&cid)).await?;
+        Ok(HasResponse {
+            has: block.is_some(),
+        })
+    }
+
+    pub async fn put(&self, request: PutRequest) -> Result<PutResponse, Status> {
+        let cid = Cid::try_from(&request.cid[..]).map_err(invalid_argument)?;
+        let block = Block::new(cid, request.data).map_err(invalid_argument)?;
+        self.blocking(move |repo| {
+            repo.publish(&CarV1::new(CarHeaderV1 { roots: vec![cid] }, vec![block]))
+        })
+        .await?;
+        Ok(PutResponse {})
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CarRepo) -> Result<T, CarError> + Send + 'static,
+    {
+        let repo = self.repo.clone();
+        tokio::task::spawn_blocking(move || f(&repo))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+}
+
+fn invalid_argument<E: std::fmt::Display>(err: E) -> Status {
+    Status::invalid_argument(err.to_string())
+}
+
+impl NamedService for BlockServiceServer {
+    const NAME: &'static str = "racecar.BlockService";
+}
+
+macro_rules! unary_method {
+    ($name:ident, $method:ident, $request:ty, $response:ty) => {
+        struct $name(BlockServiceServer);
+
+        impl UnaryService<$request> for $name {
+            type Response = $response;
+            type Future = BoxFuture<Response<$response>, Status>;
+
+            fn call(&mut self, request: Request<$request>) -> Self::Future {
+                let server = self.0.clone();
+                Box::pin(async move {
+                    server
+                        .$method(request.into_inner())
+                        .await
+                        .map(Response::new)
+                })
+            }
+        }
+    };
+}
+
+unary_method!(GetMethod, get, GetRequest, GetResponse);
+unary_method!(HasMethod, has, HasRequest, HasResponse);
+unary_method!(PutMethod, put, PutRequest, PutResponse);
+
+impl<B> Service<http::Request<B>> for BlockServiceServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let server = self.clone();
+        Box::pin(async move {
+            let response = match request.uri().path() {
+                "/racecar.BlockService/Get" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(GetMethod(server), request)
+                        .await
+                }
+                "/racecar.BlockService/Has" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(HasMethod(server), request)
+                        .await
+                }
+                "/racecar.BlockService/Put" => {
+                    Grpc::new(ProstCodec::default())
+                        .unary(PutMethod(server), request)
+                        .await
+                }
+                _ => http::Response::builder()
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header(http::header::CONTENT_TYPE, "application/grpc")
+                    .body(empty_body())
+                    .unwrap(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{raw, temp_dir};
+    use tonic::client::Grpc as Client;
+    use tonic::codegen::http::uri::PathAndQuery;
+
+    async fn call<Req, Res>(
+        server: &BlockServiceServer,
+        method: &'static str,
+        request: Req,
+    ) -> Result<Res, Status>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut client = Client::new(server.clone());
+        client.ready().await.unwrap();
+        client
+            .unary(
+                Request::new(request),
+                PathAndQuery::from_static(method),
+                ProstCodec::default(),
+            )
+            .await
+            .map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn it_serves_blocks_over_grpc() {
+        let dir = temp_dir("grpc");
+        let server = BlockServiceServer::new(CarRepo::new(&dir).unwrap());
+        let block = raw(b"served");
+        let cid = block.cid().to_bytes();
+
+        let has: HasResponse = call(
+            &server,
+            "/racecar.BlockService/Has",
+            HasRequest { cid: cid.clone() },
+        )
+        .await
+        .unwrap();
+        assert!(!has.has);
+
+        let put = PutRequest {
+            cid: cid.clone(),
+            data: block.data().to_vec(),
+        };
+        let _: PutResponse = call(&server, "/racecar.BlockService/Put", put)
+            .await
+            .unwrap();
+        let get: GetResponse = call(&server, "/racecar.BlockService/Get", GetRequest { cid })
+            .await
+            .unwrap();
+        assert_eq!(get.data.as_deref(), Some(&b"served"[..]));
+
+        let tampered = PutRequest {
+            cid: block.cid().to_bytes(),
+            data: b"tampered".to_vec(),
+        };
+        let err = call::<_, PutResponse>(&server, "/racecar.BlockService/Put", tampered)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let err =
+            call::<_, GetResponse>(&server, "/racecar.BlockService/Get", HasRequest::default())
+                .await
+                .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
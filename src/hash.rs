@@ -0,0 +1,141 @@
+//! Multihash implementations beyond the ones libipld links, see [`HasherRegistry`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use libipld::cid::Cid;
+use libipld::error::{InvalidMultihash, UnsupportedMultihash};
+use libipld::multihash::{Code, Multihash, MultihashDigest};
+
+use crate::{CarError, CarResult};
+
+/// The multihash code of the identity "hash", whose digest is the data itself.
+const IDENTITY: u64 = 0x00;
+
+/// A hash function, returning the digest of its input.
+pub type HashFn = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+/// Hash functions by multihash code, used to verify blocks when reading, see
+/// [`crate::HashPolicy`], and to hash the blocks of a [`crate::pack::Packer`].
+///
+/// Codes without a registered function fall back to the implementations linked by libipld, and
+/// to the identity hash, which libipld leaves out; registering one of those codes replaces its
+/// implementation. Digests are limited to the 64 bytes a CID's multihash can hold.
+#[derive(Clone, Default)]
+pub struct HasherRegistry {
+    hashers: HashMap<u64, Arc<HashFn>>,
+}
+
+impl fmt::Debug for HasherRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut codes: Vec<_> = self.hashers.keys().collect();
+        codes.sort();
+        f.debug_struct("HasherRegistry")
+            .field("codes", &codes)
+            .finish()
+    }
+}
+
+impl HasherRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes the multihash `code` with `hasher` from now on.
+    pub fn register<F>(&mut self, code: u64, hasher: F) -> &mut Self
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.hashers.insert(code, Arc::new(hasher));
+        self
+    }
+
+    /// Whether `code` has a registered or linked implementation.
+    pub fn can_hash(&self, code: u64) -> bool {
+        self.hashers.contains_key(&code) || code == IDENTITY || Code::try_from(code).is_ok()
+    }
+
+    /// The multihash of `data` with `code`, `None` if there is no implementation of it.
+    pub fn digest(&self, code: u64, data: &[u8]) -> CarResult<Option<Multihash>> {
+        match self.hashers.get(&code) {
+            Some(hasher) => Ok(Some(
+                Multihash::wrap(code, &hasher(data)).map_err(libipld::cid::Error::from)?,
+            )),
+            None if code == IDENTITY => Ok(Some(
+                Multihash::wrap(code, data).map_err(libipld::cid::Error::from)?,
+            )),
+            None => Ok(Code::try_from(code).ok().map(|code| code.digest(data))),
+        }
+    }
+
+    /// The CID of `data` with `codec`, hashed with `code`.
+    pub fn cid(&self, codec: u64, code: u64, data: &[u8]) -> CarResult<Cid> {
+        match self.digest(code, data)? {
+            Some(hash) => Ok(Cid::new_v1(codec, hash)),
+            None => Err(CarError::Ipld(UnsupportedMultihash(code).into())),
+        }
+    }
+
+    /// Checks `data` against `cid`, returning `false` if its hash has no implementation.
+    pub fn verify(&self, cid: &Cid, data: &[u8]) -> CarResult<bool> {
+        match self.digest(cid.hash().code(), data)? {
+            Some(hash) if hash == *cid.hash() => Ok(true),
+            Some(hash) => Err(CarError::Ipld(InvalidMultihash(hash.to_bytes()).into())),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in for a hash libipld does not link: the data reversed, padded to 8 bytes.
+    fn reversed(data: &[u8]) -> Vec<u8> {
+        let mut digest: Vec<u8> = data.iter().rev().copied().collect();
+        digest.resize(8, 0);
+        digest
+    }
+
+    #[test]
+    fn it_hashes_with_registered_and_linked_functions() {
+        let mut registry = HasherRegistry::new();
+        assert!(!registry.can_hash(0x3333));
+        assert!(registry.cid(0x55, 0x3333, b"abc").is_err());
+        registry.register(0x3333, reversed);
+        assert!(registry.can_hash(0x3333));
+
+        let cid = registry.cid(0x55, 0x3333, b"abc").unwrap();
+        assert_eq!(cid.hash().digest(), b"cba\0\0\0\0\0");
+        assert!(registry.verify(&cid, b"abc").unwrap());
+        assert!(registry.verify(&cid, b"abd").is_err());
+
+        let sha2 = Cid::new_v1(0x55, Code::Sha2_256.digest(b"abc"));
+        assert_eq!(registry.cid(0x55, 0x12, b"abc").unwrap(), sha2);
+        assert!(!registry
+            .verify(
+                &Cid::new_v1(0x55, Multihash::wrap(0x11, &[0; 20]).unwrap()),
+                b"abc"
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn it_hashes_identity_and_empty_data() {
+        let registry = HasherRegistry::new();
+        assert!(registry.can_hash(IDENTITY));
+        let empty = registry.cid(0x55, IDENTITY, b"").unwrap();
+        assert_eq!(empty.to_string(), "bafkqaaa");
+        assert!(registry.verify(&empty, b"").unwrap());
+        assert!(registry.verify(&empty, b"a").is_err());
+        let hi = registry.cid(0x55, IDENTITY, b"hi").unwrap();
+        assert_eq!(hi.hash().digest(), b"hi");
+        assert!(registry.verify(&hi, b"hi").unwrap());
+        assert!(registry.cid(0x55, IDENTITY, &[0; 65]).is_err());
+
+        let sha2 = registry.cid(0x55, 0x12, b"").unwrap();
+        assert_eq!(sha2.hash().digest(), Code::Sha2_256.digest(b"").digest());
+        assert!(registry.verify(&sha2, b"").unwrap());
+    }
+}
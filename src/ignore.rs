@@ -0,0 +1,181 @@
+//! `.gitignore`-style rules for leaving paths out of a pack, see [`IgnoreRules`].
+
+/// The rules of one ignore file, matched against paths relative to the directory holding it.
+///
+/// Lines follow `.gitignore`: blank lines and lines starting with `#` are skipped, `!`
+/// re-includes what an earlier line ignored, a trailing `/` matches directories only, and a
+/// pattern with a `/` before its end is anchored to the directory of the file, while one
+/// without matches names at any depth. `*`, `?` and `[...]` match within a name, and `**`
+/// matches any number of directories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    /// The pattern split at `/`, with `**` for any number of names.
+    segments: Vec<String>,
+    negated: bool,
+    directory_only: bool,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses the lines of an ignore file.
+    pub fn parse(text: &str) -> Self {
+        let mut rules = Self::new();
+        for line in text.lines() {
+            rules.add(line);
+        }
+        rules
+    }
+
+    /// Adds the rule on `line`, if it holds one; later rules win over earlier ones.
+    pub fn add(&mut self, line: &str) {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (directory_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let mut segments: Vec<String> = line
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        if segments.is_empty() {
+            return;
+        }
+        if !anchored {
+            segments.insert(0, "**".into());
+        }
+        self.rules.push(Rule {
+            segments,
+            negated,
+            directory_only,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the last rule matching `path`, relative to the directory of the rules and
+    /// separated by `/`, ignores it (`Some(true)`) or re-includes it (`Some(false)`); `None` if
+    /// no rule matches.
+    pub fn matched(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.directory_only) && matches_path(&rule.segments, &names))
+            .map(|rule| !rule.negated)
+    }
+}
+
+fn matches_path(segments: &[String], names: &[&str]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        // A trailing `**` matches what is inside a directory, not the directory itself.
+        Some((segment, rest)) if segment == "**" && rest.is_empty() => !names.is_empty(),
+        Some((segment, rest)) if segment == "**" => {
+            (0..=names.len()).any(|skip| matches_path(rest, &names[skip..]))
+        }
+        Some((segment, rest)) => match names.split_first() {
+            Some((name, names)) => {
+                matches_name(segment.as_bytes(), name.as_bytes()) && matches_path(rest, names)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches one name against a pattern of `*`, `?`, `[...]` and `\`-escaped characters.
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_name(rest, &name[1..]),
+        Some((b'[', rest)) => match (class(rest), name.split_first()) {
+            (Some((matches, rest)), Some((&c, name))) => matches(c) && matches_name(rest, name),
+            // An unclosed `[` is taken literally.
+            (None, Some((b'[', name))) => matches_name(rest, name),
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            name.first() == rest.first() && matches_name(&rest[1..], &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && matches_name(rest, &name[1..]),
+    }
+}
+
+/// Parses a character class after its `[`, returning what it matches and the pattern after its
+/// `]`, or `None` if it is not closed.
+fn class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negated, body) = match pattern.first() {
+        Some(b'!' | b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // A `]` first in the class is one of its characters.
+    let end = body.iter().skip(1).position(|&c| c == b']')? + 1;
+    let (body, rest) = (&body[..end], &body[end + 1..]);
+    let matches = move |c: u8| {
+        let mut i = 0;
+        let mut found = false;
+        while i < body.len() {
+            if i + 2 < body.len() && body[i + 1] == b'-' {
+                found |= (body[i]..=body[i + 2]).contains(&c);
+                i += 3;
+            } else {
+                found |= body[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((matches, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_gitignore_patterns() {
+        let rules = IgnoreRules::parse(
+            "# build output\n\
+             target/\n\
+             *.log\n\
+             !keep.log\n\
+             /docs/*.tmp\n\
+             **/cache/**\n\
+             file[0-9].txt\n\
+             \\#notes\n",
+        );
+        assert_eq!(rules.matched("target", true), Some(true));
+        assert_eq!(rules.matched("src/target", true), Some(true));
+        assert_eq!(rules.matched("target", false), None);
+        assert_eq!(rules.matched("a/b/debug.log", false), Some(true));
+        assert_eq!(rules.matched("a/keep.log", false), Some(false));
+        assert_eq!(rules.matched("docs/x.tmp", false), Some(true));
+        assert_eq!(rules.matched("src/docs/x.tmp", false), None);
+        assert_eq!(rules.matched("a/cache/b/c", false), Some(true));
+        assert_eq!(rules.matched("a/cache", true), None);
+        assert_eq!(rules.matched("file7.txt", false), Some(true));
+        assert_eq!(rules.matched("filex.txt", false), None);
+        assert_eq!(rules.matched("#notes", false), Some(true));
+        assert_eq!(rules.matched("main.rs", false), None);
+        assert!(IgnoreRules::parse("\n# only a comment\n").is_empty());
+    }
+}
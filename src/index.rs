@@ -0,0 +1,911 @@
+//! The index of a CARv2: `IndexSorted` and `MultihashIndexSorted`, see [`CarV2Index`], and
+//! its layout, see [`IndexLayout`].
+//!
+//! Every count and length is read from the archive, so none of them sizes an allocation before
+//! it is checked against the bytes left in the index, and entries are read in bounded chunks.
+//! Indexes are written as go-car writes them, see [`CarV2Index::write_to`].
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Take, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+use cid::multihash::MultihashGeneric;
+use cid::Cid;
+
+use crate::block::CarBlockReader;
+use crate::{write_varint, CarError, CarResult};
+use unsigned_varint::io::read_u64 as varint_read_u64;
+
+/// Multicodec of an index of digests sorted in buckets of one width each.
+pub const INDEX_SORTED: u64 = 0x0400;
+/// Multicodec of `IndexSorted` indexes by multihash code.
+pub const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+const OFFSET_LENGTH: u32 = 8;
+/// The longest digest a CID's multihash can hold.
+const MAX_DIGEST_LENGTH: u32 = 64;
+/// Bucket width and length.
+const BUCKET_HEADER_LENGTH: u64 = 12;
+/// Multihash code and bucket count.
+const MULTIHASH_HEADER_LENGTH: u64 = 12;
+/// How many entries are read at once.
+const CHUNK_ENTRIES: u64 = 4096;
+
+/// An index of a CARv2, mapping block digests to the offsets of their sections in the payload.
+///
+/// How the entries are laid out is [`crate::raw::IndexLayout`], see [`CarV2Index::layout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarV2Index(IndexLayout);
+
+/// The entries of a [`CarV2Index`] as the format lays them out, by codec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexLayout {
+    /// `IndexSorted`.
+    Sorted(Vec<IndexBucket>),
+    /// `MultihashIndexSorted`: the buckets of the digests of each multihash code.
+    MultihashSorted(BTreeMap<u64, Vec<IndexBucket>>),
+    /// An index with another codec, kept as it was read.
+    Unknown { codec: u64, raw_bytes: Vec<u8> },
+}
+
+/// The kinds of index [`CarV2Index::build`] builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// `IndexSorted`, by digest alone.
+    Sorted,
+    /// `MultihashIndexSorted`, by multihash code and digest, as go-car writes by default.
+    #[default]
+    MultihashSorted,
+}
+
+impl IndexKind {
+    pub fn codec(self) -> u64 {
+        match self {
+            IndexKind::Sorted => INDEX_SORTED,
+            IndexKind::MultihashSorted => MULTIHASH_INDEX_SORTED,
+        }
+    }
+}
+
+/// Entries whose digests have the same length, sorted by digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexBucket {
+    /// The length of an entry: its digest and 8 byte offset.
+    pub width: u32,
+    pub entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub digest: Vec<u8>,
+    /// From the start of the payload.
+    pub offset: u64,
+}
+
+impl CarV2Index {
+    /// An index of `kind` of blocks by CID and the offset of their section from the start of
+    /// the payload.
+    pub fn build<'a, I>(kind: IndexKind, sections: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Cid, u64)>,
+    {
+        match kind {
+            IndexKind::Sorted => Self::sorted(sections),
+            IndexKind::MultihashSorted => Self::multihash_sorted(sections),
+        }
+    }
+
+    /// An `IndexSorted` of blocks by CID and the offset of their section from the start of the
+    /// payload. Digests are indexed whatever their multihash code, identity ones included.
+    pub fn sorted<'a, I>(sections: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Cid, u64)>,
+    {
+        let mut widths = BTreeMap::new();
+        for (cid, offset) in sections {
+            add_entry(&mut widths, cid, offset);
+        }
+        CarV2Index(IndexLayout::Sorted(sorted_buckets(widths)))
+    }
+
+    /// A `MultihashIndexSorted` of blocks by CID and the offset of their section from the start
+    /// of the payload, as go-car builds one. Identity CIDs are indexed too, as go-car does for
+    /// fully indexed archives.
+    pub fn multihash_sorted<'a, I>(sections: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Cid, u64)>,
+    {
+        let mut codes: BTreeMap<u64, BTreeMap<u32, Vec<IndexEntry>>> = BTreeMap::new();
+        for (cid, offset) in sections {
+            add_entry(codes.entry(cid.hash().code()).or_default(), cid, offset);
+        }
+        CarV2Index(IndexLayout::MultihashSorted(
+            codes
+                .into_iter()
+                .map(|(code, widths)| (code, sorted_buckets(widths)))
+                .collect(),
+        ))
+    }
+
+    /// Indexes the CARv1 in `r`, or the payload of a CARv2, as a `MultihashIndexSorted`, as
+    /// `car index` does, reading the length and CID of each section but not verifying its
+    /// data. Offsets are counted from the start of the payload, so the index can be written
+    /// next to a CARv1 as a detached index or into a CARv2.
+    pub fn generate_from_reader<R: Read>(r: R) -> CarResult<Self> {
+        let mut reader = CarBlockReader::new(r)?;
+        let mut sections = vec![];
+        while let Some((block, location)) = reader.next_block_with_location()? {
+            sections.push((block.cid, location.offset - reader.payload()));
+        }
+        Ok(Self::multihash_sorted(
+            sections.iter().map(|(cid, offset)| (cid, *offset)),
+        ))
+    }
+
+    /// Reads the body of an index with `codec`, which is at most `length` bytes long. Bodies
+    /// with codecs other than [`INDEX_SORTED`] and [`MULTIHASH_INDEX_SORTED`] are read whole
+    /// as [`IndexLayout::Unknown`].
+    pub fn read_body<R: Read>(r: R, codec: u64, length: u64) -> CarResult<Self> {
+        let mut r = IndexReader { r: r.take(length) };
+        let layout = match codec {
+            INDEX_SORTED => IndexLayout::Sorted(r.buckets()?),
+            MULTIHASH_INDEX_SORTED => {
+                let count = r.count("multihash count", MULTIHASH_HEADER_LENGTH)?;
+                let mut codes = BTreeMap::new();
+                for _ in 0..count {
+                    let code = r.u64("multihash code")?;
+                    let buckets = r.buckets()?;
+                    if let Some(expected) = digest_length(code) {
+                        if let Some(bucket) = buckets
+                            .iter()
+                            .find(|bucket| bucket.width - OFFSET_LENGTH != expected)
+                        {
+                            return Err(CarError::IndexDigestWidthMismatch {
+                                code,
+                                expected,
+                                found: bucket.width - OFFSET_LENGTH,
+                            });
+                        }
+                    }
+                    if codes.insert(code, buckets).is_some() {
+                        return Err(CarError::InvalidIndex(format!(
+                            "multihash code {:#x} is indexed twice",
+                            code
+                        )));
+                    }
+                }
+                IndexLayout::MultihashSorted(codes)
+            }
+            _ => {
+                let mut raw_bytes = vec![];
+                r.r.read_to_end(&mut raw_bytes)?;
+                IndexLayout::Unknown { codec, raw_bytes }
+            }
+        };
+        Ok(CarV2Index(layout))
+    }
+
+    /// Reads an index as [`CarV2Index::write_to`] writes it, its codec first, such as a
+    /// detached index written by `car index` next to a CARv1.
+    pub fn from_bytes(bytes: &[u8]) -> CarResult<Self> {
+        let (codec, body) = unsigned_varint::decode::u64(bytes)
+            .map_err(|_| CarError::InvalidIndex("malformed codec".to_string()))?;
+        Self::read_body(body, codec, body.len() as u64)
+    }
+
+    /// Reads an index as [`CarV2Index::from_bytes`] does, from the whole of `r`, such as a
+    /// detached `.car.idx` file.
+    pub fn from_reader<R: Read>(mut r: R) -> CarResult<Self> {
+        let mut bytes = vec![];
+        r.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// The multicodec of the index.
+    pub fn codec(&self) -> u64 {
+        match &self.0 {
+            IndexLayout::Sorted(_) => INDEX_SORTED,
+            IndexLayout::MultihashSorted(_) => MULTIHASH_INDEX_SORTED,
+            IndexLayout::Unknown { codec, .. } => *codec,
+        }
+    }
+
+    /// The kind of the index, `None` if its codec is unknown.
+    pub fn kind(&self) -> Option<IndexKind> {
+        match &self.0 {
+            IndexLayout::Sorted(_) => Some(IndexKind::Sorted),
+            IndexLayout::MultihashSorted(_) => Some(IndexKind::MultihashSorted),
+            IndexLayout::Unknown { .. } => None,
+        }
+    }
+
+    /// How many entries the index has, none if its codec is unknown.
+    pub fn len(&self) -> usize {
+        self.0.buckets().map(|bucket| bucket.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries of the index as they are laid out.
+    pub fn layout(&self) -> &IndexLayout {
+        &self.0
+    }
+
+    pub fn into_layout(self) -> IndexLayout {
+        self.0
+    }
+
+    /// Writes the codec of the index and its body, as go-car does and [`Self::read_body`] reads
+    /// back: buckets by increasing width, each sorted by digest, with buckets of the same width
+    /// merged. Fails with [`CarError::InvalidIndex`] on an entry whose digest does not fit the
+    /// width of its bucket.
+    pub fn write_to<W: Write>(&self, mut w: W) -> CarResult<()> {
+        write_varint(&mut w, self.codec())?;
+        match &self.0 {
+            IndexLayout::Sorted(buckets) => write_buckets(&mut w, buckets),
+            IndexLayout::MultihashSorted(codes) => {
+                w.write_all(&count(codes.len(), "multihash count")?.to_le_bytes())?;
+                for (code, buckets) in codes {
+                    w.write_all(&code.to_le_bytes())?;
+                    write_buckets(&mut w, buckets)?;
+                }
+                Ok(())
+            }
+            IndexLayout::Unknown { raw_bytes, .. } => Ok(w.write_all(raw_bytes)?),
+        }
+    }
+
+    /// The payload offset of the section of `cid`, see [`CarV2Index::lookup_multihash`].
+    pub fn lookup(&self, cid: &Cid) -> Option<u64> {
+        self.lookup_multihash(cid.hash())
+    }
+
+    /// The payload offset of a section whose CID has the multihash `hash`, found by binary
+    /// search; the first such section if the index has several. An `IndexSorted` only has
+    /// digests, so its sections are matched by digest whatever their multihash code. `None` if
+    /// the index does not have it or its codec is unknown.
+    pub fn lookup_multihash<const S: usize>(&self, hash: &MultihashGeneric<S>) -> Option<u64> {
+        let digest = hash.digest();
+        let buckets = match &self.0 {
+            IndexLayout::Sorted(buckets) => buckets,
+            IndexLayout::MultihashSorted(codes) => codes.get(&hash.code())?,
+            IndexLayout::Unknown { .. } => return None,
+        };
+        let width = digest.len() as u64 + u64::from(OFFSET_LENGTH);
+        buckets
+            .iter()
+            .filter(|bucket| u64::from(bucket.width) == width)
+            .flat_map(|bucket| {
+                let start = bucket
+                    .entries
+                    .partition_point(|entry| entry.digest.as_slice() < digest);
+                bucket.entries[start..]
+                    .iter()
+                    .take_while(|entry| entry.digest == digest)
+                    .map(|entry| entry.offset)
+            })
+            .min()
+    }
+
+    /// Checks that every entry points into a payload of `data_size` bytes, failing with
+    /// [`CarError::IndexOutOfBounds`] otherwise.
+    pub fn check_offsets(&self, data_size: u64) -> CarResult<()> {
+        match self
+            .0
+            .buckets()
+            .flat_map(|bucket| &bucket.entries)
+            .find(|entry| entry.offset >= data_size)
+        {
+            Some(entry) => Err(CarError::IndexOutOfBounds(entry.offset)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<IndexLayout> for CarV2Index {
+    /// Wraps entries as they are laid out, which are not checked: [`CarV2Index::write_to`]
+    /// fails on a digest that does not fit its bucket, and lookups miss unsorted entries.
+    fn from(layout: IndexLayout) -> Self {
+        CarV2Index(layout)
+    }
+}
+
+impl IndexLayout {
+    /// Every entry of the index with the multihash code it is indexed under, if any, ordered by
+    /// digest, then code and offset.
+    pub fn entries(&self) -> impl Iterator<Item = (Option<u64>, &IndexEntry)> {
+        let mut entries: Vec<_> = match self {
+            IndexLayout::MultihashSorted(codes) => codes
+                .iter()
+                .flat_map(|(code, buckets)| {
+                    buckets.iter().flat_map(move |bucket| {
+                        bucket.entries.iter().map(move |e| (Some(*code), e))
+                    })
+                })
+                .collect(),
+            _ => self
+                .buckets()
+                .flat_map(|bucket| bucket.entries.iter().map(|entry| (None, entry)))
+                .collect(),
+        };
+        entries.sort_by(|(a_code, a), (b_code, b)| {
+            (&a.digest, a_code, a.offset).cmp(&(&b.digest, b_code, b.offset))
+        });
+        entries.into_iter()
+    }
+
+    /// The buckets of the index, none if its codec is unknown.
+    pub fn buckets(&self) -> impl Iterator<Item = &IndexBucket> {
+        let buckets: Box<dyn Iterator<Item = &IndexBucket>> = match self {
+            IndexLayout::Sorted(buckets) => Box::new(buckets.iter()),
+            IndexLayout::MultihashSorted(codes) => Box::new(codes.values().flatten()),
+            IndexLayout::Unknown { .. } => Box::new(std::iter::empty()),
+        };
+        buckets
+    }
+}
+
+/// Reads the index running from `index_offset` to the end of `r`.
+pub fn read_v2_index<R: Read + Seek>(mut r: R, index_offset: u64) -> CarResult<Option<CarV2Index>> {
+    if index_offset == 0 {
+        return Ok(None);
+    }
+    let end = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(index_offset))?;
+
+    let codec = varint_read_u64(&mut r)?;
+    let length = end.saturating_sub(r.stream_position()?);
+    Ok(Some(CarV2Index::read_body(r, codec, length)?))
+}
+
+/// The digest length of the common fixed-length multihashes.
+fn digest_length(code: u64) -> Option<u32> {
+    match code {
+        // sha2-256, sha3-256, blake3, blake2b-256 and blake2s-256
+        0x12 | 0x16 | 0x1e | 0xb220 | 0xb260 => Some(32),
+        // sha2-512, sha3-512 and blake2b-512
+        0x13 | 0x14 | 0xb240 => Some(64),
+        // sha3-384
+        0x15 => Some(48),
+        // sha3-224
+        0x17 => Some(28),
+        _ => None,
+    }
+}
+
+/// Adds the entry of `cid` to the bucket of its width.
+fn add_entry(widths: &mut BTreeMap<u32, Vec<IndexEntry>>, cid: &Cid, offset: u64) {
+    let digest = cid.hash().digest();
+    widths
+        .entry(digest.len() as u32 + OFFSET_LENGTH)
+        .or_default()
+        .push(IndexEntry {
+            digest: digest.to_vec(),
+            offset,
+        });
+}
+
+/// The buckets of `widths` in ascending width order, each sorted by digest and offset.
+fn sorted_buckets(widths: BTreeMap<u32, Vec<IndexEntry>>) -> Vec<IndexBucket> {
+    widths
+        .into_iter()
+        .map(|(width, mut entries)| {
+            entries.sort_by(|a, b| (&a.digest, a.offset).cmp(&(&b.digest, b.offset)));
+            IndexBucket { width, entries }
+        })
+        .collect()
+}
+
+/// A count as the signed 32 bit integer indexes store.
+fn count(count: usize, field: &str) -> CarResult<i32> {
+    i32::try_from(count)
+        .map_err(|_| CarError::InvalidIndex(format!("{} {} does not fit in 31 bits", field, count)))
+}
+
+fn write_buckets<W: Write>(mut w: W, buckets: &[IndexBucket]) -> CarResult<()> {
+    let mut widths: BTreeMap<u32, Vec<&IndexEntry>> = BTreeMap::new();
+    for bucket in buckets {
+        if let Some(entry) = bucket.entries.iter().find(|entry| {
+            entry.digest.len() as u64 + u64::from(OFFSET_LENGTH) != u64::from(bucket.width)
+        }) {
+            return Err(CarError::InvalidIndex(format!(
+                "digest of {} bytes in a bucket of width {}",
+                entry.digest.len(),
+                bucket.width
+            )));
+        }
+        widths
+            .entry(bucket.width)
+            .or_default()
+            .extend(&bucket.entries);
+    }
+    w.write_all(&count(widths.len(), "bucket count")?.to_le_bytes())?;
+    for (width, mut entries) in widths {
+        entries.sort_by(|a, b| (&a.digest, a.offset).cmp(&(&b.digest, b.offset)));
+        w.write_all(&width.to_le_bytes())?;
+        w.write_all(&(u64::from(width) * entries.len() as u64).to_le_bytes())?;
+        for entry in entries {
+            w.write_all(&entry.digest)?;
+            w.write_all(&entry.offset.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+struct IndexReader<R> {
+    r: Take<R>,
+}
+
+impl<R: Read> IndexReader<R> {
+    fn bytes(&mut self, length: u64, field: &str) -> CarResult<Vec<u8>> {
+        if length > self.r.limit() {
+            return Err(CarError::InvalidIndex(format!("{} is cut off", field)));
+        }
+        let mut buf = vec![0; length as usize];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self, field: &str) -> CarResult<u32> {
+        Ok(LittleEndian::read_u32(&self.bytes(4, field)?))
+    }
+
+    fn u64(&mut self, field: &str) -> CarResult<u64> {
+        Ok(LittleEndian::read_u64(&self.bytes(8, field)?))
+    }
+
+    /// Reads a signed 32 bit count of items taking at least `min_length` bytes each.
+    fn count(&mut self, field: &str, min_length: u64) -> CarResult<u32> {
+        let count = self.u32(field)?;
+        if count > i32::MAX as u32 {
+            return Err(CarError::InvalidIndex(format!("{} is negative", field)));
+        }
+        if u64::from(count) * min_length > self.r.limit() {
+            return Err(CarError::InvalidIndex(format!(
+                "{} {} does not fit in the {} bytes left",
+                field,
+                count,
+                self.r.limit()
+            )));
+        }
+        Ok(count)
+    }
+
+    fn buckets(&mut self) -> CarResult<Vec<IndexBucket>> {
+        let count = self.count("bucket count", BUCKET_HEADER_LENGTH)?;
+        let mut buckets = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let width = self.u32("bucket width")?;
+            // Empty identity digests make entries of just an offset.
+            if !(OFFSET_LENGTH..=OFFSET_LENGTH + MAX_DIGEST_LENGTH).contains(&width) {
+                return Err(CarError::InvalidIndex(format!(
+                    "bucket width {} is not between {} and {}",
+                    width,
+                    OFFSET_LENGTH,
+                    OFFSET_LENGTH + MAX_DIGEST_LENGTH
+                )));
+            }
+            let length = self.u64("bucket length")?;
+            if length % u64::from(width) != 0 {
+                return Err(CarError::InvalidIndex(format!(
+                    "bucket length {} is not a multiple of its width {}",
+                    length, width
+                )));
+            }
+            if length > self.r.limit() {
+                return Err(CarError::InvalidIndex(format!(
+                    "bucket length {} does not fit in the {} bytes left",
+                    length,
+                    self.r.limit()
+                )));
+            }
+            let mut left = length / u64::from(width);
+            let mut entries = vec![];
+            while left > 0 {
+                let chunk = left.min(CHUNK_ENTRIES);
+                let buf = self.bytes(chunk * u64::from(width), "bucket entries")?;
+                let digest_length = (width - OFFSET_LENGTH) as usize;
+                entries.extend(buf.chunks_exact(width as usize).map(|entry| IndexEntry {
+                    digest: entry[..digest_length].to_vec(),
+                    offset: LittleEndian::read_u64(&entry[digest_length..]),
+                }));
+                left -= chunk;
+            }
+            if entries
+                .windows(2)
+                .any(|pair| pair[0].digest > pair[1].digest)
+            {
+                return Err(CarError::IndexUnsorted);
+            }
+            buckets.push(IndexBucket { width, entries });
+        }
+        Ok(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(width: u32, entries: &[(&[u8], u64)]) -> Vec<u8> {
+        let mut bytes = width.to_le_bytes().to_vec();
+        bytes.extend((width as u64 * entries.len() as u64).to_le_bytes());
+        for (digest, offset) in entries {
+            bytes.extend(*digest);
+            bytes.extend(offset.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn buckets(buckets: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = (buckets.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(buckets.concat());
+        bytes
+    }
+
+    fn read(codec: u64, bytes: &[u8]) -> CarResult<CarV2Index> {
+        CarV2Index::read_body(bytes, codec, bytes.len() as u64)
+    }
+
+    #[test]
+    fn it_reads_sorted_indexes() {
+        let sorted = buckets(&[
+            bucket(10, &[(b"aa", 1), (b"bb", 2)]),
+            bucket(11, &[(b"ccc", 3)]),
+        ]);
+        let expected = vec![
+            IndexBucket {
+                width: 10,
+                entries: vec![
+                    IndexEntry {
+                        digest: b"aa".to_vec(),
+                        offset: 1,
+                    },
+                    IndexEntry {
+                        digest: b"bb".to_vec(),
+                        offset: 2,
+                    },
+                ],
+            },
+            IndexBucket {
+                width: 11,
+                entries: vec![IndexEntry {
+                    digest: b"ccc".to_vec(),
+                    offset: 3,
+                }],
+            },
+        ];
+        assert_eq!(
+            read(INDEX_SORTED, &sorted).unwrap().into_layout(),
+            IndexLayout::Sorted(expected.clone())
+        );
+
+        // Identity digests have no fixed length.
+        let mut multihash = 1u32.to_le_bytes().to_vec();
+        multihash.extend(0u64.to_le_bytes());
+        multihash.extend(&sorted);
+        assert_eq!(
+            read(MULTIHASH_INDEX_SORTED, &multihash)
+                .unwrap()
+                .into_layout(),
+            IndexLayout::MultihashSorted(BTreeMap::from([(0, expected)]))
+        );
+        assert_eq!(
+            read(0x0402, &sorted).unwrap().into_layout(),
+            IndexLayout::Unknown {
+                codec: 0x0402,
+                raw_bytes: sorted
+            }
+        );
+    }
+
+    #[test]
+    fn it_reads_empty_digests() {
+        let mut identity = 1u32.to_le_bytes().to_vec();
+        identity.extend(0u64.to_le_bytes());
+        identity.extend(buckets(&[bucket(8, &[(b"", 0), (b"", 42)])]));
+        let index = read(MULTIHASH_INDEX_SORTED, &identity).unwrap();
+        let entries = |offsets: &[u64]| {
+            offsets
+                .iter()
+                .map(|&offset| IndexEntry {
+                    digest: vec![],
+                    offset,
+                })
+                .collect()
+        };
+        assert_eq!(
+            index.layout(),
+            &IndexLayout::MultihashSorted(BTreeMap::from([(
+                0,
+                vec![IndexBucket {
+                    width: 8,
+                    entries: entries(&[0, 42]),
+                }]
+            )]))
+        );
+        assert!(index.check_offsets(43).is_ok());
+    }
+
+    #[test]
+    fn it_checks_counts_and_lengths_before_reading() {
+        let invalid = |bytes: &[u8]| match read(INDEX_SORTED, bytes) {
+            Err(CarError::InvalidIndex(message)) => message,
+            other => panic!("Expected InvalidIndex, got {:?}", other),
+        };
+        assert_eq!(invalid(&u32::MAX.to_le_bytes()), "bucket count is negative");
+        assert_eq!(
+            invalid(&i32::MAX.to_le_bytes()),
+            "bucket count 2147483647 does not fit in the 0 bytes left"
+        );
+
+        let mut huge = bucket(40, &[]);
+        huge[4..12].copy_from_slice(&(u64::MAX - u64::MAX % 40).to_le_bytes());
+        assert!(invalid(&buckets(&[huge])).starts_with("bucket length 18446744073709551600"));
+        assert_eq!(
+            invalid(&buckets(&[bucket(7, &[])])),
+            "bucket width 7 is not between 8 and 72"
+        );
+        let mut ragged = bucket(10, &[(b"aa", 1)]);
+        ragged[4] = 15;
+        assert_eq!(
+            invalid(&buckets(&[ragged])),
+            "bucket length 15 is not a multiple of its width 10"
+        );
+        assert_eq!(invalid(&[1, 0, 0]), "bucket count is cut off");
+    }
+
+    #[test]
+    fn it_rejects_unsorted_mismatched_and_out_of_bounds_entries() {
+        let unsorted = buckets(&[bucket(10, &[(b"bb", 1), (b"aa", 2)])]);
+        assert!(matches!(
+            read(INDEX_SORTED, &unsorted),
+            Err(CarError::IndexUnsorted)
+        ));
+
+        let mut sha2 = 1u32.to_le_bytes().to_vec();
+        sha2.extend(0x12u64.to_le_bytes());
+        sha2.extend(buckets(&[bucket(10, &[(b"aa", 1)])]));
+        assert!(matches!(
+            read(MULTIHASH_INDEX_SORTED, &sha2),
+            Err(CarError::IndexDigestWidthMismatch {
+                code: 0x12,
+                expected: 32,
+                found: 2
+            })
+        ));
+
+        let index = read(INDEX_SORTED, &buckets(&[bucket(10, &[(b"aa", 7)])])).unwrap();
+        assert!(index.check_offsets(8).is_ok());
+        assert!(matches!(
+            index.check_offsets(7),
+            Err(CarError::IndexOutOfBounds(7))
+        ));
+    }
+
+    #[test]
+    fn it_writes_indexes_as_go_car_does() {
+        // The body of the fixture's index was written by go-car, without its codec.
+        let fixture = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let body = &fixture[499..];
+        let index = read(INDEX_SORTED, body).unwrap();
+        let mut bytes = vec![];
+        index.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, [&[0x80, 0x08][..], body].concat());
+
+        let digests: Vec<_> = index
+            .layout()
+            .entries()
+            .map(|(_, entry)| &entry.digest)
+            .collect();
+        assert_eq!(digests.len(), 5);
+        assert!(digests.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // Every section of the payload is found at its offset.
+        let sections: Vec<_> = crate::layout::layout(fixture)
+            .into_iter()
+            .filter_map(|region| match region.part {
+                crate::layout::Part::Section { cid, .. } => Some((cid, region.range.start - 51)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sections.len(), 5);
+        for (cid, offset) in sections {
+            assert_eq!(index.lookup(&cid), Some(offset));
+            assert_eq!(index.lookup_multihash(cid.hash()), Some(offset));
+        }
+        let absent =
+            Cid::try_from("bafkreifw7plhl6mofk6sfvhnfh64qmkq73oeqwl6sloru6rehaoujituke").unwrap();
+        assert_eq!(index.lookup(&absent), None);
+    }
+
+    #[test]
+    fn it_generates_indexes_of_archives() {
+        // Generating the index of the fixture's payload finds what go-car wrote.
+        let fixture = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let written = read(INDEX_SORTED, &fixture[499..]).unwrap();
+        let generated = CarV2Index::generate_from_reader(&fixture[..]).unwrap();
+        assert_eq!(generated.codec(), MULTIHASH_INDEX_SORTED);
+        let entries = |index: &CarV2Index| -> Vec<IndexEntry> {
+            index
+                .layout()
+                .entries()
+                .map(|(_, entry)| entry.clone())
+                .collect()
+        };
+        assert_eq!(entries(&generated), entries(&written));
+
+        assert!(CarV2Index::generate_from_reader(&fixture[..200]).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_indexes_in_canonical_order() {
+        let entry = |digest: &[u8], offset| IndexEntry {
+            digest: digest.to_vec(),
+            offset,
+        };
+        let index = CarV2Index::from(IndexLayout::MultihashSorted(BTreeMap::from([
+            (
+                0,
+                vec![
+                    IndexBucket {
+                        width: 10,
+                        entries: vec![entry(b"zz", 5), entry(b"aa", 4)],
+                    },
+                    IndexBucket {
+                        width: 8,
+                        entries: vec![entry(b"", 3)],
+                    },
+                    IndexBucket {
+                        width: 10,
+                        entries: vec![entry(b"mm", 6)],
+                    },
+                ],
+            ),
+            (
+                0x3333,
+                vec![IndexBucket {
+                    width: 9,
+                    entries: vec![entry(b"m", 1)],
+                }],
+            ),
+        ])));
+        let mut bytes = vec![];
+        index.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..2], [0x81, 0x08]);
+        let read = read(MULTIHASH_INDEX_SORTED, &bytes[2..]).unwrap();
+        assert_eq!(
+            read.into_layout(),
+            IndexLayout::MultihashSorted(BTreeMap::from([
+                (
+                    0,
+                    vec![
+                        IndexBucket {
+                            width: 8,
+                            entries: vec![entry(b"", 3)],
+                        },
+                        IndexBucket {
+                            width: 10,
+                            entries: vec![entry(b"aa", 4), entry(b"mm", 6), entry(b"zz", 5)],
+                        },
+                    ],
+                ),
+                (
+                    0x3333,
+                    vec![IndexBucket {
+                        width: 9,
+                        entries: vec![entry(b"m", 1)],
+                    }],
+                ),
+            ]))
+        );
+        let entries: Vec<_> = index
+            .layout()
+            .entries()
+            .map(|(code, entry)| (code, entry.offset))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Some(0), 3),
+                (Some(0), 4),
+                (Some(0x3333), 1),
+                (Some(0), 6),
+                (Some(0), 5)
+            ]
+        );
+
+        let unknown = CarV2Index::from(IndexLayout::Unknown {
+            codec: 0x0402,
+            raw_bytes: b"raw".to_vec(),
+        });
+        let mut bytes = vec![];
+        unknown.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, b"\x82\x08raw");
+
+        let ragged = CarV2Index::from(IndexLayout::Sorted(vec![IndexBucket {
+            width: 10,
+            entries: vec![entry(b"a", 0)],
+        }]));
+        assert!(matches!(
+            ragged.write_to(&mut vec![]),
+            Err(CarError::InvalidIndex(_))
+        ));
+    }
+
+    #[test]
+    fn it_builds_multihash_indexes() {
+        let sha2 =
+            Cid::try_from("bafkreifw7plhl6mofk6sfvhnfh64qmkq73oeqwl6sloru6rehaoujituke").unwrap();
+        let empty = Cid::try_from("bafkqaaa").unwrap();
+        let index = CarV2Index::multihash_sorted([(&sha2, 40), (&empty, 60), (&sha2, 20)]);
+        let entry = |digest: &[u8], offset| IndexEntry {
+            digest: digest.to_vec(),
+            offset,
+        };
+        assert_eq!(
+            index.layout(),
+            &IndexLayout::MultihashSorted(BTreeMap::from([
+                (
+                    0,
+                    vec![IndexBucket {
+                        width: 8,
+                        entries: vec![entry(b"", 60)],
+                    }]
+                ),
+                (
+                    0x12,
+                    vec![IndexBucket {
+                        width: 40,
+                        entries: vec![
+                            entry(sha2.hash().digest(), 20),
+                            entry(sha2.hash().digest(), 40)
+                        ],
+                    }]
+                ),
+            ]))
+        );
+        let mut bytes = vec![];
+        index.write_to(&mut bytes).unwrap();
+        assert_eq!(read(MULTIHASH_INDEX_SORTED, &bytes[2..]).unwrap(), index);
+        assert_eq!(index.lookup(&sha2), Some(20));
+        assert_eq!(index.lookup(&empty), Some(60));
+
+        let sorted = CarV2Index::build(IndexKind::Sorted, [(&sha2, 40), (&empty, 60), (&sha2, 20)]);
+        let IndexLayout::MultihashSorted(codes) = index.into_layout() else {
+            unreachable!()
+        };
+        assert_eq!(
+            sorted.into_layout(),
+            IndexLayout::Sorted(codes.into_values().flatten().collect())
+        );
+    }
+
+    #[test]
+    fn it_reads_index_sorted_indexes() {
+        use crate::layout::{layout, Part};
+        use std::io::Cursor;
+
+        // The fixture's index body, written by go-car, behind the IndexSorted codec it lacks.
+        let fixture = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let bytes = [&fixture[..499], &[0x80, 0x08], &fixture[499..]].concat();
+        let index = read_v2_index(Cursor::new(&bytes), 499).unwrap();
+        let index = match index.map(CarV2Index::into_layout) {
+            Some(IndexLayout::Sorted(buckets)) => buckets,
+            other => panic!("Expected an IndexSorted, got {:?}", other),
+        };
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].width, 40);
+        let mut offsets: Vec<u64> = index[0].entries.iter().map(|entry| entry.offset).collect();
+        offsets.sort();
+        let sections: Vec<u64> = layout(fixture)
+            .iter()
+            .filter(|region| matches!(region.part, Part::Section { .. }))
+            .map(|region| region.range.start - 51)
+            .collect();
+        assert_eq!(offsets, sections);
+        assert_eq!(read_v2_index(Cursor::new(&bytes), 0).unwrap(), None);
+    }
+}
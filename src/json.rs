@@ -0,0 +1,40 @@
+//! The pieces of JSON the `to_json` methods of the crate and the `racecar` command build their
+//! output from, without a serializer.
+
+use cid::Cid;
+
+/// Renders `s` as a JSON string, quotes included.
+pub fn string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Renders `cids` as a JSON array of their strings.
+pub fn cids(cids: &[Cid]) -> String {
+    let cids: Vec<String> = cids.iter().map(|cid| format!("\"{}\"", cid)).collect();
+    format!("[{}]", cids.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_escapes_strings() {
+        assert_eq!(string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+        assert_eq!(cids(&[]), "[]");
+    }
+}
@@ -0,0 +1,427 @@
+//! The byte layout of an archive, for debugging interop with other implementations: where its
+//! pragma, headers, sections and index are, and how each section splits into its length varint,
+//! CID and data. See [`layout`] and [`write_layout`].
+//!
+//! Nothing is decoded beyond the framing, so archives read as far as their framing is sound;
+//! the rest becomes a [`Part::Invalid`] region.
+
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+
+use cid::Cid;
+
+use crate::block::CarHeaderV2;
+use crate::{cbor, json, CarResult, HEADER_LENGTH};
+
+/// How many bytes of a region are shown in hex.
+const HEX_PREVIEW: usize = 16;
+
+/// A part of an archive and the bytes it spans, from the start of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub range: Range<u64>,
+    pub part: Part,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// The header announcing a CARv2.
+    Pragma,
+    HeaderV2(CarHeaderV2),
+    /// Bytes between the parts of a CARv2.
+    Padding,
+    /// The `varint | dag-cbor` header of a CARv1 or of the payload of a CARv2; the dag-cbor
+    /// starts at `body`.
+    Header {
+        version: u64,
+        roots: Vec<Cid>,
+        body: u64,
+    },
+    /// The `varint | CID | data` section of the `number`th block, whose CID starts at `cid_start`
+    /// and data at `data_start`.
+    Section {
+        number: usize,
+        cid: Cid,
+        cid_start: u64,
+        data_start: u64,
+    },
+    /// A CARv2 index, with its codec.
+    Index {
+        codec: u64,
+    },
+    /// Where the framing stopped making sense, and why.
+    Invalid(String),
+}
+
+/// The regions of the archive `bytes`, in order.
+pub fn layout(bytes: &[u8]) -> Vec<Region> {
+    let mut regions = vec![];
+    let mut pos = 0;
+    let end = bytes.len() as u64;
+    let outcome = match header(bytes, &mut pos, &mut regions) {
+        Ok(2) => {
+            let last = regions.len() - 1;
+            regions[last].part = Part::Pragma;
+            v2_parts(bytes, &mut pos, &mut regions)
+        }
+        Ok(_) => sections(bytes, &mut pos, end, &mut regions),
+        Err(reason) => Err(reason),
+    };
+    if let Err(reason) = outcome {
+        regions.push(Region {
+            range: pos..end,
+            part: Part::Invalid(reason),
+        });
+    }
+    regions
+}
+
+/// Writes the regions of `bytes` one per line, with the first bytes of each in hex, e.g.
+/// `110..150 section 0: varint 110..111, cid bafk... 111..147, data 147..150`.
+pub fn write_layout<W: Write>(bytes: &[u8], mut w: W) -> CarResult<()> {
+    for region in layout(bytes) {
+        let shown = &bytes[region.range.start as usize..region.range.end as usize];
+        let mut hex: Vec<String> = shown
+            .iter()
+            .take(HEX_PREVIEW)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if shown.len() > HEX_PREVIEW {
+            hex.push("..".to_string());
+        }
+        writeln!(w, "{}\n    {}", region, hex.join(" "))?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Range { start, end } = self.range;
+        write!(f, "{}..{} ", start, end)?;
+        match &self.part {
+            Part::Pragma => write!(f, "pragma"),
+            Part::HeaderV2(header) => write!(
+                f,
+                "v2 header: data offset {}, data size {}, index offset {}{}",
+                header.data_offset,
+                header.data_size,
+                header.index_offset,
+                if header.is_fully_indexed() {
+                    ", fully indexed"
+                } else {
+                    ""
+                }
+            ),
+            Part::Padding => write!(f, "padding"),
+            Part::Header {
+                version,
+                roots,
+                body,
+            } => {
+                let roots: Vec<String> = roots.iter().map(Cid::to_string).collect();
+                write!(
+                    f,
+                    "header: varint {}..{}, version {}, roots [{}]",
+                    start,
+                    body,
+                    version,
+                    roots.join(", ")
+                )
+            }
+            Part::Section {
+                number,
+                cid,
+                cid_start,
+                data_start,
+            } => write!(
+                f,
+                "section {}: varint {}..{}, cid {} {}..{}, data {}..{}",
+                number, start, cid_start, cid, cid_start, data_start, data_start, end
+            ),
+            Part::Index { codec } => write!(f, "index: codec {:#x}", codec),
+            Part::Invalid(reason) => write!(f, "invalid: {}", reason),
+        }
+    }
+}
+
+impl Region {
+    /// Renders the region as a JSON object of its `start`, `end` and `part`, with the fields of
+    /// its part, e.g. `{"start":110,"end":150,"part":"section","number":0,...}`.
+    pub fn to_json(&self) -> String {
+        let fields = match &self.part {
+            Part::Pragma => "\"pragma\"".to_string(),
+            Part::HeaderV2(header) => format!(
+                "\"v2_header\",\"data_offset\":{},\"data_size\":{},\"index_offset\":{},\
+                 \"fully_indexed\":{}",
+                header.data_offset,
+                header.data_size,
+                header.index_offset,
+                header.is_fully_indexed()
+            ),
+            Part::Padding => "\"padding\"".to_string(),
+            Part::Header {
+                version,
+                roots,
+                body,
+            } => {
+                format!(
+                    "\"header\",\"version\":{},\"roots\":{},\"body\":{}",
+                    version,
+                    json::cids(roots),
+                    body
+                )
+            }
+            Part::Section {
+                number,
+                cid,
+                cid_start,
+                data_start,
+            } => format!(
+                "\"section\",\"number\":{},\"cid\":\"{}\",\"cid_start\":{},\"data_start\":{}",
+                number, cid, cid_start, data_start
+            ),
+            Part::Index { codec } => format!("\"index\",\"codec\":{}", codec),
+            Part::Invalid(reason) => format!("\"invalid\",\"reason\":{}", json::string(reason)),
+        };
+        format!(
+            "{{\"start\":{},\"end\":{},\"part\":{}}}",
+            self.range.start, self.range.end, fields
+        )
+    }
+}
+
+fn varint(bytes: &[u8], pos: &mut u64, end: u64) -> Result<u64, String> {
+    let rest = &bytes[*pos as usize..end as usize];
+    let (value, left) =
+        unsigned_varint::decode::u64(rest).map_err(|err| format!("bad varint: {}", err))?;
+    *pos += (rest.len() - left.len()) as u64;
+    Ok(value)
+}
+
+/// Reads a `varint | dag-cbor` header at `pos`, returning its version.
+fn header(bytes: &[u8], pos: &mut u64, regions: &mut Vec<Region>) -> Result<u64, String> {
+    let start = *pos;
+    let mut body = start;
+    let length = varint(bytes, &mut body, bytes.len() as u64)?;
+    if length > bytes.len() as u64 - body {
+        return Err(format!("header of {} bytes is cut off", length));
+    }
+    let header = cbor::decode_header(&bytes[body as usize..(body + length) as usize])
+        .map_err(|err| format!("bad header: {}", err))?;
+    *pos = body + length;
+    regions.push(Region {
+        range: start..*pos,
+        part: Part::Header {
+            version: header.version,
+            roots: header.roots.unwrap_or_default(),
+            body,
+        },
+    });
+    Ok(header.version)
+}
+
+fn v2_parts(bytes: &[u8], pos: &mut u64, regions: &mut Vec<Region>) -> Result<(), String> {
+    let file_len = bytes.len() as u64;
+    let start = *pos;
+    let raw: [u8; HEADER_LENGTH] = bytes
+        .get(start as usize..start as usize + HEADER_LENGTH)
+        .ok_or("v2 header is cut off")?
+        .try_into()
+        .unwrap();
+    let v2_header = crate::block::parse_v2_header(raw).map_err(|err| err.to_string())?;
+    *pos += HEADER_LENGTH as u64;
+    regions.push(Region {
+        range: start..*pos,
+        part: Part::HeaderV2(v2_header.clone()),
+    });
+
+    let data = v2_header.data_range();
+    if data.start < *pos || data.end > file_len {
+        return Err(format!(
+            "payload {}..{} is outside {}..{}",
+            data.start, data.end, *pos, file_len
+        ));
+    }
+    padding(pos, data.start, regions);
+    match header(bytes, pos, regions) {
+        Ok(1) if *pos <= data.end => sections(bytes, pos, data.end, regions)?,
+        Ok(1) => return Err("payload header runs past the payload".to_string()),
+        Ok(version) => return Err(format!("payload of version {}", version)),
+        Err(reason) => return Err(reason),
+    }
+
+    if let Some(index) = v2_header.index_range(file_len) {
+        if index.start < *pos {
+            return Err(format!("index at {} overlaps the payload", index.start));
+        }
+        padding(pos, index.start, regions);
+        let codec = varint(bytes, pos, file_len)?;
+        regions.push(Region {
+            range: index,
+            part: Part::Index { codec },
+        });
+        *pos = file_len;
+    } else if v2_header.has_index() {
+        return Err(format!(
+            "index at {} is past the end",
+            v2_header.index_offset
+        ));
+    }
+    padding(pos, file_len, regions);
+    Ok(())
+}
+
+fn padding(pos: &mut u64, until: u64, regions: &mut Vec<Region>) {
+    if *pos < until {
+        regions.push(Region {
+            range: *pos..until,
+            part: Part::Padding,
+        });
+        *pos = until;
+    }
+}
+
+/// Reads sections from `pos` until `end`.
+fn sections(
+    bytes: &[u8],
+    pos: &mut u64,
+    end: u64,
+    regions: &mut Vec<Region>,
+) -> Result<(), String> {
+    let mut number = 0;
+    while *pos < end {
+        let start = *pos;
+        let length = varint(bytes, pos, end)?;
+        let cid_start = *pos;
+        if length > end - cid_start {
+            *pos = start;
+            return Err(format!("section of {} bytes is cut off", length));
+        }
+        let mut section = &bytes[cid_start as usize..(cid_start + length) as usize];
+        let cid = match Cid::read_bytes(&mut section) {
+            Ok(cid) => cid,
+            Err(err) => {
+                *pos = start;
+                return Err(format!("bad CID: {}", err));
+            }
+        };
+        *pos = cid_start + length;
+        regions.push(Region {
+            range: start..*pos,
+            part: Part::Section {
+                number,
+                cid,
+                cid_start,
+                data_start: *pos - section.len() as u64,
+            },
+        });
+        number += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{write_car_blocks, CarBlock};
+
+    #[test]
+    fn it_lays_out_car_v1() {
+        let cid = Cid::try_from("bafkqaaa").unwrap();
+        let mut bytes = vec![];
+        write_car_blocks(
+            &mut bytes,
+            &[cid],
+            &[
+                CarBlock::new(cid, vec![]),
+                CarBlock::new(cid, b"x".to_vec()),
+            ],
+        )
+        .unwrap();
+        let header_end = bytes.len() as u64 - 2 * (1 + 4) - 1;
+        // Cut the second byte of the last section, so it does not fit.
+        bytes.pop();
+        bytes.pop();
+
+        let regions = layout(&bytes);
+        assert_eq!(
+            regions,
+            vec![
+                Region {
+                    range: 0..header_end,
+                    part: Part::Header {
+                        version: 1,
+                        roots: vec![cid],
+                        body: 1,
+                    },
+                },
+                Region {
+                    range: header_end..header_end + 5,
+                    part: Part::Section {
+                        number: 0,
+                        cid,
+                        cid_start: header_end + 1,
+                        data_start: header_end + 5,
+                    },
+                },
+                Region {
+                    range: header_end + 5..bytes.len() as u64,
+                    part: Part::Invalid("section of 5 bytes is cut off".to_string()),
+                },
+            ]
+        );
+        assert_eq!(
+            regions[1].to_string(),
+            format!(
+                "{}..{} section 0: varint {}..{}, cid bafkqaaa {}..{}, data {}..{}",
+                header_end,
+                header_end + 5,
+                header_end,
+                header_end + 1,
+                header_end + 1,
+                header_end + 5,
+                header_end + 5,
+                header_end + 5
+            )
+        );
+        assert_eq!(
+            regions[2].to_json(),
+            format!(
+                "{{\"start\":{},\"end\":{},\"part\":\"invalid\",\
+                 \"reason\":\"section of 5 bytes is cut off\"}}",
+                header_end + 5,
+                bytes.len()
+            )
+        );
+    }
+
+    #[test]
+    fn it_lays_out_car_v2() {
+        let bytes = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let regions = layout(bytes);
+        assert_eq!(regions[0].range, 0..11);
+        assert_eq!(regions[0].part, Part::Pragma);
+        assert_eq!(regions[1].range, 11..51);
+        assert!(matches!(regions[2].part, Part::Header { version: 1, .. }));
+        assert_eq!(regions[2].range.start, 51);
+        let sections = &regions[3..regions.len() - 1];
+        assert!(sections
+            .iter()
+            .all(|region| matches!(region.part, Part::Section { .. })));
+        assert_eq!(sections.last().unwrap().range.end, 499);
+        assert_eq!(
+            regions.last().unwrap(),
+            &Region {
+                range: 499..715,
+                part: Part::Index { codec: 1 },
+            }
+        );
+
+        let mut out = vec![];
+        write_layout(bytes, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("0..11 pragma\n    0a a1 67 76 65 72 73 69 6f 6e 02\n11..51 v2 header: data offset 51, data size 448, index offset 499\n    00 00"));
+        assert!(out.ends_with(" ..\n"));
+    }
+}
@@ -1,31 +1,154 @@
 //! Content Archive codec.
-
+//!
+//! Most programs need only [`prelude`], whose items are kept stable between releases; [`raw`]
+//! holds the framing-level items the rest is built on, which are not.
+
+#[cfg(feature = "ipld")]
+pub mod async_io;
+#[cfg(feature = "signing")]
+pub mod authorship;
+#[cfg(feature = "v2")]
+pub mod blockstore;
+#[cfg(feature = "ipld")]
+pub mod builder;
+pub mod chunker;
+#[cfg(feature = "ipld")]
+pub mod compare;
+#[cfg(feature = "ipld")]
+pub mod copy;
+#[cfg(feature = "ipld")]
+pub mod detached;
+#[cfg(feature = "v1")]
+pub mod diff;
+#[cfg(feature = "ipld")]
+pub mod export;
+#[cfg(feature = "ipld")]
+pub mod gateway;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "ipld")]
+pub mod hash;
+pub mod ignore;
+#[cfg(feature = "ipld")]
+pub mod lint;
+#[cfg(feature = "v1")]
+pub mod manifest;
+#[cfg(feature = "ipld")]
+pub mod metrics;
+#[cfg(feature = "ipld")]
+pub mod migrate;
+#[cfg(feature = "ipld")]
+pub mod pack;
+#[cfg(feature = "ipld")]
+pub mod params;
+#[cfg(feature = "ipld")]
+pub mod patch;
+pub mod prelude;
+#[cfg(feature = "ipld")]
+pub mod query;
+pub mod raw;
+#[cfg(feature = "ipld")]
+pub mod repair;
+#[cfg(feature = "v1")]
+pub mod repo;
+#[cfg(feature = "ipld")]
+pub mod schema;
+#[cfg(feature = "ipld")]
+pub mod selector;
+#[cfg(feature = "serde")]
+pub mod serde;
+#[cfg(feature = "ipld")]
+pub mod server;
+#[cfg(feature = "ipld")]
+pub mod shard;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod slice;
+#[cfg(feature = "v1")]
+pub mod split;
+#[cfg(feature = "ipld")]
+pub mod stats;
+#[cfg(feature = "stream")]
+pub mod stream;
+#[cfg(feature = "ipld")]
+pub mod subscribe;
+#[cfg(feature = "v1")]
+pub mod synthetic;
+#[cfg(feature = "ipld")]
+pub mod table;
+pub mod tee;
+pub mod throttle;
+#[cfg(feature = "ipld")]
+pub mod traversal;
+#[cfg(feature = "ipld")]
+pub mod unixfs;
+#[cfg(feature = "ipld")]
+pub mod upload;
+#[cfg(feature = "ipld")]
 pub mod v1;
+#[cfg(feature = "v2")]
 pub mod v2;
-
-use core::convert::{TryFrom, TryInto};
-use std::io::{Cursor, Read, Seek};
+#[cfg(feature = "ipld")]
+pub mod verify;
+#[cfg(feature = "zip")]
+pub mod zip;
+
+mod block;
+mod cbor;
+mod index;
+mod json;
+mod layout;
+#[cfg(all(test, feature = "ipld"))]
+mod test_utils;
+
+#[cfg(feature = "ipld")]
+use core::convert::TryFrom;
+#[cfg(feature = "ipld")]
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
 
 use thiserror::Error;
-use unsigned_varint::io::read_u64 as varint_read_u64;
 
-use libipld::{prelude::Codec, Ipld, cbor::DagCborCodec};
-use crate::v1::{CarHeaderV1, CarV1};
+#[cfg(feature = "ipld")]
+use crate::hash::HasherRegistry;
+#[cfg(feature = "ipld")]
+use crate::metrics::Metrics;
+#[cfg(feature = "ipld")]
+use crate::v1::CarV1;
+use cid::Cid;
+#[cfg(feature = "ipld")]
+use libipld::store::StoreParams;
+#[cfg(feature = "ipld")]
+use libipld::{Block, DefaultParams};
+
+/// The longest section the streaming readers, [`raw::CarBlockReader`] and [`raw::CarV1Decoder`],
+/// read unless told otherwise: 32 MiB, as go-car allows by default.
+pub const DEFAULT_MAX_SECTION_SIZE: u64 = 32 << 20;
 
 const HEADER_LENGTH: usize = 40;
 const CHARACTERISTICS_LENGTH: usize = 16;
 
 /// An IPLD Content Archive
+///
+/// Each variant is only compiled with its feature, `v1` or `v2`; reading the other version fails
+/// with [`CarError::UnsupportedVersion`], as reading any archive does with `ipld` alone.
+#[cfg(feature = "ipld")]
 #[derive(Debug, Clone)]
 pub enum ContentArchive {
+    #[cfg(feature = "v1")]
     V1(v1::CarV1),
+    #[cfg(feature = "v2")]
     V2(v2::CarV2),
 }
 
 /// An IPLD Content Archive Header
+#[cfg(feature = "ipld")]
 #[derive(Debug, Clone)]
 pub enum CarHeader {
     V1(v1::CarHeaderV1),
+    #[cfg(feature = "v2")]
     V2(v2::CarHeaderV2),
 }
 
@@ -42,6 +165,7 @@ pub enum CarError {
     InvalidFormat,
 
     /// Ipld error.
+    #[cfg(feature = "ipld")]
     #[error(transparent)]
     Ipld(#[from] libipld::error::Error),
 
@@ -59,79 +183,636 @@ pub enum CarError {
 
     /// Cid error.
     #[error(transparent)]
-    Cid(#[from] libipld::cid::Error),
+    Cid(#[from] cid::Error),
 
     /// Error while decoding Varint
     #[error(transparent)]
     VarintDecode(#[from] unsigned_varint::io::ReadError),
+
+    /// A block reachable from the requested roots is not in the archive.
+    #[error("Missing block: {0}")]
+    MissingBlock(Cid),
+
+    /// Blocks reachable from the roots that are not in the archive, in the order they are found.
+    #[error(
+        "Incomplete DAG, missing blocks: {}",
+        .0.iter().map(Cid::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    IncompleteDag(Vec<Cid>),
+
+    /// Unrecognised or malformed export parameter.
+    #[error("Invalid export parameter: {0}")]
+    InvalidExportParameter(String),
+
+    /// Malformed or unsupported selector.
+    #[error("Invalid selector: {0}")]
+    InvalidSelector(String),
+
+    /// Malformed query.
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    /// Malformed UnixFS node.
+    #[error("Invalid UnixFS node: {0}")]
+    InvalidUnixFs(String),
+
+    /// A gateway could not be reached or answered with an error.
+    #[error("Gateway error: {0}")]
+    Gateway(String),
+
+    /// The archive is not in the repository.
+    #[error("Missing archive: {0}")]
+    MissingArchive(Cid),
+
+    /// Malformed CID mapping.
+    #[error("Invalid CID mapping: {0}")]
+    InvalidMapping(String),
+
+    /// Malformed CARv2 index, naming the field that is wrong.
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+
+    /// A CARv2 index with a codec other than `IndexSorted` and `MultihashIndexSorted`.
+    #[error("Unknown index codec: {0:#x}")]
+    UnknownIndexCodec(u64),
+
+    /// A CARv2 index, or an offset in it, outside the archive or its payload.
+    #[error("Index offset out of bounds: {0}")]
+    IndexOutOfBounds(u64),
+
+    /// Entries of a CARv2 index bucket that are not sorted by digest.
+    #[error("Index entries are not sorted by digest")]
+    IndexUnsorted,
+
+    /// A `MultihashIndexSorted` bucket whose digests are not as long as its hash's.
+    #[error("Index digests of multihash {code:#x} are {found} bytes long, expected {expected}")]
+    IndexDigestWidthMismatch {
+        code: u64,
+        expected: u32,
+        found: u32,
+    },
+
+    /// A CARv2 application region that does not fit in the padding reserved for it.
+    #[error("Region of {length} bytes does not fit in {capacity} bytes of padding")]
+    RegionTooLarge { length: u64, capacity: u64 },
+
+    /// Malformed chunk cache.
+    #[error("Invalid chunk cache: {0}")]
+    InvalidChunkCache(String),
+
+    /// A signature is malformed, made by an untrusted key or does not match the archive.
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+
+    /// An authorship block is malformed, badly signed or does not cover the archive's roots.
+    #[error("Invalid authorship: {0}")]
+    InvalidAuthorship(String),
+
+    /// Malformed dataset manifest.
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+
+    /// A node does not conform to its schema type.
+    #[error("Schema violation: {0}")]
+    SchemaViolation(String),
+
+    /// A dag-cbor block with bytes after its value, read with
+    /// [`ReadOptions::reject_block_slack`].
+    #[error("Bytes after the value of block: {0}")]
+    BlockSlack(Cid),
+
+    /// A block whose data does not hash to its CID, at `offset` in the CARv1 (payload).
+    #[error("Hash mismatch of block {cid} at {offset}")]
+    HashMismatch { cid: Cid, offset: u64 },
+
+    /// A section length encoded in more bytes than it needs, at `offset` in the archive, read
+    /// without [`ReadOptions::lenient`].
+    #[error("Non-minimal varint at {offset}")]
+    NonMinimalVarint { offset: u64 },
+
+    /// A section longer than a reader's limit, at `offset` in the archive.
+    #[error("Section of {length} bytes at {offset} exceeds the limit of {limit}")]
+    SectionTooLarge {
+        offset: u64,
+        length: u64,
+        limit: u64,
+    },
+
+    /// A section at `offset` in the archive that took longer to read than a reader allows.
+    #[error("Timed out reading the section at {offset}")]
+    SectionTimedOut { offset: u64 },
+
+    /// A block larger than the store parameters it is encoded for allow, see [`params`].
+    #[error("Block {cid} of {size} bytes exceeds the limit of {max}")]
+    BlockTooLarge { cid: Cid, size: usize, max: usize },
+
+    /// A block's hash has no implementation and is not trusted by the [`HashPolicy`].
+    #[error("Unverifiable hash: {0}")]
+    UnverifiableHash(Cid),
+
+    /// Parquet error.
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    /// Serde error.
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Serde(#[from] libipld::error::SerdeError),
+
+    /// Zip error.
+    #[cfg(feature = "zip")]
+    #[error(transparent)]
+    Zip(#[from] ::zip::result::ZipError),
+
+    /// An upload broke its [`upload::UploadPolicy`].
+    #[cfg(feature = "ipld")]
+    #[error("Upload rejected: {0}")]
+    Rejected(#[from] upload::Rejection),
+
+    /// The operation did not finish before its [`Deadline`].
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
 }
 
 /// CAR result.
 pub type CarResult<T> = Result<T, CarError>;
 
-fn read_header<R: Read>(mut r: R) -> CarResult<CarHeader> {
-    let header_length = varint_read_u64(&mut r)?;
+/// A point in time after which reads and traversals give up with [`CarError::DeadlineExceeded`].
+///
+/// The deadline is checked between blocks, so a single slow read can still overrun it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Self(Some(instant))
+    }
+
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    pub fn check(&self) -> CarResult<()> {
+        match self.0 {
+            Some(deadline) if Instant::now() >= deadline => Err(CarError::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "ipld")]
+/// Options for reading archives.
+#[derive(Clone, Copy, Default)]
+pub struct ReadOptions<'a> {
+    pub deadline: Deadline,
+    /// Accept non-minimal varints, zero padding between sections and a truncated last section,
+    /// reporting them as [`ReadAnomaly`]s instead of failing.
+    pub lenient: bool,
+    /// Told about the bytes and blocks read.
+    pub metrics: Option<&'a dyn Metrics>,
+    pub hashes: HashPolicy<'a>,
+    /// Fail with [`CarError::UnknownIndexCodec`] on a CARv2 index of an unknown codec, rather
+    /// than keeping it as [`raw::IndexLayout::Unknown`].
+    pub reject_unknown_indexes: bool,
+    /// Fail with [`CarError::BlockSlack`] on a dag-cbor block with bytes after its value,
+    /// rather than keeping them and reporting [`ReadAnomaly::BlockSlack`].
+    pub reject_block_slack: bool,
+}
+
+#[cfg(feature = "ipld")]
+/// Which multihash codes are verified when blocks are read.
+///
+/// Blocks are checked against their CID whenever the hash is implemented, unless its code is
+/// trusted; reads fail with [`CarError::HashMismatch`] on the first that does not match. Blocks
+/// with other hashes fail with [`CarError::UnverifiableHash`] in strict mode, and are otherwise
+/// accepted and reported as [`ReadAnomaly::UnverifiedHash`].
+#[derive(Debug, Clone, Copy)]
+pub struct HashPolicy<'a> {
+    /// Codes accepted without hashing the block.
+    pub trusted: &'a [u64],
+    pub strict: bool,
+    /// Implementations of hashes libipld does not link, or replacements for the ones it does.
+    pub hashers: Option<&'a HasherRegistry>,
+}
+
+#[cfg(feature = "ipld")]
+impl Default for HashPolicy<'_> {
+    fn default() -> Self {
+        Self {
+            trusted: &[],
+            strict: true,
+            hashers: None,
+        }
+    }
+}
+
+#[cfg(feature = "ipld")]
+impl HashPolicy<'_> {
+    /// Builds the block `cid`, verifying `data` unless the policy trusts its hash. Also returns
+    /// whether the block was verified or trusted, `false` meaning it was accepted unchecked.
+    ///
+    /// Fails with [`CarError::HashMismatch`] at `offset`, where the section of the block starts,
+    /// if the data does not match; blocks that are not read from an archive pass 0.
+    pub fn block(
+        &self,
+        cid: Cid,
+        data: Vec<u8>,
+        offset: u64,
+    ) -> CarResult<(Block<DefaultParams>, bool)> {
+        self.block_with_params(cid, data, offset)
+    }
+
+    /// Like [`HashPolicy::block`], for blocks of the store parameters `S`.
+    pub fn block_with_params<S: StoreParams>(
+        &self,
+        cid: Cid,
+        data: Vec<u8>,
+        offset: u64,
+    ) -> CarResult<(Block<S>, bool)> {
+        let code = cid.hash().code();
+        let verified = self.trusted.contains(&code)
+            || match self.hashers {
+                Some(hashers) => hashers.verify(&cid, &data),
+                None => HasherRegistry::new().verify(&cid, &data),
+            }
+            .map_err(|err| match err {
+                CarError::Ipld(_) => CarError::HashMismatch { cid, offset },
+                err => err,
+            })?;
+        if verified {
+            Ok((Block::new_unchecked(cid, data), true))
+        } else if self.strict {
+            Err(CarError::UnverifiableHash(cid))
+        } else {
+            Ok((Block::new_unchecked(cid, data), false))
+        }
+    }
+}
+
+#[cfg(feature = "ipld")]
+impl fmt::Debug for ReadOptions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadOptions")
+            .field("deadline", &self.deadline)
+            .field("lenient", &self.lenient)
+            .field("metrics", &self.metrics.is_some())
+            .field("hashes", &self.hashes)
+            .field("reject_unknown_indexes", &self.reject_unknown_indexes)
+            .field("reject_block_slack", &self.reject_block_slack)
+            .finish()
+    }
+}
+
+/// A non-fatal irregularity found while reading an archive. Offsets are in bytes from the start
+/// of the CARv1 (payload).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadAnomaly {
+    /// A length prefix encoded with more bytes than needed.
+    NonMinimalVarint { offset: u64 },
+    /// A run of zero bytes where a section was expected.
+    Padding { offset: u64, length: u64 },
+    /// A block whose CID was already read earlier in the archive.
+    DuplicateBlock { cid: Cid, offset: u64 },
+    /// A header field other than `version` and `roots`.
+    UnknownHeaderField { name: String },
+    /// A block whose hash could not be verified, read with a non-strict [`HashPolicy`].
+    UnverifiedHash { cid: Cid, offset: u64 },
+    /// Bytes after the value of a dag-cbor block, kept as part of its data.
+    BlockSlack { cid: Cid, offset: u64, length: u64 },
+    /// A truncated section at the end of the data.
+    TrailingBytes { offset: u64, length: u64 },
+}
+
+impl fmt::Display for ReadAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonMinimalVarint { offset } => write!(f, "non-minimal varint at {}", offset),
+            Self::Padding { offset, length } => {
+                write!(f, "{} bytes of padding at {}", length, offset)
+            }
+            Self::DuplicateBlock { cid, offset } => write!(f, "duplicate of {} at {}", cid, offset),
+            Self::UnknownHeaderField { name } => write!(f, "unknown header field {:?}", name),
+            Self::UnverifiedHash { cid, offset } => {
+                write!(f, "unverified hash of {} at {}", cid, offset)
+            }
+            Self::BlockSlack {
+                cid,
+                offset,
+                length,
+            } => {
+                write!(
+                    f,
+                    "{} bytes after the value of {} at {}",
+                    length, cid, offset
+                )
+            }
+            Self::TrailingBytes { offset, length } => {
+                write!(f, "{} trailing bytes at {}", length, offset)
+            }
+        }
+    }
+}
+
+/// The anomalies found while reading an archive, in the order they were found.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReadReport {
+    pub anomalies: Vec<ReadAnomaly>,
+}
+
+impl ReadReport {
+    pub fn is_clean(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+
+/// Wraps a reader to keep track of how many bytes were read from it.
+#[derive(Debug)]
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: Seek> CountingReader<R> {
+    /// Seeks to `position` bytes past where reading started.
+    pub(crate) fn seek_to(&mut self, position: u64) -> std::io::Result<()> {
+        let start = self.inner.stream_position()? - self.position;
+        self.inner
+            .seek(SeekFrom::Start(start.saturating_add(position)))?;
+        self.position = position;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// What [`ContentArchive::read_bytes_with_report`] is reading.
+#[cfg(feature = "ipld")]
+enum ReadState {
+    /// The archive itself.
+    Archive,
+    /// The CARv1 payload of a CARv2.
+    #[cfg(feature = "v2")]
+    Payload(v2::CarHeaderV2),
+}
 
-    let mut header_buf = vec![0; header_length as usize];
-    r.read_exact(&mut header_buf)?;
+/// Reads the version of the header at the start of `r`, whose limit is the length of the
+/// archive or payload it starts.
+#[cfg(feature = "ipld")]
+fn read_version<R: Read>(r: &mut std::io::Take<R>) -> CarResult<u64> {
+    let (header_length, _) = read_varint_lenient(&mut *r)?.ok_or(CarError::InvalidFormat)?;
+    if header_length > r.limit() {
+        return Err(CarError::InvalidFormat);
+    }
+    let header_buf = read_length_prefixed(r, header_length)?;
+    Ok(cbor::decode_header(&header_buf)?.version)
+}
 
-    let header_map: Ipld = DagCborCodec.decode(&header_buf)?;
+/// How much of a section or header is allocated before its bytes arrive.
+const PREALLOCATED_LENGTH: u64 = 1 << 16;
+
+/// Reads the `length` bytes after a length prefix, growing the buffer as they arrive, so a
+/// length longer than the input fails with [`std::io::ErrorKind::UnexpectedEof`] instead of
+/// being allocated up front.
+pub(crate) fn read_length_prefixed<R: Read>(r: R, length: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(length.min(PREALLOCATED_LENGTH) as usize);
+    r.take(length).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
 
-    match header_map.get("version") {
-        Ok(Ipld::Integer(version)) => match &version {
-            2 => {
-                let mut v2_header_buf = [0; HEADER_LENGTH];
-                r.read_exact(&mut v2_header_buf)?;
-                Ok(CarHeader::V2(v2::parse_v2_header(v2_header_buf)?))
+/// Reads a varint, also accepting non-minimal encodings, which are flagged with `false`.
+/// Returns `None` when the input ends before the first byte.
+pub(crate) fn read_varint_lenient<R: Read>(mut r: R) -> CarResult<Option<(u64, bool)>> {
+    let mut value = 0u64;
+    let mut byte = [0u8; 1];
+    for i in 0..10 {
+        match r.read_exact(&mut byte) {
+            Err(err) if i == 0 && err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
             }
-            _ => Err(CarError::UnsupportedVersion(*version as u8)),
-        },
-        _ => return Err(CarError::InvalidFormat),
+            result => result?,
+        }
+        if i == 9 && byte[0] > 1 {
+            break;
+        }
+        value |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some((value, i == 0 || byte[0] != 0)));
+        }
     }
+    Err(CarError::InvalidFormat)
 }
 
+/// Reads the section of `cid` at the start of `r`, at `offset` in the payload with `left` bytes
+/// of it after the section, verifying the data against the CID as [`HashPolicy::default`] does.
+/// `None` if the section is of another multihash.
+#[cfg(feature = "ipld")]
+pub(crate) fn read_indexed_block<R: Read>(
+    r: R,
+    cid: &Cid,
+    offset: u64,
+    left: u64,
+) -> CarResult<Option<Block<DefaultParams>>> {
+    let mut section = r.take(left);
+    let length = unsigned_varint::io::read_u64(&mut section)?;
+    if length > section.limit() {
+        return Err(CarError::InvalidFormat);
+    }
+    let bytes = read_length_prefixed(&mut section, length)?;
+    let mut data = &bytes[..];
+    if Cid::read_bytes(&mut data)?.hash() != cid.hash() {
+        return Ok(None);
+    }
+    let data = data.to_vec();
+    Ok(Some(HashPolicy::default().block(*cid, data, offset)?.0))
+}
+
+pub(crate) fn write_varint<W: Write>(mut w: W, value: u64) -> CarResult<()> {
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    w.write_all(unsigned_varint::encode::u64(value, &mut buf))?;
+    Ok(())
+}
 
+#[cfg(feature = "ipld")]
 impl ContentArchive {
-    pub fn read_bytes<R: Read + Seek>(mut r: R) -> CarResult<ContentArchive> {
-        CarV1::from_reader(&mut r).map(ContentArchive::V1).or_else(|_| {
-            r.seek(std::io::SeekFrom::Start(0))?;
-            let header = read_header(&mut r)?;
-
-            match header {
-                CarHeader::V2(header) => {
-                    r.seek(std::io::SeekFrom::Start(header.data_offset))?;
-                    let mut car_v1_buf = vec![0u8; header.data_size as usize];
-                    r.read_exact(&mut car_v1_buf)?;
-                    let mut reader = Cursor::new(car_v1_buf);
-                    let index_offset = header.index_offset;
-                    Ok(ContentArchive::V2(v2::CarV2::new(
-                        header,
-                        ContentArchive::read_bytes(&mut reader)?.try_into()?,
-                        v2::read_v2_index(&mut r, index_offset)?,
-                    )))
+    pub fn read_bytes<R: Read + Seek>(r: R) -> CarResult<ContentArchive> {
+        Self::read_bytes_with_options(r, &ReadOptions::default())
+    }
+
+    pub fn read_bytes_with_options<R: Read + Seek>(
+        r: R,
+        options: &ReadOptions,
+    ) -> CarResult<ContentArchive> {
+        Self::read_bytes_with_report(r, options).map(|(car, _)| car)
+    }
+
+    /// Reads an archive along with the anomalies found in its CARv1 (payload).
+    ///
+    /// The header is read first to tell the versions apart. A CARv2 must wrap a CARv1 lying
+    /// within the archive, after its header, and an index after that payload; anything else is
+    /// [`CarError::InvalidFormat`].
+    #[cfg_attr(not(feature = "v2"), allow(unused_mut, clippy::never_loop))]
+    #[cfg_attr(not(any(feature = "v1", feature = "v2")), allow(unused_variables))]
+    pub fn read_bytes_with_report<R: Read + Seek>(
+        mut r: R,
+        options: &ReadOptions,
+    ) -> CarResult<(ContentArchive, ReadReport)> {
+        let length = r.seek(SeekFrom::End(0))?;
+        let mut range = 0..length;
+        let mut state = ReadState::Archive;
+        loop {
+            r.seek(SeekFrom::Start(range.start))?;
+            let mut section = (&mut r).take(range.end - range.start);
+            let version = read_version(&mut section)?;
+            match (version, state) {
+                #[cfg(feature = "v1")]
+                (1, ReadState::Archive) => {
+                    r.seek(SeekFrom::Start(range.start))?;
+                    let (car, report) = CarV1::from_reader_with_report(r.take(length), options)?;
+                    return Ok((ContentArchive::V1(car), report));
+                }
+                #[cfg(feature = "v2")]
+                (1, ReadState::Payload(header)) => {
+                    r.seek(SeekFrom::Start(range.start))?;
+                    let (car_v1, report) = CarV1::from_reader_with_report(
+                        (&mut r).take(range.end - range.start),
+                        options,
+                    )?;
+                    let index = index::read_v2_index(&mut r, header.index_offset)?;
+                    match &index {
+                        Some(index) if index.kind().is_none() && options.reject_unknown_indexes => {
+                            return Err(CarError::UnknownIndexCodec(index.codec()))
+                        }
+                        Some(index) => index.check_offsets(header.data_size)?,
+                        None => {}
+                    }
+                    return Ok((
+                        ContentArchive::V2(v2::CarV2::new(header, car_v1, index)),
+                        report,
+                    ));
+                }
+                #[cfg(feature = "v2")]
+                (2, ReadState::Archive) => {
+                    let mut v2_header_buf = [0; HEADER_LENGTH];
+                    section.read_exact(&mut v2_header_buf)?;
+                    let header = block::parse_v2_header(v2_header_buf)?;
+                    let data_range = header.data_range();
+                    let header_end = length - section.limit();
+                    if data_range.start < header_end || data_range.end > length {
+                        return Err(CarError::InvalidFormat);
+                    }
+                    if header.has_index()
+                        && header
+                            .index_range(length)
+                            .is_none_or(|index| index.start < data_range.end)
+                    {
+                        return Err(CarError::IndexOutOfBounds(header.index_offset));
+                    }
+                    range = data_range;
+                    state = ReadState::Payload(header);
+                }
+                // The payload of a CARv2 is a CARv1, wrappers do not nest.
+                #[cfg(feature = "v2")]
+                (2, ReadState::Payload(_)) => return Err(CarError::InvalidFormat),
+                (version, _) => {
+                    return Err(CarError::UnsupportedVersion(
+                        version.min(u8::MAX.into()) as u8
+                    ))
                 }
-                _ => Err(CarError::InvalidFormat),
             }
-        })
+        }
+    }
+
+    /// The CARv1 of the archive, which for a CARv2 is its payload.
+    pub fn car_v1(&self) -> &CarV1 {
+        match *self {
+            #[cfg(feature = "v1")]
+            ContentArchive::V1(ref car) => car,
+            #[cfg(feature = "v2")]
+            ContentArchive::V2(ref car) => &car.car_v1,
+        }
+    }
+
+    /// Checks that every block reachable from the roots is in the archive, failing with
+    /// [`CarError::IncompleteDag`] listing all that are not. Blocks are walked depth-first, and
+    /// links to missing blocks are only followed from blocks that are present.
+    pub fn verify_complete(&self) -> CarResult<()> {
+        let car = self.car_v1();
+        let blocks: HashMap<&Cid, &Block<DefaultParams>> = car
+            .blocks
+            .iter()
+            .map(|block| (block.cid(), block))
+            .collect();
+        let mut stack: Vec<Cid> = car.header.roots.iter().rev().copied().collect();
+        let mut seen = HashSet::new();
+        let mut missing = vec![];
+        while let Some(cid) = stack.pop() {
+            if !seen.insert(cid) {
+                continue;
+            }
+            match blocks.get(&cid) {
+                Some(block) => stack.extend(traversal::links(block)?.into_iter().rev()),
+                None => missing.push(cid),
+            }
+        }
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(CarError::IncompleteDag(missing)),
+        }
+    }
+
+    pub fn into_car_v1(self) -> CarV1 {
+        match self {
+            #[cfg(feature = "v1")]
+            ContentArchive::V1(car) => car,
+            #[cfg(feature = "v2")]
+            ContentArchive::V2(car) => car.car_v1,
+        }
     }
 }
 
+#[cfg(feature = "ipld")]
 impl TryFrom<ContentArchive> for v1::CarV1 {
     type Error = CarError;
 
     fn try_from(value: ContentArchive) -> CarResult<Self> {
         match value {
+            #[cfg(feature = "v1")]
             ContentArchive::V1(car) => Ok(car),
+            #[allow(unreachable_patterns)]
             _ => Err(CarError::InvalidFormat),
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "ipld"))]
 mod tests {
     use super::*;
+    use std::io::Cursor;
     use std::path::{Path, PathBuf};
-    use std::str::FromStr;
 
     struct Fixture {
         pub source: PathBuf,
@@ -148,22 +829,31 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "v2")]
     fn it_reads_car_v2() {
+        use std::str::FromStr;
+
         let car = std::fs::read(Fixture::new("carv2-basic.car").source).unwrap();
         let decoded_car = ContentArchive::read_bytes(&mut Cursor::new(car)).unwrap();
 
         match decoded_car {
+            #[cfg(feature = "v1")]
             ContentArchive::V1(_) => panic!("Expected V2"),
             ContentArchive::V2(carv2) => {
                 assert_eq!(carv2.header.data_offset, 51);
                 assert_eq!(carv2.header.data_size, 448);
                 assert_eq!(carv2.header.index_offset, 499);
-                assert!(carv2.index.is_some());
+                // The fixture's index has no codec, so its first byte is read as one.
+                assert!(matches!(
+                    carv2.index.as_ref().map(index::CarV2Index::layout),
+                    Some(index::IndexLayout::Unknown { codec: 1, raw_bytes })
+                        if raw_bytes.len() == 215
+                ));
                 assert!(!carv2.is_fully_indexed());
 
                 assert_eq!(
                     carv2.car_v1.header.roots,
-                    vec![libipld::cid::Cid::from_str("QmfEoLyB5NndqeKieExd1rtJzTduQUPEV8TwAYcUiy3H5Z").unwrap(),]
+                    vec![Cid::from_str("QmfEoLyB5NndqeKieExd1rtJzTduQUPEV8TwAYcUiy3H5Z").unwrap(),]
                 );
                 assert_eq!(carv2.car_v1.blocks.len(), 5);
             }
@@ -171,14 +861,148 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "v1")]
+    fn it_stops_reading_at_the_deadline() {
+        let car = std::fs::read(Fixture::new("carv1-basic.car").source).unwrap();
+        let options = ReadOptions {
+            deadline: Deadline::at(Instant::now()),
+            ..ReadOptions::default()
+        };
+        match ContentArchive::read_bytes_with_options(&mut Cursor::new(car), &options) {
+            Err(CarError::DeadlineExceeded) => {}
+            other => panic!("Expected DeadlineExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
     fn it_reads_car_v1() {
         let car = std::fs::read(Fixture::new("carv1-basic.car").source).unwrap();
         let decoded_car = ContentArchive::read_bytes(&mut Cursor::new(car)).unwrap();
         match decoded_car {
+            #[cfg(feature = "v2")]
             ContentArchive::V2(_) => panic!("Expected V1"),
             ContentArchive::V1(carv1) => {
                 assert_eq!(carv1.header.roots.len(), 1);
             }
         }
     }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_verifies_dags_are_complete() {
+        let car = std::fs::read(Fixture::new("carv1-basic.car").source).unwrap();
+        ContentArchive::read_bytes(Cursor::new(car))
+            .unwrap()
+            .verify_complete()
+            .unwrap();
+
+        let car = test_utils::diamond();
+        let [leaf, right, ..] = test_utils::cids(&car)[..] else {
+            unreachable!()
+        };
+        ContentArchive::V1(car.clone()).verify_complete().unwrap();
+        let mut partial = car.clone();
+        partial.blocks.drain(..2);
+        // Links of blocks the roots do not reach are not checked.
+        partial.put_block(test_utils::cbor(&libipld::ipld!([*test_utils::raw(
+            b"absent"
+        )
+        .cid()])));
+        match ContentArchive::V1(partial.clone()).verify_complete() {
+            Err(CarError::IncompleteDag(missing)) => assert_eq!(missing, vec![leaf, right]),
+            other => panic!("Expected IncompleteDag, got {:?}", other),
+        }
+        partial
+            .header
+            .roots
+            .push(*test_utils::raw(b"absent root").cid());
+        match ContentArchive::V1(partial).verify_complete() {
+            Err(CarError::IncompleteDag(missing)) => assert_eq!(missing.len(), 3),
+            other => panic!("Expected IncompleteDag, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_versions_built_without() {
+        for (fixture, version, supported) in [
+            ("carv1-basic.car", 1, cfg!(feature = "v1")),
+            ("carv2-basic.car", 2, cfg!(feature = "v2")),
+        ] {
+            let car = std::fs::read(Fixture::new(fixture).source).unwrap();
+            match ContentArchive::read_bytes(Cursor::new(car)) {
+                Ok(_) => assert!(supported),
+                Err(CarError::UnsupportedVersion(v)) => assert!(!supported && v == version),
+                Err(err) => panic!("Expected UnsupportedVersion, got {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_rejects_unknown_indexes_when_asked() {
+        let car = std::fs::read(Fixture::new("carv2-basic.car").source).unwrap();
+        let options = ReadOptions {
+            reject_unknown_indexes: true,
+            ..ReadOptions::default()
+        };
+        assert!(matches!(
+            ContentArchive::read_bytes_with_options(Cursor::new(car), &options),
+            Err(CarError::UnknownIndexCodec(1))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_rejects_hostile_car_v2_headers() {
+        let car = std::fs::read(Fixture::new("carv2-basic.car").source).unwrap();
+        let read = |bytes: &[u8]| ContentArchive::read_bytes(Cursor::new(bytes)).map(|_| ());
+        // The fixture's CARv2 header follows an 11 byte pragma; offsets are 8 bytes each.
+        let with = |field: usize, value: u64| {
+            let mut bytes = car.clone();
+            let at = 11 + CHARACTERISTICS_LENGTH + 8 * field;
+            bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            bytes
+        };
+        for bytes in [with(0, 0), with(1, u64::MAX), with(1, car.len() as u64)] {
+            assert!(matches!(read(&bytes), Err(CarError::InvalidFormat)));
+        }
+        for offset in [100, car.len() as u64 + 1] {
+            assert!(matches!(
+                read(&with(2, offset)),
+                Err(CarError::IndexOutOfBounds(o)) if o == offset
+            ));
+        }
+
+        let mut nested = with(1, car.len() as u64);
+        nested.truncate(11 + HEADER_LENGTH);
+        nested[11 + CHARACTERISTICS_LENGTH + 16..].fill(0);
+        nested.extend(&car);
+        assert!(matches!(read(&nested), Err(CarError::InvalidFormat)));
+
+        let too_long = [0xff, 0xff, 0xff, 0xff, 0x0f, 0xa1];
+        assert!(matches!(read(&too_long), Err(CarError::InvalidFormat)));
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_rejects_sections_longer_than_the_input() {
+        let mut car = std::fs::read(Fixture::new("carv1-basic.car").source).unwrap();
+        // A section claiming 2^62 - 1 bytes, which is not allocated before it is read.
+        car.extend([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3f, 0x01]);
+        assert!(matches!(
+            ContentArchive::read_bytes(Cursor::new(&car)),
+            Err(CarError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        let options = ReadOptions {
+            lenient: true,
+            ..ReadOptions::default()
+        };
+        let (_, report) =
+            ContentArchive::read_bytes_with_report(Cursor::new(&car), &options).unwrap();
+        assert!(matches!(
+            report.anomalies.last(),
+            Some(ReadAnomaly::TrailingBytes { length: 10, .. })
+        ));
+    }
 }
@@ -0,0 +1,320 @@
+//! Checks of archives against configurable ingestion policies.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek};
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::traversal;
+use crate::v1::CarV1;
+use crate::{CarResult, ContentArchive, ReadOptions};
+
+const IDENTITY_CODE: u64 = 0x00;
+
+/// A property of an archive checked by [`lint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rule {
+    /// Blocks are stored in depth-first order from the roots, as in a `dfs` export.
+    Ordering,
+    /// No block is stored more than once.
+    Dedup,
+    /// No block uses an identity multihash.
+    IdentityCids,
+    /// The archive is a CARv2 with an index.
+    IndexPresence,
+    /// The roots, every block they link to and nothing else are in the archive.
+    RootReachability,
+}
+
+impl Rule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rule::Ordering => "ordering",
+            Rule::Dedup => "dedup",
+            Rule::IdentityCids => "identity-cids",
+            Rule::IndexPresence => "index-presence",
+            Rule::RootReachability => "root-reachability",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a violated [`Rule`] is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// The rules to check and their severities. Rules that are not in the set are not checked.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RuleSet {
+    pub rules: BTreeMap<Rule, Severity>,
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every rule, with the same severity.
+    pub fn all(severity: Severity) -> Self {
+        [
+            Rule::Ordering,
+            Rule::Dedup,
+            Rule::IdentityCids,
+            Rule::IndexPresence,
+            Rule::RootReachability,
+        ]
+        .into_iter()
+        .fold(Self::new(), |rules, rule| rules.with(rule, severity))
+    }
+
+    pub fn with(mut self, rule: Rule, severity: Severity) -> Self {
+        self.rules.insert(rule, severity);
+        self
+    }
+
+    pub fn without(mut self, rule: Rule) -> Self {
+        self.rules.remove(&rule);
+        self
+    }
+}
+
+/// A violation of a [`Rule`], about `cid` when it concerns a single block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub rule: Rule,
+    pub severity: Severity,
+    pub cid: Option<Cid>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}[{}]: {}", severity, self.rule, self.message)
+    }
+}
+
+/// Reads an archive leniently and checks it against `rules`.
+///
+/// Only archives that cannot be read at all fail; everything else is reported as findings.
+pub fn lint<R: Read + Seek>(r: R, rules: &RuleSet) -> CarResult<Vec<Finding>> {
+    let options = ReadOptions {
+        lenient: true,
+        ..ReadOptions::default()
+    };
+    Ok(lint_archive(
+        &ContentArchive::read_bytes_with_options(r, &options)?,
+        rules,
+    ))
+}
+
+/// Checks an archive that was already read against `rules`.
+#[cfg_attr(
+    not(any(feature = "v1", feature = "v2")),
+    allow(unreachable_code, unused_variables)
+)]
+pub fn lint_archive(archive: &ContentArchive, rules: &RuleSet) -> Vec<Finding> {
+    let (car, indexed): (&CarV1, bool) = match *archive {
+        #[cfg(feature = "v1")]
+        ContentArchive::V1(ref car) => (car, false),
+        #[cfg(feature = "v2")]
+        ContentArchive::V2(ref car) => (&car.car_v1, car.index.is_some()),
+    };
+
+    let mut findings = vec![];
+    for (rule, severity) in &rules.rules {
+        let mut report = |cid: Option<Cid>, message: String| {
+            findings.push(Finding {
+                rule: *rule,
+                severity: *severity,
+                cid,
+                message,
+            })
+        };
+        match rule {
+            Rule::Ordering => {
+                if let Some(cid) = first_out_of_order(car) {
+                    report(
+                        Some(cid),
+                        format!("{} is not stored in depth-first order", cid),
+                    );
+                }
+            }
+            Rule::Dedup => {
+                let mut seen = HashSet::new();
+                for block in &car.blocks {
+                    if !seen.insert(*block.cid()) {
+                        report(
+                            Some(*block.cid()),
+                            format!("{} is stored more than once", block.cid()),
+                        );
+                    }
+                }
+            }
+            Rule::IdentityCids => {
+                let mut seen = HashSet::new();
+                for block in &car.blocks {
+                    if block.cid().hash().code() == IDENTITY_CODE && seen.insert(*block.cid()) {
+                        report(
+                            Some(*block.cid()),
+                            format!("{} uses an identity multihash", block.cid()),
+                        );
+                    }
+                }
+            }
+            Rule::IndexPresence => {
+                if !indexed {
+                    report(None, "the archive has no index".into());
+                }
+            }
+            Rule::RootReachability => {
+                let (order, missing) = dfs(car);
+                for cid in missing {
+                    report(
+                        Some(cid),
+                        format!("{} is linked from the roots but missing", cid),
+                    );
+                }
+                let reachable: HashSet<_> = order.into_iter().collect();
+                let mut seen = HashSet::new();
+                for block in &car.blocks {
+                    if !reachable.contains(block.cid()) && seen.insert(*block.cid()) {
+                        report(
+                            Some(*block.cid()),
+                            format!("{} is not reachable from the roots", block.cid()),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    findings
+}
+
+/// The first stored block that breaks the depth-first order of the blocks reachable from the
+/// roots. Unreachable blocks are left to [`Rule::RootReachability`].
+fn first_out_of_order(car: &CarV1) -> Option<Cid> {
+    let (order, _) = dfs(car);
+    let reachable: HashSet<_> = order.iter().collect();
+    let mut seen = HashSet::new();
+    let stored = car
+        .blocks
+        .iter()
+        .map(|block| block.cid())
+        .filter(|cid| reachable.contains(cid) && seen.insert(*cid));
+    order
+        .iter()
+        .zip(stored)
+        .find(|(expected, stored)| expected != stored)
+        .map(|(_, stored)| *stored)
+}
+
+/// Depth-first order of the blocks reachable from the roots, and the CIDs of any linked blocks
+/// that are missing. Blocks whose links cannot be decoded are treated as leaves.
+fn dfs(car: &CarV1) -> (Vec<Cid>, Vec<Cid>) {
+    let blocks: HashMap<&Cid, &Block<DefaultParams>> = car
+        .blocks
+        .iter()
+        .map(|block| (block.cid(), block))
+        .collect();
+    let mut order = vec![];
+    let mut missing = vec![];
+    let mut visited = HashSet::new();
+    let mut stack: Vec<Cid> = car.header.roots.iter().rev().copied().collect();
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        match blocks.get(&cid) {
+            Some(block) => {
+                order.push(cid);
+                stack.extend(
+                    traversal::links(block)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .rev(),
+                );
+            }
+            None => missing.push(cid),
+        }
+    }
+    (order, missing)
+}
+
+#[cfg(all(test, feature = "v1"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond, raw};
+    use std::io::Cursor;
+
+    fn rules_of(findings: &[Finding]) -> Vec<Rule> {
+        findings.iter().map(|finding| finding.rule).collect()
+    }
+
+    #[test]
+    fn it_lints_archives() {
+        let car = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/carv1-basic.car"
+        ))
+        .unwrap();
+        let findings = lint(Cursor::new(car), &RuleSet::all(Severity::Error)).unwrap();
+        assert_eq!(rules_of(&findings), vec![Rule::IndexPresence]);
+        assert_eq!(
+            findings[0].to_string(),
+            "error[index-presence]: the archive has no index"
+        );
+
+        let mut car = diamond();
+        let [leaf, _, root, left] = [0, 1, 2, 3].map(|i| cids(&car)[i]);
+        car.blocks.push(car.blocks[0].clone());
+        car.blocks.push(raw(b"stray"));
+        car.blocks.remove(1);
+        let rules = RuleSet::all(Severity::Warning)
+            .without(Rule::IndexPresence)
+            .with(Rule::RootReachability, Severity::Error);
+        let findings = lint_archive(&ContentArchive::V1(car.clone()), &rules);
+
+        assert_eq!(
+            rules_of(&findings),
+            vec![
+                Rule::Ordering,
+                Rule::Dedup,
+                Rule::RootReachability,
+                Rule::RootReachability
+            ]
+        );
+        assert_eq!(findings[0].cid, Some(leaf));
+        assert_eq!(findings[1].cid, Some(leaf));
+        assert_eq!(findings[2].severity, Severity::Error);
+        assert_eq!(findings[3].cid, Some(*car.blocks.last().unwrap().cid()));
+        assert!(findings[2].message.contains("missing"));
+
+        car.blocks = vec![root, left, leaf]
+            .into_iter()
+            .map(|cid| {
+                car.blocks
+                    .iter()
+                    .find(|block| *block.cid() == cid)
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+        assert_eq!(
+            rules_of(&lint_archive(&ContentArchive::V1(car), &rules)),
+            vec![Rule::RootReachability]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Dataset manifests: the summary of an archive that onboarding pipelines attach to deals and
+//! databases, see [`manifest`].
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::multihash::{Hasher, Multihash, Sha2_256};
+use libipld::Ipld;
+
+use crate::repo::car_cid;
+use crate::{CarError, CarResult, ContentArchive};
+
+/// The `fil-commitment-unsealed` multicodec of piece CIDs.
+pub const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+
+/// The `sha2-256-trunc254-padded` multihash code of piece CIDs.
+pub const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
+
+/// Roots, sizes and digests of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatasetManifest {
+    pub roots: Vec<Cid>,
+    /// The size of the archive file.
+    pub car_size: u64,
+    /// The total size of the block data, without framing.
+    pub payload_size: u64,
+    pub block_count: u64,
+    /// The CID of the archive file, see [`car_cid`].
+    pub car_digest: Cid,
+    pub piece: Option<PieceInfo>,
+}
+
+/// The Filecoin piece commitment (CommP) of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceInfo {
+    pub cid: Cid,
+    /// The padded piece size, a power of two.
+    pub size: u64,
+}
+
+/// Builds the manifest of the archive `bytes`, computing its piece commitment if `piece` is set.
+pub fn manifest(bytes: &[u8], piece: bool) -> CarResult<DatasetManifest> {
+    let car = ContentArchive::read_bytes(Cursor::new(bytes))?.into_car_v1();
+    Ok(DatasetManifest {
+        roots: car.header.roots.clone(),
+        car_size: bytes.len() as u64,
+        payload_size: car
+            .blocks
+            .iter()
+            .map(|block| block.data().len() as u64)
+            .sum(),
+        block_count: car.blocks.len() as u64,
+        car_digest: car_cid(bytes),
+        piece: if piece {
+            Some(piece_commitment(bytes)?)
+        } else {
+            None
+        },
+    })
+}
+
+/// Computes the piece commitment of `bytes`: the root of a binary sha2-256 (truncated to 254
+/// bits) merkle tree over the data, Fr32 padded and zero filled to a power of two no smaller
+/// than 128 bytes.
+pub fn piece_commitment(bytes: &[u8]) -> CarResult<PieceInfo> {
+    let chunks = (bytes.len() as u64)
+        .div_ceil(127)
+        .max(1)
+        .next_power_of_two();
+    let size = chunks * 128;
+
+    let mut layer = Vec::with_capacity(size as usize / 32);
+    let mut chunk = [0u8; 127];
+    for i in 0..chunks as usize {
+        let start = (i * 127).min(bytes.len());
+        let end = ((i + 1) * 127).min(bytes.len());
+        chunk.fill(0);
+        chunk[..end - start].copy_from_slice(&bytes[start..end]);
+        let padded = fr32_pad(&chunk);
+        layer.extend(padded.chunks(32).map(|leaf| {
+            let mut node = [0u8; 32];
+            node.copy_from_slice(leaf);
+            node
+        }));
+    }
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha2_256::default();
+                hasher.update(&pair[0]);
+                hasher.update(&pair[1]);
+                let mut node = [0u8; 32];
+                node.copy_from_slice(hasher.finalize());
+                node[31] &= 0x3f;
+                node
+            })
+            .collect();
+    }
+
+    let hash =
+        Multihash::wrap(SHA2_256_TRUNC254_PADDED, &layer[0]).map_err(libipld::cid::Error::from)?;
+    Ok(PieceInfo {
+        cid: Cid::new_v1(FIL_COMMITMENT_UNSEALED, hash),
+        size,
+    })
+}
+
+/// Spreads 127 bytes over four 32-byte field elements, zeroing the top two bits of each.
+fn fr32_pad(input: &[u8; 127]) -> [u8; 128] {
+    let mut out = [0u8; 128];
+    out[..32].copy_from_slice(&input[..32]);
+    out[31] &= 0x3f;
+
+    let mut t = input[31] >> 6;
+    for i in 32..64 {
+        out[i] = (input[i] << 2) | t;
+        t = input[i] >> 6;
+    }
+    out[63] &= 0x3f;
+
+    t = input[63] >> 4;
+    for i in 64..96 {
+        out[i] = (input[i] << 4) | t;
+        t = input[i] >> 4;
+    }
+    out[95] &= 0x3f;
+
+    t = input[95] >> 2;
+    for i in 96..127 {
+        out[i] = (input[i] << 6) | t;
+        t = input[i] >> 2;
+    }
+    out[127] = t & 0x3f;
+    out
+}
+
+impl DatasetManifest {
+    /// Encodes the manifest as a dag-cbor map.
+    pub fn encode(&self) -> CarResult<Vec<u8>> {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "roots".to_string(),
+            Ipld::List(self.roots.iter().copied().map(Ipld::Link).collect()),
+        );
+        map.insert("carSize".to_string(), Ipld::Integer(self.car_size.into()));
+        map.insert(
+            "payloadSize".to_string(),
+            Ipld::Integer(self.payload_size.into()),
+        );
+        map.insert(
+            "blockCount".to_string(),
+            Ipld::Integer(self.block_count.into()),
+        );
+        map.insert("carDigest".to_string(), Ipld::Link(self.car_digest));
+        if let Some(piece) = &self.piece {
+            map.insert("pieceCid".to_string(), Ipld::Link(piece.cid));
+            map.insert("pieceSize".to_string(), Ipld::Integer(piece.size.into()));
+        }
+        Ok(DagCborCodec.encode(&Ipld::Map(map))?)
+    }
+
+    /// Decodes a manifest encoded by [`DatasetManifest::encode`].
+    pub fn decode(bytes: &[u8]) -> CarResult<Self> {
+        let map = DagCborCodec.decode::<Ipld>(bytes)?;
+        let invalid = |field: &str| CarError::InvalidManifest(format!("malformed {}", field));
+        let integer = |field: &str| match map.get(field) {
+            Ok(Ipld::Integer(value)) => u64::try_from(*value).map_err(|_| invalid(field)),
+            _ => Err(invalid(field)),
+        };
+        let link = |field: &str| match map.get(field) {
+            Ok(Ipld::Link(cid)) => Ok(*cid),
+            _ => Err(invalid(field)),
+        };
+        let roots = match map.get("roots") {
+            Ok(Ipld::List(roots)) => roots
+                .iter()
+                .map(|root| match root {
+                    Ipld::Link(cid) => Ok(*cid),
+                    _ => Err(invalid("roots")),
+                })
+                .collect::<CarResult<_>>()?,
+            _ => return Err(invalid("roots")),
+        };
+        let piece = match map.get("pieceCid") {
+            Ok(_) => Some(PieceInfo {
+                cid: link("pieceCid")?,
+                size: integer("pieceSize")?,
+            }),
+            Err(_) => None,
+        };
+        Ok(Self {
+            roots,
+            car_size: integer("carSize")?,
+            payload_size: integer("payloadSize")?,
+            block_count: integer("blockCount")?,
+            car_digest: link("carDigest")?,
+            piece,
+        })
+    }
+
+    /// Renders the manifest as a JSON object with the field names of [`DatasetManifest::encode`].
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"roots\":{},\"carSize\":{},\"payloadSize\":{},\"blockCount\":{},\"carDigest\":\"{}\"",
+            crate::json::cids(&self.roots),
+            self.car_size,
+            self.payload_size,
+            self.block_count,
+            self.car_digest
+        );
+        if let Some(piece) = &self.piece {
+            json.push_str(&format!(
+                ",\"pieceCid\":\"{}\",\"pieceSize\":{}",
+                piece.cid, piece.size
+            ));
+        }
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::diamond;
+
+    #[test]
+    fn it_computes_piece_commitments() {
+        // The root of two layers of zero leaves, as listed in the Filecoin zero commitments.
+        let zeros = piece_commitment(&[0; 127]).unwrap();
+        assert_eq!(zeros.size, 128);
+        assert_eq!(
+            zeros.cid.hash().digest(),
+            &hex("3731bb99ac689f66eef5973e4a94da188f4ddcae580724fc6f3fd60dfd488333")[..]
+        );
+        assert_eq!(piece_commitment(&[]).unwrap().cid, zeros.cid);
+
+        let bytes: Vec<u8> = (0..300).map(|i| (i * 7 % 256) as u8).collect();
+        let piece = piece_commitment(&bytes).unwrap();
+        assert_eq!(piece.size, 512);
+        assert_eq!(piece.cid.codec(), FIL_COMMITMENT_UNSEALED);
+        assert_eq!(
+            piece.cid.hash().digest(),
+            &hex("02921ededa9bf4738191727627922457fac918dc964bfebb6529a91f8971431b")[..]
+        );
+    }
+
+    #[test]
+    fn it_builds_manifests() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let manifest = manifest(&bytes, true).unwrap();
+        assert_eq!(manifest.roots, car.header.roots);
+        assert_eq!(manifest.block_count, 4);
+        assert_eq!(manifest.car_size, bytes.len() as u64);
+        assert_eq!(manifest.car_digest, car_cid(&bytes));
+        assert_eq!(
+            DatasetManifest::decode(&manifest.encode().unwrap()).unwrap(),
+            manifest
+        );
+        assert!(manifest.to_json().contains("\"blockCount\":4"));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,118 @@
+//! Callbacks for instrumenting reads, writes and repositories.
+
+use std::io::{self, Read, Write};
+
+use libipld::cid::Cid;
+
+/// Receives counts from the places it is plugged into: [`crate::ReadOptions::metrics`],
+/// [`MeteredReader`], [`MeteredWriter`] and [`crate::repo::CarRepo::with_metrics`].
+///
+/// Every callback defaults to doing nothing, so implementations only override what they track.
+/// Rates such as blocks per second are left to the metrics backend.
+pub trait Metrics: Send + Sync {
+    fn bytes_read(&self, _bytes: u64) {}
+
+    fn bytes_written(&self, _bytes: u64) {}
+
+    /// A block of `size` bytes of data was decoded.
+    fn block_read(&self, _cid: &Cid, _size: usize) {}
+
+    /// A block lookup in a repository was served locally.
+    fn cache_hit(&self, _cid: &Cid) {}
+
+    /// A block lookup in a repository was not served locally.
+    fn cache_miss(&self, _cid: &Cid) {}
+}
+
+/// [`Metrics`] that ignores everything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Reports the bytes read from `inner`.
+pub struct MeteredReader<'a, R> {
+    inner: R,
+    metrics: &'a dyn Metrics,
+}
+
+impl<'a, R> MeteredReader<'a, R> {
+    pub fn new(inner: R, metrics: &'a dyn Metrics) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for MeteredReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.metrics.bytes_read(read as u64);
+        Ok(read)
+    }
+}
+
+/// Reports the bytes written to `inner`.
+pub struct MeteredWriter<'a, W> {
+    inner: W,
+    metrics: &'a dyn Metrics,
+}
+
+impl<'a, W> MeteredWriter<'a, W> {
+    pub fn new(inner: W, metrics: &'a dyn Metrics) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for MeteredWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.metrics.bytes_written(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{diamond, Counters};
+    use crate::v1::CarV1;
+    use crate::ReadOptions;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn it_reports_reads_and_writes() {
+        let counters = Counters::default();
+        let mut bytes = vec![];
+        diamond()
+            .write_to(MeteredWriter::new(&mut bytes, &counters))
+            .unwrap();
+        assert_eq!(
+            counters.bytes_written.load(Ordering::Relaxed),
+            bytes.len() as u64
+        );
+
+        let options = ReadOptions {
+            metrics: Some(&counters),
+            ..ReadOptions::default()
+        };
+        CarV1::from_reader_with_options(&bytes[..], &options).unwrap();
+        assert_eq!(counters.blocks_read.load(Ordering::Relaxed), 4);
+        assert_eq!(
+            counters.bytes_read.load(Ordering::Relaxed),
+            bytes.len() as u64
+        );
+
+        CarV1::from_reader(MeteredReader::new(&bytes[..], &NoopMetrics)).unwrap();
+    }
+}
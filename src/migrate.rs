@@ -0,0 +1,336 @@
+//! Migrations that rewrite the CIDs of an archive: changing the multihash and upgrading CIDv0.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::{BufRead, Read, Seek, Write};
+use std::str::FromStr;
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Version;
+use libipld::multihash::{Code, MultihashDigest};
+use libipld::prelude::Codec;
+use libipld::{cid::Cid, Block, DefaultParams, Ipld, IpldCodec};
+
+use crate::copy;
+use crate::traversal;
+use crate::v1::CarV1;
+use crate::{CarError, CarResult, ContentArchive};
+
+/// The old to new CIDs of the blocks rewritten by a migration.
+pub type CidMapping = BTreeMap<Cid, Cid>;
+
+/// Copies the archive in `r` to `w` as a CARv1 with every CID recomputed using `code`.
+///
+/// Links inside the blocks are rewritten to the new CIDs. Returns the old to new CID mapping.
+pub fn rehash<R: Read + Seek, W: Write>(r: R, w: W, code: Code) -> CarResult<CidMapping> {
+    migrate(r, w, |car| rehash_car(car, code))
+}
+
+/// Recomputes every CID of `car` using `code`, see [`rehash`].
+///
+/// Links to blocks that are not in the archive cannot be translated and are kept as they are.
+/// CIDv0 is kept for sha2-256 and upgraded to CIDv1 otherwise.
+pub fn rehash_car(car: &CarV1, code: Code) -> CarResult<(CarV1, CidMapping)> {
+    migrate_car(
+        car,
+        |cid, data| {
+            let hash = code.digest(data);
+            Ok(match cid.version() {
+                Version::V0 if code == Code::Sha2_256 => Cid::new_v0(hash)?,
+                _ => Cid::new_v1(cid.codec(), hash),
+            })
+        },
+        |_| None,
+    )
+}
+
+/// Copies the archive in `r` to `w` as a CARv1 with every CIDv0 upgraded to CIDv1, see
+/// [`upgrade_car_to_v1`]. Returns the old to new CID mapping.
+pub fn upgrade_to_v1<R: Read + Seek, W: Write>(r: R, w: W) -> CarResult<CidMapping> {
+    migrate(r, w, upgrade_car_to_v1)
+}
+
+/// Upgrades the roots, blocks and links of `car` from CIDv0 to CIDv1, keeping their multihash
+/// function. Nodes whose links change are re-encoded and so get new hashes too.
+///
+/// Links to blocks that are not in the archive are upgraded without rehashing.
+pub fn upgrade_car_to_v1(car: &CarV1) -> CarResult<(CarV1, CidMapping)> {
+    migrate_car(
+        car,
+        |cid, data| {
+            let code = Code::try_from(cid.hash().code()).map_err(libipld::error::Error::from)?;
+            Ok(Cid::new_v1(cid.codec(), code.digest(data)))
+        },
+        |cid| (cid.version() == Version::V0).then(|| to_v1(cid)),
+    )
+}
+
+fn to_v1(cid: &Cid) -> Cid {
+    Cid::new_v1(cid.codec(), *cid.hash())
+}
+
+fn migrate<R, W, F>(r: R, w: W, migration: F) -> CarResult<CidMapping>
+where
+    R: Read + Seek,
+    W: Write,
+    F: FnOnce(&CarV1) -> CarResult<(CarV1, CidMapping)>,
+{
+    let car = ContentArchive::read_bytes(r)?.into_car_v1();
+    let (car, cids) = migration(&car)?;
+    car.write_to(w)?;
+    Ok(cids)
+}
+
+/// Writes `mapping` as CSV with an `old,new` header line and one row per CID.
+pub fn write_mapping_csv<W: Write>(mapping: &CidMapping, mut w: W) -> CarResult<()> {
+    writeln!(w, "old,new")?;
+    for (old, new) in mapping {
+        writeln!(w, "{},{}", old, new)?;
+    }
+    Ok(())
+}
+
+/// Reads a mapping written by [`write_mapping_csv`].
+pub fn read_mapping_csv<R: BufRead>(r: R) -> CarResult<CidMapping> {
+    let mut mapping = CidMapping::new();
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        if (i == 0 && line == "old,new") || line.is_empty() {
+            continue;
+        }
+        let invalid = || CarError::InvalidMapping(format!("line {}: {}", i + 1, line));
+        let (old, new) = line.split_once(',').ok_or_else(invalid)?;
+        let old = Cid::from_str(old).map_err(|_| invalid())?;
+        let new = Cid::from_str(new).map_err(|_| invalid())?;
+        mapping.insert(old, new);
+    }
+    Ok(mapping)
+}
+
+/// Encodes `mapping` as a dag-cbor list of `[old, new]` link pairs.
+pub fn encode_mapping_cbor(mapping: &CidMapping) -> CarResult<Vec<u8>> {
+    let pairs = mapping
+        .iter()
+        .map(|(old, new)| Ipld::List(vec![Ipld::Link(*old), Ipld::Link(*new)]))
+        .collect();
+    Ok(DagCborCodec.encode(&Ipld::List(pairs))?)
+}
+
+/// Decodes a mapping encoded by [`encode_mapping_cbor`].
+pub fn decode_mapping_cbor(bytes: &[u8]) -> CarResult<CidMapping> {
+    let invalid = || CarError::InvalidMapping("expected a list of [old, new] links".into());
+    match DagCborCodec.decode(bytes)? {
+        Ipld::List(pairs) => pairs
+            .into_iter()
+            .map(|pair| match pair {
+                Ipld::List(pair) => match pair.as_slice() {
+                    [Ipld::Link(old), Ipld::Link(new)] => Ok((*old, *new)),
+                    _ => Err(invalid()),
+                },
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// Rewrites every block of `car` bottom-up. `new_cid` computes the CID of a block from its old
+/// CID and new data, and `external` translates links to blocks that are not in the archive.
+/// The returned mapping only holds the CIDs that changed.
+pub(crate) fn migrate_car<N, E>(
+    car: &CarV1,
+    new_cid: N,
+    external: E,
+) -> CarResult<(CarV1, CidMapping)>
+where
+    N: Fn(&Cid, &[u8]) -> CarResult<Cid>,
+    E: Fn(&Cid) -> Option<Cid>,
+{
+    let blocks: HashMap<&Cid, &Block<DefaultParams>> = car
+        .blocks
+        .iter()
+        .map(|block| (block.cid(), block))
+        .collect();
+
+    // Children have to be translated before the parents linking to them.
+    let mut translated: HashMap<Cid, Block<DefaultParams>> = HashMap::new();
+    for block in &car.blocks {
+        let mut stack = vec![(*block.cid(), false)];
+        while let Some((cid, expanded)) = stack.pop() {
+            let block = match blocks.get(&cid) {
+                Some(block) if !translated.contains_key(&cid) => *block,
+                _ => continue,
+            };
+            let links = traversal::links(block)?;
+            if expanded {
+                let translate = |link: &Cid| match translated.get(link) {
+                    Some(block) => Some(*block.cid()),
+                    None => external(link),
+                };
+                let data = if links
+                    .iter()
+                    .any(|link| translate(link).is_some_and(|new| new != *link))
+                {
+                    relink(block, &translate)?
+                } else {
+                    block.data().to_vec()
+                };
+                let new_block = Block::new_unchecked(new_cid(&cid, &data)?, data);
+                translated.insert(cid, new_block);
+            } else {
+                stack.push((cid, true));
+                stack.extend(links.into_iter().map(|link| (link, false)));
+            }
+        }
+    }
+
+    let mut car = copy::transform(car, &|cid, _| {
+        translated
+            .get(cid)
+            .map(|block| (*block.cid(), block.data().to_vec()))
+    })?;
+    for root in car.header.roots.iter_mut() {
+        if !translated.contains_key(root) {
+            *root = external(root).unwrap_or(*root);
+        }
+    }
+    let cids = translated
+        .into_iter()
+        .map(|(old, block)| (old, *block.cid()))
+        .filter(|(old, new)| old != new)
+        .collect();
+    Ok((car, cids))
+}
+
+/// Re-encodes `block` with its links replaced by `translate`.
+fn relink<F>(block: &Block<DefaultParams>, translate: &F) -> CarResult<Vec<u8>>
+where
+    F: Fn(&Cid) -> Option<Cid>,
+{
+    let codec = IpldCodec::try_from(block.cid().codec()).map_err(libipld::error::Error::from)?;
+    let mut node: Ipld = codec.decode(block.data())?;
+    rewrite_links(&mut node, translate);
+    Ok(codec.encode(&node)?)
+}
+
+fn rewrite_links<F>(node: &mut Ipld, translate: &F)
+where
+    F: Fn(&Cid) -> Option<Cid>,
+{
+    match node {
+        Ipld::Link(cid) => {
+            if let Some(new) = translate(cid) {
+                *cid = new;
+            }
+        }
+        Ipld::List(items) => items
+            .iter_mut()
+            .for_each(|item| rewrite_links(item, translate)),
+        Ipld::Map(entries) => entries
+            .values_mut()
+            .for_each(|value| rewrite_links(value, translate)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "v1")]
+    use crate::test_utils::diamond;
+    use crate::test_utils::{cids, unixfs_file};
+    #[cfg(feature = "v1")]
+    use std::io::Cursor;
+    use std::str::FromStr;
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_rehashes_archives_and_rewrites_links() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let mut out = vec![];
+        let map = rehash(Cursor::new(bytes), &mut out, Code::Blake3_256).unwrap();
+        let rehashed = CarV1::from_reader(&out[..]).unwrap();
+
+        assert_eq!(map.len(), 4);
+        let old = cids(&car);
+        assert_eq!(
+            cids(&rehashed),
+            old.iter().map(|cid| map[cid]).collect::<Vec<_>>()
+        );
+        assert_eq!(rehashed.header.roots, vec![map[&car.header.roots[0]]]);
+        for block in &rehashed.blocks {
+            assert_eq!(block.cid().hash().code(), u64::from(Code::Blake3_256));
+            for link in traversal::links(block).unwrap() {
+                assert!(rehashed.blocks.iter().any(|block| *block.cid() == link));
+            }
+        }
+        // The leaf has no links, so only its CID changes.
+        assert_eq!(rehashed.blocks[0].data(), car.blocks[0].data());
+    }
+
+    #[test]
+    fn it_rehashes_dag_pb() {
+        let car = unixfs_file(&[b"hello ", b"world"]);
+        let (rehashed, map) = rehash_car(&car, Code::Sha2_512).unwrap();
+        let links = traversal::links(&rehashed.blocks[0]).unwrap();
+        assert_eq!(links, vec![map[&cids(&car)[1]], map[&cids(&car)[2]]]);
+
+        // Rehashing back gives the original archive.
+        let (original, _) = rehash_car(&rehashed, Code::Sha2_256).unwrap();
+        assert_eq!(original.blocks, car.blocks);
+    }
+
+    #[test]
+    fn it_serializes_mappings() {
+        let (_, mapping) = upgrade_car_to_v1(
+            &CarV1::from_reader(&include_bytes!("../tests/fixtures/carv1-basic.car")[..]).unwrap(),
+        )
+        .unwrap();
+
+        let mut csv = vec![];
+        write_mapping_csv(&mapping, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let (old, new) = mapping.iter().next().unwrap();
+        assert!(csv.starts_with(&format!("old,new\n{},{}\n", old, new)));
+        assert_eq!(read_mapping_csv(csv.as_bytes()).unwrap(), mapping);
+        assert!(matches!(
+            read_mapping_csv(&b"old,new\nnot,cids\n"[..]),
+            Err(CarError::InvalidMapping(_))
+        ));
+
+        let cbor = encode_mapping_cbor(&mapping).unwrap();
+        assert_eq!(decode_mapping_cbor(&cbor).unwrap(), mapping);
+    }
+
+    #[test]
+    fn it_upgrades_cid_v0_to_v1() {
+        let car =
+            CarV1::from_reader(&include_bytes!("../tests/fixtures/carv1-basic.car")[..]).unwrap();
+        let (upgraded, map) = upgrade_car_to_v1(&car).unwrap();
+
+        assert!(cids(&car).iter().all(|cid| cid.version() == Version::V0));
+        assert_eq!(map.len(), car.blocks.len());
+        assert_eq!(upgraded.header.roots, vec![map[&car.header.roots[0]]]);
+        for block in &upgraded.blocks {
+            assert_eq!(block.cid().version(), Version::V1);
+            assert!(block.cid().to_string().starts_with("bafy"));
+            for link in traversal::links(block).unwrap() {
+                assert_eq!(link.version(), Version::V1);
+            }
+        }
+        // Leaves without links keep their hash.
+        let leaf = &car.blocks[1];
+        assert_eq!(map[leaf.cid()], to_v1(leaf.cid()));
+
+        let external = Cid::from_str("QmfEoLyB5NndqeKieExd1rtJzTduQUPEV8TwAYcUiy3H5Z").unwrap();
+        let mut partial = car.clone();
+        partial.header.roots = vec![external];
+        partial.blocks.clear();
+        let (upgraded, map) = upgrade_car_to_v1(&partial).unwrap();
+        assert_eq!(upgraded.header.roots, vec![to_v1(&external)]);
+        assert!(map.is_empty());
+    }
+}
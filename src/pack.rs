@@ -0,0 +1,1040 @@
+//! Packing files and directories into UnixFS DAGs, incrementally, see [`Packer`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::multihash::Code;
+use libipld::pb::DagPbCodec;
+use libipld::raw::RawCodec;
+use libipld::{Block, DefaultParams, Ipld};
+
+use crate::chunker::{Chunker, FixedSize};
+use crate::hash::HasherRegistry;
+use crate::ignore::IgnoreRules;
+use crate::unixfs::{DataType, KeyProvider, UnixFsData};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{CarError, CarResult};
+
+/// The chunk size of `ipfs add`.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Most links in one file node, as in go-unixfs' balanced layout.
+const MAX_LINKS: usize = 174;
+
+/// What [`Packer`] does with symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Packs what a link points to as if it were where the link is. A link to a directory it
+    /// is in fails the pack rather than looping.
+    #[default]
+    Follow,
+    /// Packs links as UnixFS symlink nodes holding their targets, as `ipfs add` does.
+    Preserve,
+}
+
+/// Packs trees of files as UnixFS with raw leaves and a balanced layout, producing a delta
+/// archive per pack.
+///
+/// A file whose size and modification time are unchanged since an earlier pack is not read
+/// again, and blocks written by an earlier pack are left out of later ones, so repacking a
+/// tree after an edit only yields the blocks that changed.
+#[derive(Clone)]
+pub struct Packer {
+    chunker: Arc<dyn Chunker>,
+    files: HashMap<PathBuf, PackedFile>,
+    emitted: HashSet<Cid>,
+    cache: Option<ChunkCache>,
+    hash: u64,
+    hashers: HasherRegistry,
+    ignore_files: Vec<String>,
+    symlinks: SymlinkPolicy,
+    keys: Option<Arc<dyn KeyProvider + Send + Sync>>,
+}
+
+impl fmt::Debug for Packer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Packer")
+            .field("chunker", &self.chunker)
+            .field("files", &self.files.len())
+            .field("emitted", &self.emitted.len())
+            .field("cache", &self.cache)
+            .field("hash", &self.hash)
+            .field("hashers", &self.hashers)
+            .field("ignore_files", &self.ignore_files)
+            .field("symlinks", &self.symlinks)
+            .field("keys", &self.keys.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PackedFile {
+    len: u64,
+    modified: Option<SystemTime>,
+    link: Link,
+}
+
+/// The state of one pack as it walks down the trees packed. What it packs is only recorded in
+/// the [`Packer`] once the whole pack succeeds, so a failed pack leaves out nothing of the next.
+#[derive(Debug, Default)]
+struct Walk {
+    /// The blocks of the archive.
+    blocks: Vec<Block<DefaultParams>>,
+    /// The CIDs of blocks this pack emitted or found in the chunk cache.
+    emitted: HashSet<Cid>,
+    /// The files packed.
+    seen: HashSet<PathBuf>,
+    /// The files read, rather than found unchanged.
+    files: HashMap<PathBuf, PackedFile>,
+    /// The chunks read, for the chunk cache.
+    chunks: Vec<(PathBuf, SystemTime, u64, CachedChunk)>,
+    /// The canonical paths of the directories being packed, to stop at links back up to them.
+    directories: Vec<PathBuf>,
+    /// The ignore rules of the directories being packed, innermost last.
+    rules: Vec<(PathBuf, IgnoreRules)>,
+}
+
+impl Walk {
+    /// Whether the rules of the innermost directory with any matching `path` leave it out.
+    fn ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for (dir, rules) in self.rules.iter().rev() {
+            let Ok(relative) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let names: Vec<_> = relative.iter().filter_map(|name| name.to_str()).collect();
+            if let Some(ignored) = rules.matched(&names.join("/"), is_dir) {
+                return ignored;
+            }
+        }
+        false
+    }
+}
+
+/// The CID of a packed entity and its `Tsize`: the size of all blocks below it.
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    cid: Cid,
+    tsize: u64,
+}
+
+impl Default for Packer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Packer {
+    pub fn new() -> Self {
+        Self {
+            chunker: Arc::new(FixedSize::new(DEFAULT_CHUNK_SIZE)),
+            files: HashMap::new(),
+            emitted: HashSet::new(),
+            cache: None,
+            hash: Code::Sha2_256.into(),
+            hashers: HasherRegistry::new(),
+            ignore_files: vec![],
+            symlinks: SymlinkPolicy::default(),
+            keys: None,
+        }
+    }
+
+    /// Looks chunks up in `cache` before reading them, and records the chunks it reads.
+    ///
+    /// Chunks found in the cache are taken to be published already, so they are not read and
+    /// are left out of the archives, just like blocks of earlier packs. Only chunkers of a
+    /// [`Chunker::fixed_size`] use the cache.
+    pub fn with_cache(mut self, cache: ChunkCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&ChunkCache> {
+        self.cache.as_ref()
+    }
+
+    /// Hashes blocks with the multihash `code`, as implemented by `hashers`, instead of
+    /// sha2-256.
+    pub fn with_hash(mut self, code: u64, hashers: HasherRegistry) -> Self {
+        self.hash = code;
+        self.hashers = hashers;
+        self
+    }
+
+    /// Cuts files into chunks of `chunk_size` bytes, the default being [`DEFAULT_CHUNK_SIZE`].
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        self.with_chunker(FixedSize::new(chunk_size))
+    }
+
+    /// Cuts files into chunks with `chunker`, e.g. [`crate::chunker::Buzhash`] for chunks that
+    /// still deduplicate after bytes are inserted before them.
+    pub fn with_chunker<C: Chunker + 'static>(mut self, chunker: C) -> Self {
+        self.chunker = Arc::new(chunker);
+        self
+    }
+
+    /// Leaves out of directories the paths matched by the rules in their files named `name`,
+    /// e.g. `.gitignore`, as git does: rules apply to the tree below their file, and those of
+    /// deeper files win. Can be given several names, whose rules are read in turn.
+    pub fn with_ignore_file(mut self, name: impl Into<String>) -> Self {
+        self.ignore_files.push(name.into());
+        self
+    }
+
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Stores each chunk of a file as the raw block [`KeyProvider::encrypt`] returns for it, so
+    /// the archives are read back with [`crate::unixfs::UnixFsReader::with_keys`] or
+    /// [`crate::unixfs::extract_with_keys`]. Directories and the nodes above leaves are stored
+    /// in the clear.
+    pub fn with_keys<K: KeyProvider + Send + Sync + 'static>(mut self, keys: K) -> Self {
+        self.keys = Some(Arc::new(keys));
+        self
+    }
+
+    /// Packs the file or directory at `path` and returns an archive rooted at it holding the
+    /// blocks that no earlier pack returned.
+    pub fn pack(&mut self, path: &Path) -> CarResult<CarV1> {
+        self.pack_all(&[path])
+    }
+
+    /// Packs each of `paths` as [`Packer::pack`] does into one archive, with a root for each in
+    /// turn.
+    pub fn pack_all<P: AsRef<Path>>(&mut self, paths: &[P]) -> CarResult<CarV1> {
+        let mut walk = Walk::default();
+        let mut roots = vec![];
+        for path in paths {
+            roots.push(self.pack_path(path.as_ref(), &mut walk)?.cid);
+        }
+        let seen = walk.seen;
+        self.emitted.extend(walk.emitted);
+        self.files.extend(walk.files);
+        self.files.retain(|path, _| seen.contains(path));
+        if let Some(cache) = &mut self.cache {
+            for (path, modified, offset, chunk) in walk.chunks {
+                cache.insert(&path, modified, offset, chunk);
+            }
+            cache.retain(|cached| {
+                !paths.iter().any(|path| cached.starts_with(path)) || seen.contains(cached)
+            });
+        }
+        Ok(CarV1::new(CarHeaderV1 { roots }, walk.blocks))
+    }
+
+    fn pack_path(&mut self, path: &Path, walk: &mut Walk) -> CarResult<Link> {
+        let mut metadata = fs::symlink_metadata(path)?;
+        if metadata.is_symlink() {
+            match self.symlinks {
+                SymlinkPolicy::Follow => metadata = fs::metadata(path)?,
+                SymlinkPolicy::Preserve => return self.pack_symlink(path, walk),
+            }
+        }
+        if metadata.is_dir() {
+            return self.pack_directory(path, walk);
+        }
+        walk.seen.insert(path.to_path_buf());
+        let modified = metadata.modified().ok();
+        if let Some(packed) = walk.files.get(path).or_else(|| self.files.get(path)) {
+            if packed.len == metadata.len() && packed.modified == modified && modified.is_some() {
+                return Ok(packed.link);
+            }
+        }
+        let link = self.pack_file(path, metadata.len(), modified, walk)?;
+        walk.files.insert(
+            path.to_path_buf(),
+            PackedFile {
+                len: metadata.len(),
+                modified,
+                link,
+            },
+        );
+        Ok(link)
+    }
+
+    fn pack_directory(&mut self, path: &Path, walk: &mut Walk) -> CarResult<Link> {
+        let canonical = fs::canonicalize(path)?;
+        if walk.directories.contains(&canonical) {
+            return Err(CarError::InvalidUnixFs(format!(
+                "{} links to a directory it is in",
+                path.display()
+            )));
+        }
+        let mut rules = IgnoreRules::new();
+        for name in &self.ignore_files {
+            match fs::read_to_string(path.join(name)) {
+                Ok(text) => text.lines().for_each(|line| rules.add(line)),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        walk.directories.push(canonical);
+        walk.rules.push((path.to_path_buf(), rules));
+
+        let mut entries = vec![];
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                CarError::InvalidUnixFs(format!("non UTF-8 file name {:?}", name))
+            })?;
+            entries.push((name, entry.path()));
+        }
+        entries.sort();
+
+        let mut links = vec![];
+        for (name, path) in entries {
+            let metadata = match self.symlinks {
+                SymlinkPolicy::Follow => fs::metadata(&path),
+                SymlinkPolicy::Preserve => fs::symlink_metadata(&path),
+            };
+            if walk.ignored(&path, metadata.is_ok_and(|metadata| metadata.is_dir())) {
+                continue;
+            }
+            let link = self.pack_path(&path, walk)?;
+            links.push((Some(name), link));
+        }
+        walk.directories.pop();
+        walk.rules.pop();
+        let data = UnixFsData::new(DataType::Directory);
+        self.node(data, links, walk)
+    }
+
+    fn pack_file(
+        &mut self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+        walk: &mut Walk,
+    ) -> CarResult<Link> {
+        let mut leaves = match self.cached_leaves(path, len, modified, walk) {
+            Some(leaves) => leaves,
+            None => self.read_leaves(path, modified, walk)?,
+        };
+        while leaves.len() > 1 {
+            let mut parents = vec![];
+            for children in leaves.chunks(MAX_LINKS) {
+                let data = UnixFsData {
+                    filesize: Some(children.iter().map(|(size, _)| size).sum()),
+                    blocksizes: children.iter().map(|(size, _)| *size).collect(),
+                    ..UnixFsData::new(DataType::File)
+                };
+                let links = children.iter().map(|(_, link)| (None, *link)).collect();
+                let size = data.filesize.unwrap_or_default();
+                parents.push((size, self.node(data, links, walk)?));
+            }
+            leaves = parents;
+        }
+        Ok(leaves[0].1)
+    }
+
+    /// Encodes the link at `path` as a symlink node holding its target.
+    fn pack_symlink(&mut self, path: &Path, walk: &mut Walk) -> CarResult<Link> {
+        let target = fs::read_link(path)?;
+        let target = target.to_str().ok_or_else(|| {
+            CarError::InvalidUnixFs(format!("non UTF-8 symlink target {:?}", target))
+        })?;
+        let data = UnixFsData {
+            data: target.as_bytes().to_vec(),
+            ..UnixFsData::new(DataType::Symlink)
+        };
+        self.node(data, vec![], walk)
+    }
+
+    fn read_leaves(
+        &mut self,
+        path: &Path,
+        modified: Option<SystemTime>,
+        walk: &mut Walk,
+    ) -> CarResult<Vec<(u64, Link)>> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut leaves = vec![];
+        let mut offset = 0;
+        let cached = self.chunker.fixed_size().is_some();
+        loop {
+            let chunk = self.chunker.next_chunk(&mut r)?;
+            if chunk.is_empty() && !leaves.is_empty() {
+                break;
+            }
+            let size = chunk.len() as u64;
+            let chunk = match &self.keys {
+                Some(keys) => keys.encrypt(&chunk)?.unwrap_or(chunk),
+                None => chunk,
+            };
+            let block = self.block(RawCodec.into(), chunk)?;
+            let link = self.emit(block, walk);
+            if let (Some(_), Some(modified), true) = (&self.cache, modified, cached) {
+                let chunk = CachedChunk {
+                    cid: link.cid,
+                    len: size,
+                };
+                walk.chunks
+                    .push((path.to_path_buf(), modified, offset, chunk));
+            }
+            leaves.push((size, link));
+            offset += size;
+        }
+        Ok(leaves)
+    }
+
+    /// The leaves of the file at `path` if the cache has every chunk of it, in the same layout
+    /// [`Packer::read_leaves`] would produce.
+    fn cached_leaves(
+        &self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+        walk: &mut Walk,
+    ) -> Option<Vec<(u64, Link)>> {
+        let (cache, modified) = (self.cache.as_ref()?, modified?);
+        let chunk_size = self.chunker.fixed_size()? as u64;
+        let mut leaves = vec![];
+        let mut offset = 0;
+        loop {
+            let size = (len - offset).min(chunk_size);
+            let chunk = cache.get(path, modified, offset)?;
+            if chunk.len != size || chunk.cid.hash().code() != self.hash {
+                return None;
+            }
+            leaves.push((
+                size,
+                Link {
+                    cid: chunk.cid,
+                    tsize: size,
+                },
+            ));
+            offset += size;
+            if offset >= len {
+                break;
+            }
+        }
+        walk.emitted.extend(leaves.iter().map(|(_, link)| link.cid));
+        Some(leaves)
+    }
+
+    /// Encodes a dag-pb node with `links` and emits it.
+    fn node(
+        &mut self,
+        data: UnixFsData,
+        links: Vec<(Option<String>, Link)>,
+        walk: &mut Walk,
+    ) -> CarResult<Link> {
+        let tsize: u64 = links.iter().map(|(_, link)| link.tsize).sum();
+        let links = links
+            .into_iter()
+            .map(|(name, link)| {
+                let mut map = BTreeMap::new();
+                map.insert("Hash".to_string(), Ipld::Link(link.cid));
+                if let Some(name) = name {
+                    map.insert("Name".to_string(), Ipld::String(name));
+                }
+                map.insert("Tsize".to_string(), Ipld::Integer(link.tsize.into()));
+                Ipld::Map(map)
+            })
+            .collect();
+        let mut node = BTreeMap::new();
+        node.insert("Data".to_string(), Ipld::Bytes(data.encode()));
+        node.insert("Links".to_string(), Ipld::List(links));
+        let block = self.block(DagPbCodec.into(), DagPbCodec.encode(&Ipld::Map(node))?)?;
+        let link = self.emit(block, walk);
+        Ok(Link {
+            tsize: link.tsize + tsize,
+            ..link
+        })
+    }
+
+    fn block(&self, codec: u64, data: Vec<u8>) -> CarResult<Block<DefaultParams>> {
+        let cid = self.hashers.cid(codec, self.hash, &data)?;
+        Ok(Block::new_unchecked(cid, data))
+    }
+
+    /// Adds `block` to the archive unless an earlier pack or this one already did.
+    fn emit(&self, block: Block<DefaultParams>, walk: &mut Walk) -> Link {
+        let link = Link {
+            cid: *block.cid(),
+            tsize: block.data().len() as u64,
+        };
+        if !self.emitted.contains(&link.cid) && walk.emitted.insert(link.cid) {
+            walk.blocks.push(block);
+        }
+        link
+    }
+}
+
+/// Packs the file at `path` into an archive of its UnixFS DAG, with chunks of
+/// [`DEFAULT_CHUNK_SIZE`] as raw leaves below dag-pb nodes, as `ipfs add --car --raw-leaves`
+/// does.
+///
+/// Fails with [`CarError::InvalidUnixFs`] if `path` is a directory; see [`Packer::pack`] for
+/// those.
+pub fn pack_file(path: &Path) -> CarResult<CarV1> {
+    if fs::metadata(path)?.is_dir() {
+        return Err(CarError::InvalidUnixFs(format!(
+            "{} is a directory, not a file",
+            path.display()
+        )));
+    }
+    Packer::new().pack(path)
+}
+
+/// Packs the tree below the directory at `path` into an archive rooted at its UnixFS
+/// directory node, with files laid out as [`pack_file`] lays them out and directories as plain
+/// directory nodes, without HAMT sharding, as `ipfs add --car --raw-leaves -r` does for
+/// directories of moderate size.
+///
+/// Fails with [`CarError::InvalidUnixFs`] if `path` is not a directory, or has an entry whose
+/// name is not UTF-8.
+pub fn pack_directory(path: &Path) -> CarResult<CarV1> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err(CarError::InvalidUnixFs(format!(
+            "{} is not a directory",
+            path.display()
+        )));
+    }
+    Packer::new().pack(path)
+}
+
+/// A chunk of a file recorded in a [`ChunkCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedChunk {
+    pub cid: Cid,
+    pub len: u64,
+}
+
+/// The CIDs of file chunks, by path, modification time and offset, so that packing a file that
+/// has not changed does not need to read it.
+///
+/// A cache is only valid for one chunk size; chunks of another length are treated as misses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkCache {
+    files: BTreeMap<PathBuf, CachedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CachedFile {
+    /// Nanoseconds since the Unix epoch.
+    modified: u64,
+    chunks: BTreeMap<u64, CachedChunk>,
+}
+
+impl ChunkCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a cache saved with [`ChunkCache::save`], or an empty one if `path` does not exist.
+    pub fn load(path: &Path) -> CarResult<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the cache to `path`, replacing it atomically.
+    pub fn save(&self, path: &Path) -> CarResult<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, self.encode()?)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path, modified: SystemTime, offset: u64) -> Option<CachedChunk> {
+        let file = self.files.get(path)?;
+        if file.modified != nanos(modified)? {
+            return None;
+        }
+        file.chunks.get(&offset).copied()
+    }
+
+    /// Records a chunk, forgetting the chunks recorded for an older version of the file.
+    pub fn insert(&mut self, path: &Path, modified: SystemTime, offset: u64, chunk: CachedChunk) {
+        let modified = match nanos(modified) {
+            Some(modified) => modified,
+            None => return,
+        };
+        let file = self
+            .files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| CachedFile {
+                modified,
+                chunks: BTreeMap::new(),
+            });
+        if file.modified != modified {
+            file.modified = modified;
+            file.chunks.clear();
+        }
+        file.chunks.insert(offset, chunk);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Keeps only the files whose path satisfies `keep`.
+    pub fn retain<F: FnMut(&Path) -> bool>(&mut self, mut keep: F) {
+        self.files.retain(|path, _| keep(path));
+    }
+
+    /// The number of chunks recorded.
+    pub fn len(&self) -> usize {
+        self.files.values().map(|file| file.chunks.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Encodes the cache as a dag-cbor list of `{path, modified, chunks: [[offset, cid, len]]}`.
+    pub fn encode(&self) -> CarResult<Vec<u8>> {
+        let files = self
+            .files
+            .iter()
+            .filter_map(|(path, file)| {
+                let chunks = file
+                    .chunks
+                    .iter()
+                    .map(|(offset, chunk)| {
+                        Ipld::List(vec![
+                            Ipld::Integer((*offset).into()),
+                            Ipld::Link(chunk.cid),
+                            Ipld::Integer(chunk.len.into()),
+                        ])
+                    })
+                    .collect();
+                let mut map = BTreeMap::new();
+                map.insert("path".to_string(), Ipld::String(path.to_str()?.to_string()));
+                map.insert("modified".to_string(), Ipld::Integer(file.modified.into()));
+                map.insert("chunks".to_string(), Ipld::List(chunks));
+                Some(Ipld::Map(map))
+            })
+            .collect();
+        Ok(DagCborCodec.encode(&Ipld::List(files))?)
+    }
+
+    /// Decodes a cache encoded by [`ChunkCache::encode`].
+    pub fn decode(bytes: &[u8]) -> CarResult<Self> {
+        let invalid = || CarError::InvalidChunkCache("expected a list of cached files".into());
+        let integer = |ipld: &Ipld| match ipld {
+            Ipld::Integer(value) => u64::try_from(*value).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        };
+        let files = match DagCborCodec.decode(bytes)? {
+            Ipld::List(files) => files,
+            _ => return Err(invalid()),
+        };
+        let mut cache = Self::new();
+        for file in &files {
+            let path = match file.get("path") {
+                Ok(Ipld::String(path)) => PathBuf::from(path),
+                _ => return Err(invalid()),
+            };
+            let modified = integer(file.get("modified").map_err(|_| invalid())?)?;
+            let mut chunks = BTreeMap::new();
+            match file.get("chunks") {
+                Ok(Ipld::List(list)) => {
+                    for chunk in list {
+                        match chunk {
+                            Ipld::List(chunk) => match chunk.as_slice() {
+                                [offset, Ipld::Link(cid), len] => {
+                                    let len = integer(len)?;
+                                    chunks.insert(integer(offset)?, CachedChunk { cid: *cid, len });
+                                }
+                                _ => return Err(invalid()),
+                            },
+                            _ => return Err(invalid()),
+                        }
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+            cache.files.insert(path, CachedFile { modified, chunks });
+        }
+        Ok(cache)
+    }
+}
+
+fn nanos(time: SystemTime) -> Option<u64> {
+    u64::try_from(time.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Buzhash;
+    use crate::test_utils::{cids, raw, temp_dir};
+    use crate::traversal::traverse;
+    use crate::unixfs::{UnixFsNode, UnixFsReader};
+    use std::io::Read;
+    use std::ops::ControlFlow;
+
+    #[test]
+    fn it_packs_directories_incrementally() {
+        let dir = temp_dir("pack");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        fs::write(dir.join("sub/b.bin"), vec![7u8; 1000]).unwrap();
+
+        let mut packer = Packer::new().with_chunk_size(100);
+        let first = packer.pack(&dir).unwrap();
+        let root = first.header.roots[0];
+        let node = UnixFsNode::from_block(first.blocks.iter().last().unwrap()).unwrap();
+        assert_eq!(node.data.data_type, DataType::Directory);
+        let names: Vec<_> = node.links.iter().map(|link| link.name.clone()).collect();
+        assert_eq!(names, vec![Some("a.txt".into()), Some("sub".into())]);
+        // Ten identical chunks share one leaf.
+        assert_eq!(first.blocks.len(), 5);
+
+        let mut visited = 0;
+        traverse(
+            &first,
+            &[root],
+            &mut |_: usize, _: &Cid, _: &Block<DefaultParams>| {
+                visited += 1;
+                ControlFlow::Continue(crate::traversal::Visit::Descend)
+            },
+        )
+        .unwrap();
+        assert_eq!(visited, 5);
+
+        assert_eq!(packer.pack(&dir).unwrap().blocks.len(), 0);
+
+        fs::write(dir.join("a.txt"), b"hello, world").unwrap();
+        let delta = packer.pack(&dir).unwrap();
+        assert_ne!(delta.header.roots[0], root);
+        // The new leaf of `a.txt` and the new root; `sub` is unchanged.
+        assert_eq!(delta.blocks.len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_several_paths_leaving_out_ignored_ones() {
+        let dir = temp_dir("pack-ignore");
+        fs::create_dir_all(dir.join("a/target")).unwrap();
+        fs::create_dir_all(dir.join("a/src")).unwrap();
+        fs::write(dir.join("a/.gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(dir.join("a/target/out"), b"out").unwrap();
+        fs::write(dir.join("a/src/.gitignore"), "!keep.log\n").unwrap();
+        fs::write(dir.join("a/src/debug.log"), b"debug").unwrap();
+        fs::write(dir.join("a/src/keep.log"), b"keep").unwrap();
+        fs::write(dir.join("b.txt"), b"b").unwrap();
+
+        let names = |car: &CarV1, cid: &Cid| -> Vec<String> {
+            let block = car.blocks.iter().find(|block| block.cid() == cid).unwrap();
+            let node = UnixFsNode::from_block(block).unwrap();
+            node.links
+                .into_iter()
+                .filter_map(|link| link.name)
+                .collect()
+        };
+        let car = Packer::new()
+            .with_ignore_file(".gitignore")
+            .pack_all(&[dir.join("a"), dir.join("b.txt")])
+            .unwrap();
+        assert_eq!(car.header.roots.len(), 2);
+        assert_eq!(car.header.roots[1], *raw(b"b").cid());
+        assert_eq!(names(&car, &car.header.roots[0]), [".gitignore", "src"]);
+        let a = UnixFsNode::from_block(
+            car.blocks
+                .iter()
+                .find(|block| *block.cid() == car.header.roots[0])
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(names(&car, &a.links[1].cid), [".gitignore", "keep.log"]);
+
+        let all = Packer::new().pack(&dir.join("a")).unwrap();
+        assert_eq!(names(&all, &all.header.roots[0]).len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn it_follows_or_preserves_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("pack-symlinks");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), b"data").unwrap();
+        symlink("sub/file", dir.join("link")).unwrap();
+
+        let followed = Packer::new().pack(&dir).unwrap();
+        let preserved = Packer::new()
+            .with_symlinks(SymlinkPolicy::Preserve)
+            .pack(&dir)
+            .unwrap();
+        let link = |car: &CarV1| {
+            let root = car.blocks.iter().last().unwrap();
+            UnixFsNode::from_block(root).unwrap().links[0].cid
+        };
+        assert_eq!(link(&followed), *raw(b"data").cid());
+        let target = link(&preserved);
+        let block = preserved.blocks.iter().find(|block| *block.cid() == target);
+        let node = UnixFsNode::from_block(block.unwrap()).unwrap();
+        assert_eq!(node.data.data_type, DataType::Symlink);
+        assert_eq!(node.data.data, b"sub/file");
+
+        let extracted = dir.join("extracted");
+        crate::unixfs::extract(&preserved, &extracted).unwrap();
+        assert_eq!(fs::read(extracted.join("link")).unwrap(), b"data");
+
+        // A link back up would otherwise be followed forever.
+        symlink("..", dir.join("sub/up")).unwrap();
+        assert!(matches!(
+            Packer::new().pack(&dir.join("sub")),
+            Err(CarError::InvalidUnixFs(_))
+        ));
+        assert!(Packer::new()
+            .with_symlinks(SymlinkPolicy::Preserve)
+            .pack(&dir.join("sub"))
+            .is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn it_records_nothing_of_a_failed_pack() {
+        let dir = temp_dir("pack-failed");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), vec![7u8; 250]).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("sub/up")).unwrap();
+
+        let mut packer = Packer::new()
+            .with_chunk_size(100)
+            .with_cache(ChunkCache::new());
+        // `a.txt` is read before the link back up fails the pack.
+        assert!(packer.pack(&dir).is_err());
+        assert!(packer.cache().unwrap().is_empty());
+
+        fs::remove_file(dir.join("sub/up")).unwrap();
+        let car = packer.pack(&dir).unwrap();
+        let fresh = Packer::new().with_chunk_size(100).pack(&dir).unwrap();
+        assert_eq!(cids(&car), cids(&fresh));
+        assert_eq!(packer.cache().unwrap().len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_repacks_unchanged_files_from_the_chunk_cache() {
+        let dir = temp_dir("pack-cache");
+        let file = dir.join("big.bin");
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&file, &data).unwrap();
+
+        let mut packer = Packer::new()
+            .with_chunk_size(100)
+            .with_cache(ChunkCache::new());
+        let first = packer.pack(&file).unwrap();
+        assert_eq!(first.blocks.len(), 11);
+        let cache = packer.cache().unwrap().clone();
+        assert_eq!(cache.len(), 10);
+        let saved = dir.join("cache");
+        cache.save(&saved).unwrap();
+        assert_eq!(ChunkCache::load(&saved).unwrap(), cache);
+        assert!(ChunkCache::load(&dir.join("missing")).unwrap().is_empty());
+
+        // A fresh packer finds every chunk in the cache, so it only builds the file node.
+        let mut packer = Packer::new()
+            .with_chunk_size(100)
+            .with_cache(ChunkCache::load(&saved).unwrap());
+        fs::remove_file(&saved).unwrap();
+        let second = packer.pack(&file).unwrap();
+        assert_eq!(second.header.roots, first.header.roots);
+        assert_eq!(second.blocks.len(), 1);
+
+        // Chunks of another size miss, so the file is read again.
+        let mut packer = Packer::new().with_chunk_size(50).with_cache(cache);
+        assert_eq!(packer.pack(&file).unwrap().blocks.len(), 21);
+        assert_eq!(packer.cache().unwrap().len(), 20);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_files_as_read_back() {
+        let dir = temp_dir("pack-one");
+        let file = dir.join("big.bin");
+        let data: Vec<u8> = (0..DEFAULT_CHUNK_SIZE as u32 * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&file, &data).unwrap();
+        let car = pack_file(&file).unwrap();
+        // Three leaves and the file node.
+        assert_eq!(car.blocks.len(), 4);
+        let root = car.header.roots[0];
+        assert_eq!(root.codec(), 0x70);
+        let mut read = vec![];
+        UnixFsReader::new(&car, &root)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(matches!(pack_file(&dir), Err(CarError::InvalidUnixFs(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_directory_trees_under_one_root() {
+        let dir = temp_dir("pack-tree");
+        fs::create_dir_all(dir.join("site/assets")).unwrap();
+        fs::write(dir.join("site/index.html"), b"<h1>hi</h1>").unwrap();
+        fs::write(dir.join("site/assets/style.css"), b"h1 {}").unwrap();
+        let car = pack_directory(&dir.join("site")).unwrap();
+        assert_eq!(car.header.roots.len(), 1);
+        assert!(car.missing_links().unwrap().is_empty());
+
+        let root = car.header.roots[0];
+        let block = car
+            .blocks
+            .iter()
+            .find(|block| *block.cid() == root)
+            .unwrap();
+        let node = UnixFsNode::from_block(block).unwrap();
+        assert_eq!(node.data.data_type, DataType::Directory);
+        let names: Vec<_> = node.links.iter().map(|link| link.name.clone()).collect();
+        assert_eq!(
+            names,
+            vec![Some("assets".into()), Some("index.html".into())]
+        );
+        assert!(matches!(
+            pack_directory(&dir.join("site/index.html")),
+            Err(CarError::InvalidUnixFs(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_with_content_defined_chunks() {
+        let dir = temp_dir("pack-buzhash");
+        let file = dir.join("file");
+        let data: Vec<u8> = (0..20_000u64)
+            .map(|i| (i.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8)
+            .collect();
+        let buzhash = Buzhash {
+            min_size: 256,
+            max_size: 2048,
+            mask_bits: 9,
+            ..Buzhash::default()
+        };
+        let pack = |content: &[u8]| {
+            fs::write(&file, content).unwrap();
+            Packer::new()
+                .with_chunker(buzhash)
+                .with_cache(ChunkCache::new())
+                .pack(&file)
+                .unwrap()
+        };
+        let car = pack(&data);
+        let mut read = vec![];
+        UnixFsReader::new(&car, &car.header.roots[0])
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        // Leaves are shared with a copy that has bytes inserted at its start.
+        let shifted = pack(&[b"inserted".as_slice(), &data].concat());
+        let leaves = |car: &CarV1| -> HashSet<Cid> {
+            car.blocks
+                .iter()
+                .map(|block| *block.cid())
+                .filter(|cid| cid.codec() == 0x55)
+                .collect()
+        };
+        let (before, after) = (leaves(&car), leaves(&shifted));
+        assert!(before.intersection(&after).count() >= before.len() - 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_single_chunk_files_as_raw_blocks() {
+        let dir = temp_dir("pack-file");
+        fs::write(dir.join("empty"), b"").unwrap();
+        let car = Packer::new().pack(&dir.join("empty")).unwrap();
+        assert_eq!(car.blocks.len(), 1);
+        assert_eq!(car.header.roots[0].codec(), 0x55);
+        assert!(car.blocks[0].data().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_packs_with_registered_hashes() {
+        let dir = temp_dir("pack-hash");
+        fs::write(dir.join("file"), vec![7; 100]).unwrap();
+        let mut hashers = HasherRegistry::new();
+        hashers.register(0x3333, |data: &[u8]| vec![data.len() as u8; 4]);
+        let car = Packer::new()
+            .with_chunk_size(40)
+            .with_hash(0x3333, hashers.clone())
+            .pack(&dir.join("file"))
+            .unwrap();
+        assert!(car
+            .blocks
+            .iter()
+            .all(|block| block.cid().hash().code() == 0x3333));
+
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert!(CarV1::from_reader(&bytes[..]).is_err());
+        let options = crate::ReadOptions {
+            hashes: crate::HashPolicy {
+                hashers: Some(&hashers),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let read = CarV1::from_reader_with_options(&bytes[..], &options).unwrap();
+        assert_eq!(read.blocks, car.blocks);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Xors the chunks it encrypts with its key, and every raw block it is asked to decrypt.
+    struct Xor(u8);
+
+    impl KeyProvider for Xor {
+        fn decrypt(&self, cid: &Cid, data: &[u8]) -> CarResult<Option<Vec<u8>>> {
+            Ok((cid.codec() == 0x55).then(|| self.encrypt(data).unwrap().unwrap()))
+        }
+
+        fn encrypt(&self, data: &[u8]) -> CarResult<Option<Vec<u8>>> {
+            Ok(Some(data.iter().map(|byte| byte ^ self.0).collect()))
+        }
+    }
+
+    #[test]
+    fn it_packs_encrypted_leaves() {
+        let dir = temp_dir("pack-keys");
+        fs::create_dir_all(dir.join("tree")).unwrap();
+        let content: Vec<u8> = (0..300).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(dir.join("tree/file"), &content).unwrap();
+        let car = Packer::new()
+            .with_chunk_size(100)
+            .with_keys(Xor(0x5a))
+            .pack(&dir.join("tree"))
+            .unwrap();
+        let leaves: Vec<_> = car
+            .blocks
+            .iter()
+            .filter(|block| block.cid().codec() == 0x55)
+            .collect();
+        assert_eq!(leaves.len(), 3);
+        assert!(leaves
+            .iter()
+            .all(|leaf| !content.windows(100).any(|window| window == leaf.data())));
+
+        let out = dir.join("out");
+        crate::unixfs::extract_with_keys(&car, &out, &Xor(0x5a)).unwrap();
+        assert_eq!(fs::read(out.join("file")).unwrap(), content);
+        crate::unixfs::extract(&car, &dir.join("clear")).unwrap();
+        assert_ne!(fs::read(dir.join("clear/file")).unwrap(), content);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
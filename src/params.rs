@@ -0,0 +1,74 @@
+//! Block size limits, see [`LargeParams`] and [`encode`].
+//!
+//! libipld's [`DefaultParams`](libipld::DefaultParams) caps blocks at 1 MiB, which real archives
+//! do not always keep to. Reading never enforces a limit; encoding does, failing with
+//! [`CarError::BlockTooLarge`] rather than libipld's error, which does not say which block.
+
+use libipld::cid::Cid;
+use libipld::codec::{Codec, Encode};
+use libipld::multihash::MultihashDigest;
+use libipld::store::StoreParams;
+use libipld::Block;
+
+use crate::{CarError, CarResult};
+
+/// Store parameters like [`DefaultParams`](libipld::DefaultParams), but for blocks of up to
+/// 32 MiB, the largest section go-car reads by default. Archives of them are
+/// `CarV1<LargeParams>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargeParams;
+
+impl StoreParams for LargeParams {
+    type Hashes = libipld::multihash::Code;
+    type Codecs = libipld::IpldCodec;
+    const MAX_BLOCK_SIZE: usize = 32 << 20;
+}
+
+/// Encodes `value` with `codec` and hashes it with `hash`, as [`Block::encode`] does, but
+/// fails with [`CarError::BlockTooLarge`] if the block is larger than `S` allows.
+pub fn encode<S, C, T>(codec: C, hash: S::Hashes, value: &T) -> CarResult<Block<S>>
+where
+    S: StoreParams,
+    C: Codec + Into<S::Codecs>,
+    T: Encode<C> + ?Sized,
+{
+    let data = codec.encode(value)?;
+    let cid = Cid::new_v1(codec.into(), hash.digest(&data));
+    check_size::<S>(&cid, data.len())?;
+    Ok(Block::new_unchecked(cid, data))
+}
+
+/// Fails with [`CarError::BlockTooLarge`] if `size` bytes of the block `cid` are more than `S`
+/// allows.
+pub fn check_size<S: StoreParams>(cid: &Cid, size: usize) -> CarResult<()> {
+    if size > S::MAX_BLOCK_SIZE {
+        return Err(CarError::BlockTooLarge {
+            cid: *cid,
+            size,
+            max: S::MAX_BLOCK_SIZE,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::Code;
+    use libipld::raw::RawCodec;
+    use libipld::{DefaultParams, Ipld};
+
+    #[test]
+    fn it_names_blocks_too_large_for_their_params() {
+        let data = Ipld::Bytes(vec![0; 2 << 20]);
+        match encode::<DefaultParams, _, _>(RawCodec, Code::Sha2_256, &data) {
+            Err(CarError::BlockTooLarge { cid, size, max }) => {
+                assert_eq!(cid.codec(), 0x55);
+                assert_eq!((size, max), (2 << 20, 1 << 20));
+            }
+            other => panic!("expected BlockTooLarge, got {:?}", other),
+        }
+        let block = encode::<LargeParams, _, _>(RawCodec, Code::Sha2_256, &data).unwrap();
+        assert_eq!(block.data().len(), 2 << 20);
+    }
+}
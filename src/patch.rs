@@ -0,0 +1,350 @@
+//! Fixing single blocks of archive files without rewriting more than needed, see
+//! [`patch_block`].
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use libipld::cid::Cid;
+use libipld::Block;
+
+use crate::block::{parse_v2_header, CarBlock, CarBlockReader, CarHeaderV2, PRAGMA};
+use crate::hash::HasherRegistry;
+use crate::index::{read_v2_index, CarV2Index, IndexBucket, IndexLayout};
+use crate::migrate::{migrate_car, CidMapping};
+#[cfg(feature = "v2")]
+use crate::v2;
+use crate::{CarError, CarResult, ContentArchive, HEADER_LENGTH};
+
+/// How [`patch_block`] changed the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Patch {
+    /// The new data had the length of the old and was written over it.
+    InPlace,
+    /// The sections of the block were replaced, moving the rest of the archive and, for a
+    /// CARv2, updating its header and index.
+    Spliced,
+    /// The new data hashed to another CID, so the block and the blocks linking to it, directly
+    /// or not, were rewritten along with the whole archive; their CIDs changed as mapped.
+    Relinked(CidMapping),
+}
+
+/// Where a section of the block being patched is, from the start of the file.
+struct Located {
+    start: u64,
+    data_start: u64,
+    end: u64,
+}
+
+/// Replaces the data of the block `cid` in the archive at `path` with `data`, every copy of it
+/// if it is stored more than once.
+///
+/// When `data` hashes to `cid`, only its sections are touched: it is written in place if it has
+/// the length of the old data, and otherwise the archive is copied around the new sections.
+/// When it does not, the block gets the CID of `data` with the same codec and multihash, and
+/// every block linking to it is re-encoded in turn, up to the roots; that needs the whole
+/// archive in memory. Anything but an in-place write goes through a temporary file next to
+/// `path`, renamed over it once complete.
+///
+/// Fails with [`CarError::MissingBlock`] if the archive does not store `cid`, and with
+/// [`CarError::UnverifiableHash`] if its hash has no implementation.
+pub fn patch_block<P: AsRef<Path>>(path: P, cid: &Cid, data: &[u8]) -> CarResult<Patch> {
+    let path = path.as_ref();
+    let hash = HasherRegistry::new()
+        .digest(cid.hash().code(), data)?
+        .ok_or(CarError::UnverifiableHash(*cid))?;
+    if hash != *cid.hash() {
+        return relink(path, cid, data).map(Patch::Relinked);
+    }
+
+    let sections = locate(path, cid)?;
+    if sections.is_empty() {
+        return Err(CarError::MissingBlock(*cid));
+    }
+    if sections
+        .iter()
+        .all(|section| section.end - section.data_start == data.len() as u64)
+    {
+        let mut file = fs::OpenOptions::new().write(true).open(path)?;
+        for section in &sections {
+            file.seek(SeekFrom::Start(section.data_start))?;
+            file.write_all(data)?;
+        }
+        file.sync_data()?;
+        return Ok(Patch::InPlace);
+    }
+    replace_file(path, |out| splice(path, &sections, cid, data, out))?;
+    Ok(Patch::Spliced)
+}
+
+fn locate(path: &Path, cid: &Cid) -> CarResult<Vec<Located>> {
+    let mut reader = CarBlockReader::new(BufReader::new(File::open(path)?))?;
+    let mut sections = vec![];
+    loop {
+        let start = reader.position();
+        match reader.next().transpose()? {
+            Some(block) if block.cid == *cid => {
+                let end = reader.position();
+                sections.push(Located {
+                    start,
+                    data_start: end - block.data.len() as u64,
+                    end,
+                });
+            }
+            Some(_) => {}
+            None => return Ok(sections),
+        }
+    }
+}
+
+/// Writes the archive at `path` to `out` with `sections` replaced by the section of `cid` and
+/// `data`.
+fn splice(
+    path: &Path,
+    sections: &[Located],
+    cid: &Cid,
+    data: &[u8],
+    out: &mut BufWriter<File>,
+) -> CarResult<()> {
+    let mut section = vec![];
+    CarBlock::new(*cid, data.to_vec()).write_to(&mut section)?;
+    // How much longer the archive got before an offset.
+    let shift = |offset: u64| -> i64 {
+        sections
+            .iter()
+            .filter(|located| located.start < offset)
+            .map(|located| section.len() as i64 - (located.end - located.start) as i64)
+            .sum()
+    };
+
+    let mut input = BufReader::new(File::open(path)?);
+    let mut pragma = [0; PRAGMA.len()];
+    let header = match input.read_exact(&mut pragma) {
+        Ok(()) if pragma == PRAGMA => {
+            let mut header = [0; HEADER_LENGTH];
+            input.read_exact(&mut header)?;
+            Some(parse_v2_header(header)?)
+        }
+        _ => None,
+    };
+    input.seek(SeekFrom::Start(0))?;
+
+    let mut position = 0;
+    for located in sections {
+        io::copy(&mut (&mut input).take(located.start - position), out)?;
+        out.write_all(&section)?;
+        input.seek(SeekFrom::Start(located.end))?;
+        position = located.end;
+    }
+    let header = match header {
+        Some(header) => header,
+        None => {
+            io::copy(&mut input, out)?;
+            return Ok(());
+        }
+    };
+
+    let total = shift(u64::MAX);
+    let moved = |offset: u64| {
+        offset
+            .checked_add_signed(total)
+            .ok_or(CarError::InvalidFormat)
+    };
+    let mut patched = header.clone();
+    patched.data_size = moved(header.data_size)?;
+    if header.has_index() {
+        io::copy(&mut (&mut input).take(header.index_offset - position), out)?;
+        let mut layout = read_v2_index(&mut input, header.index_offset)?
+            .ok_or(CarError::InvalidFormat)?
+            .into_layout();
+        match &mut layout {
+            IndexLayout::Sorted(buckets) => shift_entries(buckets, &header, &shift),
+            IndexLayout::MultihashSorted(codes) => {
+                codes
+                    .values_mut()
+                    .for_each(|buckets| shift_entries(buckets, &header, &shift));
+            }
+            IndexLayout::Unknown { codec, .. } => {
+                return Err(CarError::UnknownIndexCodec(*codec));
+            }
+        }
+        CarV2Index::from(layout).write_to(&mut *out)?;
+        patched.index_offset = moved(header.index_offset)?;
+    } else {
+        io::copy(&mut input, out)?;
+    }
+    out.seek(SeekFrom::Start(PRAGMA.len() as u64))?;
+    out.write_all(&patched.to_bytes())?;
+    Ok(())
+}
+
+/// Moves the payload offsets of index entries by the growth of the sections before them.
+fn shift_entries<F>(buckets: &mut [IndexBucket], header: &CarHeaderV2, shift: &F)
+where
+    F: Fn(u64) -> i64,
+{
+    for entry in buckets.iter_mut().flat_map(|bucket| &mut bucket.entries) {
+        let moved = shift(header.data_offset + entry.offset);
+        entry.offset = entry.offset.saturating_add_signed(moved);
+    }
+}
+
+/// Rewrites the archive at `path` with the block `cid` replaced by `data` under a new CID, and
+/// the blocks linking to it relinked.
+fn relink(path: &Path, cid: &Cid, data: &[u8]) -> CarResult<CidMapping> {
+    let archive = ContentArchive::read_bytes(BufReader::new(File::open(path)?))?;
+    #[cfg(feature = "v2")]
+    let indexed = matches!(&archive, ContentArchive::V2(car) if car.index.is_some());
+    #[cfg(feature = "v2")]
+    let v2 = matches!(&archive, ContentArchive::V2(_));
+    let mut car = archive.into_car_v1();
+    let original: HashMap<Cid, Vec<u8>> = car
+        .blocks
+        .iter()
+        .map(|block| (*block.cid(), block.data().to_vec()))
+        .collect();
+    if !original.contains_key(cid) {
+        return Err(CarError::MissingBlock(*cid));
+    }
+    for block in car.blocks.iter_mut().filter(|block| block.cid() == cid) {
+        *block = Block::new_unchecked(*cid, data.to_vec());
+    }
+
+    let registry = HasherRegistry::new();
+    let (car, mapping) = migrate_car(
+        &car,
+        |old, data| {
+            if old != cid && original.get(old).is_some_and(|old_data| old_data == data) {
+                return Ok(*old);
+            }
+            let hash = registry
+                .digest(old.hash().code(), data)?
+                .ok_or(CarError::UnverifiableHash(*old))?;
+            Ok(Cid::new(old.version(), old.codec(), hash)?)
+        },
+        |_| None,
+    )?;
+    replace_file(path, |out| {
+        #[cfg(feature = "v2")]
+        if v2 {
+            v2::CarV2::from_car_v1(car, indexed)?.write_to(out)?;
+            return Ok(());
+        }
+        car.write_to(out)
+    })?;
+    Ok(mapping)
+}
+
+/// Writes a temporary file next to `path` with `write` and renames it over `path`.
+fn replace_file<F>(path: &Path, write: F) -> CarResult<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> CarResult<()>,
+{
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".patch");
+    let mut out = BufWriter::new(File::create(&temp)?);
+    let written =
+        write(&mut out).and_then(|()| Ok(out.into_inner().map_err(io::Error::from)?.sync_all()?));
+    match written {
+        Ok(()) => Ok(fs::rename(&temp, path)?),
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "v1", feature = "v2")))]
+mod tests {
+    use super::*;
+    #[cfg(feature = "v1")]
+    use crate::test_utils::raw;
+    use crate::test_utils::{cids, diamond, temp_dir};
+    use crate::v1::CarV1;
+
+    /// The diamond with the data of its leaf replaced by `data`, under the same CID.
+    fn forged(data: &[u8]) -> CarV1 {
+        let mut car = diamond();
+        car.blocks[0] = Block::new_unchecked(*car.blocks[0].cid(), data.to_vec());
+        car
+    }
+
+    #[cfg(feature = "v1")]
+    fn write(car: &CarV1, path: &Path) {
+        car.write_to(File::create(path).unwrap()).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_patches_blocks_in_place_or_by_splicing() {
+        let dir = temp_dir("patch");
+        let path = dir.join("a.car");
+        let car = diamond();
+        let leaf = cids(&car)[0];
+        let mut canonical = vec![];
+        car.write_to(&mut canonical).unwrap();
+
+        write(&forged(b"lemf"), &path);
+        assert_eq!(patch_block(&path, &leaf, b"leaf").unwrap(), Patch::InPlace);
+        assert_eq!(fs::read(&path).unwrap(), canonical);
+
+        let mut stored_twice = forged(b"leafy");
+        stored_twice.blocks.push(stored_twice.blocks[0].clone());
+        write(&stored_twice, &path);
+        assert_eq!(patch_block(&path, &leaf, b"leaf").unwrap(), Patch::Spliced);
+        let mut expected = car.clone();
+        expected.blocks.push(car.blocks[0].clone());
+        let mut expected_bytes = vec![];
+        expected.write_to(&mut expected_bytes).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), expected_bytes);
+
+        assert!(matches!(
+            patch_block(&path, raw(b"absent").cid(), b"absent"),
+            Err(CarError::MissingBlock(_))
+        ));
+        assert!(!dir.join("a.car.patch").exists());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_splices_car_v2_and_shifts_its_index() {
+        let dir = temp_dir("patch-v2");
+        let path = dir.join("a.car");
+        let leaf = cids(&diamond())[0];
+        v2::CarV2::from_car_v1(forged(b"a much longer leaf"), true)
+            .unwrap()
+            .write_to(File::create(&path).unwrap())
+            .unwrap();
+        assert_eq!(patch_block(&path, &leaf, b"leaf").unwrap(), Patch::Spliced);
+
+        let mut expected = vec![];
+        v2::CarV2::from_car_v1(diamond(), true)
+            .unwrap()
+            .write_to(io::Cursor::new(&mut expected))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_relinks_blocks_whose_cid_changes() {
+        let dir = temp_dir("patch-relink");
+        let path = dir.join("a.car");
+        let car = diamond();
+        write(&car, &path);
+        let leaf = cids(&car)[0];
+
+        let mapping = match patch_block(&path, &leaf, b"new leaf").unwrap() {
+            Patch::Relinked(mapping) => mapping,
+            other => panic!("Expected Relinked, got {:?}", other),
+        };
+        assert_eq!(mapping.len(), 4);
+        assert_eq!(mapping[&leaf], *raw(b"new leaf").cid());
+
+        let patched = CarV1::from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(patched.header.roots, vec![mapping[&car.header.roots[0]]]);
+        assert_eq!(patched.blocks[0], raw(b"new leaf"));
+        assert_eq!(patched.missing_links().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,84 @@
+//! The types and traits most programs need, for `use rust_racecar::prelude::*`.
+//!
+//! Everything here is stable: it is only renamed, removed or given a new signature in a release
+//! that semver marks as breaking. The CID and block types of libipld are re-exported, so
+//! programs name the versions the crate was built with. Framing-level items that may change in
+//! any release are in [`crate::raw`].
+
+pub use crate::index::{CarV2Index, IndexKind};
+pub use crate::{CarError, CarResult, Deadline};
+pub use cid::Cid;
+
+#[cfg(feature = "ipld")]
+pub use crate::builder::CarBuilder;
+#[cfg(feature = "ipld")]
+pub use crate::gateway::BlockFetcher;
+#[cfg(feature = "ipld")]
+pub use crate::metrics::Metrics;
+#[cfg(feature = "ipld")]
+pub use crate::traversal::{Visit, Visitor};
+#[cfg(feature = "ipld")]
+pub use crate::v1::{CarHeaderV1, CarV1, CarV1Reader, CarWriter};
+#[cfg(feature = "ipld")]
+pub use crate::{CarHeader, ContentArchive, HashPolicy, ReadAnomaly, ReadOptions, ReadReport};
+#[cfg(feature = "ipld")]
+pub use libipld::{Block, DefaultParams};
+
+#[cfg(feature = "v2")]
+pub use crate::blockstore::{CarBlockStore, CarV2Store};
+#[cfg(feature = "v2")]
+pub use crate::v2::{
+    read_region, region_len, write_region, CarHeaderV2, CarV2, CarV2Reader, CarV2WriteOptions,
+    CarV2Writer,
+};
+
+#[cfg(all(test, feature = "v2"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::raw;
+    use std::io::Cursor;
+
+    /// Writes, reads and serves an archive naming nothing outside the prelude.
+    #[test]
+    fn it_covers_a_round_trip() {
+        let block: Block<DefaultParams> = raw(b"prelude");
+        let mut builder = CarBuilder::new();
+        builder.add_root(*block.cid());
+        builder.add_block(block.clone());
+        let mut bytes = Cursor::new(vec![]);
+        builder
+            .build_v2(Some(IndexKind::MultihashSorted))
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let bytes = bytes.into_inner();
+        let car = ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap();
+        let roots: &[Cid] = &car.car_v1().header.roots;
+        assert_eq!(roots, [*block.cid()]);
+        let store = CarV2Store::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(store.get(block.cid()).unwrap(), Some(block));
+    }
+
+    /// Keeps data in the padding of an archive, replaces it in place and reads the archive past it.
+    #[test]
+    fn it_covers_application_regions() {
+        let block: Block<DefaultParams> = raw(b"region");
+        let mut builder = CarBuilder::new();
+        builder.add_root(*block.cid());
+        builder.add_block(block.clone());
+        let mut bytes = Cursor::new(vec![]);
+        builder
+            .build_v2(None)
+            .unwrap()
+            .write_to_with_region(&mut bytes, b"meta", region_len(16))
+            .unwrap();
+
+        let mut bytes = bytes.into_inner();
+        assert_eq!(read_region(&bytes[..]).unwrap(), Some(b"meta".to_vec()));
+        write_region(Cursor::new(&mut bytes), &[7; 16]).unwrap();
+        assert_eq!(read_region(&bytes[..]).unwrap(), Some(vec![7; 16]));
+        let car = ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap();
+        assert_eq!(car.car_v1().blocks, [block]);
+    }
+}
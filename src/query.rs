@@ -0,0 +1,318 @@
+//! A small path query language over the nodes of an archive, for finding data in unfamiliar
+//! archives without writing traversal code, see [`Query`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use libipld::codec::Codec;
+use libipld::json::DagJsonCodec;
+use libipld::{cid::Cid, Block, DefaultParams, Ipld, IpldCodec};
+
+use crate::v1::CarV1;
+use crate::{json, CarError, CarResult};
+
+/// A path of `/`-separated steps from a root, optionally followed by a comparison of the nodes
+/// it reaches with a literal, e.g. `**/name == 'config.json'` or `entries/*/size != 0`.
+///
+/// A step is a map key or list index, `*` for any child or `**` for any number of levels,
+/// none included. Links are followed as if the node they point to were inline. Literals are
+/// strings in single or double quotes, without escapes, integers, `true`, `false` and `null`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    steps: Vec<Step>,
+    predicate: Option<(Comparison, Ipld)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Any,
+    Descendants,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+}
+
+/// A node a [`Query`] selected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    /// The block holding the node.
+    pub cid: Cid,
+    /// The keys and indexes from the root to the node, `/`-separated, links left out.
+    pub path: String,
+    pub node: Ipld,
+}
+
+/// Parses `query` and runs it from `root`, see [`Query::select`].
+pub fn select(car: &CarV1, root: &Cid, query: &str) -> CarResult<Vec<Match>> {
+    query.parse::<Query>()?.select(car, root)
+}
+
+impl Query {
+    /// The nodes below `root` the query selects, in depth-first order.
+    ///
+    /// Each block is searched once at each step of the query, along the first path to reach
+    /// it, so nodes below blocks linked more than once are selected once. Fails with
+    /// [`CarError::MissingBlock`] when the search follows a link outside of the archive.
+    pub fn select(&self, car: &CarV1, root: &Cid) -> CarResult<Vec<Match>> {
+        let mut search = Search {
+            query: self,
+            blocks: car
+                .blocks
+                .iter()
+                .map(|block| (*block.cid(), block))
+                .collect(),
+            searched: HashSet::new(),
+            path: vec![],
+            matches: vec![],
+        };
+        search.link(root, 0)?;
+        Ok(search.matches)
+    }
+
+    fn matches(&self, node: &Ipld) -> bool {
+        match &self.predicate {
+            None => true,
+            Some((Comparison::Equal, literal)) => node == literal,
+            Some((Comparison::NotEqual, literal)) => node != literal,
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = CarError;
+
+    fn from_str(query: &str) -> CarResult<Self> {
+        let invalid = |reason: &str| CarError::InvalidQuery(format!("{}: {}", reason, query));
+        let operator = ["==", "!="]
+            .iter()
+            .filter_map(|operator| query.find(operator))
+            .min();
+        let (path, predicate) = match operator {
+            Some(at) => {
+                let comparison = match &query[at..at + 2] {
+                    "==" => Comparison::Equal,
+                    _ => Comparison::NotEqual,
+                };
+                let literal =
+                    parse_literal(query[at + 2..].trim()).ok_or_else(|| invalid("bad literal"))?;
+                (&query[..at], Some((comparison, literal)))
+            }
+            None => (query, None),
+        };
+        let path = path.trim().trim_start_matches('/');
+        let steps = match path {
+            "" => vec![],
+            _ => path
+                .split('/')
+                .map(|step| match step.trim() {
+                    "" => Err(invalid("empty step")),
+                    "*" => Ok(Step::Any),
+                    "**" => Ok(Step::Descendants),
+                    key => Ok(Step::Key(key.to_string())),
+                })
+                .collect::<CarResult<_>>()?,
+        };
+        Ok(Self { steps, predicate })
+    }
+}
+
+impl Match {
+    /// Renders the match as a JSON object of its `cid`, `path` and `node`, the node as
+    /// dag-json.
+    pub fn to_json(&self) -> CarResult<String> {
+        let node = DagJsonCodec.encode(&self.node)?;
+        Ok(format!(
+            "{{\"cid\":\"{}\",\"path\":{},\"node\":{}}}",
+            self.cid,
+            json::string(&self.path),
+            String::from_utf8_lossy(&node)
+        ))
+    }
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} /{} {:?}", self.cid, self.path, self.node)
+    }
+}
+
+fn parse_literal(literal: &str) -> Option<Ipld> {
+    let quoted = |quote| literal.strip_prefix(quote)?.strip_suffix(quote);
+    if literal.len() >= 2 {
+        if let Some(string) = quoted('\'').or_else(|| quoted('"')) {
+            return Some(Ipld::String(string.to_string()));
+        }
+    }
+    match literal {
+        "true" => Some(Ipld::Bool(true)),
+        "false" => Some(Ipld::Bool(false)),
+        "null" => Some(Ipld::Null),
+        _ => literal.parse().ok().map(Ipld::Integer),
+    }
+}
+
+struct Search<'a> {
+    query: &'a Query,
+    blocks: HashMap<Cid, &'a Block<DefaultParams>>,
+    /// Blocks already searched from a step.
+    searched: HashSet<(Cid, usize)>,
+    path: Vec<String>,
+    matches: Vec<Match>,
+}
+
+impl Search<'_> {
+    fn link(&mut self, cid: &Cid, step: usize) -> CarResult<()> {
+        if !self.searched.insert((*cid, step)) {
+            return Ok(());
+        }
+        let block = *self.blocks.get(cid).ok_or(CarError::MissingBlock(*cid))?;
+        let node = block.decode::<IpldCodec, Ipld>()?;
+        self.node(cid, &node, step)
+    }
+
+    /// Searches `node`, in the block `cid`, from the `step`th step of the query.
+    fn node(&mut self, cid: &Cid, node: &Ipld, step: usize) -> CarResult<()> {
+        if let Ipld::Link(link) = node {
+            return self.link(link, step);
+        }
+        match self.query.steps.get(step) {
+            None => {
+                if self.query.matches(node) {
+                    self.matches.push(Match {
+                        cid: *cid,
+                        path: self.path.join("/"),
+                        node: node.clone(),
+                    });
+                }
+                Ok(())
+            }
+            Some(Step::Key(key)) => {
+                let child = match node {
+                    Ipld::Map(map) => map.get(key),
+                    Ipld::List(list) => key.parse().ok().and_then(|i: usize| list.get(i)),
+                    _ => None,
+                };
+                match child {
+                    Some(child) => self.child(cid, key.clone(), child, step + 1),
+                    None => Ok(()),
+                }
+            }
+            Some(Step::Any) => self.children(cid, node, step + 1),
+            Some(Step::Descendants) => {
+                self.node(cid, node, step + 1)?;
+                self.children(cid, node, step)
+            }
+        }
+    }
+
+    fn children(&mut self, cid: &Cid, node: &Ipld, step: usize) -> CarResult<()> {
+        match node {
+            Ipld::Map(map) => map
+                .iter()
+                .try_for_each(|(key, child)| self.child(cid, key.clone(), child, step)),
+            Ipld::List(list) => list
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, child)| self.child(cid, i.to_string(), child, step)),
+            _ => Ok(()),
+        }
+    }
+
+    fn child(&mut self, cid: &Cid, segment: String, child: &Ipld, step: usize) -> CarResult<()> {
+        self.path.push(segment);
+        let result = self.node(cid, child, step);
+        self.path.pop();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cbor, diamond};
+    use crate::v1::CarHeaderV1;
+    use libipld::ipld;
+
+    fn archive() -> CarV1 {
+        let nested = cbor(&ipld!({ "name": "config.json" }));
+        let data = cbor(&ipld!({ "name": "data.bin", "parts": [*nested.cid()] }));
+        let config = cbor(&ipld!({ "name": "config.json", "size": 10 }));
+        let root = cbor(&ipld!({
+            "files": [*config.cid(), *data.cid()],
+            "meta": { "name": "root" },
+        }));
+        CarV1::new(
+            CarHeaderV1 {
+                roots: vec![*root.cid()],
+            },
+            vec![root, config, data, nested],
+        )
+    }
+
+    fn paths(car: &CarV1, query: &str) -> Vec<String> {
+        select(car, &car.header.roots[0], query)
+            .unwrap()
+            .into_iter()
+            .map(|found| found.path)
+            .collect()
+    }
+
+    #[test]
+    fn it_selects_nodes_by_path_and_value() {
+        let car = archive();
+        let found = select(&car, &car.header.roots[0], "**/name == 'config.json'").unwrap();
+        assert_eq!(
+            found.iter().map(|found| &found.path).collect::<Vec<_>>(),
+            vec!["files/0/name", "files/1/parts/0/name"]
+        );
+        assert_eq!(found[0].cid, *car.blocks[1].cid());
+        assert_eq!(found[1].cid, *car.blocks[3].cid());
+        assert_eq!(found[0].node, Ipld::String("config.json".to_string()));
+        assert_eq!(
+            found[0].to_json().unwrap(),
+            format!(
+                "{{\"cid\":\"{}\",\"path\":\"files/0/name\",\"node\":\"config.json\"}}",
+                found[0].cid
+            )
+        );
+
+        assert_eq!(
+            paths(&car, "files/*/name"),
+            vec!["files/0/name", "files/1/name"]
+        );
+        assert_eq!(paths(&car, "/meta/name != \"x\""), vec!["meta/name"]);
+        assert_eq!(paths(&car, "files/0/size == 10"), vec!["files/0/size"]);
+        assert_eq!(paths(&car, "files/0/size == 11"), Vec::<String>::new());
+        assert_eq!(paths(&car, "files/2"), Vec::<String>::new());
+        assert_eq!(paths(&car, ""), vec![""]);
+    }
+
+    #[test]
+    fn it_searches_shared_blocks_once_and_rejects_malformed_queries() {
+        // Both sides of the diamond link to the leaf, which is found along the left side only.
+        let car = diamond();
+        assert_eq!(paths(&car, "** == null"), Vec::<String>::new());
+        let leaves = select(&car, &car.header.roots[0], "*/*").unwrap();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].path, "0/l");
+        assert_eq!(leaves[0].node, Ipld::Bytes(b"leaf".to_vec()));
+
+        let mut missing = car.clone();
+        missing.blocks.remove(0);
+        assert!(matches!(
+            select(&missing, &car.header.roots[0], "**"),
+            Err(CarError::MissingBlock(_))
+        ));
+        for query in ["a//b", "a ==", "a == 'b", "a != x"] {
+            assert!(matches!(
+                query.parse::<Query>(),
+                Err(CarError::InvalidQuery(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! Archives at the level of their bytes: section framing, header fields and index layout,
+//! without decoding or verifying blocks.
+//!
+//! Unlike [`crate::prelude`], nothing here is stable; these items follow the format closely and
+//! may change in any release as the readers and writers built on them do. This is the only path
+//! to them: the modules that define them are private to the crate.
+
+pub use crate::block::{
+    parse_v2_header, quick_count, write_car_blocks, BlockCount, BlockLocation, CarBlock,
+    CarBlockReader, CarHeaderV2, PRAGMA,
+};
+pub use crate::index::{
+    read_v2_index, IndexBucket, IndexEntry, IndexLayout, INDEX_SORTED, MULTIHASH_INDEX_SORTED,
+};
+pub use crate::layout::{layout, write_layout, Part, Region};
+
+#[cfg(feature = "ipld")]
+pub use crate::block::{
+    read_car_v1_data, read_car_v1_data_with_options, write_car_v1_block, write_car_v1_data,
+    CarV1Decoder,
+};
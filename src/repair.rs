@@ -0,0 +1,193 @@
+//! Recovering what is left of damaged archives, see [`repair`] and [`truncate_to_valid`].
+
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::block::CarBlockReader;
+use crate::v1::{section_len, CarHeaderV1, CarV1};
+use crate::{json, CarError, CarResult, ContentArchive, HashPolicy, ReadAnomaly, ReadOptions};
+
+/// What was recovered from an archive, as a CARv1 to write in its place with
+/// [`Recovery::write_to`].
+#[derive(Debug)]
+pub struct Recovery {
+    pub car: CarV1,
+    /// The irregularities that were read past and are left out of the cleaned copy.
+    pub anomalies: Vec<ReadAnomaly>,
+    /// The error [`truncate_to_valid`] stopped at, `None` if it read to the end.
+    pub stopped: Option<CarError>,
+    /// How many bytes shorter the cleaned copy is than the input.
+    pub dropped_bytes: u64,
+}
+
+impl Recovery {
+    fn new(car: CarV1, anomalies: Vec<ReadAnomaly>, stopped: Option<CarError>, input: u64) -> Self {
+        let mut length: u64 = car.blocks.iter().map(section_len).sum();
+        let mut header = vec![];
+        // A header of CIDs always encodes.
+        car.header.write_to(&mut header).unwrap();
+        length += header.len() as u64;
+        Self {
+            car,
+            anomalies,
+            stopped,
+            dropped_bytes: input.saturating_sub(length),
+        }
+    }
+
+    /// Writes the cleaned copy.
+    pub fn write_to<W: Write>(&self, w: W) -> CarResult<()> {
+        self.car.write_to(w)
+    }
+
+    /// Renders what was recovered as a JSON object of the number of `blocks`, `dropped_bytes`,
+    /// the `anomalies` and where it `stopped`, as messages.
+    pub fn to_json(&self) -> String {
+        let anomalies: Vec<String> = self
+            .anomalies
+            .iter()
+            .map(|anomaly| json::string(&anomaly.to_string()))
+            .collect();
+        let stopped = match &self.stopped {
+            Some(err) => json::string(&err.to_string()),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"blocks\":{},\"dropped_bytes\":{},\"anomalies\":[{}],\"stopped\":{}}}",
+            self.car.blocks.len(),
+            self.dropped_bytes,
+            anomalies.join(","),
+            stopped
+        )
+    }
+}
+
+/// Reads the archive in `r` leniently, past non-minimal varints, padding between sections and a
+/// truncated last section, and keeps every block once, in the order it first appeared. A CARv2
+/// is recovered as its payload. Blocks that fail to verify still fail the read; see
+/// [`truncate_to_valid`] for archives corrupt past their framing.
+pub fn repair<R: Read + Seek>(mut r: R) -> CarResult<Recovery> {
+    let input = r.seek(SeekFrom::End(0))?;
+    let options = ReadOptions {
+        lenient: true,
+        ..ReadOptions::default()
+    };
+    let (archive, report) = ContentArchive::read_bytes_with_report(r, &options)?;
+    let car = archive.into_car_v1();
+    let mut seen = HashSet::new();
+    let blocks = car
+        .blocks
+        .into_iter()
+        .filter(|block| seen.insert(*block.cid()))
+        .collect();
+    let car = CarV1::new(car.header, blocks);
+    Ok(Recovery::new(car, report.anomalies, None, input))
+}
+
+/// Keeps the sections of the archive in `r`, from where it is, up to the first that is cut
+/// short, malformed or fails to verify as [`HashPolicy::default`] does, which is kept in
+/// [`Recovery::stopped`] along with everything after it. Only a malformed header fails. A CARv2
+/// is recovered as its payload.
+pub fn truncate_to_valid<R: Read + Seek>(mut r: R) -> CarResult<Recovery> {
+    let here = r.stream_position()?;
+    let input = r.seek(SeekFrom::End(0))? - here;
+    r.seek(SeekFrom::Start(here))?;
+    let mut reader = CarBlockReader::new(r)?;
+    let mut blocks = vec![];
+    let stopped = loop {
+        let (block, location) = match reader.next_block_with_location() {
+            Ok(Some(read)) => read,
+            Ok(None) => break None,
+            Err(err) => break Some(err),
+        };
+        let cid = block.cid;
+        match HashPolicy::default().block(cid, block.data, location.offset) {
+            Ok((block, _)) => blocks.push(block),
+            Err(err) => break Some(err),
+        }
+    };
+    let header = CarHeaderV1 {
+        roots: reader.roots().to_vec(),
+    };
+    Ok(Recovery::new(
+        CarV1::new(header, blocks),
+        vec![],
+        stopped,
+        input,
+    ))
+}
+
+#[cfg(all(test, feature = "v1"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond};
+    use std::io::Cursor;
+
+    #[test]
+    fn it_repairs_irregular_framing() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let clean = bytes.len() as u64;
+        let mut section = vec![];
+        crate::block::write_car_v1_block(&mut section, &car.blocks[0]).unwrap();
+        bytes.extend(&section);
+        bytes.extend([0, 0]);
+        bytes.extend(&section[..3]);
+
+        let recovery = repair(Cursor::new(&bytes)).unwrap();
+        assert_eq!(cids(&recovery.car), cids(&car));
+        assert_eq!(recovery.dropped_bytes, bytes.len() as u64 - clean);
+        assert!(matches!(
+            recovery.anomalies[..],
+            [
+                ReadAnomaly::DuplicateBlock { .. },
+                ReadAnomaly::Padding { length: 2, .. },
+                ReadAnomaly::TrailingBytes { length: 3, .. },
+            ]
+        ));
+        let mut written = vec![];
+        recovery.write_to(&mut written).unwrap();
+        assert_eq!(written, bytes[..clean as usize]);
+        assert!(recovery.stopped.is_none());
+    }
+
+    #[test]
+    fn it_truncates_to_the_last_valid_section() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let recovery = truncate_to_valid(Cursor::new(&bytes)).unwrap();
+        assert_eq!((recovery.car.blocks.len(), recovery.dropped_bytes), (4, 0));
+        assert!(recovery.stopped.is_none());
+
+        // The third block is corrupt: it and the fourth are dropped.
+        let [_, _, root, last] = [0, 1, 2, 3].map(|i| {
+            let mut section = vec![];
+            crate::block::write_car_v1_block(&mut section, &car.blocks[i]).unwrap();
+            section
+        });
+        let at = bytes.len() - last.len() - 1;
+        bytes[at] ^= 1;
+        let recovery = truncate_to_valid(Cursor::new(&bytes)).unwrap();
+        assert_eq!(cids(&recovery.car), cids(&car)[..2]);
+        assert_eq!(recovery.dropped_bytes, (root.len() + last.len()) as u64);
+        assert!(matches!(
+            recovery.stopped,
+            Some(CarError::HashMismatch { offset, .. })
+                if offset == (bytes.len() - last.len() - root.len()) as u64
+        ));
+        let mut written = vec![];
+        recovery.write_to(&mut written).unwrap();
+        assert_eq!(written, bytes[..bytes.len() - root.len() - last.len()]);
+
+        // A cut off section is dropped as well.
+        let recovery =
+            truncate_to_valid(Cursor::new(&bytes[..bytes.len() - last.len() - 3])).unwrap();
+        assert_eq!(recovery.car.blocks.len(), 2);
+        assert!(matches!(recovery.stopped, Some(CarError::Io(_))));
+        let json = recovery.to_json();
+        assert!(json.starts_with("{\"blocks\":2,\"dropped_bytes\":"));
+        assert!(json.contains("\"anomalies\":[],\"stopped\":\""));
+    }
+}
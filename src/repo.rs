@@ -0,0 +1,521 @@
+//! Storing archives in a directory under their own CIDs, see [`CarRepo`].
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use libipld::cid::Cid;
+use libipld::multihash::{Code, Hasher, MultihashDigest, Sha2_256};
+use libipld::{Block, DefaultParams};
+
+use crate::gateway::BlockFetcher;
+use crate::metrics::{MeteredWriter, Metrics};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{CarError, CarResult, ContentArchive, HashPolicy, ReadOptions};
+
+/// The `car` multicodec, used for the CIDs of whole archives.
+pub const CAR_CODEC: u64 = 0x0202;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The CID of an archive: the sha2-256 of its bytes with the `car` codec.
+pub fn car_cid(bytes: &[u8]) -> Cid {
+    Cid::new_v1(CAR_CODEC, Code::Sha2_256.digest(bytes))
+}
+
+/// Where an archive with CID `cid` is stored in `dir`.
+pub fn car_path(dir: &Path, cid: &Cid) -> PathBuf {
+    dir.join(format!("{}.car", cid))
+}
+
+/// Writes `car` to `dir` as `<cid>.car`, see [`car_cid`].
+///
+/// The archive is written to a temporary file in `dir` first and renamed once complete, so
+/// readers never see a partial archive.
+pub fn write_named(car: &CarV1, dir: &Path) -> CarResult<Cid> {
+    write_named_with(dir, |w| car.write_to(w))
+}
+
+/// Like [`write_named`], for archives written by `write`.
+pub fn write_named_with<F>(dir: &Path, write: F) -> CarResult<Cid>
+where
+    F: FnOnce(&mut dyn Write) -> CarResult<()>,
+{
+    let temp = dir.join(format!(
+        ".tmp-{}-{}.car",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = (|| {
+        let mut w = HashingWriter {
+            inner: BufWriter::new(File::create(&temp)?),
+            hasher: Sha2_256::default(),
+        };
+        write(&mut w)?;
+        let file = w.inner.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        let hash = Code::Sha2_256
+            .wrap(w.hasher.finalize())
+            .map_err(libipld::cid::Error::from)?;
+        let cid = Cid::new_v1(CAR_CODEC, hash);
+        fs::rename(&temp, car_path(dir, &cid))?;
+        Ok(cid)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// A directory of archives stored as `<cid>.car`, see [`write_named`].
+///
+/// With a fallback, blocks missing from every archive are fetched from it and published as a
+/// delta archive, so the repository works as a read-through cache.
+#[derive(Clone)]
+pub struct CarRepo {
+    pub dir: PathBuf,
+    fallback: Option<Arc<dyn BlockFetcher + Send + Sync>>,
+    quota: Option<Quota>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+/// A limit on the total size of a [`CarRepo`], enforced by [`CarRepo::evict`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: u64,
+    /// Archives holding one of these roots, or with one of these CIDs, are never evicted.
+    pub pins: Vec<Cid>,
+}
+
+impl fmt::Debug for CarRepo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CarRepo")
+            .field("dir", &self.dir)
+            .field("fallback", &self.fallback.is_some())
+            .field("quota", &self.quota)
+            .field("metrics", &self.metrics.is_some())
+            .finish()
+    }
+}
+
+impl CarRepo {
+    /// Opens the repository in `dir`, creating the directory if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> CarResult<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            fallback: None,
+            quota: None,
+            metrics: None,
+        })
+    }
+
+    /// Reports the bytes read and written, and whether block lookups were served locally.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Enforces `quota` after every publish. Archives are evicted least recently used first,
+    /// going by their modification time, which [`CarRepo::open`] and block lookups update.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    pub fn with_fallback<F: BlockFetcher + Send + Sync + 'static>(mut self, fetcher: F) -> Self {
+        self.fallback = Some(Arc::new(fetcher));
+        self
+    }
+
+    pub fn get_block(&self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        Ok(self.get_blocks(&[*cid])?.pop().flatten())
+    }
+
+    /// Looks `cids` up in the stored archives, in the order given. Blocks that are missing are
+    /// fetched from the fallback, if any, and published together as one delta archive rooted
+    /// at them. A fetch that fails stops the others, but what was fetched before it is still
+    /// published.
+    pub fn get_blocks(&self, cids: &[Cid]) -> CarResult<Vec<Option<Block<DefaultParams>>>> {
+        let mut found: Vec<Option<Block<DefaultParams>>> = vec![None; cids.len()];
+        let wanted: HashSet<&Cid> = cids.iter().collect();
+        for archive in self.list()? {
+            if found.iter().all(Option::is_some) {
+                break;
+            }
+            let car = self.read(&archive)?.into_car_v1();
+            let mut used = false;
+            for block in car
+                .blocks
+                .into_iter()
+                .filter(|block| wanted.contains(block.cid()))
+            {
+                for (i, cid) in cids.iter().enumerate() {
+                    if cid == block.cid() && found[i].is_none() {
+                        found[i] = Some(block.clone());
+                        used = true;
+                    }
+                }
+            }
+            if used {
+                self.touch(&archive);
+            }
+        }
+
+        if let Some(metrics) = &self.metrics {
+            for (cid, block) in cids.iter().zip(&found) {
+                match block {
+                    Some(_) => metrics.cache_hit(cid),
+                    None => metrics.cache_miss(cid),
+                }
+            }
+        }
+
+        let fallback = match &self.fallback {
+            Some(fallback) => fallback,
+            None => return Ok(found),
+        };
+        let fetch = |cid: &Cid| match fallback.fetch(cid)? {
+            Some(data) => Ok(Some(HashPolicy::default().block(*cid, data, 0)?.0)),
+            None => Ok(None),
+        };
+        let mut fetched: Vec<Block<DefaultParams>> = vec![];
+        let mut failed = None;
+        for (i, cid) in cids.iter().enumerate() {
+            if found[i].is_some() {
+                continue;
+            }
+            if let Some(block) = fetched.iter().find(|block| block.cid() == cid) {
+                found[i] = Some(block.clone());
+                continue;
+            }
+            match fetch(cid) {
+                Ok(Some(block)) => {
+                    found[i] = Some(block.clone());
+                    fetched.push(block);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        if !fetched.is_empty() {
+            let roots = fetched.iter().map(|block| *block.cid()).collect();
+            self.publish(&CarV1::new(CarHeaderV1 { roots }, fetched))?;
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(found),
+        }
+    }
+
+    /// Stores `car` atomically and returns its CID. Publishing an archive twice is a no-op.
+    ///
+    /// With a quota, other archives are evicted as needed afterwards.
+    pub fn publish(&self, car: &CarV1) -> CarResult<Cid> {
+        let cid = match &self.metrics {
+            Some(metrics) => write_named_with(&self.dir, |w| {
+                car.write_to(MeteredWriter::new(w, &**metrics))
+            })?,
+            None => write_named(car, &self.dir)?,
+        };
+        if let Some(quota) = &self.quota {
+            self.evict_to(quota.max_bytes, &quota.pins, Some(&cid))?;
+        }
+        Ok(cid)
+    }
+
+    /// Evicts archives until the repository fits its quota and returns their CIDs, least
+    /// recently used first. Does nothing without a quota.
+    pub fn evict(&self) -> CarResult<Vec<Cid>> {
+        match &self.quota {
+            Some(quota) => self.evict_to(quota.max_bytes, &quota.pins, None),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Total size in bytes of the stored archives.
+    pub fn size(&self) -> CarResult<u64> {
+        self.list()?
+            .iter()
+            .map(|cid| Ok(fs::metadata(car_path(&self.dir, cid))?.len()))
+            .sum()
+    }
+
+    fn evict_to(&self, max_bytes: u64, pins: &[Cid], keep: Option<&Cid>) -> CarResult<Vec<Cid>> {
+        let mut archives = vec![];
+        let mut size = 0;
+        for cid in self.list()? {
+            let metadata = fs::metadata(car_path(&self.dir, &cid))?;
+            size += metadata.len();
+            archives.push((metadata.modified()?, cid, metadata.len()));
+        }
+        archives.sort();
+
+        let mut evicted = vec![];
+        for (_, cid, len) in archives {
+            if size <= max_bytes {
+                break;
+            }
+            if Some(&cid) == keep || self.is_pinned(&cid, pins)? {
+                continue;
+            }
+            fs::remove_file(car_path(&self.dir, &cid))?;
+            size -= len;
+            evicted.push(cid);
+        }
+        Ok(evicted)
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        car_path(&self.dir, cid).is_file()
+    }
+
+    pub fn open(&self, cid: &Cid) -> CarResult<ContentArchive> {
+        let archive = self.read(cid)?;
+        self.touch(cid);
+        Ok(archive)
+    }
+
+    fn read(&self, cid: &Cid) -> CarResult<ContentArchive> {
+        match File::open(car_path(&self.dir, cid)) {
+            Ok(file) => {
+                let options = ReadOptions {
+                    metrics: self.metrics.as_deref(),
+                    ..ReadOptions::default()
+                };
+                ContentArchive::read_bytes_with_options(BufReader::new(file), &options)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(CarError::MissingArchive(*cid))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The CIDs of the stored archives, in order. Other files in the directory are ignored.
+    pub fn list(&self) -> CarResult<Vec<Cid>> {
+        let mut cids = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let cid = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".car"))
+                .and_then(|name| Cid::from_str(name).ok());
+            if let Some(cid) = cid {
+                cids.push(cid);
+            }
+        }
+        cids.sort();
+        Ok(cids)
+    }
+
+    /// Removes every archive that is not pinned and returns their CIDs. An archive is pinned
+    /// when its own CID or one of its roots is in `pins`.
+    pub fn gc(&self, pins: &[Cid]) -> CarResult<Vec<Cid>> {
+        let mut removed = vec![];
+        for cid in self.list()? {
+            if !self.is_pinned(&cid, pins)? {
+                fs::remove_file(car_path(&self.dir, &cid))?;
+                removed.push(cid);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Marks the archive as used for eviction. Failing to do so only affects the eviction order.
+    fn touch(&self, cid: &Cid) {
+        if let Ok(file) = File::open(car_path(&self.dir, cid)) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    fn is_pinned(&self, cid: &Cid, pins: &[Cid]) -> CarResult<bool> {
+        if pins.contains(cid) {
+            return Ok(true);
+        }
+        let roots = self.read(cid)?.into_car_v1().header.roots;
+        Ok(roots.iter().any(|root| pins.contains(root)))
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha2_256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::HttpGateway;
+    use crate::test_utils::{diamond, ok, raw, serve, temp_dir, unixfs_file, Counters};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[test]
+    fn it_writes_archives_under_their_cid() {
+        let dir = temp_dir("write-named");
+        let car = diamond();
+        let cid = write_named(&car, &dir).unwrap();
+
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert_eq!(cid, car_cid(&bytes));
+        assert_eq!(fs::read(car_path(&dir, &cid)).unwrap(), bytes);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let failed = write_named_with(&dir, |w| {
+            w.write_all(b"partial")?;
+            Err(crate::CarError::InvalidFormat)
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_publishes_lists_and_collects_archives() {
+        let dir = temp_dir("repo");
+        let repo = CarRepo::new(&dir).unwrap();
+        let diamond = diamond();
+        let file = unixfs_file(&[b"hello"]);
+
+        let first = repo.publish(&diamond).unwrap();
+        let second = repo.publish(&file).unwrap();
+        assert_eq!(repo.publish(&diamond).unwrap(), first);
+        fs::write(dir.join("notes.txt"), b"not an archive").unwrap();
+
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(repo.list().unwrap(), expected);
+        match repo.open(&first).unwrap() {
+            ContentArchive::V1(car) => assert_eq!(car.blocks, diamond.blocks),
+            #[cfg(feature = "v2")]
+            ContentArchive::V2(_) => panic!("Expected V1"),
+        }
+
+        assert_eq!(repo.gc(&[diamond.header.roots[0]]).unwrap(), vec![second]);
+        assert!(!repo.contains(&second));
+        assert!(matches!(repo.open(&second), Err(CarError::MissingArchive(cid)) if cid == second));
+        assert_eq!(repo.gc(&[first]).unwrap(), vec![]);
+        assert_eq!(repo.gc(&[]).unwrap(), vec![first]);
+        assert!(repo.list().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_fetches_missing_blocks_into_a_delta_archive() {
+        let dir = temp_dir("repo-fallback");
+        let local = diamond();
+        let remote = raw(b"remote");
+        let corrupt = raw(b"corrupt");
+        let url = serve(vec![ok(remote.data()), ok(b"not the block")]);
+        let counters = Arc::new(Counters::default());
+        let repo = CarRepo::new(&dir)
+            .unwrap()
+            .with_fallback(HttpGateway::new(&url).unwrap())
+            .with_metrics(counters.clone());
+        repo.publish(&local).unwrap();
+
+        let cids = [*local.blocks[0].cid(), *remote.cid(), *remote.cid()];
+        let blocks = repo.get_blocks(&cids).unwrap();
+        assert_eq!(
+            blocks,
+            vec![
+                Some(local.blocks[0].clone()),
+                Some(remote.clone()),
+                Some(remote.clone())
+            ]
+        );
+        assert_eq!(repo.list().unwrap().len(), 2);
+        assert_eq!(counters.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.misses.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            counters.bytes_written.load(Ordering::Relaxed),
+            repo.size().unwrap()
+        );
+        assert!(counters.bytes_read.load(Ordering::Relaxed) > 0);
+
+        // Served from the delta archive without asking the gateway again.
+        let offline = CarRepo::new(&dir).unwrap();
+        assert_eq!(offline.get_block(remote.cid()).unwrap(), Some(remote));
+        assert_eq!(offline.get_block(corrupt.cid()).unwrap(), None);
+
+        assert!(repo.get_block(corrupt.cid()).is_err());
+        assert_eq!(repo.list().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_blocks_fetched_before_a_failed_fetch() {
+        let dir = temp_dir("repo-fallback-failure");
+        let remote = raw(b"remote");
+        let corrupt = raw(b"corrupt");
+        let url = serve(vec![ok(remote.data()), ok(b"not the block")]);
+        let repo = CarRepo::new(&dir)
+            .unwrap()
+            .with_fallback(HttpGateway::new(&url).unwrap());
+
+        assert!(matches!(
+            repo.get_blocks(&[*remote.cid(), *corrupt.cid()]),
+            Err(CarError::HashMismatch { .. })
+        ));
+        let offline = CarRepo::new(&dir).unwrap();
+        assert_eq!(offline.get_block(remote.cid()).unwrap(), Some(remote));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_evicts_least_recently_used_archives() {
+        let dir = temp_dir("repo-quota");
+        let archives: Vec<CarV1> = (0..4)
+            .map(|i| unixfs_file(&[format!("file {}", i).as_bytes()]))
+            .collect();
+        let repo = CarRepo::new(&dir).unwrap();
+        let cids: Vec<Cid> = archives
+            .iter()
+            .map(|car| repo.publish(car).unwrap())
+            .collect();
+        let len = fs::metadata(car_path(&dir, &cids[0])).unwrap().len();
+        for (i, cid) in cids.iter().enumerate() {
+            let file = File::open(car_path(&dir, cid)).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(i as u64 + 1))
+                .unwrap();
+        }
+        // Reading the oldest archive makes it the most recently used.
+        repo.open(&cids[0]).unwrap();
+
+        let repo = repo.with_quota(Quota {
+            max_bytes: 2 * len,
+            pins: vec![archives[1].header.roots[0]],
+        });
+        assert_eq!(repo.evict().unwrap(), vec![cids[2], cids[3]]);
+        assert_eq!(repo.size().unwrap(), 2 * len);
+
+        let newest = repo.publish(&unixfs_file(&[b"file 4"])).unwrap();
+        assert_eq!(repo.list().unwrap().len(), 2);
+        assert!(repo.contains(&newest) && repo.contains(&cids[1]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
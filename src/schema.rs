@@ -0,0 +1,410 @@
+//! Validation of DAGs against a subset of [IPLD Schemas](https://ipld.io/docs/schemas/):
+//! scalar kinds, lists, maps, structs with map representation, keyed and kinded unions, and
+//! typed links, which are followed, see [`Schema::validate`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
+
+use libipld::{cid::Cid, Ipld, IpldCodec};
+
+use crate::traversal::walk;
+use crate::v1::CarV1;
+use crate::{CarError, CarResult, Deadline};
+
+/// A type a node must conform to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Any,
+    Bool,
+    Int,
+    Float,
+    String,
+    Bytes,
+    /// A link, to a node of the named type if set. Untyped links are not followed.
+    Link(Option<String>),
+    List(Box<Type>),
+    /// A map with string keys.
+    Map(Box<Type>),
+    Struct(Vec<Field>),
+    /// A single-entry map whose key selects the named type of its value.
+    KeyedUnion(BTreeMap<String, String>),
+    /// Any of the named types, tried in order.
+    KindedUnion(Vec<String>),
+    Nullable(Box<Type>),
+    /// A type defined in the schema.
+    Named(String),
+}
+
+/// A field of a [`Type::Struct`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
+    /// Whether the field may be left out.
+    pub optional: bool,
+}
+
+impl Field {
+    pub fn new(name: &str, ty: Type) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+            optional: false,
+        }
+    }
+
+    pub fn optional(name: &str, ty: Type) -> Self {
+        Self {
+            optional: true,
+            ..Self::new(name, ty)
+        }
+    }
+}
+
+/// Named types.
+///
+/// A type may refer to itself through a list, map, struct, keyed union or link, which each
+/// descend into the node, but not only through names, nullables and kinded unions, which would
+/// check the same value against it forever; see [`Schema::check_types`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Schema {
+    pub types: BTreeMap<String, Type>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_type(mut self, name: &str, ty: Type) -> Self {
+        self.types.insert(name.to_string(), ty);
+        self
+    }
+
+    /// Fails with [`CarError::SchemaViolation`] if a type refers to itself without descending
+    /// into the node, e.g. `A = B` and `B = A | Int`. [`Schema::validate`] checks this before
+    /// reading anything, but a schema can be checked once built.
+    pub fn check_types(&self) -> CarResult<()> {
+        let mut done = BTreeSet::new();
+        for name in self.types.keys() {
+            self.check_aliases(name, &mut vec![], &mut done)?;
+        }
+        Ok(())
+    }
+
+    /// Follows the types `name` may check a value against without descending into it, with
+    /// `chain` the names followed to it and `done` those known to lead to no cycle.
+    fn check_aliases<'a>(
+        &'a self,
+        name: &'a str,
+        chain: &mut Vec<&'a str>,
+        done: &mut BTreeSet<&'a str>,
+    ) -> CarResult<()> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if let Some(start) = chain.iter().position(|seen| *seen == name) {
+            let mut cycle = chain[start..].to_vec();
+            cycle.push(name);
+            return Err(CarError::SchemaViolation(format!(
+                "type {} refers to itself through {}",
+                name,
+                cycle.join(" -> ")
+            )));
+        }
+        let Some(ty) = self.types.get(name) else {
+            return Ok(());
+        };
+        chain.push(name);
+        let mut ty = ty;
+        while let Type::Nullable(inner) = ty {
+            ty = inner;
+        }
+        match ty {
+            Type::Named(alias) => self.check_aliases(alias, chain, done)?,
+            Type::KindedUnion(members) => {
+                for member in members {
+                    self.check_aliases(member, chain, done)?;
+                }
+            }
+            _ => {}
+        }
+        chain.pop();
+        done.insert(name);
+        Ok(())
+    }
+
+    /// Checks that `root` conforms to the type `name`, and so does every node reached through
+    /// typed links from it, failing with [`CarError::SchemaViolation`] on the first node that
+    /// does not, or if the schema fails [`Schema::check_types`].
+    ///
+    /// A node reached as several types is checked against each of them.
+    pub fn validate(&self, car: &CarV1, root: &Cid, name: &str) -> CarResult<()> {
+        self.check_types()?;
+        // Blocks are visited once per (CID, type name) pair, not once per CID.
+        walk(
+            car,
+            vec![(*root, name.to_string())],
+            false,
+            Deadline::none(),
+            |_, block, name| {
+                let node = block.decode::<IpldCodec, Ipld>()?;
+                let mut links = vec![];
+                self.check(&Type::Named(name), &node, "", &mut links)
+                    .map_err(|err| CarError::SchemaViolation(format!("{}{}", block.cid(), err)))?;
+                Ok(ControlFlow::Continue(links))
+            },
+        )
+    }
+
+    /// Checks `node` against `ty`, collecting typed links to follow. Errors are the path of the
+    /// nonconforming value and what was wrong with it.
+    fn check(
+        &self,
+        ty: &Type,
+        node: &Ipld,
+        path: &str,
+        links: &mut Vec<(Cid, String)>,
+    ) -> Result<(), String> {
+        let expected = |what: &str| format!("{}: expected {}, found {}", path, what, kind(node));
+        match (ty, node) {
+            (Type::Any, _)
+            | (Type::Bool, Ipld::Bool(_))
+            | (Type::Int, Ipld::Integer(_))
+            | (Type::Float, Ipld::Float(_))
+            | (Type::String, Ipld::String(_))
+            | (Type::Bytes, Ipld::Bytes(_))
+            | (Type::Nullable(_), Ipld::Null) => Ok(()),
+            (Type::Link(target), Ipld::Link(cid)) => {
+                if let Some(target) = target {
+                    links.push((*cid, target.clone()));
+                }
+                Ok(())
+            }
+            (Type::List(item), Ipld::List(items)) => {
+                for (i, value) in items.iter().enumerate() {
+                    self.check(item, value, &format!("{}[{}]", path, i), links)?;
+                }
+                Ok(())
+            }
+            (Type::Map(value_ty), Ipld::Map(map)) => {
+                for (key, value) in map {
+                    self.check(value_ty, value, &format!("{}.{}", path, key), links)?;
+                }
+                Ok(())
+            }
+            (Type::Struct(fields), Ipld::Map(map)) => {
+                if let Some(key) = map
+                    .keys()
+                    .find(|key| !fields.iter().any(|field| &field.name == *key))
+                {
+                    return Err(format!("{}: unknown field {}", path, key));
+                }
+                for field in fields {
+                    let path = format!("{}.{}", path, field.name);
+                    match map.get(&field.name) {
+                        Some(value) => self.check(&field.ty, value, &path, links)?,
+                        None if field.optional => {}
+                        None => return Err(format!("{}: missing", path)),
+                    }
+                }
+                Ok(())
+            }
+            (Type::KeyedUnion(variants), Ipld::Map(map)) if map.len() == 1 => {
+                let (key, value) = map.iter().next().unwrap();
+                match variants.get(key) {
+                    Some(name) => self.check(
+                        &Type::Named(name.clone()),
+                        value,
+                        &format!("{}.{}", path, key),
+                        links,
+                    ),
+                    None => Err(format!("{}: unknown union member {}", path, key)),
+                }
+            }
+            (Type::KindedUnion(members), _) => {
+                for name in members {
+                    let mut member_links = vec![];
+                    if self
+                        .check(&Type::Named(name.clone()), node, path, &mut member_links)
+                        .is_ok()
+                    {
+                        links.extend(member_links);
+                        return Ok(());
+                    }
+                }
+                Err(expected(&members.join(" | ")))
+            }
+            (Type::Nullable(ty), _) => self.check(ty, node, path, links),
+            (Type::Named(name), _) => match self.types.get(name) {
+                Some(ty) => self.check(ty, node, path, links),
+                None => Err(format!("{}: undefined type {}", path, name)),
+            },
+            (Type::KeyedUnion(_), _) => Err(expected("a single-entry map")),
+            (Type::Bool, _) => Err(expected("bool")),
+            (Type::Int, _) => Err(expected("int")),
+            (Type::Float, _) => Err(expected("float")),
+            (Type::String, _) => Err(expected("string")),
+            (Type::Bytes, _) => Err(expected("bytes")),
+            (Type::Link(_), _) => Err(expected("link")),
+            (Type::List(_), _) => Err(expected("list")),
+            (Type::Map(_) | Type::Struct(_), _) => Err(expected("map")),
+        }
+    }
+}
+
+fn kind(node: &Ipld) -> &'static str {
+    match node {
+        Ipld::Null => "null",
+        Ipld::Bool(_) => "bool",
+        Ipld::Integer(_) => "int",
+        Ipld::Float(_) => "float",
+        Ipld::String(_) => "string",
+        Ipld::Bytes(_) => "bytes",
+        Ipld::List(_) => "list",
+        Ipld::Map(_) => "map",
+        Ipld::Link(_) => "link",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DagBuilder;
+    use libipld::ipld;
+
+    fn schema() -> Schema {
+        Schema::new()
+            .with_type(
+                "Person",
+                Type::Struct(vec![
+                    Field::new("name", Type::String),
+                    Field::optional("age", Type::Int),
+                ]),
+            )
+            .with_type(
+                "Member",
+                Type::KeyedUnion(BTreeMap::from([
+                    ("person".to_string(), "PersonLink".to_string()),
+                    ("guest".to_string(), "Guest".to_string()),
+                ])),
+            )
+            .with_type("PersonLink", Type::Link(Some("Person".to_string())))
+            .with_type("Guest", Type::Nullable(Box::new(Type::String)))
+            .with_type(
+                "Team",
+                Type::Struct(vec![Field::new(
+                    "members",
+                    Type::List(Box::new(Type::Named("Member".to_string()))),
+                )]),
+            )
+    }
+
+    #[test]
+    fn it_validates_nodes_and_follows_typed_links() {
+        let mut builder = DagBuilder::new();
+        let alice = builder.add(&ipld!({ "name": "alice", "age": 30 })).unwrap();
+        builder
+            .add(&ipld!({ "members": [{ "person": alice }, { "guest": null }] }))
+            .unwrap();
+        let car = builder.finish().unwrap();
+        schema()
+            .validate(&car, &car.header.roots[0], "Team")
+            .unwrap();
+
+        let mut builder = DagBuilder::new();
+        let alice = builder
+            .add(&ipld!({ "name": "alice", "age": "30" }))
+            .unwrap();
+        builder
+            .add(&ipld!({ "members": [{ "person": alice }] }))
+            .unwrap();
+        let car = builder.finish().unwrap();
+        match schema().validate(&car, &car.header.roots[0], "Team") {
+            Err(CarError::SchemaViolation(message)) => {
+                assert_eq!(
+                    message,
+                    format!("{}.age: expected int, found string", alice)
+                )
+            }
+            other => panic!("Expected a SchemaViolation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_nonconforming_structs_and_unions() {
+        let schema = schema();
+        let check = |ty: &str, node: Ipld| {
+            schema.check(&Type::Named(ty.to_string()), &node, "", &mut vec![])
+        };
+        assert!(check("Person", ipld!({ "name": "bob" })).is_ok());
+        assert_eq!(
+            check("Person", ipld!({})),
+            Err(".name: missing".to_string())
+        );
+        assert_eq!(
+            check("Person", ipld!({ "name": "bob", "email": "b@example.com" })),
+            Err(": unknown field email".to_string())
+        );
+        assert_eq!(
+            check("Member", ipld!({ "robot": null })),
+            Err(": unknown union member robot".to_string())
+        );
+        assert!(check("Member", ipld!({ "guest": 1 })).is_err());
+    }
+
+    #[test]
+    fn it_checks_nodes_reached_as_several_types() {
+        let schema = schema().with_type(
+            "Pair",
+            Type::Struct(vec![
+                Field::new("any", Type::Link(Some("Guest".to_string()))),
+                Field::new("person", Type::Link(Some("Person".to_string()))),
+            ]),
+        );
+        let mut builder = DagBuilder::new();
+        let guest = builder.add(&ipld!("carol")).unwrap();
+        builder
+            .add(&ipld!({ "any": guest, "person": guest }))
+            .unwrap();
+        let car = builder.finish().unwrap();
+        assert!(matches!(
+            schema.validate(&car, &car.header.roots[0], "Pair"),
+            Err(CarError::SchemaViolation(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_types_aliasing_themselves() {
+        let cyclic = Schema::new()
+            .with_type("A", Type::Named("B".to_string()))
+            .with_type(
+                "B",
+                Type::Nullable(Box::new(Type::KindedUnion(vec![
+                    "Int".to_string(),
+                    "A".to_string(),
+                ]))),
+            )
+            .with_type("Int", Type::Int);
+        match cyclic.check_types() {
+            Err(CarError::SchemaViolation(message)) => {
+                assert_eq!(message, "type A refers to itself through A -> B -> A")
+            }
+            other => panic!("Expected a SchemaViolation, got {:?}", other),
+        }
+        let mut builder = DagBuilder::new();
+        builder.add(&ipld!("text")).unwrap();
+        let car = builder.finish().unwrap();
+        assert!(cyclic.validate(&car, &car.header.roots[0], "A").is_err());
+        let itself = Schema::new().with_type("Self", Type::Named("Self".to_string()));
+        assert!(itself.check_types().is_err());
+
+        // Recursion through a list descends into the node, so it ends.
+        let tree = Schema::new().with_type(
+            "Tree",
+            Type::List(Box::new(Type::Named("Tree".to_string()))),
+        );
+        tree.check_types().unwrap();
+        assert!(schema().check_types().is_ok());
+    }
+}
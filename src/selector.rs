@@ -0,0 +1,413 @@
+//! [IPLD selectors](https://ipld.io/specs/selectors/) in their dag-cbor wire representation, as
+//! exchanged by graphsync peers and gateways.
+
+use std::collections::BTreeMap;
+
+use libipld::{cbor::DagCborCodec, cid::Cid, prelude::Codec, Ipld};
+
+use crate::{CarError, CarResult};
+
+/// A selector, as described by the selector schema.
+///
+/// Conditions (`ExploreConditional`, the `stopAt` of `ExploreRecursive`) and ADL interpretation
+/// (`ExploreInterpretAs`) are not supported and are rejected while parsing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Selector {
+    /// Matches the current node, optionally only a byte range of it.
+    Matcher {
+        subset: Option<(i64, i64)>,
+    },
+    ExploreAll {
+        next: Box<Selector>,
+    },
+    ExploreFields {
+        fields: BTreeMap<String, Selector>,
+    },
+    ExploreIndex {
+        index: u64,
+        next: Box<Selector>,
+    },
+    /// Explores list entries in `start..end`.
+    ExploreRange {
+        start: u64,
+        end: u64,
+        next: Box<Selector>,
+    },
+    ExploreRecursive {
+        limit: RecursionLimit,
+        sequence: Box<Selector>,
+    },
+    /// Marks where an enclosing `ExploreRecursive` repeats its sequence.
+    ExploreRecursiveEdge,
+    ExploreUnion(Vec<Selector>),
+}
+
+/// How many times an `ExploreRecursive` may repeat its sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecursionLimit {
+    None,
+    Depth(u64),
+}
+
+/// A step from a node to one of its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+impl Selector {
+    /// The selector matching every node of a DAG: `ExploreRecursive(none, ExploreAll(edge))`.
+    pub fn explore_all_recursively() -> Self {
+        Selector::ExploreRecursive {
+            limit: RecursionLimit::None,
+            sequence: Box::new(Selector::ExploreAll {
+                next: Box::new(Selector::ExploreRecursiveEdge),
+            }),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> CarResult<Self> {
+        Self::from_ipld(&DagCborCodec.decode(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> CarResult<Vec<u8>> {
+        Ok(DagCborCodec.encode(&self.to_ipld())?)
+    }
+
+    pub fn from_ipld(ipld: &Ipld) -> CarResult<Self> {
+        let (kind, body) = match ipld {
+            Ipld::Map(map) if map.len() == 1 => map.iter().next().unwrap(),
+            _ => return Err(invalid("selector must be a single-entry map")),
+        };
+        Ok(match kind.as_str() {
+            "." => Selector::Matcher {
+                subset: match body.get("subset") {
+                    Ok(subset) => Some((int_field(subset, "[")?, int_field(subset, "]")?)),
+                    Err(_) => None,
+                },
+            },
+            "a" => Selector::ExploreAll {
+                next: next_field(body, ">")?,
+            },
+            "f" => match body.get("f>") {
+                Ok(Ipld::Map(fields)) => Selector::ExploreFields {
+                    fields: fields
+                        .iter()
+                        .map(|(name, selector)| Ok((name.clone(), Self::from_ipld(selector)?)))
+                        .collect::<CarResult<_>>()?,
+                },
+                _ => return Err(invalid("ExploreFields without fields")),
+            },
+            "i" => Selector::ExploreIndex {
+                index: uint_field(body, "i")?,
+                next: next_field(body, ">")?,
+            },
+            "r" => Selector::ExploreRange {
+                start: uint_field(body, "^")?,
+                end: uint_field(body, "$")?,
+                next: next_field(body, ">")?,
+            },
+            "R" => {
+                if body.get("!").is_ok() {
+                    return Err(invalid("stopAt conditions are not supported"));
+                }
+                let limit = match body.get("l") {
+                    Ok(Ipld::Map(limit)) if limit.contains_key("none") => RecursionLimit::None,
+                    Ok(limit @ Ipld::Map(_)) => RecursionLimit::Depth(uint_field(limit, "depth")?),
+                    _ => return Err(invalid("ExploreRecursive without limit")),
+                };
+                Selector::ExploreRecursive {
+                    limit,
+                    sequence: next_field(body, ":>")?,
+                }
+            }
+            "@" => Selector::ExploreRecursiveEdge,
+            "|" => match body {
+                Ipld::List(members) => Selector::ExploreUnion(
+                    members
+                        .iter()
+                        .map(Self::from_ipld)
+                        .collect::<CarResult<_>>()?,
+                ),
+                _ => return Err(invalid("ExploreUnion must be a list")),
+            },
+            other => return Err(invalid(&format!("unsupported selector `{}`", other))),
+        })
+    }
+
+    pub fn to_ipld(&self) -> Ipld {
+        let body = match self {
+            Selector::Matcher { subset } => {
+                let mut matcher = BTreeMap::new();
+                if let Some((from, to)) = subset {
+                    matcher.insert(
+                        "subset".to_string(),
+                        map([
+                            ("[", Ipld::Integer(*from as i128)),
+                            ("]", Ipld::Integer(*to as i128)),
+                        ]),
+                    );
+                }
+                (".", Ipld::Map(matcher))
+            }
+            Selector::ExploreAll { next } => ("a", map([(">", next.to_ipld())])),
+            Selector::ExploreFields { fields } => (
+                "f",
+                map([(
+                    "f>",
+                    Ipld::Map(
+                        fields
+                            .iter()
+                            .map(|(name, selector)| (name.clone(), selector.to_ipld()))
+                            .collect(),
+                    ),
+                )]),
+            ),
+            Selector::ExploreIndex { index, next } => (
+                "i",
+                map([("i", Ipld::Integer(*index as i128)), (">", next.to_ipld())]),
+            ),
+            Selector::ExploreRange { start, end, next } => (
+                "r",
+                map([
+                    ("^", Ipld::Integer(*start as i128)),
+                    ("$", Ipld::Integer(*end as i128)),
+                    (">", next.to_ipld()),
+                ]),
+            ),
+            Selector::ExploreRecursive { limit, sequence } => {
+                let limit = match limit {
+                    RecursionLimit::None => map([("none", map([]))]),
+                    RecursionLimit::Depth(depth) => map([("depth", Ipld::Integer(*depth as i128))]),
+                };
+                ("R", map([("l", limit), (":>", sequence.to_ipld())]))
+            }
+            Selector::ExploreRecursiveEdge => ("@", map([])),
+            Selector::ExploreUnion(members) => {
+                ("|", Ipld::List(members.iter().map(Self::to_ipld).collect()))
+            }
+        };
+        map([body])
+    }
+
+    /// The selector to apply to the child at `segment`, if that child is selected at all.
+    pub fn explore(&self, segment: Segment) -> Option<Selector> {
+        // An empty union is what is left of a recursion edge past the recursion limit.
+        self.explore_unnormalized(segment)
+            .filter(|next| !matches!(next, Selector::ExploreUnion(members) if members.is_empty()))
+    }
+
+    fn explore_unnormalized(&self, segment: Segment) -> Option<Selector> {
+        match self {
+            Selector::Matcher { .. } | Selector::ExploreRecursiveEdge => None,
+            Selector::ExploreAll { next } => Some(*next.clone()),
+            Selector::ExploreFields { fields } => match segment {
+                Segment::Key(key) => fields.get(key).cloned(),
+                Segment::Index(index) => fields.get(&index.to_string()).cloned(),
+            },
+            Selector::ExploreIndex { index, next } => match segment {
+                Segment::Index(i) if i as u64 == *index => Some(*next.clone()),
+                _ => None,
+            },
+            Selector::ExploreRange { start, end, next } => match segment {
+                Segment::Index(i) if (*start..*end).contains(&(i as u64)) => Some(*next.clone()),
+                _ => None,
+            },
+            Selector::ExploreRecursive { limit, sequence } => {
+                let repeat = match limit {
+                    RecursionLimit::None => Some(self.clone()),
+                    RecursionLimit::Depth(depth) if *depth > 1 => {
+                        Some(Selector::ExploreRecursive {
+                            limit: RecursionLimit::Depth(depth - 1),
+                            sequence: sequence.clone(),
+                        })
+                    }
+                    RecursionLimit::Depth(_) => None,
+                };
+                sequence.replace_edges(&repeat).explore(segment)
+            }
+            Selector::ExploreUnion(members) => {
+                let mut explored: Vec<Selector> = members
+                    .iter()
+                    .filter_map(|member| member.explore(segment))
+                    .collect();
+                match explored.len() {
+                    0 => None,
+                    1 => explored.pop(),
+                    _ => Some(Selector::ExploreUnion(explored)),
+                }
+            }
+        }
+    }
+
+    /// Substitutes the recursion edges belonging to this level of recursion, dropping them
+    /// when the recursion limit has been reached.
+    fn replace_edges(&self, repeat: &Option<Selector>) -> Selector {
+        let replace = |selector: &Selector| Box::new(selector.replace_edges(repeat));
+        match self {
+            Selector::ExploreRecursiveEdge => repeat
+                .clone()
+                .unwrap_or_else(|| Selector::ExploreUnion(vec![])),
+            Selector::ExploreAll { next } => Selector::ExploreAll {
+                next: replace(next),
+            },
+            Selector::ExploreFields { fields } => Selector::ExploreFields {
+                fields: fields
+                    .iter()
+                    .map(|(name, selector)| (name.clone(), selector.replace_edges(repeat)))
+                    .collect(),
+            },
+            Selector::ExploreIndex { index, next } => Selector::ExploreIndex {
+                index: *index,
+                next: replace(next),
+            },
+            Selector::ExploreRange { start, end, next } => Selector::ExploreRange {
+                start: *start,
+                end: *end,
+                next: replace(next),
+            },
+            Selector::ExploreUnion(members) => Selector::ExploreUnion(
+                members
+                    .iter()
+                    .map(|member| member.replace_edges(repeat))
+                    .collect(),
+            ),
+            // Edges below a nested recursion belong to it.
+            Selector::Matcher { .. } | Selector::ExploreRecursive { .. } => self.clone(),
+        }
+    }
+
+    /// Applies the selector to the structure of a single decoded block, returning, in
+    /// traversal order, the links it reaches and the selector to continue with at each.
+    pub fn select_links(&self, node: &Ipld) -> Vec<(Cid, Selector)> {
+        let mut links = vec![];
+        self.select_links_into(node, &mut links);
+        links
+    }
+
+    fn select_links_into(&self, node: &Ipld, links: &mut Vec<(Cid, Selector)>) {
+        let mut visit = |segment: Segment, child: &Ipld| {
+            if let Some(next) = self.explore(segment) {
+                match child {
+                    Ipld::Link(cid) => links.push((*cid, next)),
+                    child => next.select_links_into(child, links),
+                }
+            }
+        };
+        match node {
+            Ipld::Map(map) => map
+                .iter()
+                .for_each(|(key, child)| visit(Segment::Key(key), child)),
+            Ipld::List(list) => list
+                .iter()
+                .enumerate()
+                .for_each(|(index, child)| visit(Segment::Index(index), child)),
+            _ => {}
+        }
+    }
+}
+
+fn invalid(reason: &str) -> CarError {
+    CarError::InvalidSelector(reason.to_string())
+}
+
+fn map<const N: usize>(entries: [(&str, Ipld); N]) -> Ipld {
+    Ipld::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn next_field(body: &Ipld, key: &str) -> CarResult<Box<Selector>> {
+    match body.get(key) {
+        Ok(next) => Ok(Box::new(Selector::from_ipld(next)?)),
+        Err(_) => Err(invalid(&format!("missing `{}`", key))),
+    }
+}
+
+fn int_field(body: &Ipld, key: &str) -> CarResult<i64> {
+    match body.get(key) {
+        Ok(Ipld::Integer(value)) => {
+            i64::try_from(*value).map_err(|_| invalid(&format!("`{}` out of range", key)))
+        }
+        _ => Err(invalid(&format!("missing integer `{}`", key))),
+    }
+}
+
+fn uint_field(body: &Ipld, key: &str) -> CarResult<u64> {
+    u64::try_from(int_field(body, key)?)
+        .map_err(|_| invalid(&format!("`{}` must not be negative", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_the_wire_format() {
+        // `{"R": {"l": {"none": {}}, ":>": {"a": {">": {"@": {}}}}}}` as produced by go-ipld-prime.
+        let bytes = [
+            0xa1, 0x61, 0x52, 0xa2, 0x61, 0x6c, 0xa1, 0x64, 0x6e, 0x6f, 0x6e, 0x65, 0xa0, 0x62,
+            0x3a, 0x3e, 0xa1, 0x61, 0x61, 0xa1, 0x61, 0x3e, 0xa1, 0x61, 0x40, 0xa0,
+        ];
+        let selector = Selector::from_bytes(&bytes).unwrap();
+        assert_eq!(selector, Selector::explore_all_recursively());
+        assert_eq!(selector.to_bytes().unwrap(), bytes);
+
+        let fields = Selector::ExploreUnion(vec![
+            Selector::Matcher {
+                subset: Some((0, 10)),
+            },
+            Selector::ExploreFields {
+                fields: [(
+                    "Links".to_string(),
+                    Selector::ExploreRange {
+                        start: 1,
+                        end: 3,
+                        next: Box::new(Selector::ExploreIndex {
+                            index: 0,
+                            next: Box::new(Selector::Matcher { subset: None }),
+                        }),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+        ]);
+        assert_eq!(
+            Selector::from_bytes(&fields.to_bytes().unwrap()).unwrap(),
+            fields
+        );
+    }
+
+    #[test]
+    fn it_rejects_unsupported_selectors() {
+        let conditional = map([("&", map([]))]);
+        assert!(matches!(
+            Selector::from_ipld(&conditional),
+            Err(CarError::InvalidSelector(_))
+        ));
+    }
+
+    #[test]
+    fn it_limits_recursion_depth() {
+        let selector = Selector::ExploreRecursive {
+            limit: RecursionLimit::Depth(2),
+            sequence: Box::new(Selector::ExploreAll {
+                next: Box::new(Selector::ExploreRecursiveEdge),
+            }),
+        };
+        let child = selector.explore(Segment::Index(0)).unwrap();
+        assert!(matches!(
+            child,
+            Selector::ExploreRecursive {
+                limit: RecursionLimit::Depth(1),
+                ..
+            }
+        ));
+        assert_eq!(child.explore(Segment::Index(0)), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Archives as snapshots of any serde type, stored as a dag-cbor root, see [`from_serde`] and
+//! [`to_serde`].
+
+use std::collections::{HashMap, HashSet};
+
+use ::serde::de::DeserializeOwned;
+use ::serde::Serialize;
+use libipld::cid::Cid;
+use libipld::serde::{from_ipld, to_ipld};
+use libipld::{Ipld, IpldCodec};
+
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{CarError, CarResult};
+
+const RAW_CODEC: u64 = 0x55;
+
+/// Serializes `value` as a single dag-cbor block and returns an archive rooted at it.
+pub fn from_serde<T: Serialize>(value: &T) -> CarResult<CarV1> {
+    from_serde_split(value, usize::MAX)
+}
+
+/// Like [`from_serde`], but moves every byte string longer than `threshold` to a raw block of
+/// its own, linked from where it was.
+///
+/// Only fields serialized as bytes are split, e.g. those using `serde_bytes`; a plain `Vec<u8>`
+/// is serialized as a list of integers. Equal byte strings share a block, stored once.
+pub fn from_serde_split<T: Serialize>(value: &T, threshold: usize) -> CarResult<CarV1> {
+    let mut car = CarV1::new(CarHeaderV1 { roots: vec![] }, vec![]);
+    let node = split(to_ipld(value)?, threshold, &mut car)?;
+    let root = car.put_ipld(IpldCodec::DagCbor, &node)?;
+    car.header.roots = vec![root];
+    let mut seen = HashSet::new();
+    car.blocks.retain(|block| seen.insert(*block.cid()));
+    Ok(car)
+}
+
+/// Deserializes the dag-cbor root of `car`, reading links to raw blocks of the archive back as
+/// their bytes, so values written by [`from_serde_split`] round trip. Values holding CIDs of
+/// raw blocks of their own should be stored without splitting.
+pub fn to_serde<T: DeserializeOwned>(car: &CarV1) -> CarResult<T> {
+    let root = match car.header.roots[..] {
+        [root] => root,
+        _ => return Err(CarError::InvalidFormat),
+    };
+    let blocks: HashMap<&Cid, &[u8]> = car
+        .blocks
+        .iter()
+        .map(|block| (block.cid(), block.data()))
+        .collect();
+    let block = car
+        .blocks
+        .iter()
+        .find(|block| *block.cid() == root)
+        .ok_or(CarError::MissingBlock(root))?;
+    let node = block.decode::<IpldCodec, Ipld>()?;
+    Ok(from_ipld(join(node, &blocks))?)
+}
+
+fn split(node: Ipld, threshold: usize, car: &mut CarV1) -> CarResult<Ipld> {
+    Ok(match node {
+        Ipld::Bytes(bytes) if bytes.len() > threshold => {
+            Ipld::Link(car.put_ipld(IpldCodec::Raw, &Ipld::Bytes(bytes))?)
+        }
+        Ipld::List(items) => Ipld::List(
+            items
+                .into_iter()
+                .map(|item| split(item, threshold, car))
+                .collect::<CarResult<_>>()?,
+        ),
+        Ipld::Map(map) => Ipld::Map(
+            map.into_iter()
+                .map(|(key, value)| Ok((key, split(value, threshold, car)?)))
+                .collect::<CarResult<_>>()?,
+        ),
+        node => node,
+    })
+}
+
+fn join(node: Ipld, blocks: &HashMap<&Cid, &[u8]>) -> Ipld {
+    match node {
+        Ipld::Link(cid) if cid.codec() == RAW_CODEC => match blocks.get(&cid) {
+            Some(data) => Ipld::Bytes(data.to_vec()),
+            None => Ipld::Link(cid),
+        },
+        Ipld::List(items) => Ipld::List(items.into_iter().map(|item| join(item, blocks)).collect()),
+        Ipld::Map(map) => Ipld::Map(
+            map.into_iter()
+                .map(|(key, value)| (key, join(value, blocks)))
+                .collect(),
+        ),
+        node => node,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt;
+
+    /// A byte string serialized as bytes rather than a list.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Blob(Vec<u8>);
+
+    impl Serialize for Blob {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Blob {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct BlobVisitor;
+            impl de::Visitor<'_> for BlobVisitor {
+                type Value = Blob;
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("bytes")
+                }
+                fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Blob, E> {
+                    Ok(Blob(bytes.to_vec()))
+                }
+                fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Blob, E> {
+                    Ok(Blob(bytes))
+                }
+            }
+            deserializer.deserialize_bytes(BlobVisitor)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Snapshot {
+        name: String,
+        version: u32,
+        thumbnail: Blob,
+        layers: Vec<Blob>,
+    }
+
+    #[test]
+    fn it_round_trips_serde_values() {
+        let snapshot = Snapshot {
+            name: "app".to_string(),
+            version: 3,
+            thumbnail: Blob(vec![1; 8]),
+            layers: vec![Blob(vec![2; 100]), Blob(vec![3; 100])],
+        };
+        let car = from_serde(&snapshot).unwrap();
+        assert_eq!(car.blocks.len(), 1);
+        assert_eq!(to_serde::<Snapshot>(&car).unwrap(), snapshot);
+
+        let car = from_serde_split(&snapshot, 16).unwrap();
+        assert_eq!(car.blocks.len(), 3);
+        assert_eq!(car.missing_links().unwrap(), vec![]);
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let read = CarV1::from_reader(&bytes[..]).unwrap();
+        assert_eq!(to_serde::<Snapshot>(&read).unwrap(), snapshot);
+
+        // Layers with the same bytes are stored once.
+        let shared = Snapshot {
+            layers: vec![Blob(vec![2; 100]), Blob(vec![2; 100])],
+            ..snapshot
+        };
+        let car = from_serde_split(&shared, 16).unwrap();
+        assert_eq!(car.blocks.len(), 2);
+        assert_eq!(to_serde::<Snapshot>(&car).unwrap(), shared);
+    }
+}
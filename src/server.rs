@@ -0,0 +1,589 @@
+//! Serving an archive over HTTP with trustless gateway style endpoints, see [`CarServer`].
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use libipld::cid::Cid;
+
+use crate::export::{gather, gather_entity_bytes, EntityBytes, ExportOptions};
+use crate::gateway::{BlockFetcher, DeadlineReader};
+use crate::{CarError, CarResult, Deadline, HashPolicy};
+
+const RAW: &str = "application/vnd.ipld.raw";
+const CAR: &str = "application/vnd.ipld.car";
+
+/// How long the request line and headers of a request may be in all, past which it is refused
+/// with `431 Request Header Fields Too Large`.
+pub const MAX_HEAD_LENGTH: u64 = 8 * 1024;
+
+/// How many connections [`CarServer::serve`] handles at once by default.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// How long a client has to send the head of its request by default.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many bytes of a `car` response are gathered into one chunk before it is sent.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Answers `GET /ipfs/{cid}` from the blocks of one archive, each connection on its own thread.
+///
+/// Blocks are fetched as requests need them, from a [`crate::blockstore::CarV2Store`] or any
+/// other [`BlockFetcher`], and verified against their CIDs before they are sent, so the archive
+/// is never held in memory. Neither is a `car` response, which is sent with chunked transfer
+/// encoding as the DAG is walked.
+///
+/// `?format=raw` (or `Accept: application/vnd.ipld.raw`) returns the block itself, and
+/// `?format=car` (or `Accept: application/vnd.ipld.car`) the DAG below it, following the
+/// `order` and `dups` parameters of the `Accept` header and an optional `entity-bytes` query.
+/// Blocks of a `car` response are in depth-first order whatever `order` asks for.
+#[derive(Clone)]
+pub struct CarServer {
+    source: Arc<dyn BlockFetcher + Send + Sync>,
+    max_connections: usize,
+    request_timeout: Duration,
+}
+
+impl fmt::Debug for CarServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CarServer")
+            .field("max_connections", &self.max_connections)
+            .field("request_timeout", &self.request_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: String,
+    body: Body,
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    /// The DAG below `root`, exported as it is sent.
+    Car {
+        root: Cid,
+        range: Option<EntityBytes>,
+        options: ExportOptions,
+    },
+}
+
+impl Response {
+    fn error(status: u16, message: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8".to_string(),
+            body: Body::Bytes(message.into_bytes()),
+        }
+    }
+}
+
+/// Sends each write as one chunk of a body with chunked transfer encoding.
+struct Chunked<W>(W);
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body.
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A connection being handled, counted until it is dropped.
+struct Slot(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (active, freed) = &*self.0;
+        *active.lock().unwrap_or_else(|err| err.into_inner()) -= 1;
+        freed.notify_one();
+    }
+}
+
+impl CarServer {
+    pub fn new<F: BlockFetcher + Send + Sync + 'static>(source: F) -> Self {
+        Self {
+            source: Arc::new(source),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Opens the archive at `path` for serving: a CARv2 through its index, which is built when
+    /// it has none, and a CARv1 through an index of its sections built when it is opened.
+    #[cfg(feature = "v2")]
+    pub fn open(path: &std::path::Path) -> CarResult<Self> {
+        use crate::blockstore::CarV2Store;
+        use crate::detached::DetachedCar;
+        use crate::index::CarV2Index;
+        use std::fs::File;
+        use std::io::{Seek, SeekFrom};
+        use std::ops::Range;
+
+        match CarV2Store::open(path) {
+            Ok(store) => Ok(Self::new(store)),
+            Err(CarError::UnsupportedVersion(1)) => {
+                let index = CarV2Index::generate_from_reader(BufReader::new(File::open(path)?))?;
+                let file = Mutex::new(File::open(path)?);
+                let read_range = move |range: Range<u64>| -> CarResult<Vec<u8>> {
+                    let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+                    file.seek(SeekFrom::Start(range.start))?;
+                    let mut data = vec![];
+                    (&mut *file)
+                        .take(range.end - range.start)
+                        .read_to_end(&mut data)?;
+                    Ok(data)
+                };
+                Ok(Self::new(DetachedCar::new(index, read_range)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Handles at most `max_connections` connections at once in [`CarServer::serve`]; further
+    /// ones wait to be accepted until one closes. [`DEFAULT_MAX_CONNECTIONS`] by default.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Gives up on a request whose head has not arrived `timeout` after it started being read,
+    /// however steadily its bytes arrive, and on a connection where a single read or write
+    /// waits that long. [`DEFAULT_REQUEST_TIMEOUT`] by default.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Accepts connections on `listener` until it fails.
+    pub fn serve(&self, listener: TcpListener) -> CarResult<()> {
+        let slots = Arc::new((Mutex::new(0), Condvar::new()));
+        loop {
+            let (active, freed) = &*slots;
+            *freed
+                .wait_while(
+                    active.lock().unwrap_or_else(|err| err.into_inner()),
+                    |active| *active >= self.max_connections,
+                )
+                .unwrap_or_else(|err| err.into_inner()) += 1;
+            let slot = Slot(slots.clone());
+            let (stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(self.request_timeout))?;
+            stream.set_write_timeout(Some(self.request_timeout))?;
+            let server = self.clone();
+            std::thread::spawn(move || {
+                let _slot = slot;
+                let _ = server.handle(&stream, &stream);
+            });
+        }
+    }
+
+    /// Reads one request from `r` and writes the response to `w`. Fails with
+    /// [`CarError::DeadlineExceeded`] if the head of the request takes longer than the request
+    /// timeout to read.
+    ///
+    /// A `car` response is only started once its root has been found. If the export fails
+    /// after that, the error is returned and the body is left without its last chunk, so that
+    /// clients see it as cut short.
+    pub fn handle<R: Read, W: Write>(&self, r: R, mut w: W) -> CarResult<()> {
+        let deadline = Deadline::after(self.request_timeout);
+        let (request_line, accept, fits) =
+            read_head(DeadlineReader { r, deadline }).map_err(|err| match deadline.check() {
+                Ok(()) => err,
+                Err(exceeded) => exceeded,
+            })?;
+
+        let mut parts = request_line.split_whitespace();
+        let response = match (parts.next(), parts.next()) {
+            _ if !fits => Response::error(
+                431,
+                format!("request head longer than {} bytes", MAX_HEAD_LENGTH),
+            ),
+            (Some("GET"), Some(target)) => self.respond(target, &accept),
+            (Some(_), Some(_)) => Response::error(405, "only GET is supported".to_string()),
+            _ => Response::error(400, "malformed request line".to_string()),
+        };
+        write!(
+            w,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n",
+            response.status,
+            reason(response.status),
+            response.content_type
+        )?;
+        match response.body {
+            Body::Bytes(body) => {
+                write!(
+                    w,
+                    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )?;
+                w.write_all(&body)?;
+            }
+            Body::Car {
+                root,
+                range,
+                options,
+            } => {
+                w.write_all(b"Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n")?;
+                let mut body = BufWriter::with_capacity(CHUNK_SIZE, Chunked(&mut w));
+                let sources: &[&dyn BlockFetcher] = &[&*self.source];
+                match range {
+                    Some(range) => gather_entity_bytes(&root, range, sources, &options, &mut body)?,
+                    None => gather(&[root], sources, &options, &mut body)?,
+                }
+                body.flush()?;
+                drop(body);
+                w.write_all(b"0\r\n\r\n")?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    fn respond(&self, target: &str, accept: &str) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let cid = match path.strip_prefix("/ipfs/").map(str::parse::<Cid>) {
+            Some(Ok(cid)) => cid,
+            Some(Err(err)) => return Response::error(400, err.to_string()),
+            None => return Response::error(404, format!("no route for {}", path)),
+        };
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        };
+        let media = accept
+            .split(',')
+            .map(str::trim)
+            .find(|media| media.starts_with(RAW) || media.starts_with(CAR));
+        let format = match (param("format"), media) {
+            (Some(format), _) => format,
+            (None, Some(media)) if media.starts_with(CAR) => "car",
+            (None, _) => "raw",
+        };
+
+        let missing = || Response::error(404, format!("{} is not in the archive", cid));
+        match format {
+            "raw" => match self.source.fetch(&cid) {
+                Ok(Some(data)) => match HashPolicy::default().block(cid, data, 0) {
+                    Ok((block, _)) => Response {
+                        status: 200,
+                        content_type: RAW.to_string(),
+                        body: Body::Bytes(block.into_inner().1),
+                    },
+                    Err(err) => Response::error(500, err.to_string()),
+                },
+                Ok(None) => missing(),
+                Err(err) => Response::error(500, err.to_string()),
+            },
+            "car" => {
+                let params = media
+                    .filter(|media| media.starts_with(CAR))
+                    .map_or("", |media| &media[CAR.len()..]);
+                self.respond_car(cid, params, param("entity-bytes"))
+                    .unwrap_or_else(|err| match err {
+                        CarError::InvalidExportParameter(_) => {
+                            Response::error(400, err.to_string())
+                        }
+                        CarError::MissingBlock(missed) if missed == cid => missing(),
+                        _ => Response::error(500, err.to_string()),
+                    })
+            }
+            _ => Response::error(400, format!("unsupported format {}", format)),
+        }
+    }
+
+    /// Checks the parameters and the root of a `car` response before any of it is sent.
+    fn respond_car(
+        &self,
+        cid: Cid,
+        params: &str,
+        entity_bytes: Option<&str>,
+    ) -> CarResult<Response> {
+        let options = ExportOptions::from_params(params)?;
+        let range = entity_bytes.map(str::parse::<EntityBytes>).transpose()?;
+        let data = self
+            .source
+            .fetch(&cid)?
+            .ok_or(CarError::MissingBlock(cid))?;
+        HashPolicy::default().block(cid, data, 0)?;
+        Ok(Response {
+            status: 200,
+            content_type: options.content_type(),
+            body: Body::Car {
+                root: cid,
+                range,
+                options,
+            },
+        })
+    }
+}
+
+/// Reads the request line and the headers of a request, keeping the `Accept` header, and
+/// whether they ended within [`MAX_HEAD_LENGTH`] bytes.
+fn read_head<R: Read>(r: R) -> CarResult<(String, String, bool)> {
+    let mut head = BufReader::new(r).take(MAX_HEAD_LENGTH);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut accept = String::new();
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Ok((request_line, accept, head.limit() > 0));
+        }
+        if line.trim_end().is_empty() {
+            return Ok((request_line, accept, true));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("accept") {
+                accept = value.trim().to_string();
+            }
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(all(test, feature = "v2"))]
+mod tests {
+    use super::*;
+    use crate::blockstore::CarV2Store;
+    use crate::gateway::{read_chunked, HttpGateway};
+    use crate::index::IndexKind;
+    use crate::test_utils::{cids, diamond, temp_dir, unixfs_file};
+    use crate::v1::CarV1;
+    use crate::v2::CarV2;
+    use std::io::Cursor;
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn car_v2(car: &CarV1) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        CarV2::from_car_v1_with_index(car.clone(), Some(IndexKind::MultihashSorted))
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    fn server(car: &CarV1) -> CarServer {
+        CarServer::new(CarV2Store::new(Cursor::new(car_v2(car))).unwrap())
+    }
+
+    fn get(server: &CarServer, request: &str) -> (String, Vec<u8>) {
+        let mut response = vec![];
+        server.handle(request.as_bytes(), &mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let mut body = response[end + 4..].to_vec();
+        if head.contains("Transfer-Encoding: chunked") {
+            let mut chunks = &body[..];
+            let mut dechunked = vec![];
+            read_chunked(&mut chunks, &mut dechunked, u64::MAX).unwrap();
+            // Only the blank line ending the body is left after its last chunk.
+            assert_eq!(chunks, b"\r\n");
+            body = dechunked;
+        }
+        (head, body)
+    }
+
+    #[test]
+    fn it_serves_blocks_and_dags() {
+        let car = diamond();
+        let root = car.header.roots[0];
+        let server = server(&car);
+
+        let (head, body) = get(
+            &server,
+            &format!("GET /ipfs/{}?format=car HTTP/1.1\r\n\r\n", root),
+        );
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("order=dfs; dups=n"));
+        let exported = CarV1::from_reader(&body[..]).unwrap();
+        assert_eq!(exported.header.roots, vec![root]);
+        assert_eq!(exported.blocks.len(), 4);
+
+        let (head, body) = get(
+            &server,
+            &format!(
+                "GET /ipfs/{} HTTP/1.1\r\nAccept: application/vnd.ipld.car; order=unk\r\n\r\n",
+                root
+            ),
+        );
+        assert!(head.contains("order=unk"));
+        assert_eq!(CarV1::from_reader(&body[..]).unwrap().blocks.len(), 4);
+
+        let missing = crate::test_utils::raw(b"missing");
+        for format in ["raw", "car"] {
+            let (head, _) = get(
+                &server,
+                &format!(
+                    "GET /ipfs/{}?format={} HTTP/1.1\r\n\r\n",
+                    missing.cid(),
+                    format
+                ),
+            );
+            assert!(head.starts_with("HTTP/1.1 404"));
+        }
+        let (head, _) = get(&server, "GET /ipfs/nope HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400"));
+        let (head, _) = get(&server, &format!("POST /ipfs/{} HTTP/1.1\r\n\r\n", root));
+        assert!(head.starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn it_streams_dags_larger_than_a_chunk() {
+        /// Keeps what is written along with the size of each write.
+        #[derive(Default)]
+        struct Writes {
+            bytes: Vec<u8>,
+            sizes: Vec<usize>,
+        }
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.bytes.extend_from_slice(buf);
+                self.sizes.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let chunks: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; CHUNK_SIZE / 4]).collect();
+        let car = unixfs_file(&chunks.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        let root = car.header.roots[0];
+        let request = format!("GET /ipfs/{}?format=car HTTP/1.1\r\n\r\n", root);
+        let mut writes = Writes::default();
+        server(&car)
+            .handle(request.as_bytes(), &mut writes)
+            .unwrap();
+
+        assert!(writes.bytes.len() > 2 * CHUNK_SIZE);
+        assert!(writes.sizes.iter().all(|size| *size <= CHUNK_SIZE));
+        let (head, body) = get(&server(&car), &request);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        let exported = CarV1::from_reader(&body[..]).unwrap();
+        assert_eq!(cids(&exported), cids(&car));
+    }
+
+    #[test]
+    fn it_refuses_long_request_heads() {
+        let car = diamond();
+        let request = format!(
+            "GET /ipfs/{} HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            car.header.roots[0],
+            "a".repeat(MAX_HEAD_LENGTH as usize)
+        );
+        let (head, _) = get(&server(&car), &request);
+        assert!(head.starts_with("HTTP/1.1 431"));
+    }
+
+    #[test]
+    fn it_gives_up_on_slow_request_heads() {
+        /// Yields a byte at a time, waiting before each.
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(10));
+                let read = (&self.0[..self.0.len().min(1)]).read(buf)?;
+                self.0 = &self.0[read..];
+                Ok(read)
+            }
+        }
+
+        let car = diamond();
+        let request = format!("GET /ipfs/{} HTTP/1.1\r\n\r\n", car.header.roots[0]);
+        let server = server(&car).with_request_timeout(Duration::from_millis(50));
+        let mut response = vec![];
+        assert!(matches!(
+            server.handle(Trickle(request.as_bytes()), &mut response),
+            Err(CarError::DeadlineExceeded)
+        ));
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn it_opens_car_v1_and_car_v2_files() {
+        let car = diamond();
+        let dir = temp_dir("serve-open");
+        let mut v1 = vec![];
+        car.write_to(&mut v1).unwrap();
+        std::fs::write(dir.join("v1.car"), v1).unwrap();
+        std::fs::write(dir.join("v2.car"), car_v2(&car)).unwrap();
+
+        for name in ["v1.car", "v2.car"] {
+            let server = CarServer::open(&dir.join(name)).unwrap();
+            for block in &car.blocks {
+                let (head, body) = get(
+                    &server,
+                    &format!("GET /ipfs/{} HTTP/1.1\r\n\r\n", block.cid()),
+                );
+                assert!(head.starts_with("HTTP/1.1 200 OK"));
+                assert_eq!(body, block.data());
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_serves_raw_blocks_to_gateway_clients() {
+        let car = diamond();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = server(&car);
+        std::thread::spawn(move || server.serve(listener));
+
+        let gateway = HttpGateway::new(&url).unwrap();
+        for (cid, block) in cids(&car).iter().zip(&car.blocks) {
+            assert_eq!(gateway.fetch(cid).unwrap().as_deref(), Some(block.data()));
+        }
+    }
+
+    #[test]
+    fn it_caps_connections() {
+        let car = diamond();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server(&car).with_max_connections(1);
+        std::thread::spawn(move || server.serve(listener));
+
+        // An idle connection holds the only slot until it closes.
+        let idle = TcpStream::connect(addr).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let cid = car.header.roots[0];
+        std::thread::spawn(move || {
+            let gateway = HttpGateway::new(&format!("http://{}", addr)).unwrap();
+            let _ = tx.send(gateway.fetch(&cid).unwrap());
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(rx.try_recv().is_err());
+        drop(idle);
+        assert!(rx.recv_timeout(Duration::from_secs(10)).unwrap().is_some());
+    }
+}
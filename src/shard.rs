@@ -0,0 +1,209 @@
+//! Archives split into ordered shards, read as one logical archive, see [`ShardedCar`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::compare::BlockStore;
+use crate::gateway::BlockFetcher;
+use crate::traversal::{self, Visitor};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{CarResult, ContentArchive, Deadline};
+
+/// The shards of an archive, as a splitter leaves them, looked up and traversed as a single
+/// archive whose links cross freely from one shard to another.
+///
+/// A block stored in several shards is taken from the first.
+#[derive(Debug, Clone)]
+pub struct ShardedCar {
+    shards: Vec<CarV1>,
+    /// The shard and position of every block.
+    blocks: HashMap<Cid, (usize, usize)>,
+}
+
+impl ShardedCar {
+    /// Joins `shards`, in order.
+    pub fn new(shards: Vec<CarV1>) -> Self {
+        let mut blocks = HashMap::new();
+        for (shard, car) in shards.iter().enumerate() {
+            for (i, block) in car.blocks.iter().enumerate() {
+                blocks.entry(*block.cid()).or_insert((shard, i));
+            }
+        }
+        Self { shards, blocks }
+    }
+
+    /// Reads the CARv1 or CARv2 shards at `paths`, in order.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> CarResult<Self> {
+        let shards = paths
+            .iter()
+            .map(|path| {
+                let r = BufReader::new(File::open(path)?);
+                Ok(ContentArchive::read_bytes(r)?.into_car_v1())
+            })
+            .collect::<CarResult<_>>()?;
+        Ok(Self::new(shards))
+    }
+
+    pub fn shards(&self) -> &[CarV1] {
+        &self.shards
+    }
+
+    /// The roots of the first shard, where splitters put the roots of the archive they split.
+    pub fn roots(&self) -> &[Cid] {
+        self.shards
+            .first()
+            .map_or(&[], |shard| &shard.header.roots[..])
+    }
+
+    pub fn get_block(&self, cid: &Cid) -> Option<&Block<DefaultParams>> {
+        let (shard, i) = *self.blocks.get(cid)?;
+        Some(&self.shards[shard].blocks[i])
+    }
+
+    /// The position in [`ShardedCar::shards`] of the shard a block is taken from.
+    pub fn shard_of(&self, cid: &Cid) -> Option<usize> {
+        self.blocks.get(cid).map(|(shard, _)| *shard)
+    }
+
+    /// How many distinct blocks the shards hold.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Like [`traversal::traverse`] over the blocks of every shard.
+    pub fn traverse<V: Visitor + ?Sized>(&self, roots: &[Cid], visitor: &mut V) -> CarResult<()> {
+        self.traverse_with_deadline(roots, Deadline::none(), visitor)
+    }
+
+    /// Like [`traversal::traverse_with_deadline`] over the blocks of every shard.
+    pub fn traverse_with_deadline<V: Visitor + ?Sized>(
+        &self,
+        roots: &[Cid],
+        deadline: Deadline,
+        visitor: &mut V,
+    ) -> CarResult<()> {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|(cid, &(shard, i))| (cid, &self.shards[shard].blocks[i]))
+            .collect();
+        traversal::traverse_blocks(&blocks, roots, deadline, visitor)
+    }
+
+    /// Joins the shards into one archive with [`ShardedCar::roots`], holding every block once,
+    /// in shard order.
+    pub fn into_car_v1(self) -> CarV1 {
+        let roots = self.roots().to_vec();
+        let blocks = self
+            .shards
+            .into_iter()
+            .enumerate()
+            .flat_map(|(shard, car)| {
+                car.blocks
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, block)| (shard, i, block))
+            })
+            .filter(|(shard, i, block)| self.blocks.get(block.cid()) == Some(&(*shard, *i)))
+            .map(|(_, _, block)| block)
+            .collect();
+        CarV1::new(CarHeaderV1 { roots }, blocks)
+    }
+}
+
+impl BlockFetcher for ShardedCar {
+    fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+        Ok(self.get_block(cid).map(|block| block.data().to_vec()))
+    }
+}
+
+impl BlockStore for ShardedCar {
+    /// Every distinct block, in shard order.
+    fn cids(&self) -> CarResult<Vec<Cid>> {
+        let mut cids: Vec<_> = self.blocks.iter().collect();
+        cids.sort_by_key(|(_, position)| **position);
+        Ok(cids.into_iter().map(|(cid, _)| *cid).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond};
+    use crate::CarError;
+    use std::ops::ControlFlow;
+
+    fn shards() -> Vec<CarV1> {
+        let car = diamond();
+        // The root and the left node first, the leaf in both shards.
+        let pick = |roots: Vec<Cid>, positions: &[usize]| {
+            let blocks = positions.iter().map(|&i| car.blocks[i].clone()).collect();
+            CarV1::new(CarHeaderV1 { roots }, blocks)
+        };
+        vec![
+            pick(car.header.roots.clone(), &[2, 3, 0]),
+            pick(vec![], &[0, 1]),
+        ]
+    }
+
+    #[test]
+    fn it_traverses_across_shards() {
+        let car = diamond();
+        let [leaf, right, root, left] = cids(&car)[..] else {
+            unreachable!()
+        };
+        let sharded = ShardedCar::new(shards());
+        assert_eq!(sharded.roots(), &[root]);
+        assert_eq!(sharded.len(), 4);
+        assert_eq!(sharded.shard_of(&leaf), Some(0));
+        assert_eq!(sharded.shard_of(&right), Some(1));
+        assert_eq!(sharded.get_block(&right), Some(&car.blocks[1]));
+        assert_eq!(sharded.cids().unwrap(), vec![root, left, leaf, right]);
+
+        let mut visited = vec![];
+        sharded
+            .traverse(&[root], &mut |_, cid: &Cid, _: &Block<DefaultParams>| {
+                visited.push(*cid);
+                ControlFlow::Continue(traversal::Visit::Descend)
+            })
+            .unwrap();
+        assert_eq!(visited, vec![root, left, leaf, right]);
+
+        let joined = sharded.into_car_v1();
+        assert_eq!(joined.header.roots, vec![root]);
+        assert_eq!(cids(&joined), vec![root, left, leaf, right]);
+
+        let mut first = shards();
+        first.pop();
+        let result = ShardedCar::new(first).traverse(&[root], &mut |_, _: &Cid, _: &_| {
+            ControlFlow::Continue(traversal::Visit::Descend)
+        });
+        assert!(matches!(result, Err(CarError::MissingBlock(cid)) if cid == right));
+    }
+
+    #[test]
+    #[cfg(feature = "v1")]
+    fn it_opens_shard_files() {
+        let dir = crate::test_utils::temp_dir("shards");
+        let paths: Vec<_> = shards()
+            .iter()
+            .enumerate()
+            .map(|(i, shard)| {
+                let path = dir.join(format!("{}.car", i));
+                shard.write_to(File::create(&path).unwrap()).unwrap();
+                path
+            })
+            .collect();
+        let sharded = ShardedCar::open(&paths).unwrap();
+        assert_eq!(sharded.shards().len(), 2);
+        assert_eq!(sharded.len(), 4);
+    }
+}
@@ -0,0 +1,191 @@
+//! Detached ed25519 signatures over whole archives, kept in a `.sig` sidecar file.
+//!
+//! An archive is signed by its CID (see [`crate::repo::car_cid`]), so a signature also names
+//! the exact bytes it covers.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::Ipld;
+
+use crate::repo::car_cid;
+use crate::{CarError, CarResult, ContentArchive};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// A signature by `public_key` over the CID of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveSignature {
+    pub archive: Cid,
+    pub public_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl ArchiveSignature {
+    /// Signs the archive `bytes`.
+    pub fn sign(bytes: &[u8], key: &SigningKey) -> Self {
+        let archive = car_cid(bytes);
+        Self {
+            archive,
+            public_key: key.verifying_key(),
+            signature: key.sign(&archive.to_bytes()),
+        }
+    }
+
+    /// Checks that the signature is valid and covers exactly `bytes`.
+    pub fn verify(&self, bytes: &[u8]) -> CarResult<()> {
+        let archive = car_cid(bytes);
+        if archive != self.archive {
+            return Err(CarError::InvalidSignature(format!(
+                "signature covers {}, not {}",
+                self.archive, archive
+            )));
+        }
+        self.public_key
+            .verify(&archive.to_bytes(), &self.signature)
+            .map_err(|err| CarError::InvalidSignature(err.to_string()))
+    }
+
+    /// Like [`ArchiveSignature::verify`], also requiring the signature to be made by `trusted`.
+    pub fn verify_by(&self, bytes: &[u8], trusted: &VerifyingKey) -> CarResult<()> {
+        if &self.public_key != trusted {
+            return Err(CarError::InvalidSignature(
+                "signed by an untrusted key".to_string(),
+            ));
+        }
+        self.verify(bytes)
+    }
+
+    /// Encodes the signature as a dag-cbor map of `archive`, `key` and `signature`.
+    pub fn encode(&self) -> CarResult<Vec<u8>> {
+        let mut map = BTreeMap::new();
+        map.insert("archive".to_string(), Ipld::Link(self.archive));
+        map.insert(
+            "key".to_string(),
+            Ipld::Bytes(self.public_key.to_bytes().to_vec()),
+        );
+        map.insert(
+            "signature".to_string(),
+            Ipld::Bytes(self.signature.to_bytes().to_vec()),
+        );
+        Ok(DagCborCodec.encode(&Ipld::Map(map))?)
+    }
+
+    /// Decodes a signature encoded by [`ArchiveSignature::encode`].
+    pub fn decode(bytes: &[u8]) -> CarResult<Self> {
+        let invalid = |field: &str| CarError::InvalidSignature(format!("malformed {}", field));
+        let map = DagCborCodec.decode::<Ipld>(bytes)?;
+        let archive = match map.get("archive") {
+            Ok(Ipld::Link(cid)) => *cid,
+            _ => return Err(invalid("archive")),
+        };
+        let public_key = match map.get("key") {
+            Ok(Ipld::Bytes(key)) => <[u8; 32]>::try_from(key.as_slice())
+                .ok()
+                .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+                .ok_or_else(|| invalid("key"))?,
+            _ => return Err(invalid("key")),
+        };
+        let signature = match map.get("signature") {
+            Ok(Ipld::Bytes(signature)) => {
+                Signature::from_slice(signature).map_err(|_| invalid("signature"))?
+            }
+            _ => return Err(invalid("signature")),
+        };
+        Ok(Self {
+            archive,
+            public_key,
+            signature,
+        })
+    }
+}
+
+/// The sidecar of the archive at `path`: the same path with `.sig` appended.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = OsString::from(path.as_os_str());
+    sidecar.push(".sig");
+    PathBuf::from(sidecar)
+}
+
+/// Signs the archive file at `path` and writes the signature to its sidecar.
+pub fn sign_file(path: &Path, key: &SigningKey) -> CarResult<ArchiveSignature> {
+    let signature = ArchiveSignature::sign(&fs::read(path)?, key);
+    fs::write(sidecar_path(path), signature.encode()?)?;
+    Ok(signature)
+}
+
+/// Reads the archive file at `path` after checking that its sidecar holds a valid signature by
+/// `trusted` over it.
+pub fn read_signed_file(path: &Path, trusted: &VerifyingKey) -> CarResult<ContentArchive> {
+    let bytes = fs::read(path)?;
+    let signature = match fs::read(sidecar_path(path)) {
+        Ok(signature) => ArchiveSignature::decode(&signature)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(CarError::InvalidSignature(format!(
+                "{} is not signed",
+                path.display()
+            )))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    signature.verify_by(&bytes, trusted)?;
+    ContentArchive::read_bytes(Cursor::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{diamond, temp_dir};
+
+    #[test]
+    fn it_signs_and_verifies_archives() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let mut bytes = vec![];
+        diamond().write_to(&mut bytes).unwrap();
+
+        let signature = ArchiveSignature::sign(&bytes, &key);
+        signature.verify_by(&bytes, &key.verifying_key()).unwrap();
+        let decoded = ArchiveSignature::decode(&signature.encode().unwrap()).unwrap();
+        assert_eq!(decoded, signature);
+        assert!(signature.verify_by(&bytes, &other.verifying_key()).is_err());
+
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            signature.verify(&tampered),
+            Err(CarError::InvalidSignature(_))
+        ));
+        let forged = ArchiveSignature {
+            public_key: other.verifying_key(),
+            ..signature
+        };
+        assert!(forged.verify(&bytes).is_err());
+    }
+
+    #[test]
+    fn it_reads_signed_files() {
+        let dir = temp_dir("signing");
+        let path = dir.join("a.car");
+        let key = SigningKey::from_bytes(&[7; 32]);
+        diamond()
+            .write_to(fs::File::create(&path).unwrap())
+            .unwrap();
+        assert!(read_signed_file(&path, &key.verifying_key()).is_err());
+
+        sign_file(&path, &key).unwrap();
+        assert!(sidecar_path(&path).ends_with("a.car.sig"));
+        match read_signed_file(&path, &key.verifying_key()).unwrap() {
+            ContentArchive::V1(car) => assert_eq!(car.blocks, diamond().blocks),
+            other => panic!("Expected a CARv1, got {:?}", other),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
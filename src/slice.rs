@@ -0,0 +1,215 @@
+//! Reading archives held in memory without copying their blocks, see [`CarSlice`].
+
+use std::io;
+
+use cid::Cid;
+#[cfg(feature = "ipld")]
+use libipld::{Block, DefaultParams};
+
+use crate::block::{BlockLocation, CarBlock, CarBlockReader};
+use crate::{CarError, CarResult};
+
+/// A CARv1, or a CARv2 and its payload, in a byte slice such as a memory-mapped file, whose
+/// blocks borrow their data from the slice rather than copying it, see [`CarSlice::blocks`].
+///
+/// Sections are split into CIDs and data as [`CarBlockReader`] splits them, without decoding or
+/// verifying them.
+#[derive(Debug, Clone)]
+pub struct CarSlice<'a> {
+    bytes: &'a [u8],
+    roots: Vec<Cid>,
+    /// Where the first section starts.
+    start: usize,
+    /// Where the sections end: the end of the slice, or of the payload of a CARv2.
+    end: usize,
+}
+
+/// A block of a [`CarSlice`], borrowing its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRef<'a> {
+    pub cid: Cid,
+    pub data: &'a [u8],
+}
+
+impl BlockRef<'_> {
+    /// Copies the block into a [`CarBlock`].
+    pub fn to_car_block(&self) -> CarBlock {
+        CarBlock::new(self.cid, self.data.to_vec())
+    }
+
+    /// Copies the block into a libipld block, checking the data against the CID as
+    /// [`crate::HashPolicy::default`] does.
+    #[cfg(feature = "ipld")]
+    pub fn to_block(&self) -> CarResult<Block<DefaultParams>> {
+        self.to_car_block().into_block()
+    }
+}
+
+impl<'a> CarSlice<'a> {
+    /// Reads the header of the archive in `bytes`, and of the payload of a CARv2.
+    ///
+    /// Fails with [`CarError::InvalidFormat`] if the payload of a CARv2 ends before its header
+    /// does or after the slice.
+    pub fn new(bytes: &'a [u8]) -> CarResult<Self> {
+        let reader = CarBlockReader::new(bytes)?;
+        let start = reader.position();
+        let end = reader.end().unwrap_or(bytes.len() as u64);
+        if start > end || end > bytes.len() as u64 {
+            return Err(CarError::InvalidFormat);
+        }
+        Ok(Self {
+            bytes,
+            roots: reader.roots().to_vec(),
+            start: start as usize,
+            end: end as usize,
+        })
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// The blocks in the order they are stored. Iteration stops after the first error.
+    pub fn blocks(&self) -> SliceBlocks<'a> {
+        SliceBlocks {
+            bytes: &self.bytes[..self.end],
+            offset: self.start,
+        }
+    }
+
+    /// The block whose section starts at `offset` from the start of the archive, as
+    /// [`BlockLocation::offset`] counts it, so for a CARv2 an offset from its index plus the
+    /// `data_offset` of its header. Fails with [`CarError::IndexOutOfBounds`] if `offset`
+    /// is outside the sections.
+    pub fn block_at(&self, offset: u64) -> CarResult<BlockRef<'a>> {
+        if offset < self.start as u64 || offset >= self.end as u64 {
+            return Err(CarError::IndexOutOfBounds(offset));
+        }
+        let mut blocks = SliceBlocks {
+            bytes: &self.bytes[..self.end],
+            offset: offset as usize,
+        };
+        match blocks.next_block_with_location()? {
+            Some((block, _)) => Ok(block),
+            None => Err(CarError::IndexOutOfBounds(offset)),
+        }
+    }
+}
+
+/// The blocks of a [`CarSlice`], see [`CarSlice::blocks`].
+#[derive(Debug, Clone)]
+pub struct SliceBlocks<'a> {
+    /// The archive, up to the end of its sections.
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SliceBlocks<'a> {
+    /// The next block, along with where its section is in the archive.
+    pub fn next_block_with_location(&mut self) -> CarResult<Option<(BlockRef<'a>, BlockLocation)>> {
+        let offset = self.offset;
+        let mut rest = &self.bytes[offset..];
+        // Reading stops here on an error, as it does at the end.
+        self.offset = self.bytes.len();
+        let length = match crate::read_varint_lenient(&mut rest)? {
+            Some((length, _)) => length,
+            None => return Ok(None),
+        };
+        if length > rest.len() as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut data = &rest[..length as usize];
+        let cid = Cid::read_bytes(&mut data)?;
+        let end = self.bytes.len() - rest.len() + length as usize;
+        self.offset = end;
+        let location = BlockLocation {
+            offset: offset as u64,
+            length: (end - offset) as u64,
+        };
+        Ok(Some((BlockRef { cid, data }, location)))
+    }
+}
+
+impl<'a> Iterator for SliceBlocks<'a> {
+    type Item = CarResult<BlockRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block_with_location()
+            .map(|block| block.map(|(block, _)| block))
+            .transpose()
+    }
+}
+
+#[cfg(all(test, feature = "ipld"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::diamond;
+
+    #[test]
+    fn it_borrows_blocks_from_the_slice() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let slice = CarSlice::new(&bytes).unwrap();
+        assert_eq!(slice.roots(), &car.header.roots[..]);
+
+        let mut blocks = slice.blocks();
+        let mut reader = CarBlockReader::new(&bytes[..]).unwrap();
+        for expected in &car.blocks {
+            let (block, location) = blocks.next_block_with_location().unwrap().unwrap();
+            assert_eq!(
+                reader.next_block_with_location().unwrap().unwrap().1,
+                location
+            );
+            assert_eq!(block.to_block().unwrap(), *expected);
+            // The data is the slice's own bytes.
+            let start = bytes.as_ptr() as usize;
+            let at = block.data.as_ptr() as usize - start;
+            assert!(at < bytes.len());
+            assert_eq!(slice.block_at(location.offset).unwrap(), block);
+        }
+        assert!(blocks.next().is_none());
+        assert!(matches!(
+            slice.block_at(bytes.len() as u64),
+            Err(CarError::IndexOutOfBounds(_))
+        ));
+
+        let truncated = CarSlice::new(&bytes[..bytes.len() - 1]).unwrap();
+        let read: Vec<_> = truncated.blocks().collect();
+        assert_eq!(read.len(), car.blocks.len());
+        assert!(read.last().unwrap().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn it_reads_car_v2_payloads() {
+        use crate::v2::CarV2Reader;
+        use std::io::Cursor;
+
+        let bytes = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let slice = CarSlice::new(bytes).unwrap();
+        let blocks: Vec<_> = slice.blocks().collect::<CarResult<_>>().unwrap();
+        assert_eq!(blocks.len(), 5);
+
+        // Through the index, whose offsets are from the start of the payload.
+        let reader = CarV2Reader::new(Cursor::new(&bytes[..])).unwrap();
+        for block in &blocks {
+            let offset = reader.index().lookup(&block.cid).unwrap();
+            let found = slice
+                .block_at(reader.header().data_offset + offset)
+                .unwrap();
+            assert_eq!(found, *block);
+        }
+
+        // The payload ends before its header, or after the archive.
+        let data_size = 11 + 16 + 8;
+        for size in [0, bytes.len() as u64] {
+            let mut bytes = bytes.to_vec();
+            bytes[data_size..data_size + 8].copy_from_slice(&size.to_le_bytes());
+            assert!(matches!(
+                CarSlice::new(&bytes),
+                Err(CarError::InvalidFormat)
+            ));
+        }
+    }
+}
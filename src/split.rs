@@ -0,0 +1,261 @@
+//! Splitting an archive into shards of bounded size, named by their CIDs and listed in a
+//! [`SplitManifest`], see [`split`]. The shards are read back as one archive with
+//! [`crate::shard::ShardedCar`].
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::Ipld;
+
+use crate::block::write_car_v1_block;
+use crate::repo::{car_cid, car_path};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{json, CarError, CarResult};
+
+/// The name of the manifest [`write_split`] writes next to the shards.
+pub const MANIFEST_NAME: &str = "manifest.cbor";
+
+/// The shards of a split archive, in order, and what each holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitManifest {
+    /// The roots of the archive that was split.
+    pub roots: Vec<Cid>,
+    pub shards: Vec<ShardInfo>,
+}
+
+/// A shard of a split archive, stored as `<cid>.car`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardInfo {
+    /// The CID of the shard file, see [`car_cid`].
+    pub cid: Cid,
+    pub size: u64,
+    /// The roots of the archive whose blocks are in the shard.
+    pub roots: Vec<Cid>,
+    pub blocks: Vec<Cid>,
+}
+
+/// Splits `car` into CARv1 shards of at most `max_size` bytes, keeping the order of its blocks.
+/// The first shard carries the roots and the others none; a block too large for `max_size`
+/// gets a shard of its own. The same archive always splits into the same shards.
+pub fn split(car: &CarV1, max_size: u64) -> CarResult<(Vec<Vec<u8>>, SplitManifest)> {
+    let mut shards = vec![];
+    let mut infos = vec![];
+    let mut shard = header(car.header.roots.clone())?;
+    let mut blocks = vec![];
+    for block in &car.blocks {
+        let mut section = vec![];
+        write_car_v1_block(&mut section, block)?;
+        if !blocks.is_empty() && (shard.len() + section.len()) as u64 > max_size {
+            infos.push(shard_info(car, &shard, mem::take(&mut blocks)));
+            shards.push(mem::replace(&mut shard, header(vec![])?));
+        }
+        shard.extend_from_slice(&section);
+        blocks.push(*block.cid());
+    }
+    if !blocks.is_empty() || shards.is_empty() {
+        infos.push(shard_info(car, &shard, blocks));
+        shards.push(shard);
+    }
+    let manifest = SplitManifest {
+        roots: car.header.roots.clone(),
+        shards: infos,
+    };
+    Ok((shards, manifest))
+}
+
+fn header(roots: Vec<Cid>) -> CarResult<Vec<u8>> {
+    let mut header = vec![];
+    CarHeaderV1 { roots }.write_to(&mut header)?;
+    Ok(header)
+}
+
+fn shard_info(car: &CarV1, shard: &[u8], blocks: Vec<Cid>) -> ShardInfo {
+    ShardInfo {
+        cid: car_cid(shard),
+        size: shard.len() as u64,
+        roots: car
+            .header
+            .roots
+            .iter()
+            .filter(|root| blocks.contains(root))
+            .copied()
+            .collect(),
+        blocks,
+    }
+}
+
+/// Splits `car` as [`split`] does into `dir`, writing each shard as `<cid>.car` and the encoded
+/// manifest as [`MANIFEST_NAME`].
+pub fn write_split(car: &CarV1, max_size: u64, dir: &Path) -> CarResult<SplitManifest> {
+    let (shards, manifest) = split(car, max_size)?;
+    fs::create_dir_all(dir)?;
+    for (shard, info) in shards.iter().zip(&manifest.shards) {
+        fs::write(car_path(dir, &info.cid), shard)?;
+    }
+    fs::write(dir.join(MANIFEST_NAME), manifest.encode()?)?;
+    Ok(manifest)
+}
+
+impl SplitManifest {
+    /// The shard holding the block `cid`.
+    pub fn shard_of(&self, cid: &Cid) -> Option<&ShardInfo> {
+        self.shards.iter().find(|shard| shard.blocks.contains(cid))
+    }
+
+    /// Encodes the manifest as a dag-cbor map.
+    pub fn encode(&self) -> CarResult<Vec<u8>> {
+        let links = |cids: &[Cid]| Ipld::List(cids.iter().copied().map(Ipld::Link).collect());
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let mut map = BTreeMap::new();
+                map.insert("cid".to_string(), Ipld::Link(shard.cid));
+                map.insert("size".to_string(), Ipld::Integer(shard.size.into()));
+                map.insert("roots".to_string(), links(&shard.roots));
+                map.insert("blocks".to_string(), links(&shard.blocks));
+                Ipld::Map(map)
+            })
+            .collect();
+        let mut map = BTreeMap::new();
+        map.insert("roots".to_string(), links(&self.roots));
+        map.insert("shards".to_string(), Ipld::List(shards));
+        Ok(DagCborCodec.encode(&Ipld::Map(map))?)
+    }
+
+    /// Decodes a manifest encoded by [`SplitManifest::encode`].
+    pub fn decode(bytes: &[u8]) -> CarResult<Self> {
+        let map = DagCborCodec.decode::<Ipld>(bytes)?;
+        let invalid = |field: &str| CarError::InvalidManifest(format!("malformed {}", field));
+        let links = |node: &Ipld, field: &str| match node.get(field) {
+            Ok(Ipld::List(cids)) => cids
+                .iter()
+                .map(|cid| match cid {
+                    Ipld::Link(cid) => Ok(*cid),
+                    _ => Err(invalid(field)),
+                })
+                .collect::<CarResult<Vec<_>>>(),
+            _ => Err(invalid(field)),
+        };
+        let shards = match map.get("shards") {
+            Ok(Ipld::List(shards)) => shards
+                .iter()
+                .map(|shard| {
+                    Ok(ShardInfo {
+                        cid: match shard.get("cid") {
+                            Ok(Ipld::Link(cid)) => *cid,
+                            _ => return Err(invalid("cid")),
+                        },
+                        size: match shard.get("size") {
+                            Ok(Ipld::Integer(size)) => {
+                                u64::try_from(*size).map_err(|_| invalid("size"))?
+                            }
+                            _ => return Err(invalid("size")),
+                        },
+                        roots: links(shard, "roots")?,
+                        blocks: links(shard, "blocks")?,
+                    })
+                })
+                .collect::<CarResult<_>>()?,
+            _ => return Err(invalid("shards")),
+        };
+        Ok(Self {
+            roots: links(&map, "roots")?,
+            shards,
+        })
+    }
+
+    /// Renders the manifest as a JSON object with the field names of [`SplitManifest::encode`].
+    pub fn to_json(&self) -> String {
+        let shards: Vec<String> = self
+            .shards
+            .iter()
+            .map(|shard| {
+                format!(
+                    "{{\"cid\":\"{}\",\"size\":{},\"roots\":{},\"blocks\":{}}}",
+                    shard.cid,
+                    shard.size,
+                    json::cids(&shard.roots),
+                    json::cids(&shard.blocks)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"roots\":{},\"shards\":[{}]}}",
+            json::cids(&self.roots),
+            shards.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::ShardedCar;
+    use crate::test_utils::{cids, diamond, raw, temp_dir};
+
+    #[test]
+    fn it_splits_archives_into_named_shards() {
+        let mut car = diamond();
+        car.put_block(raw(&[7; 200]));
+        let [leaf, right, root, left, large] = cids(&car)[..] else {
+            unreachable!()
+        };
+        let (shards, manifest) = split(&car, 120).unwrap();
+        assert_eq!(split(&car, 120).unwrap().1, manifest);
+        assert!(shards.len() > 2);
+        for (shard, info) in shards.iter().zip(&manifest.shards) {
+            assert_eq!(info.cid, car_cid(shard));
+            assert_eq!(info.size, shard.len() as u64);
+            assert!(info.size <= 120 || info.blocks.len() == 1);
+        }
+        let listed: Vec<Cid> = manifest
+            .shards
+            .iter()
+            .flat_map(|shard| shard.blocks.clone())
+            .collect();
+        assert_eq!(listed, vec![leaf, right, root, left, large]);
+        assert_eq!(manifest.shard_of(&root).unwrap().roots, vec![root]);
+        assert_eq!(manifest.shard_of(&large).unwrap().blocks, vec![large]);
+        assert_eq!(manifest.roots, vec![root]);
+
+        let shards: Vec<CarV1> = shards
+            .iter()
+            .map(|shard| CarV1::from_reader(&shard[..]).unwrap())
+            .collect();
+        assert_eq!(shards[0].header.roots, vec![root]);
+        assert!(shards[1..]
+            .iter()
+            .all(|shard| shard.header.roots.is_empty()));
+        assert_eq!(ShardedCar::new(shards).into_car_v1().blocks, car.blocks);
+
+        let (shards, whole) = split(&car, u64::MAX).unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(whole.shards[0].roots, vec![root]);
+    }
+
+    #[test]
+    fn it_writes_shards_and_their_manifest() {
+        let car = diamond();
+        let dir = temp_dir("split");
+        let manifest = write_split(&car, 100, &dir).unwrap();
+        let encoded = fs::read(dir.join(MANIFEST_NAME)).unwrap();
+        assert_eq!(SplitManifest::decode(&encoded).unwrap(), manifest);
+        let paths: Vec<_> = manifest
+            .shards
+            .iter()
+            .map(|shard| car_path(&dir, &shard.cid))
+            .collect();
+        assert_eq!(ShardedCar::open(&paths).unwrap().len(), car.blocks.len());
+
+        let json = manifest.to_json();
+        assert!(json.starts_with(&format!("{{\"roots\":[\"{}\"]", car.header.roots[0])));
+        assert!(json.contains(&format!("\"cid\":\"{}\"", manifest.shards[0].cid)));
+        assert!(SplitManifest::decode(&DagCborCodec.encode(&Ipld::Null).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! Size and shape statistics of the DAGs stored in an archive, see [`dag_stats`], and of the
+//! archive as a whole, see [`archive_stats`] and [`write_stats`].
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::ops::ControlFlow;
+
+use libipld::cid::Cid;
+
+use crate::table::multicodec_name;
+use crate::traversal;
+use crate::v1::CarV1;
+use crate::{CarResult, Deadline};
+
+/// Number of subtrees reported in [`DagStats::largest_subtrees`].
+pub const LARGEST_SUBTREES: usize = 10;
+
+/// The width of the longest bar of a histogram written by [`write_stats`].
+const BAR_WIDTH: usize = 40;
+
+/// Statistics of the DAG below a root, see [`dag_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DagStats {
+    /// Number of distinct blocks.
+    pub blocks: usize,
+    /// Sum of the data sizes of the distinct blocks.
+    pub total_size: u64,
+    /// Length in links of the longest path from the root.
+    pub max_depth: usize,
+    /// Number of blocks by number of links.
+    pub branching: BTreeMap<usize, usize>,
+    /// The largest subtrees below the root and their tree sizes, largest first.
+    pub largest_subtrees: Vec<(Cid, u64)>,
+}
+
+/// Computes [`DagStats`] for the DAG below `root`.
+///
+/// Subtree sizes are tree sizes, like UnixFS `Tsize`: a block shared by several parents counts
+/// towards each of them.
+pub fn dag_stats(car: &CarV1, root: &Cid) -> CarResult<DagStats> {
+    let mut order = vec![];
+    let mut nodes: HashMap<Cid, (u64, Vec<Cid>)> = HashMap::new();
+    traversal::walk(
+        car,
+        vec![(*root, ())],
+        false,
+        Deadline::none(),
+        |_, block, _| {
+            let links = traversal::links(block)?;
+            order.push(*block.cid());
+            nodes.insert(*block.cid(), (block.data().len() as u64, links.clone()));
+            Ok(ControlFlow::Continue(
+                links.into_iter().map(|link| (link, ())).collect(),
+            ))
+        },
+    )?;
+
+    let mut branching = BTreeMap::new();
+    for (_, links) in nodes.values() {
+        *branching.entry(links.len()).or_insert(0) += 1;
+    }
+
+    // Tree size and height of every node, children first.
+    let mut measured: HashMap<Cid, (u64, usize)> = HashMap::new();
+    for cid in post_order(root, &nodes) {
+        let (size, links) = &nodes[&cid];
+        let mut tree_size = *size;
+        let mut height = 0;
+        for link in links {
+            let (child_size, child_height) = measured[link];
+            tree_size += child_size;
+            height = height.max(child_height + 1);
+        }
+        measured.insert(cid, (tree_size, height));
+    }
+
+    let mut largest_subtrees: Vec<(Cid, u64)> = order
+        .iter()
+        .filter(|cid| *cid != root)
+        .map(|cid| (*cid, measured[cid].0))
+        .collect();
+    largest_subtrees.sort_by_key(|(_, size)| Reverse(*size));
+    largest_subtrees.truncate(LARGEST_SUBTREES);
+
+    Ok(DagStats {
+        blocks: nodes.len(),
+        total_size: nodes.values().map(|(size, _)| size).sum(),
+        max_depth: measured[root].1,
+        branching,
+        largest_subtrees,
+    })
+}
+
+/// Statistics of every block of an archive, see [`archive_stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveStats {
+    /// Number of sections, counting duplicates.
+    pub sections: usize,
+    /// Number of distinct blocks.
+    pub blocks: usize,
+    /// Sum of the data sizes of the distinct blocks.
+    pub total_size: u64,
+    /// Number of distinct blocks by the smallest power of two their data size is at most, with
+    /// empty blocks under 0.
+    pub sizes: BTreeMap<u64, usize>,
+    /// Number of distinct blocks by codec.
+    pub codecs: BTreeMap<u64, usize>,
+    /// Number of blocks reachable from the roots by their depth below the nearest one.
+    pub depths: BTreeMap<usize, usize>,
+    /// Sum of the data sizes of the blocks reachable from the roots.
+    pub reachable_size: u64,
+    /// Sum of the tree sizes of the roots, which counts a block once per path to it, as it
+    /// would be stored without deduplication.
+    pub tree_size: u64,
+}
+
+impl ArchiveStats {
+    /// How many times over the DAGs below the roots would be stored without deduplication,
+    /// 1 if nothing is shared; 1 as well for an archive with nothing reachable.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.reachable_size == 0 {
+            return 1.0;
+        }
+        self.tree_size as f64 / self.reachable_size as f64
+    }
+
+    /// Renders the statistics as a JSON object with the field names of [`ArchiveStats`], plus
+    /// `max_depth` and `dedup_ratio`, and codecs by name as [`write_stats`] shows them.
+    pub fn to_json(&self) -> String {
+        fn counts<K: std::fmt::Display>(counts: impl Iterator<Item = (K, usize)>) -> String {
+            let counts: Vec<String> = counts
+                .map(|(key, count)| format!("\"{}\":{}", key, count))
+                .collect();
+            format!("{{{}}}", counts.join(","))
+        }
+        format!(
+            "{{\"sections\":{},\"blocks\":{},\"total_size\":{},\"max_depth\":{},\
+             \"dedup_ratio\":{},\"reachable_size\":{},\"tree_size\":{},\"sizes\":{},\
+             \"codecs\":{},\"depths\":{}}}",
+            self.sections,
+            self.blocks,
+            self.total_size,
+            self.depths.keys().last().map_or(0, |depth| *depth),
+            self.dedup_ratio(),
+            self.reachable_size,
+            self.tree_size,
+            counts(self.sizes.iter().map(|(bucket, count)| (bucket, *count))),
+            counts(
+                self.codecs
+                    .iter()
+                    .map(|(codec, count)| (multicodec_name(*codec), *count))
+            ),
+            counts(self.depths.iter().map(|(depth, count)| (depth, *count)))
+        )
+    }
+}
+
+/// Computes [`ArchiveStats`] for `car`. Missing blocks are not an error: the blocks below them
+/// are just not reachable.
+pub fn archive_stats(car: &CarV1) -> CarResult<ArchiveStats> {
+    let mut nodes: HashMap<Cid, (u64, Vec<Cid>)> = HashMap::new();
+    let mut sizes = BTreeMap::new();
+    let mut codecs = BTreeMap::new();
+    for block in &car.blocks {
+        if nodes.contains_key(block.cid()) {
+            continue;
+        }
+        let size = block.data().len() as u64;
+        let bucket = if size == 0 {
+            0
+        } else {
+            size.next_power_of_two()
+        };
+        *sizes.entry(bucket).or_insert(0) += 1;
+        *codecs.entry(block.cid().codec()).or_insert(0) += 1;
+        nodes.insert(*block.cid(), (size, traversal::links(block)?));
+    }
+
+    // Breadth first from every root at once, so blocks are found at their least depth.
+    let mut found: HashSet<Cid> = HashSet::new();
+    let mut level: Vec<Cid> = car.header.roots.clone();
+    let mut depths = BTreeMap::new();
+    let mut depth = 0;
+    while !level.is_empty() {
+        let mut next = vec![];
+        for cid in level {
+            let Some((_, links)) = nodes.get(&cid) else {
+                continue;
+            };
+            if found.insert(cid) {
+                *depths.entry(depth).or_insert(0) += 1;
+                next.extend(links);
+            }
+        }
+        level = next;
+        depth += 1;
+    }
+
+    // Tree sizes of every node below the roots, children first.
+    let mut measured: HashMap<Cid, u64> = HashMap::new();
+    for root in car
+        .header
+        .roots
+        .iter()
+        .filter(|root| nodes.contains_key(root))
+    {
+        for cid in post_order(root, &nodes) {
+            let (size, links) = &nodes[&cid];
+            let below = links.iter().filter_map(|link| measured.get(link));
+            let tree_size = below.fold(*size, |sum, size| sum.saturating_add(*size));
+            measured.insert(cid, tree_size);
+        }
+    }
+
+    Ok(ArchiveStats {
+        sections: car.blocks.len(),
+        blocks: nodes.len(),
+        total_size: nodes.values().map(|(size, _)| size).sum(),
+        sizes,
+        codecs,
+        depths,
+        reachable_size: found.iter().map(|cid| nodes[cid].0).sum(),
+        tree_size: car
+            .header
+            .roots
+            .iter()
+            .filter_map(|root| measured.get(root))
+            .fold(0, |sum, size| sum.saturating_add(*size)),
+    })
+}
+
+/// Writes a summary of `stats`, and with `histograms` the block sizes, codecs and depths as text
+/// bars, e.g. `dag-pb     12 ########`.
+pub fn write_stats<W: Write>(stats: &ArchiveStats, histograms: bool, mut w: W) -> CarResult<()> {
+    writeln!(w, "sections: {}", stats.sections)?;
+    writeln!(w, "blocks: {}", stats.blocks)?;
+    writeln!(w, "total size: {}", stats.total_size)?;
+    writeln!(
+        w,
+        "max depth: {}",
+        stats.depths.keys().last().map_or(0, |depth| *depth)
+    )?;
+    writeln!(w, "dedup ratio: {:.2}", stats.dedup_ratio())?;
+    if !histograms {
+        return Ok(());
+    }
+    let sizes = stats
+        .sizes
+        .iter()
+        .map(|(bucket, count)| (format!("<= {}", bucket), *count));
+    write_histogram(&mut w, "block sizes", sizes)?;
+    let codecs = stats
+        .codecs
+        .iter()
+        .map(|(codec, count)| (multicodec_name(*codec).into_owned(), *count));
+    write_histogram(&mut w, "codecs", codecs)?;
+    let depths = stats
+        .depths
+        .iter()
+        .map(|(depth, count)| (depth.to_string(), *count));
+    write_histogram(&mut w, "depths", depths)
+}
+
+fn write_histogram<W: Write>(
+    mut w: W,
+    title: &str,
+    rows: impl Iterator<Item = (String, usize)>,
+) -> CarResult<()> {
+    let rows: Vec<_> = rows.collect();
+    let label = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let count = rows
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let most = rows
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    writeln!(w, "\n{}:", title)?;
+    for (name, n) in &rows {
+        // Every row with a block gets at least one mark.
+        let bar = (n * BAR_WIDTH).div_ceil(most);
+        writeln!(w, "  {:label$} {:>count$} {}", name, n, "#".repeat(bar))?;
+    }
+    Ok(())
+}
+
+fn post_order(root: &Cid, nodes: &HashMap<Cid, (u64, Vec<Cid>)>) -> Vec<Cid> {
+    let mut order = vec![];
+    let mut done = HashSet::new();
+    let mut stack = vec![(*root, false)];
+    while let Some((cid, expanded)) = stack.pop() {
+        if expanded {
+            order.push(cid);
+            continue;
+        }
+        if !done.insert(cid) {
+            continue;
+        }
+        stack.push((cid, true));
+        for link in nodes[&cid].1.iter().rev() {
+            if !done.contains(link) && nodes.contains_key(link) {
+                stack.push((*link, false));
+            }
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond, raw};
+
+    #[test]
+    fn it_computes_dag_stats() {
+        let car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| cids(&car)[i]);
+        let size = |i: usize| car.blocks[i].data().len() as u64;
+
+        let stats = dag_stats(&car, &root).unwrap();
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.total_size, (0..4).map(size).sum::<u64>());
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(
+            stats.branching,
+            [(0, 1), (1, 2), (2, 1)].into_iter().collect()
+        );
+
+        let mut expected = vec![
+            (left, size(3) + size(0)),
+            (right, size(1) + size(0)),
+            (leaf, size(0)),
+        ];
+        expected.sort_by_key(|(_, size)| Reverse(*size));
+        assert_eq!(stats.largest_subtrees, expected);
+
+        let leaf_stats = dag_stats(&car, &leaf).unwrap();
+        assert_eq!((leaf_stats.blocks, leaf_stats.max_depth), (1, 0));
+        assert!(leaf_stats.largest_subtrees.is_empty());
+    }
+
+    #[test]
+    fn it_computes_archive_stats() {
+        let mut car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| car.blocks[i].clone());
+        car.put_block(leaf.clone());
+        car.put_block(raw(b"unreachable"));
+
+        let stats = archive_stats(&car).unwrap();
+        assert_eq!((stats.sections, stats.blocks), (6, 5));
+        assert_eq!(stats.codecs, [(0x55, 2), (0x71, 3)].into_iter().collect());
+        assert_eq!(stats.sizes.values().sum::<usize>(), 5);
+        assert_eq!(stats.sizes[&16], 1);
+        assert_eq!(stats.depths, [(0, 1), (1, 2), (2, 1)].into_iter().collect());
+        let size = |block: &libipld::Block<_>| block.data().len() as u64;
+        assert_eq!(
+            stats.reachable_size,
+            [&leaf, &right, &root, &left].map(size).iter().sum::<u64>()
+        );
+        // The leaf is stored once but below both sides.
+        assert_eq!(stats.tree_size, stats.reachable_size + size(&leaf));
+        assert!(stats.dedup_ratio() > 1.0);
+
+        let mut text = vec![];
+        write_stats(&stats, true, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("max depth: 2\n"));
+        assert!(text.contains(&format!("  dag-cbor 3 {}\n", "#".repeat(BAR_WIDTH))));
+        assert!(text.contains(&format!("  raw      2 {}\n", "#".repeat(27))));
+        let json = stats.to_json();
+        assert!(json.starts_with("{\"sections\":6,\"blocks\":5,"));
+        assert!(json.contains("\"codecs\":{\"raw\":2,\"dag-cbor\":3},\"depths\":{\"0\":1,"));
+    }
+
+    #[test]
+    fn it_skips_missing_blocks_in_archive_stats() {
+        let mut car = diamond();
+        car.blocks.remove(0);
+        let stats = archive_stats(&car).unwrap();
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.tree_size, stats.reachable_size);
+        assert_eq!(stats.dedup_ratio(), 1.0);
+    }
+}
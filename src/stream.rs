@@ -0,0 +1,212 @@
+//! Framing a stream of blocks as a stream of CARv1 bytes, for HTTP bodies and uploads, and
+//! decoding a stream of CARv1 bytes back into blocks.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_core::Stream;
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::block::CarV1Decoder;
+use crate::v1::CarHeaderV1;
+use crate::{write_varint, CarError, CarResult};
+
+/// A CARv1 with `roots`, produced as the header followed by one `varint | CID | data` chunk
+/// per block of `blocks`.
+///
+/// Blocks are framed as they arrive and are not checked against their CIDs.
+#[derive(Debug)]
+pub struct CarStream<S> {
+    header: Option<Bytes>,
+    blocks: S,
+}
+
+impl<S> CarStream<S>
+where
+    S: Stream<Item = (Cid, Bytes)> + Unpin,
+{
+    pub fn new(roots: Vec<Cid>, blocks: S) -> Self {
+        let mut header = vec![];
+        CarHeaderV1 { roots }
+            .write_to(&mut header)
+            .expect("headers always encode");
+        Self {
+            header: Some(header.into()),
+            blocks,
+        }
+    }
+}
+
+impl<S> Stream for CarStream<S>
+where
+    S: Stream<Item = (Cid, Bytes)> + Unpin,
+{
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        if let Some(header) = self.header.take() {
+            return Poll::Ready(Some(header));
+        }
+        Pin::new(&mut self.blocks).poll_next(cx).map(|block| {
+            block.map(|(cid, data)| {
+                let cid = cid.to_bytes();
+                let mut section = Vec::with_capacity(cid.len() + data.len() + 10);
+                write_varint(&mut section, (cid.len() + data.len()) as u64)
+                    .expect("writing to a Vec cannot fail");
+                section.extend_from_slice(&cid);
+                section.extend_from_slice(&data);
+                section.into()
+            })
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let header = self.header.is_some() as usize;
+        let (lower, upper) = self.blocks.size_hint();
+        (lower + header, upper.map(|upper| upper + header))
+    }
+}
+
+/// The blocks of a CARv1 arriving as a stream of byte chunks, such as an HTTP body, decoded
+/// and verified as [`CarV1Decoder`] does.
+///
+/// Ends after the first error, including one from `chunks`.
+#[derive(Debug)]
+pub struct CarBlockStream<S> {
+    chunks: S,
+    decoder: CarV1Decoder,
+    done: bool,
+}
+
+impl<S> CarBlockStream<S> {
+    pub fn new(chunks: S) -> Self {
+        Self {
+            chunks,
+            decoder: CarV1Decoder::new(),
+            done: false,
+        }
+    }
+
+    /// Fails on a section longer than `max` bytes, see [`CarV1Decoder::with_max_section_size`].
+    pub fn with_max_section_size(mut self, max: u64) -> Self {
+        self.decoder = self.decoder.with_max_section_size(max);
+        self
+    }
+
+    /// Fails on a section still incomplete `timeout` after it started arriving, see
+    /// [`CarV1Decoder::with_section_timeout`]. A stream that stops yielding chunks altogether
+    /// is not woken for it, and needs a timeout of its own.
+    pub fn with_section_timeout(mut self, timeout: Duration) -> Self {
+        self.decoder = self.decoder.with_section_timeout(timeout);
+        self
+    }
+
+    /// The roots of the archive, once its header has arrived.
+    pub fn roots(&self) -> Option<&[Cid]> {
+        self.decoder.header().map(|header| &header.roots[..])
+    }
+}
+
+impl<S, E> Stream for CarBlockStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    CarError: From<E>,
+{
+    type Item = CarResult<Block<DefaultParams>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            match self.decoder.next_block() {
+                Ok(Some(block)) => return Poll::Ready(Some(Ok(block))),
+                Ok(None) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+            match std::task::ready!(Pin::new(&mut self.chunks).poll_next(cx)) {
+                Some(Ok(chunk)) => self.decoder.push(&chunk),
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                None => {
+                    self.done = true;
+                    return Poll::Ready(self.decoder.finish().err().map(Err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::diamond;
+    use crate::v1::CarV1;
+    use futures::{stream, StreamExt};
+
+    #[tokio::test]
+    async fn it_frames_blocks_as_car_bytes() {
+        let car = diamond();
+        let blocks = car
+            .blocks
+            .iter()
+            .map(|block| (*block.cid(), Bytes::copy_from_slice(block.data())));
+        let chunks: Vec<Bytes> = CarStream::new(car.header.roots.clone(), stream::iter(blocks))
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), car.blocks.len() + 1);
+
+        let mut expected = vec![];
+        car.write_to(&mut expected).unwrap();
+        assert_eq!(chunks.concat(), expected);
+        let read = CarV1::from_reader(&chunks.concat()[..]).unwrap();
+        assert_eq!(read.blocks, car.blocks);
+    }
+
+    #[tokio::test]
+    async fn it_decodes_blocks_from_byte_chunks() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let chunks = |bytes: &[u8]| {
+            let chunks: Vec<_> = bytes
+                .chunks(5)
+                .map(|chunk| Ok::<_, CarError>(Bytes::copy_from_slice(chunk)))
+                .collect();
+            stream::iter(chunks)
+        };
+
+        let mut blocks = CarBlockStream::new(chunks(&bytes));
+        assert_eq!(blocks.roots(), None);
+        let first = blocks.next().await.unwrap().unwrap();
+        assert_eq!(blocks.roots(), Some(&car.header.roots[..]));
+        let rest: Vec<_> = blocks.map(Result::unwrap).collect().await;
+        assert_eq!([vec![first], rest].concat(), car.blocks);
+
+        let truncated: Vec<_> = CarBlockStream::new(chunks(&bytes[..bytes.len() - 1]))
+            .collect()
+            .await;
+        assert_eq!(truncated.len(), car.blocks.len());
+        assert!(matches!(
+            truncated.last(),
+            Some(Err(CarError::InvalidFormat))
+        ));
+
+        let guarded: Vec<_> = CarBlockStream::new(chunks(&bytes))
+            .with_max_section_size(60)
+            .collect()
+            .await;
+        assert_eq!(guarded.len(), 2);
+        assert!(matches!(
+            guarded.last(),
+            Some(Err(CarError::SectionTooLarge { limit: 60, .. }))
+        ));
+    }
+}
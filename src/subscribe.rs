@@ -0,0 +1,127 @@
+//! Reading an archive in a single pass, handing every block to a handler for its codec as a
+//! decoded node, see [`Subscriptions`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+use libipld::cid::Cid;
+use libipld::{Ipld, IpldCodec};
+
+use crate::block::CarBlockReader;
+use crate::{CarResult, HashPolicy};
+
+/// Called with the CID and decoded node of a block. Returning an error stops the read.
+pub type Handler<'a> = dyn FnMut(&Cid, Ipld) -> CarResult<()> + 'a;
+
+/// Handlers by codec.
+#[derive(Default)]
+pub struct Subscriptions<'a> {
+    handlers: HashMap<u64, Box<Handler<'a>>>,
+}
+
+impl fmt::Debug for Subscriptions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut codecs: Vec<_> = self.handlers.keys().collect();
+        codecs.sort();
+        f.debug_struct("Subscriptions")
+            .field("codecs", &codecs)
+            .finish()
+    }
+}
+
+impl<'a> Subscriptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands the blocks of `codec` to `handler`, replacing any handler it had.
+    pub fn on<F>(mut self, codec: u64, handler: F) -> Self
+    where
+        F: FnMut(&Cid, Ipld) -> CarResult<()> + 'a,
+    {
+        self.handlers.insert(codec, Box::new(handler));
+        self
+    }
+
+    pub fn on_raw<F>(self, handler: F) -> Self
+    where
+        F: FnMut(&Cid, Ipld) -> CarResult<()> + 'a,
+    {
+        self.on(IpldCodec::Raw.into(), handler)
+    }
+
+    pub fn on_dag_cbor<F>(self, handler: F) -> Self
+    where
+        F: FnMut(&Cid, Ipld) -> CarResult<()> + 'a,
+    {
+        self.on(IpldCodec::DagCbor.into(), handler)
+    }
+
+    pub fn on_dag_pb<F>(self, handler: F) -> Self
+    where
+        F: FnMut(&Cid, Ipld) -> CarResult<()> + 'a,
+    {
+        self.on(IpldCodec::DagPb.into(), handler)
+    }
+
+    /// Reads the CARv1 or CARv2 in `r` one block at a time, verifying every block with a handler
+    /// and calling it with the decoded node, in the order blocks are stored. Blocks of other
+    /// codecs are skipped without being checked or decoded. Returns the roots of the archive.
+    pub fn read<R: Read>(&mut self, r: R) -> CarResult<Vec<Cid>> {
+        let mut reader = CarBlockReader::new(r)?;
+        let roots = reader.roots().to_vec();
+        while let Some((block, location)) = reader.next_block_with_location()? {
+            if let Some(handler) = self.handlers.get_mut(&block.cid.codec()) {
+                let (block, _) =
+                    HashPolicy::default().block(block.cid, block.data, location.offset)?;
+                handler(block.cid(), block.decode::<IpldCodec, Ipld>()?)?;
+            }
+        }
+        Ok(roots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::diamond;
+    use crate::CarError;
+
+    #[test]
+    fn it_calls_the_handler_of_each_codec() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let mut raw = vec![];
+        let mut links = 0;
+        let roots = Subscriptions::new()
+            .on_raw(|_, node| {
+                raw.push(node);
+                Ok(())
+            })
+            .on_dag_cbor(|_, node| {
+                links += node
+                    .iter()
+                    .filter(|node| matches!(node, Ipld::Link(_)))
+                    .count();
+                Ok(())
+            })
+            .read(&bytes[..])
+            .unwrap();
+        assert_eq!(roots, car.header.roots);
+        assert_eq!(raw, vec![Ipld::Bytes(b"leaf".to_vec())]);
+        assert_eq!(links, 4);
+
+        let mut seen = 0;
+        let result = Subscriptions::new()
+            .on_dag_cbor(|cid, _| {
+                seen += 1;
+                Err(CarError::MissingBlock(*cid))
+            })
+            .read(&bytes[..]);
+        assert!(matches!(result, Err(CarError::MissingBlock(_))));
+        assert_eq!(seen, 1);
+    }
+}
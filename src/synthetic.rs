@@ -0,0 +1,312 @@
+//! Reproducible archives of a given shape and size, for measuring readers, writers and indexes
+//! on more data than fixtures hold, see [`generate`] and [`generate_to`].
+
+use std::io::Write;
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::multihash::Code;
+use libipld::pb::DagPbCodec;
+use libipld::raw::RawCodec;
+use libipld::{Block, DefaultParams, Ipld};
+
+use crate::params;
+use crate::unixfs::{DataType, UnixFsData};
+use crate::v1::{CarHeaderV1, CarV1, CarWriter};
+use crate::CarResult;
+
+/// How leaves are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafCodec {
+    Raw,
+    /// A dag-cbor byte string.
+    DagCbor,
+}
+
+/// How the nodes above the leaves are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeCodec {
+    /// A dag-cbor list of links.
+    DagCbor,
+    /// A dag-pb UnixFS file node, with the `blocksizes` and `filesize` of its links.
+    DagPb,
+}
+
+/// The shape of the trees [`generate`] builds.
+///
+/// Each tree has `depth` levels of nodes with `fanout` children above its leaves, so
+/// `fanout^depth` leaves, but for the last tree, which stops where the data does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DagShape {
+    pub fanout: usize,
+    /// Levels of nodes above the leaves; with none, every leaf is a root.
+    pub depth: usize,
+    /// The length of leaves is picked between these, but for the last, which is cut short.
+    pub min_leaf_size: usize,
+    pub max_leaf_size: usize,
+    pub leaf_codec: LeafCodec,
+    pub node_codec: NodeCodec,
+    /// Seeds the leaf data and lengths; the same shape, seed and size give the same archive.
+    pub seed: u64,
+}
+
+impl Default for DagShape {
+    /// Files of up to 11 GiB as UnixFS file nodes over raw leaves, with the chunk size and
+    /// fanout of `ipfs add --raw-leaves`, though every tree has both levels of nodes.
+    fn default() -> Self {
+        Self {
+            fanout: 174,
+            depth: 2,
+            min_leaf_size: 256 * 1024,
+            max_leaf_size: 256 * 1024,
+            leaf_codec: LeafCodec::Raw,
+            node_codec: NodeCodec::DagPb,
+            seed: 0,
+        }
+    }
+}
+
+/// Generates trees of `shape` holding `size` bytes of leaf data in total, each tree a root of
+/// the archive. Blocks are stored children first.
+///
+/// The archive is held in memory; [`generate_to`] writes larger ones.
+pub fn generate(shape: DagShape, size: u64) -> CarResult<CarV1> {
+    let mut blocks = vec![];
+    let roots = Generator::new(shape, size).run(&mut |block| {
+        blocks.push(block);
+        Ok(())
+    })?;
+    Ok(CarV1::new(CarHeaderV1 { roots }, blocks))
+}
+
+/// Writes the CARv1 archive [`generate`] returns to `w`, holding no more than the nodes on the
+/// way down the tree being generated.
+///
+/// The header comes first, so the archive is generated twice: once to find its roots, then
+/// again to write its blocks.
+pub fn generate_to<W: Write>(shape: DagShape, size: u64, w: W) -> CarResult<W> {
+    let roots = Generator::new(shape, size).run(&mut |_| Ok(()))?;
+    let mut writer = CarWriter::new(w, roots)?;
+    Generator::new(shape, size).run(&mut |block| writer.write_block(&block))?;
+    writer.finish()
+}
+
+/// A child of a node: its CID, the size of all blocks of its tree, and its leaf data length.
+type Child = (Cid, u64, u64);
+
+struct Generator {
+    shape: DagShape,
+    /// Bytes of leaf data left to generate.
+    remaining: u64,
+    state: u64,
+}
+
+impl Generator {
+    fn new(shape: DagShape, size: u64) -> Self {
+        Self {
+            shape,
+            remaining: size,
+            state: shape.seed,
+        }
+    }
+
+    /// Generates every tree, giving each block to `emit`, and returns the roots.
+    fn run(
+        mut self,
+        emit: &mut dyn FnMut(Block<DefaultParams>) -> CarResult<()>,
+    ) -> CarResult<Vec<Cid>> {
+        let mut roots = vec![];
+        while let Some((root, _, _)) = self.tree(self.shape.depth, emit)? {
+            roots.push(root);
+        }
+        Ok(roots)
+    }
+
+    /// A tree of `depth` levels, `None` once the data has run out.
+    fn tree(
+        &mut self,
+        depth: usize,
+        emit: &mut dyn FnMut(Block<DefaultParams>) -> CarResult<()>,
+    ) -> CarResult<Option<Child>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        if depth == 0 {
+            return self.leaf(emit).map(Some);
+        }
+        let mut children = vec![];
+        while children.len() < self.shape.fanout.max(1) {
+            match self.tree(depth - 1, emit)? {
+                Some(child) => children.push(child),
+                None => break,
+            }
+        }
+        let tsize = children.iter().map(|(_, tsize, _)| tsize).sum::<u64>();
+        let size = children.iter().map(|(_, _, size)| size).sum::<u64>();
+        let block = match self.shape.node_codec {
+            NodeCodec::DagCbor => {
+                let links = children
+                    .iter()
+                    .map(|(cid, _, _)| Ipld::Link(*cid))
+                    .collect();
+                params::encode(DagCborCodec, Code::Sha2_256, &Ipld::List(links))?
+            }
+            NodeCodec::DagPb => {
+                let links = children
+                    .iter()
+                    .map(|(cid, tsize, _)| {
+                        Ipld::Map(
+                            [
+                                ("Hash".to_string(), Ipld::Link(*cid)),
+                                ("Tsize".to_string(), Ipld::Integer(*tsize as i128)),
+                            ]
+                            .into(),
+                        )
+                    })
+                    .collect();
+                let data = UnixFsData {
+                    filesize: Some(size),
+                    blocksizes: children.iter().map(|(_, _, size)| *size).collect(),
+                    ..UnixFsData::new(DataType::File)
+                };
+                let node = Ipld::Map(
+                    [
+                        ("Data".to_string(), Ipld::Bytes(data.encode())),
+                        ("Links".to_string(), Ipld::List(links)),
+                    ]
+                    .into(),
+                );
+                params::encode(DagPbCodec, Code::Sha2_256, &node)?
+            }
+        };
+        Self::push(block, tsize, size, emit).map(Some)
+    }
+
+    fn leaf(
+        &mut self,
+        emit: &mut dyn FnMut(Block<DefaultParams>) -> CarResult<()>,
+    ) -> CarResult<Child> {
+        let min = self.shape.min_leaf_size.max(1);
+        let max = self.shape.max_leaf_size.max(min);
+        let len =
+            (min + (self.next() % (max - min + 1) as u64) as usize).min(self.remaining as usize);
+        self.remaining -= len as u64;
+        let mut data = Vec::with_capacity(len + 8);
+        while data.len() < len {
+            data.extend_from_slice(&self.next().to_le_bytes());
+        }
+        data.truncate(len);
+        let block = match self.shape.leaf_codec {
+            LeafCodec::Raw => params::encode(RawCodec, Code::Sha2_256, &Ipld::Bytes(data))?,
+            LeafCodec::DagCbor => params::encode(DagCborCodec, Code::Sha2_256, &Ipld::Bytes(data))?,
+        };
+        Self::push(block, 0, len as u64, emit)
+    }
+
+    /// Emits `block` of a tree holding `size` bytes of leaf data, returning it as a child, with
+    /// `below` the size of its children.
+    fn push(
+        block: Block<DefaultParams>,
+        below: u64,
+        size: u64,
+        emit: &mut dyn FnMut(Block<DefaultParams>) -> CarResult<()>,
+    ) -> CarResult<Child> {
+        let child = (*block.cid(), block.data().len() as u64 + below, size);
+        emit(block)?;
+        Ok(child)
+    }
+
+    /// The next output of SplitMix64.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::cids;
+    use crate::unixfs::UnixFsReader;
+    use std::io::Read;
+
+    fn shape() -> DagShape {
+        DagShape {
+            fanout: 4,
+            depth: 2,
+            min_leaf_size: 100,
+            max_leaf_size: 300,
+            ..DagShape::default()
+        }
+    }
+
+    #[test]
+    fn it_generates_the_same_archive_for_the_same_shape() {
+        let car = generate(shape(), 50_000).unwrap();
+        assert_eq!(cids(&car), cids(&generate(shape(), 50_000).unwrap()));
+        assert_ne!(
+            cids(&car),
+            cids(&generate(DagShape { seed: 1, ..shape() }, 50_000).unwrap())
+        );
+        assert!(car.missing_links().unwrap().is_empty());
+
+        let leaves: Vec<_> = car
+            .blocks
+            .iter()
+            .filter(|block| block.cid().codec() == 0x55)
+            .collect();
+        assert_eq!(
+            leaves
+                .iter()
+                .map(|leaf| leaf.data().len() as u64)
+                .sum::<u64>(),
+            50_000
+        );
+        let (last, rest) = leaves.split_last().unwrap();
+        assert!(rest
+            .iter()
+            .all(|leaf| (100..=300).contains(&leaf.data().len())));
+        assert!(last.data().len() <= 300);
+        // Every full tree holds 16 leaves.
+        assert_eq!(car.header.roots.len(), leaves.len().div_ceil(16));
+
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert_eq!(generate_to(shape(), 50_000, vec![]).unwrap(), bytes);
+    }
+
+    #[test]
+    fn it_generates_unixfs_files() {
+        let car = generate(shape(), 5_000).unwrap();
+        let mut read = vec![];
+        for root in &car.header.roots {
+            let mut reader = UnixFsReader::new(&car, root).unwrap();
+            let len = reader.len();
+            assert_eq!(reader.read_to_end(&mut read).unwrap() as u64, len);
+        }
+        let leaves: Vec<u8> = car
+            .blocks
+            .iter()
+            .filter(|block| block.cid().codec() == 0x55)
+            .flat_map(|block| block.data().to_vec())
+            .collect();
+        assert_eq!(read, leaves);
+    }
+
+    #[test]
+    fn it_generates_dag_cbor_trees() {
+        let shape = DagShape {
+            leaf_codec: LeafCodec::DagCbor,
+            node_codec: NodeCodec::DagCbor,
+            depth: 0,
+            ..shape()
+        };
+        let car = generate(shape, 1_000).unwrap();
+        assert_eq!(car.header.roots, cids(&car));
+        assert!(car.blocks.iter().all(|block| block.cid().codec() == 0x71));
+        assert!(generate(shape, 0).unwrap().blocks.is_empty());
+    }
+}
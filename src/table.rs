@@ -0,0 +1,296 @@
+//! Block-level metadata as a table, one row per block, for analysis in tools such as DuckDB or
+//! Spark. Written as CSV, or as Parquet with the `parquet` feature.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+
+use libipld::cid::Cid;
+
+use crate::block::CarBlockReader;
+use crate::traversal::{traverse, Visit};
+use crate::v1::{CarHeaderV1, CarV1};
+use crate::{CarResult, HashPolicy};
+
+/// The metadata of one block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRow {
+    pub cid: Cid,
+    pub codec: u64,
+    /// The multihash code.
+    pub hash: u64,
+    /// The size of the block data.
+    pub size: u64,
+    /// The offset of the block's section from the start of the file.
+    pub offset: u64,
+    /// The depth below the nearest root, if the block is reachable from one.
+    pub depth: Option<u64>,
+}
+
+/// The rows of every block of the CARv1 or CARv2 in `r`, in the order they are stored, with
+/// the offsets their sections have in `r`, padding and non-minimal varints included. Blocks are
+/// checked against their CIDs as [`HashPolicy::default`] does.
+pub fn block_rows<R: Read>(r: R) -> CarResult<Vec<BlockRow>> {
+    let mut reader = CarBlockReader::new(r)?;
+    let mut rows = vec![];
+    let mut blocks = vec![];
+    while let Some((block, location)) = reader.next_block_with_location()? {
+        let cid = block.cid;
+        rows.push(BlockRow {
+            cid,
+            codec: cid.codec(),
+            hash: cid.hash().code(),
+            size: block.data.len() as u64,
+            offset: location.offset,
+            depth: None,
+        });
+        let offset = location.offset - reader.payload();
+        blocks.push(HashPolicy::default().block(cid, block.data, offset)?.0);
+    }
+    let roots = reader.roots().to_vec();
+    let depths = depths(&CarV1::new(CarHeaderV1 { roots }, blocks))?;
+    for row in &mut rows {
+        row.depth = depths.get(&row.cid).copied();
+    }
+    Ok(rows)
+}
+
+/// Writes `rows` as CSV with a `cid,codec,hash,size,offset,depth` header. Codecs and hashes
+/// are written by name when known, and the depth of unreachable blocks is left empty.
+pub fn write_csv<W: Write>(rows: &[BlockRow], mut w: W) -> CarResult<()> {
+    writeln!(w, "cid,codec,hash,size,offset,depth")?;
+    for row in rows {
+        writeln!(
+            w,
+            "{},{},{},{},{},{}",
+            row.cid,
+            multicodec_name(row.codec),
+            multicodec_name(row.hash),
+            row.size,
+            row.offset,
+            row.depth.map(|depth| depth.to_string()).unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `rows` as a Parquet file with the columns of [`write_csv`]; `depth` is optional.
+#[cfg(feature = "parquet")]
+pub fn write_parquet<W: Write + Send>(rows: &[BlockRow], w: W) -> CarResult<()> {
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    let schema = parse_message_type(
+        "message block {
+            REQUIRED BYTE_ARRAY cid (UTF8);
+            REQUIRED BYTE_ARRAY codec (UTF8);
+            REQUIRED BYTE_ARRAY hash (UTF8);
+            REQUIRED INT64 size;
+            REQUIRED INT64 offset;
+            OPTIONAL INT64 depth;
+        }",
+    )?;
+    let mut writer = SerializedFileWriter::new(
+        w,
+        Arc::new(schema),
+        Arc::new(WriterProperties::builder().build()),
+    )?;
+    let strings = |f: &dyn Fn(&BlockRow) -> String| -> Vec<ByteArray> {
+        rows.iter()
+            .map(|row| ByteArray::from(f(row).as_str()))
+            .collect()
+    };
+    let integers = |f: &dyn Fn(&BlockRow) -> u64| -> Vec<i64> {
+        rows.iter().map(|row| f(row) as i64).collect()
+    };
+
+    let mut row_group = writer.next_row_group()?;
+    let mut column = 0;
+    while let Some(mut writer) = row_group.next_column()? {
+        match column {
+            0 => writer.typed::<ByteArrayType>().write_batch(
+                &strings(&|row| row.cid.to_string()),
+                None,
+                None,
+            )?,
+            1 => writer.typed::<ByteArrayType>().write_batch(
+                &strings(&|row| multicodec_name(row.codec).into_owned()),
+                None,
+                None,
+            )?,
+            2 => writer.typed::<ByteArrayType>().write_batch(
+                &strings(&|row| multicodec_name(row.hash).into_owned()),
+                None,
+                None,
+            )?,
+            3 => writer
+                .typed::<Int64Type>()
+                .write_batch(&integers(&|row| row.size), None, None)?,
+            4 => {
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(&integers(&|row| row.offset), None, None)?
+            }
+            _ => {
+                let depths: Vec<i64> = rows
+                    .iter()
+                    .filter_map(|row| row.depth)
+                    .map(|depth| depth as i64)
+                    .collect();
+                let levels: Vec<i16> = rows.iter().map(|row| row.depth.is_some() as i16).collect();
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(&depths, Some(&levels), None)?
+            }
+        };
+        writer.close()?;
+        column += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// The name of a codec or multihash code, or its hex code if it is not a common one.
+pub fn multicodec_name(code: u64) -> Cow<'static, str> {
+    Cow::Borrowed(match code {
+        0x00 => "identity",
+        0x11 => "sha1",
+        0x12 => "sha2-256",
+        0x13 => "sha2-512",
+        0x16 => "sha3-256",
+        0x1b => "keccak-256",
+        0x1e => "blake3",
+        0x51 => "cbor",
+        0x55 => "raw",
+        0x70 => "dag-pb",
+        0x71 => "dag-cbor",
+        0x0129 => "dag-json",
+        0x0200 => "json",
+        0x0202 => "car",
+        0xb220 => "blake2b-256",
+        0xb260 => "blake2s-256",
+        _ => return Cow::Owned(format!("0x{:x}", code)),
+    })
+}
+
+/// The depth at which a depth-first walk from the roots first reaches each block.
+fn depths(car: &CarV1) -> CarResult<HashMap<Cid, u64>> {
+    let present: std::collections::HashSet<Cid> =
+        car.blocks.iter().map(|block| *block.cid()).collect();
+    let roots: Vec<Cid> = car
+        .header
+        .roots
+        .iter()
+        .filter(|root| present.contains(root))
+        .copied()
+        .collect();
+    let mut depths = HashMap::new();
+    let result = traverse(car, &roots, &mut |depth: usize, cid: &Cid, _: &_| {
+        depths.insert(*cid, depth as u64);
+        ControlFlow::Continue(Visit::Descend)
+    });
+    match result {
+        // Blocks below a missing link just have no depth.
+        Ok(()) | Err(crate::CarError::MissingBlock(_)) => Ok(depths),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(all(test, feature = "v1"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond};
+
+    fn varint_len(value: u64) -> u64 {
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        unsigned_varint::encode::u64(value, &mut buf).len() as u64
+    }
+
+    #[test]
+    fn it_lists_blocks_with_offsets_and_depths() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        let rows = block_rows(&bytes[..]).unwrap();
+
+        assert_eq!(
+            rows.iter().map(|row| row.cid).collect::<Vec<_>>(),
+            cids(&car)
+        );
+        // Stored as [leaf, right, root, left].
+        let depths: Vec<_> = rows.iter().map(|row| row.depth).collect();
+        assert_eq!(depths, vec![Some(2), Some(1), Some(0), Some(1)]);
+        for (row, block) in rows.iter().zip(&car.blocks) {
+            let start = row.offset as usize
+                + varint_len((row.cid.encoded_len() + block.data().len()) as u64) as usize;
+            assert_eq!(
+                &bytes[start..start + row.cid.encoded_len()],
+                &row.cid.to_bytes()[..]
+            );
+        }
+
+        let mut csv = vec![];
+        write_csv(&rows, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.lines().nth(1).unwrap().contains(",raw,sha2-256,4,"));
+    }
+
+    #[cfg(feature = "v2")]
+    #[test]
+    fn it_lists_offsets_in_padded_car_v2_archives() {
+        use crate::v2::{CarV2, CarV2WriteOptions};
+
+        let car = diamond();
+        let options = CarV2WriteOptions {
+            data_padding: 100,
+            index_padding: 0,
+        };
+        let mut bytes = std::io::Cursor::new(vec![]);
+        CarV2::from_car_v1(car.clone(), false)
+            .unwrap()
+            .write_to_with_options(&mut bytes, &options)
+            .unwrap();
+        let bytes = bytes.into_inner();
+        let rows = block_rows(&bytes[..]).unwrap();
+
+        assert_eq!(rows.len(), car.blocks.len());
+        for (row, block) in rows.iter().zip(&car.blocks) {
+            let start = row.offset as usize
+                + varint_len((row.cid.encoded_len() + block.data().len()) as u64) as usize;
+            assert_eq!(
+                &bytes[start..start + row.cid.encoded_len()],
+                &row.cid.to_bytes()[..]
+            );
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn it_writes_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let mut bytes = vec![];
+        diamond().write_to(&mut bytes).unwrap();
+        let rows = block_rows(&bytes[..]).unwrap();
+        let dir = crate::test_utils::temp_dir("parquet");
+        let path = dir.join("blocks.parquet");
+        write_parquet(&rows, std::fs::File::create(&path).unwrap()).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        assert_eq!(
+            reader
+                .metadata()
+                .file_metadata()
+                .schema_descr()
+                .num_columns(),
+            6
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
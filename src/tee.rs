@@ -0,0 +1,89 @@
+//! Persisting an archive while it is parsed, see [`TeeReader`].
+
+use std::io::{self, Read, Write};
+
+/// Copies every byte read from `inner` to `dest`, so an upload can be parsed and verified on
+/// its way to storage in one pass, without buffering it.
+///
+/// Readers may stop short of the end, as a CARv1 reader does before the index of a CARv2;
+/// [`TeeReader::finish`] copies what is left.
+#[derive(Debug)]
+pub struct TeeReader<R, W> {
+    inner: R,
+    dest: W,
+    copied: u64,
+}
+
+impl<R, W> TeeReader<R, W> {
+    pub fn new(inner: R, dest: W) -> Self {
+        Self {
+            inner,
+            dest,
+            copied: 0,
+        }
+    }
+
+    /// How many bytes were copied to the destination.
+    pub fn copied(&self) -> u64 {
+        self.copied
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.inner, self.dest)
+    }
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    /// Copies the bytes not read yet, flushes the destination and returns both ends.
+    pub fn finish(mut self) -> io::Result<(R, W)> {
+        io::copy(&mut self, &mut io::sink())?;
+        self.dest.flush()?;
+        Ok((self.inner, self.dest))
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    /// Fails if the destination does, after the bytes were read from `inner`.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.dest.write_all(&buf[..read])?;
+        self.copied += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(all(test, feature = "ipld"))]
+mod tests {
+    use super::*;
+    use crate::block::CarBlockReader;
+    use crate::test_utils::diamond;
+    use crate::v1::CarV1;
+
+    #[test]
+    fn it_persists_archives_while_they_are_parsed() {
+        let car = diamond();
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let mut stored = vec![];
+        let mut tee = TeeReader::new(&bytes[..], &mut stored);
+        let read = CarV1::from_reader(&mut tee).unwrap();
+        assert_eq!(read.blocks, car.blocks);
+        assert_eq!(tee.copied(), bytes.len() as u64);
+        tee.finish().unwrap();
+        assert_eq!(stored, bytes);
+
+        // Blocks left unread are still stored.
+        let mut stored = vec![];
+        let mut tee = TeeReader::new(&bytes[..], &mut stored);
+        let first = CarBlockReader::new(&mut tee)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.cid, *car.blocks[0].cid());
+        assert!(tee.copied() < bytes.len() as u64);
+        tee.finish().unwrap();
+        assert_eq!(stored, bytes);
+    }
+}
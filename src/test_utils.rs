@@ -0,0 +1,142 @@
+//! Archives shared by the unit tests.
+
+use libipld::{cbor::DagCborCodec, cid::Cid, ipld, multihash::Code, pb::DagPbCodec, raw::RawCodec};
+use libipld::{Block, DefaultParams, Ipld};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metrics::Metrics;
+use crate::unixfs::{DataType, UnixFsData};
+use crate::v1::{CarHeaderV1, CarV1};
+
+pub fn raw(data: &[u8]) -> Block<DefaultParams> {
+    Block::encode(RawCodec, Code::Sha2_256, data).unwrap()
+}
+
+pub fn cbor(node: &Ipld) -> Block<DefaultParams> {
+    Block::encode(DagCborCodec, Code::Sha2_256, node).unwrap()
+}
+
+pub fn cids(car: &CarV1) -> Vec<Cid> {
+    car.blocks.iter().map(|block| *block.cid()).collect()
+}
+
+/// `root -> [left, right]`, where both `left` and `right` link to `leaf`.
+///
+/// Blocks are stored as `[leaf, right, root, left]`, which is not a traversal order.
+pub fn diamond() -> CarV1 {
+    let leaf = raw(b"leaf");
+    let left = cbor(&ipld!({ "l": *leaf.cid() }));
+    let right = cbor(&ipld!({ "r": *leaf.cid() }));
+    let root = cbor(&ipld!([*left.cid(), *right.cid()]));
+    CarV1::new(
+        CarHeaderV1 {
+            roots: vec![*root.cid()],
+        },
+        vec![leaf, right, root, left],
+    )
+}
+
+/// A UnixFS file with one raw leaf per chunk, stored as `[root, leaves..]`.
+pub fn unixfs_file(chunks: &[&[u8]]) -> CarV1 {
+    let blocksizes: Vec<_> = chunks.iter().map(|chunk| chunk.len() as u64).collect();
+    unixfs_file_with_blocksizes(chunks, &blocksizes)
+}
+
+/// Like [`unixfs_file`], with the root claiming `blocksizes` for the chunks, as a hostile
+/// archive may.
+pub fn unixfs_file_with_blocksizes(chunks: &[&[u8]], blocksizes: &[u64]) -> CarV1 {
+    let leaves: Vec<_> = chunks.iter().map(|chunk| raw(chunk)).collect();
+    let data = UnixFsData {
+        filesize: Some(
+            blocksizes
+                .iter()
+                .fold(0, |sum, size| sum.saturating_add(*size)),
+        ),
+        blocksizes: blocksizes.to_vec(),
+        ..UnixFsData::new(DataType::File)
+    };
+    let links = leaves
+        .iter()
+        .map(|leaf| ipld!({ "Hash": *leaf.cid(), "Tsize": leaf.data().len() }))
+        .collect();
+    let node = ipld!({ "Data": Ipld::Bytes(data.encode()), "Links": Ipld::List(links) });
+    let root = Block::encode(DagPbCodec, Code::Sha2_256, &node).unwrap();
+
+    let mut blocks = vec![root];
+    blocks.extend(leaves);
+    CarV1::new(
+        CarHeaderV1 {
+            roots: vec![*blocks[0].cid()],
+        },
+        blocks,
+    )
+}
+
+/// An empty directory, unique to `name` and the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("racecar-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Serves `responses` to consecutive connections and returns the gateway URL.
+pub fn serve(responses: Vec<Vec<u8>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for (response, stream) in responses.into_iter().zip(listener.incoming()) {
+            let mut stream = stream.unwrap();
+            let mut r = BufReader::new(&stream);
+            let mut line = String::new();
+            while r.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(&response).unwrap();
+        }
+    });
+    url
+}
+
+/// A `200 OK` response with `body`.
+pub fn ok(body: &[u8]) -> Vec<u8> {
+    let mut response =
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+    response.extend(body);
+    response
+}
+
+/// Counts every callback.
+#[derive(Default)]
+pub struct Counters {
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub blocks_read: AtomicU64,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl Metrics for Counters {
+    fn bytes_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn block_read(&self, _: &Cid, _: usize) {
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_hit(&self, _: &Cid) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_miss(&self, _: &Cid) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,176 @@
+//! Rate-limited reads and writes, for background jobs such as verification and replication that
+//! must not saturate a disk shared with latency-sensitive services. See [`RateLimiter`].
+
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A budget of bytes per second, refilled continuously and shared by every [`ThrottledReader`]
+/// and [`ThrottledWriter`] it is plugged into, so one limiter caps a whole job.
+///
+/// Up to `burst` bytes go through at once, including at the start; it defaults to a second's
+/// worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    burst: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "a rate limit must allow some bytes");
+        Self {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Lets at most `burst` bytes through at once, and at least one.
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst.max(1);
+        self.bucket.get_mut().unwrap().available = self.burst as f64;
+        self
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Waits until `wanted` bytes, or a burst if that is less, are available and takes them.
+    fn take(&self, wanted: usize) -> usize {
+        let wanted = (wanted as u64).min(self.burst);
+        loop {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.available =
+                (bucket.available + elapsed * self.bytes_per_sec as f64).min(self.burst as f64);
+            bucket.refilled = now;
+            let missing = wanted as f64 - bucket.available;
+            if missing <= 0.0 {
+                bucket.available -= wanted as f64;
+                return wanted as usize;
+            }
+            drop(bucket);
+            thread::sleep(Duration::from_secs_f64(missing / self.bytes_per_sec as f64));
+        }
+    }
+
+    /// Gives back bytes taken but not transferred.
+    fn refund(&self, unused: usize) {
+        if unused > 0 {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+            bucket.available = (bucket.available + unused as f64).min(self.burst as f64);
+        }
+    }
+}
+
+/// Reads from `inner` no faster than its limiter allows.
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    limiter: &'a RateLimiter,
+}
+
+impl<'a, R> ThrottledReader<'a, R> {
+    pub fn new(inner: R, limiter: &'a RateLimiter) -> Self {
+        Self { inner, limiter }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = self.limiter.take(buf.len());
+        let result = self.inner.read(&mut buf[..allowed]);
+        self.limiter
+            .refund(allowed - result.as_ref().map_or(0, |read| *read));
+        result
+    }
+}
+
+/// Writes to `inner` no faster than its limiter allows.
+pub struct ThrottledWriter<'a, W> {
+    inner: W,
+    limiter: &'a RateLimiter,
+}
+
+impl<'a, W> ThrottledWriter<'a, W> {
+    pub fn new(inner: W, limiter: &'a RateLimiter) -> Self {
+        Self { inner, limiter }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = self.limiter.take(buf.len());
+        let result = self.inner.write(&buf[..allowed]);
+        self.limiter
+            .refund(allowed - result.as_ref().map_or(0, |written| *written));
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_limits_reads_and_writes_together() {
+        let limiter = RateLimiter::new(2000).with_burst(100);
+        let data = vec![7; 300];
+        let started = Instant::now();
+        let mut read = vec![];
+        ThrottledReader::new(&data[..], &limiter)
+            .read_to_end(&mut read)
+            .unwrap();
+        let mut written = vec![];
+        ThrottledWriter::new(&mut written, &limiter)
+            .write_all(&read)
+            .unwrap();
+        assert_eq!(written, data);
+        // The first burst is free, the other 500 bytes come at 2000 a second.
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn it_refunds_bytes_not_transferred() {
+        let limiter = RateLimiter::new(1).with_burst(10);
+        let mut buf = [0; 10];
+        let mut reader = ThrottledReader::new(&b"abc"[..], &limiter);
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        // The 7 bytes left over still allow this read without waiting a second a byte.
+        let started = Instant::now();
+        assert_eq!(reader.read(&mut buf[..7]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
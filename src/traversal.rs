@@ -0,0 +1,225 @@
+//! Depth-first traversal of the DAGs stored in an archive.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::ControlFlow;
+
+use libipld::{cid::Cid, Block, DefaultParams};
+
+use crate::v1::CarV1;
+use crate::{CarError, CarResult, Deadline};
+
+/// What to do with the links of a visited block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    Descend,
+    /// Prune: don't visit the block's links (they may still be reached through other blocks).
+    Skip,
+}
+
+/// Called for each block of a [`traverse`], with its depth below the roots (roots are at 0).
+///
+/// Returning `ControlFlow::Break` ends the traversal.
+pub trait Visitor {
+    fn visit(
+        &mut self,
+        depth: usize,
+        cid: &Cid,
+        block: &Block<DefaultParams>,
+    ) -> ControlFlow<(), Visit>;
+}
+
+impl<F> Visitor for F
+where
+    F: FnMut(usize, &Cid, &Block<DefaultParams>) -> ControlFlow<(), Visit>,
+{
+    fn visit(
+        &mut self,
+        depth: usize,
+        cid: &Cid,
+        block: &Block<DefaultParams>,
+    ) -> ControlFlow<(), Visit> {
+        self(depth, cid, block)
+    }
+}
+
+/// Walks the DAGs below `roots` depth-first, following links in the order they appear in each
+/// block. Every block is visited once, the first time the walk reaches it.
+///
+/// Fails with [`CarError::MissingBlock`] when a link leads outside of the archive.
+pub fn traverse<V: Visitor + ?Sized>(car: &CarV1, roots: &[Cid], visitor: &mut V) -> CarResult<()> {
+    traverse_with_deadline(car, roots, Deadline::none(), visitor)
+}
+
+/// Like [`traverse`], but fails with [`CarError::DeadlineExceeded`] once `deadline` has passed.
+pub fn traverse_with_deadline<V: Visitor + ?Sized>(
+    car: &CarV1,
+    roots: &[Cid],
+    deadline: Deadline,
+    visitor: &mut V,
+) -> CarResult<()> {
+    traverse_blocks(&index(car), roots, deadline, visitor)
+}
+
+/// Like [`traverse_with_deadline`], over the blocks of `blocks`.
+pub(crate) fn traverse_blocks<V: Visitor + ?Sized>(
+    blocks: &HashMap<&Cid, &Block<DefaultParams>>,
+    roots: &[Cid],
+    deadline: Deadline,
+    visitor: &mut V,
+) -> CarResult<()> {
+    let start = roots.iter().map(|root| (*root, ())).collect();
+    walk_blocks(blocks, start, false, deadline, |depth, block, _| {
+        Ok(match visitor.visit(depth, block.cid(), block) {
+            ControlFlow::Break(()) => ControlFlow::Break(()),
+            ControlFlow::Continue(Visit::Skip) => ControlFlow::Continue(vec![]),
+            ControlFlow::Continue(Visit::Descend) => {
+                ControlFlow::Continue(links(block)?.into_iter().map(|link| (link, ())).collect())
+            }
+        })
+    })
+}
+
+/// The links of a block, in the order they appear in it.
+pub fn links(block: &Block<DefaultParams>) -> CarResult<Vec<Cid>> {
+    let mut links = vec![];
+    block.references(&mut links)?;
+    Ok(links)
+}
+
+/// Depth-first walk from `start`, where `visit` returns the children to descend into along with
+/// the state they are visited with. A `(cid, state)` pair is only visited once unless `revisit`.
+pub(crate) fn walk<'a, T, F>(
+    car: &'a CarV1,
+    start: Vec<(Cid, T)>,
+    revisit: bool,
+    deadline: Deadline,
+    visit: F,
+) -> CarResult<()>
+where
+    T: Clone + Eq + Hash,
+    F: FnMut(usize, &'a Block<DefaultParams>, T) -> CarResult<ControlFlow<(), Vec<(Cid, T)>>>,
+{
+    walk_blocks(&index(car), start, revisit, deadline, visit)
+}
+
+fn index(car: &CarV1) -> HashMap<&Cid, &Block<DefaultParams>> {
+    car.blocks
+        .iter()
+        .map(|block| (block.cid(), block))
+        .collect()
+}
+
+/// Like [`walk`], over the blocks of `blocks`.
+pub(crate) fn walk_blocks<'a, T, F>(
+    blocks: &HashMap<&'a Cid, &'a Block<DefaultParams>>,
+    start: Vec<(Cid, T)>,
+    revisit: bool,
+    deadline: Deadline,
+    mut visit: F,
+) -> CarResult<()>
+where
+    T: Clone + Eq + Hash,
+    F: FnMut(usize, &'a Block<DefaultParams>, T) -> CarResult<ControlFlow<(), Vec<(Cid, T)>>>,
+{
+    let mut visited = HashSet::new();
+    let mut stack: Vec<(Cid, T, usize)> = start
+        .into_iter()
+        .rev()
+        .map(|(cid, state)| (cid, state, 0))
+        .collect();
+    while let Some((cid, state, depth)) = stack.pop() {
+        if !visited.insert((cid, state.clone())) && !revisit {
+            continue;
+        }
+        deadline.check()?;
+        let block = *blocks.get(&cid).ok_or(CarError::MissingBlock(cid))?;
+        match visit(depth, block, state)? {
+            ControlFlow::Break(()) => break,
+            ControlFlow::Continue(children) => stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|(cid, state)| (cid, state, depth + 1)),
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond};
+    use std::time::Instant;
+
+    #[test]
+    fn it_visits_blocks_once_with_depth() {
+        let car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| cids(&car)[i]);
+
+        let mut visited = vec![];
+        traverse(
+            &car,
+            &[root],
+            &mut |depth, cid: &Cid, _: &Block<DefaultParams>| {
+                visited.push((depth, *cid));
+                ControlFlow::Continue(Visit::Descend)
+            },
+        )
+        .unwrap();
+        assert_eq!(visited, vec![(0, root), (1, left), (2, leaf), (1, right)]);
+    }
+
+    #[test]
+    fn it_prunes_and_stops() {
+        let car = diamond();
+        let [leaf, right, root, left] = [0, 1, 2, 3].map(|i| cids(&car)[i]);
+
+        let mut visited = vec![];
+        traverse(
+            &car,
+            &[root],
+            &mut |_, cid: &Cid, _: &Block<DefaultParams>| {
+                visited.push(*cid);
+                ControlFlow::Continue(if *cid == left {
+                    Visit::Skip
+                } else {
+                    Visit::Descend
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!(visited, vec![root, left, right, leaf]);
+
+        let mut count = 0;
+        traverse(
+            &car,
+            &[root],
+            &mut |_, _: &Cid, _: &Block<DefaultParams>| {
+                count += 1;
+                if count == 2 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(Visit::Descend)
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn it_stops_at_the_deadline() {
+        let car = diamond();
+        let mut visitor =
+            |_, _: &Cid, _: &Block<DefaultParams>| ControlFlow::Continue(Visit::Descend);
+        let result = traverse_with_deadline(
+            &car,
+            &car.header.roots,
+            Deadline::at(Instant::now()),
+            &mut visitor,
+        );
+        assert!(matches!(result, Err(CarError::DeadlineExceeded)));
+    }
+}
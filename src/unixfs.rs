@@ -0,0 +1,773 @@
+//! The parts of the [UnixFS](https://specs.ipfs.tech/unixfs/) data model needed to navigate
+//! file and directory DAGs, and to [`extract`] them to the filesystem.
+
+use core::convert::TryFrom;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use libipld::{cid::Cid, pb::DagPbCodec, Block, DefaultParams, Ipld};
+
+use crate::gateway::BlockFetcher;
+use crate::v1::{CarV1, IndexedCarV1};
+use crate::{CarError, CarResult, HashPolicy};
+
+const RAW_CODEC: u64 = 0x55;
+const DAG_PB_CODEC: u64 = 0x70;
+
+/// The `Type` field of a UnixFS `Data` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Raw = 0,
+    Directory = 1,
+    File = 2,
+    Metadata = 3,
+    Symlink = 4,
+    HamtShard = 5,
+}
+
+impl TryFrom<u64> for DataType {
+    type Error = CarError;
+
+    fn try_from(value: u64) -> CarResult<Self> {
+        Ok(match value {
+            0 => DataType::Raw,
+            1 => DataType::Directory,
+            2 => DataType::File,
+            3 => DataType::Metadata,
+            4 => DataType::Symlink,
+            5 => DataType::HamtShard,
+            _ => {
+                return Err(CarError::InvalidUnixFs(format!(
+                    "unknown data type {}",
+                    value
+                )))
+            }
+        })
+    }
+}
+
+/// A decoded UnixFS `Data` message, as stored in the `Data` field of a dag-pb node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixFsData {
+    pub data_type: DataType,
+    pub data: Vec<u8>,
+    pub filesize: Option<u64>,
+    pub blocksizes: Vec<u64>,
+    pub hash_type: Option<u64>,
+    pub fanout: Option<u64>,
+}
+
+impl UnixFsData {
+    pub fn new(data_type: DataType) -> Self {
+        Self {
+            data_type,
+            data: vec![],
+            filesize: None,
+            blocksizes: vec![],
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> CarResult<Self> {
+        let mut data_type = None;
+        let mut message = Self::new(DataType::Raw);
+        while !bytes.is_empty() {
+            let key = read_proto_varint(&mut bytes)?;
+            match (key >> 3, key & 0b111) {
+                (1, 0) => data_type = Some(DataType::try_from(read_proto_varint(&mut bytes)?)?),
+                (2, 2) => message.data = read_proto_bytes(&mut bytes)?.to_vec(),
+                (3, 0) => message.filesize = Some(read_proto_varint(&mut bytes)?),
+                (4, 0) => message.blocksizes.push(read_proto_varint(&mut bytes)?),
+                (4, 2) => {
+                    let mut packed = read_proto_bytes(&mut bytes)?;
+                    while !packed.is_empty() {
+                        message.blocksizes.push(read_proto_varint(&mut packed)?);
+                    }
+                }
+                (5, 0) => message.hash_type = Some(read_proto_varint(&mut bytes)?),
+                (6, 0) => message.fanout = Some(read_proto_varint(&mut bytes)?),
+                (_, wire_type) => skip_proto_field(&mut bytes, wire_type)?,
+            }
+        }
+        message.data_type =
+            data_type.ok_or_else(|| CarError::InvalidUnixFs("missing data type".into()))?;
+        Ok(message)
+    }
+
+    /// Encodes the message the way go-unixfs does: optional fields are only written when set
+    /// and `blocksizes` is not packed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write_proto_varint(&mut buf, 1 << 3);
+        write_proto_varint(&mut buf, self.data_type as u64);
+        if !self.data.is_empty() {
+            write_proto_varint(&mut buf, 2 << 3 | 2);
+            write_proto_varint(&mut buf, self.data.len() as u64);
+            buf.extend_from_slice(&self.data);
+        }
+        if let Some(filesize) = self.filesize {
+            write_proto_varint(&mut buf, 3 << 3);
+            write_proto_varint(&mut buf, filesize);
+        }
+        for blocksize in &self.blocksizes {
+            write_proto_varint(&mut buf, 4 << 3);
+            write_proto_varint(&mut buf, *blocksize);
+        }
+        if let Some(hash_type) = self.hash_type {
+            write_proto_varint(&mut buf, 5 << 3);
+            write_proto_varint(&mut buf, hash_type);
+        }
+        if let Some(fanout) = self.fanout {
+            write_proto_varint(&mut buf, 6 << 3);
+            write_proto_varint(&mut buf, fanout);
+        }
+        buf
+    }
+}
+
+/// A named link of a dag-pb node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixFsLink {
+    pub cid: Cid,
+    pub name: Option<String>,
+    pub tsize: Option<u64>,
+}
+
+/// A UnixFS node: its `Data` message and its links, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixFsNode {
+    pub data: UnixFsData,
+    pub links: Vec<UnixFsLink>,
+}
+
+impl UnixFsNode {
+    /// Decodes a dag-pb block; raw blocks are treated as a file leaf holding their bytes.
+    pub fn from_block(block: &Block<DefaultParams>) -> CarResult<Self> {
+        match block.cid().codec() {
+            RAW_CODEC => Ok(Self {
+                data: UnixFsData {
+                    data: block.data().to_vec(),
+                    filesize: Some(block.data().len() as u64),
+                    ..UnixFsData::new(DataType::Raw)
+                },
+                links: vec![],
+            }),
+            DAG_PB_CODEC => Self::from_ipld(&block.decode::<DagPbCodec, Ipld>()?),
+            codec => Err(CarError::InvalidUnixFs(format!(
+                "unsupported codec 0x{:x}",
+                codec
+            ))),
+        }
+    }
+
+    fn from_ipld(node: &Ipld) -> CarResult<Self> {
+        let data = match node.get("Data") {
+            Ok(Ipld::Bytes(data)) => UnixFsData::decode(data)?,
+            _ => return Err(CarError::InvalidUnixFs("missing Data".into())),
+        };
+        let links = match node.get("Links") {
+            Ok(Ipld::List(links)) => links
+                .iter()
+                .map(|link| match link.get("Hash") {
+                    Ok(Ipld::Link(cid)) => Ok(UnixFsLink {
+                        cid: *cid,
+                        name: match link.get("Name") {
+                            Ok(Ipld::String(name)) => Some(name.clone()),
+                            _ => None,
+                        },
+                        tsize: match link.get("Tsize") {
+                            Ok(Ipld::Integer(tsize)) => u64::try_from(*tsize).ok(),
+                            _ => None,
+                        },
+                    }),
+                    _ => Err(CarError::InvalidUnixFs("link without Hash".into())),
+                })
+                .collect::<CarResult<_>>()?,
+            _ => vec![],
+        };
+        Ok(Self { data, links })
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.data.data_type, DataType::File | DataType::Raw)
+    }
+
+    /// Size of the file content below this node.
+    pub fn file_size(&self) -> u64 {
+        self.data.filesize.unwrap_or_else(|| {
+            let below = self
+                .data
+                .blocksizes
+                .iter()
+                .fold(0u64, |sum, size| sum.saturating_add(*size));
+            below.saturating_add(self.data.data.len() as u64)
+        })
+    }
+}
+
+/// Decrypts the leaves of files whose raw blocks hold ciphertext, as in WNFS, with a key per
+/// block; see [`UnixFsReader::with_keys`] and [`extract_with_keys`]. Such archives are written
+/// like any other, as the encrypted leaves are raw blocks of their ciphertext, with the
+/// `blocksizes` of their parents counting plaintext; [`crate::pack::Packer::with_keys`] packs
+/// them.
+pub trait KeyProvider {
+    /// The plaintext of the raw block `cid` holding `data`, `None` if it is stored in the clear.
+    fn decrypt(&self, cid: &Cid, data: &[u8]) -> CarResult<Option<Vec<u8>>>;
+
+    /// The ciphertext to store for the chunk `data` of a file being packed, `None` to store it
+    /// in the clear, as the default does. [`KeyProvider::decrypt`] is later given only the CID
+    /// and ciphertext of the block, so the key must be found from those.
+    fn encrypt(&self, data: &[u8]) -> CarResult<Option<Vec<u8>>> {
+        let _ = data;
+        Ok(None)
+    }
+}
+
+impl<F> KeyProvider for F
+where
+    F: Fn(&Cid, &[u8]) -> CarResult<Option<Vec<u8>>>,
+{
+    fn decrypt(&self, cid: &Cid, data: &[u8]) -> CarResult<Option<Vec<u8>>> {
+        self(cid, data)
+    }
+}
+
+/// A `Read + Seek` view of the UnixFS file `root` of an archive, or of any other source of
+/// blocks.
+///
+/// Only the nodes on the way to the current position are fetched and decoded, on demand, and
+/// the leaf being read is kept until the position moves past it.
+pub struct UnixFsReader<'a, F = IndexedCarV1<'a>> {
+    source: F,
+    root_cid: Cid,
+    root: UnixFsNode,
+    size: u64,
+    position: u64,
+    /// The offset and bytes of the leaf holding the last position read.
+    leaf: Option<(u64, Vec<u8>)>,
+    keys: Option<&'a dyn KeyProvider>,
+}
+
+impl<F> fmt::Debug for UnixFsReader<'_, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixFsReader")
+            .field("root", &self.root_cid)
+            .field("size", &self.size)
+            .field("position", &self.position)
+            .field("keys", &self.keys.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> UnixFsReader<'a> {
+    pub fn new(car: &'a CarV1, root: &Cid) -> CarResult<Self> {
+        Self::from_fetcher(car.indexed(), root)
+    }
+}
+
+impl<'a, F: BlockFetcher> UnixFsReader<'a, F> {
+    /// Reads the file `root` from the blocks of `source`, such as a `CarV2Store` over an indexed
+    /// archive or a [`crate::detached::DetachedCar`], so only the blocks on the way to the
+    /// positions read are fetched. Each block is checked against its CID once fetched.
+    pub fn from_fetcher(source: F, root: &Cid) -> CarResult<Self> {
+        let mut reader = Self {
+            source,
+            root_cid: *root,
+            root: UnixFsNode {
+                data: UnixFsData::new(DataType::File),
+                links: vec![],
+            },
+            size: 0,
+            position: 0,
+            leaf: None,
+            keys: None,
+        };
+        reader.load_root()?;
+        Ok(reader)
+    }
+
+    /// Decrypts raw leaves with `keys` as they are read, including a root that is one, failing
+    /// with the provider's error if it cannot decrypt the root.
+    pub fn with_keys(mut self, keys: &'a dyn KeyProvider) -> CarResult<Self> {
+        self.keys = Some(keys);
+        self.leaf = None;
+        self.load_root()?;
+        Ok(self)
+    }
+
+    /// The size of the file.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn load_root(&mut self) -> CarResult<()> {
+        let root = self.node(&self.root_cid)?;
+        if !root.is_file() {
+            return Err(CarError::InvalidUnixFs(format!(
+                "{} is a {:?}, not a file",
+                self.root_cid, root.data.data_type
+            )));
+        }
+        self.size = root.file_size();
+        self.root = root;
+        Ok(())
+    }
+
+    /// Decodes the node `cid`, decrypting it if it is a raw leaf the key provider has a key for.
+    fn node(&self, cid: &Cid) -> CarResult<UnixFsNode> {
+        let data = self
+            .source
+            .fetch(cid)?
+            .ok_or(CarError::MissingBlock(*cid))?;
+        let (block, _) = HashPolicy::default().block(*cid, data, 0)?;
+        let mut node = UnixFsNode::from_block(&block)?;
+        if let (Some(keys), RAW_CODEC) = (self.keys, cid.codec()) {
+            if let Some(plaintext) = keys.decrypt(cid, block.data())? {
+                node.data.filesize = Some(plaintext.len() as u64);
+                node.data.data = plaintext;
+            }
+        }
+        Ok(node)
+    }
+
+    /// Finds the leaf holding `position`, returning its offset in the file and its bytes.
+    fn locate(&self, position: u64) -> CarResult<(u64, Vec<u8>)> {
+        let mut node = Cow::Borrowed(&self.root);
+        let mut start = 0u64;
+        loop {
+            let overflow = || CarError::InvalidUnixFs("blocksizes overflow".into());
+            let inline = node.data.data.len() as u64;
+            let mut child_start = start.checked_add(inline).ok_or_else(overflow)?;
+            if position < child_start || node.links.is_empty() {
+                return Ok((start, node.into_owned().data.data));
+            }
+            let mut child = None;
+            for (link, size) in node.links.iter().zip(&node.data.blocksizes) {
+                let end = child_start.checked_add(*size).ok_or_else(overflow)?;
+                if position < end {
+                    child = Some(link.cid);
+                    break;
+                }
+                child_start = end;
+            }
+            let cid = child.ok_or_else(|| {
+                CarError::InvalidUnixFs("blocksizes do not cover the file".into())
+            })?;
+            node = Cow::Owned(self.node(&cid)?);
+            start = child_start;
+        }
+    }
+}
+
+impl<F: BlockFetcher> Read for UnixFsReader<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let holds = |(start, bytes): &(u64, Vec<u8>)| {
+            *start <= self.position && self.position - start < bytes.len() as u64
+        };
+        let (start, bytes) = match self.leaf.take() {
+            Some(leaf) if holds(&leaf) => leaf,
+            _ => {
+                let leaf = self
+                    .locate(self.position)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                if !holds(&leaf) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        CarError::InvalidUnixFs("leaf shorter than its blocksize".into()),
+                    ));
+                }
+                leaf
+            }
+        };
+        let from = (self.position - start) as usize;
+        let n = buf.len().min(bytes.len() - from);
+        buf[..n].copy_from_slice(&bytes[from..from + n]);
+        self.position += n as u64;
+        self.leaf = Some((start, bytes));
+        Ok(n)
+    }
+}
+
+impl<F: BlockFetcher> Seek for UnixFsReader<'_, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Writes the files, directories and symlinks below the roots of `car` into `dir`, as
+/// `ipfs get` does: the entries of a directory root into `dir` itself, and any other root as
+/// `dir/<cid>`. Every block is checked against its CID before anything of it is written.
+///
+/// Entries are created, never overwritten, so extracting over existing files or through a
+/// symlink the archive just created fails. HAMT-sharded directories and entry names that are
+/// not a single path component fail with [`CarError::InvalidUnixFs`].
+pub fn extract(car: &CarV1, dir: &Path) -> CarResult<()> {
+    extract_files(car, dir, None)
+}
+
+/// Extracts as [`extract`] does, decrypting the raw leaves of files with `keys`.
+pub fn extract_with_keys(car: &CarV1, dir: &Path, keys: &dyn KeyProvider) -> CarResult<()> {
+    extract_files(car, dir, Some(keys))
+}
+
+fn extract_files(car: &CarV1, dir: &Path, keys: Option<&dyn KeyProvider>) -> CarResult<()> {
+    let mut index = HashMap::new();
+    for (i, block) in car.blocks.iter().enumerate() {
+        index.entry(*block.cid()).or_insert(i);
+    }
+    let extractor = Extractor { car, index, keys };
+    fs::create_dir_all(dir)?;
+    for root in &car.header.roots {
+        let node = extractor.node(root)?;
+        match node.data.data_type {
+            DataType::Directory => extractor.directory(&node, dir)?,
+            _ => extractor.entry(root, &node, &dir.join(root.to_string()))?,
+        }
+    }
+    Ok(())
+}
+
+struct Extractor<'a> {
+    car: &'a CarV1,
+    index: HashMap<Cid, usize>,
+    keys: Option<&'a dyn KeyProvider>,
+}
+
+impl Extractor<'_> {
+    /// Decodes the node `cid` once its block is verified.
+    fn node(&self, cid: &Cid) -> CarResult<UnixFsNode> {
+        let block = match self.index.get(cid) {
+            Some(&i) => &self.car.blocks[i],
+            None => return Err(CarError::MissingBlock(*cid)),
+        };
+        HashPolicy::default().block(*cid, block.data().to_vec(), 0)?;
+        UnixFsNode::from_block(block)
+    }
+
+    fn entry(&self, cid: &Cid, node: &UnixFsNode, path: &Path) -> CarResult<()> {
+        match node.data.data_type {
+            DataType::Directory => {
+                fs::create_dir(path)?;
+                self.directory(node, path)
+            }
+            DataType::File | DataType::Raw => {
+                self.verify_below(node)?;
+                let mut file = File::options().write(true).create_new(true).open(path)?;
+                let mut reader = UnixFsReader::new(self.car, cid)?;
+                if let Some(keys) = self.keys {
+                    reader = reader.with_keys(keys)?;
+                }
+                io::copy(&mut reader, &mut file)?;
+                Ok(())
+            }
+            DataType::Symlink => symlink(&node.data.data, path),
+            data_type => Err(CarError::InvalidUnixFs(format!(
+                "cannot extract {} of type {:?}",
+                cid, data_type
+            ))),
+        }
+    }
+
+    fn directory(&self, node: &UnixFsNode, path: &Path) -> CarResult<()> {
+        for link in &node.links {
+            let name = link.name.as_deref().unwrap_or_default();
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+                return Err(CarError::InvalidUnixFs(format!(
+                    "invalid entry name {:?}",
+                    name
+                )));
+            }
+            let child = self.node(&link.cid)?;
+            self.entry(&link.cid, &child, &path.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Verifies every block of the file below `node`.
+    fn verify_below(&self, node: &UnixFsNode) -> CarResult<()> {
+        for link in &node.links {
+            self.verify_below(&self.node(&link.cid)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], path: &Path) -> CarResult<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(_: &[u8], path: &Path) -> CarResult<()> {
+    Err(CarError::InvalidUnixFs(format!(
+        "cannot create symlink {} on this platform",
+        path.display()
+    )))
+}
+
+fn read_proto_varint(bytes: &mut &[u8]) -> CarResult<u64> {
+    let (value, rest) = unsigned_varint::decode::u64(bytes)
+        .map_err(|_| CarError::InvalidUnixFs("malformed varint".into()))?;
+    *bytes = rest;
+    Ok(value)
+}
+
+fn read_proto_bytes<'a>(bytes: &mut &'a [u8]) -> CarResult<&'a [u8]> {
+    let length = read_proto_varint(bytes)? as usize;
+    if length > bytes.len() {
+        return Err(CarError::InvalidUnixFs("truncated field".into()));
+    }
+    let (value, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn skip_proto_field(bytes: &mut &[u8], wire_type: u64) -> CarResult<()> {
+    let skip = match wire_type {
+        0 => return read_proto_varint(bytes).map(|_| ()),
+        1 => 8,
+        2 => return read_proto_bytes(bytes).map(|_| ()),
+        5 => 4,
+        _ => {
+            return Err(CarError::InvalidUnixFs(format!(
+                "unknown wire type {}",
+                wire_type
+            )))
+        }
+    };
+    if skip > bytes.len() {
+        return Err(CarError::InvalidUnixFs("truncated field".into()));
+    }
+    *bytes = &bytes[skip..];
+    Ok(())
+}
+
+fn write_proto_varint(buf: &mut Vec<u8>, value: u64) {
+    let mut varint_buf = unsigned_varint::encode::u64_buffer();
+    buf.extend_from_slice(unsigned_varint::encode::u64(value, &mut varint_buf));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::Packer;
+    use crate::test_utils::{temp_dir, unixfs_file, unixfs_file_with_blocksizes};
+    use std::cell::Cell;
+
+    /// Counts the blocks fetched from an archive.
+    struct Counting<'a>(IndexedCarV1<'a>, Cell<usize>);
+
+    impl BlockFetcher for Counting<'_> {
+        fn fetch(&self, cid: &Cid) -> CarResult<Option<Vec<u8>>> {
+            self.1.set(self.1.get() + 1);
+            self.0.fetch(cid)
+        }
+    }
+
+    #[test]
+    fn it_round_trips_data_messages() {
+        let message = UnixFsData {
+            data: b"hello".to_vec(),
+            filesize: Some(12),
+            blocksizes: vec![5, 7],
+            ..UnixFsData::new(DataType::File)
+        };
+        assert_eq!(UnixFsData::decode(&message.encode()).unwrap(), message);
+        // Packed `blocksizes` as written by some encoders.
+        assert_eq!(
+            UnixFsData::decode(&[0x08, 0x02, 0x22, 0x02, 0x05, 0x07])
+                .unwrap()
+                .blocksizes,
+            vec![5, 7]
+        );
+    }
+
+    #[test]
+    fn it_reads_unixfs_directories() {
+        let car =
+            crate::v1::CarV1::from_reader(&include_bytes!("../tests/fixtures/carv1-basic.car")[..])
+                .unwrap();
+        let root = UnixFsNode::from_block(&car.blocks[0]).unwrap();
+        assert_eq!(root.data.data_type, DataType::Directory);
+        let names: Vec<_> = root.links.iter().map(|link| link.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("0"), Some("1"), Some("2")]);
+        assert_eq!(root.links[0].cid, *car.blocks[1].cid());
+
+        let file = UnixFsNode::from_block(&car.blocks[1]).unwrap();
+        assert!(file.is_file());
+        assert_eq!(file.file_size(), 512);
+    }
+
+    #[test]
+    fn it_reads_and_seeks_files() {
+        let car = unixfs_file(&[b"hello ", b"", b"world"]);
+        let mut reader = UnixFsReader::new(&car, &car.header.roots[0]).unwrap();
+        assert_eq!(reader.len(), 11);
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello world");
+
+        reader.seek(SeekFrom::End(-3)).unwrap();
+        let mut end = vec![];
+        reader.read_to_end(&mut end).unwrap();
+        assert_eq!(end, b"rld");
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+
+        let leaf = *car.blocks[1].cid();
+        assert!(UnixFsReader::new(&car, &leaf).is_ok());
+        let mut missing = car.clone();
+        missing.blocks.pop();
+        let mut reader = UnixFsReader::new(&missing, &car.header.roots[0]).unwrap();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn it_decrypts_leaves_with_their_keys() {
+        let xor =
+            |data: &[u8], key: u8| -> Vec<u8> { data.iter().map(|byte| byte ^ key).collect() };
+        let (hello, world) = (xor(b"hello ", 1), xor(b"world", 2));
+        let car = unixfs_file(&[&hello, b"clear", &world]);
+        let keys: HashMap<Cid, u8> =
+            HashMap::from([(*car.blocks[1].cid(), 1), (*car.blocks[3].cid(), 2)]);
+        let provider = |cid: &Cid, data: &[u8]| Ok(keys.get(cid).map(|key| xor(data, *key)));
+        let mut text = String::new();
+        UnixFsReader::new(&car, &car.header.roots[0])
+            .unwrap()
+            .with_keys(&provider)
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "hello clearworld");
+
+        // A file of a single encrypted leaf, and a provider failing on a wrong key.
+        let leaf = car.blocks[3].cid();
+        let mut text = String::new();
+        let mut reader = UnixFsReader::new(&car, leaf)
+            .unwrap()
+            .with_keys(&provider)
+            .unwrap();
+        assert_eq!(reader.len(), 5);
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "world");
+        let wrong = |cid: &Cid, _: &[u8]| Err(CarError::MissingBlock(*cid));
+        assert!(UnixFsReader::new(&car, leaf)
+            .unwrap()
+            .with_keys(&wrong)
+            .is_err());
+    }
+
+    #[test]
+    fn it_reads_multi_level_files() {
+        let dir = temp_dir("unixfs-reader");
+        let path = dir.join("file");
+        let content: Vec<u8> = (0..2000).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let car = Packer::new().with_chunk_size(7).pack(&path).unwrap();
+
+        let mut reader = UnixFsReader::new(&car, &car.header.roots[0]).unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, content);
+        reader.seek(SeekFrom::Start(1234)).unwrap();
+        let mut buf = [0; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &content[1234..1254]);
+
+        // Reading a range fetches only the nodes on the way to it.
+        let counting = Counting(car.indexed(), Cell::new(0));
+        let mut reader = UnixFsReader::from_fetcher(&counting, &car.header.roots[0]).unwrap();
+        reader.seek(SeekFrom::Start(1234)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &content[1234..1254]);
+        assert!(counting.1.get() < car.blocks.len() / 10);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "v2")]
+    #[test]
+    fn it_reads_files_of_indexed_archives() {
+        use crate::blockstore::CarV2Store;
+        use crate::v2::IndexKind;
+
+        let car = unixfs_file(&[b"hello ", b"world"]);
+        let mut bytes = io::Cursor::new(vec![]);
+        car.clone()
+            .into_v2(IndexKind::MultihashSorted)
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+        let store = CarV2Store::new(io::Cursor::new(bytes.into_inner())).unwrap();
+        let mut text = String::new();
+        UnixFsReader::from_fetcher(store, &car.header.roots[0])
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "hello world");
+    }
+
+    #[test]
+    fn it_fails_on_blocksizes_that_overflow() {
+        let car = unixfs_file_with_blocksizes(&[b"aaaa", b"bbbb"], &[4, u64::MAX]);
+        let mut reader = UnixFsReader::new(&car, &car.header.roots[0]).unwrap();
+        assert_eq!(reader.len(), u64::MAX);
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        let err = reader.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn it_extracts_files_and_directories() {
+        let dir = temp_dir("unixfs-extract");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        let content: Vec<u8> = (0..2000).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(source.join("big.bin"), &content).unwrap();
+        fs::write(source.join("sub/empty"), b"").unwrap();
+        let car = Packer::new().with_chunk_size(100).pack(&source).unwrap();
+
+        let out = dir.join("out");
+        extract(&car, &out).unwrap();
+        assert_eq!(fs::read(out.join("big.bin")).unwrap(), content);
+        assert_eq!(fs::read(out.join("sub/empty")).unwrap(), b"");
+        // Nothing is overwritten.
+        assert!(extract(&car, &out).is_err());
+
+        let file = unixfs_file(&[b"hello ", b"world"]);
+        extract(&file, &out).unwrap();
+        let root = file.header.roots[0].to_string();
+        assert_eq!(fs::read(out.join(root)).unwrap(), b"hello world");
+
+        let mut corrupt = file.clone();
+        corrupt.blocks[2] = Block::new_unchecked(*file.blocks[2].cid(), b"wrold".to_vec());
+        let out = dir.join("corrupt");
+        assert!(extract(&corrupt, &out).is_err());
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
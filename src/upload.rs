@@ -0,0 +1,424 @@
+//! One-pass validation of CARv1 uploads as their bytes stream through to storage, see
+//! [`UploadValidator`].
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::prelude::Codec;
+
+use crate::v1::CarHeaderV1;
+use crate::{CarError, CarResult, HashPolicy, DEFAULT_MAX_SECTION_SIZE};
+
+const CHUNK: usize = 64 * 1024;
+
+/// What an upload must satisfy to be accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadPolicy<'a> {
+    /// The most bytes an upload may have.
+    pub max_size: Option<u64>,
+    /// The longest section an upload may have, header included, [`DEFAULT_MAX_SECTION_SIZE`] by
+    /// default. It bounds what is buffered while a section arrives, even without `max_size`.
+    pub max_section_size: u64,
+    /// Require the first block to be one of the roots, so a DAG can be served as it arrives.
+    pub root_first: bool,
+    /// The block codecs accepted, any if `None`.
+    pub allowed_codecs: Option<&'a [u64]>,
+    pub hashes: HashPolicy<'a>,
+}
+
+impl Default for UploadPolicy<'_> {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            max_section_size: DEFAULT_MAX_SECTION_SIZE,
+            root_first: true,
+            allowed_codecs: None,
+            hashes: HashPolicy::default(),
+        }
+    }
+}
+
+/// Why an upload was rejected, with the offset in the upload of the section at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    TooLarge {
+        limit: u64,
+    },
+    SectionTooLarge {
+        offset: u64,
+        length: u64,
+        limit: u64,
+    },
+    Malformed {
+        offset: u64,
+        reason: String,
+    },
+    /// The upload ended within the section at `offset`, or before the header.
+    Truncated {
+        offset: u64,
+    },
+    /// No blocks, when the first block must be a root.
+    Empty,
+    FirstBlockNotRoot {
+        cid: Cid,
+    },
+    CodecNotAllowed {
+        offset: u64,
+        cid: Cid,
+    },
+    HashMismatch {
+        offset: u64,
+        cid: Cid,
+    },
+    /// A hash with no implementation, rejected by a strict [`HashPolicy`].
+    UnverifiableHash {
+        offset: u64,
+        cid: Cid,
+    },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { limit } => write!(f, "larger than {} bytes", limit),
+            Self::SectionTooLarge {
+                offset,
+                length,
+                limit,
+            } => write!(
+                f,
+                "section of {} bytes at {} exceeds the limit of {}",
+                length, offset, limit
+            ),
+            Self::Malformed { offset, reason } => write!(f, "malformed at {}: {}", offset, reason),
+            Self::Truncated { offset } => write!(f, "truncated at {}", offset),
+            Self::Empty => write!(f, "no blocks"),
+            Self::FirstBlockNotRoot { cid } => write!(f, "first block {} is not a root", cid),
+            Self::CodecNotAllowed { offset, cid } => write!(
+                f,
+                "codec {:#x} of {} at {} is not allowed",
+                cid.codec(),
+                cid,
+                offset
+            ),
+            Self::HashMismatch { offset, cid } => {
+                write!(f, "data of {} at {} does not match its hash", cid, offset)
+            }
+            Self::UnverifiableHash { offset, cid } => {
+                write!(f, "hash of {} at {} cannot be verified", cid, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// What an accepted upload holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadSummary {
+    pub roots: Vec<Cid>,
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Checks an upload as its bytes are pushed, failing with [`CarError::Rejected`] as soon as
+/// they break the policy. Only the current section is held, and sections longer than
+/// [`UploadPolicy::max_section_size`] are rejected once their length is read, so uploads of any
+/// size are checked in memory bounded by that limit and the chunks pushed.
+#[derive(Debug)]
+pub struct UploadValidator<'a> {
+    policy: UploadPolicy<'a>,
+    buf: Vec<u8>,
+    /// The offset in the upload of the start of `buf`.
+    offset: u64,
+    roots: Option<Vec<Cid>>,
+    blocks: u64,
+}
+
+impl<'a> UploadValidator<'a> {
+    pub fn new(policy: UploadPolicy<'a>) -> Self {
+        Self {
+            policy,
+            buf: vec![],
+            offset: 0,
+            roots: None,
+            blocks: 0,
+        }
+    }
+
+    /// How many bytes were pushed.
+    pub fn received(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+
+    /// Checks the sections `bytes` complete. Keeps failing once it has failed.
+    pub fn push(&mut self, bytes: &[u8]) -> CarResult<()> {
+        self.buf.extend_from_slice(bytes);
+        if let Some(limit) = self.policy.max_size {
+            if self.received() > limit {
+                return Err(Rejection::TooLarge { limit }.into());
+            }
+        }
+        let mut pos = 0;
+        let result = loop {
+            match self.next_section(pos) {
+                Ok(Some(end)) => pos = end,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.buf.drain(..pos);
+        self.offset += pos as u64;
+        result
+    }
+
+    /// Checks that the upload ended between two sections, and had a first block if needed.
+    pub fn finish(self) -> CarResult<UploadSummary> {
+        let roots = match self.roots {
+            Some(roots) if self.buf.is_empty() => roots,
+            _ => {
+                return Err(Rejection::Truncated {
+                    offset: self.offset,
+                }
+                .into())
+            }
+        };
+        if self.policy.root_first && self.blocks == 0 {
+            return Err(Rejection::Empty.into());
+        }
+        Ok(UploadSummary {
+            roots,
+            blocks: self.blocks,
+            bytes: self.offset,
+        })
+    }
+
+    /// Checks the section at `pos` in the buffer, returning where it ends, or `None` if it is
+    /// not complete yet.
+    fn next_section(&mut self, pos: usize) -> CarResult<Option<usize>> {
+        let offset = self.offset + pos as u64;
+        let malformed = |reason: &dyn fmt::Display| Rejection::Malformed {
+            offset,
+            reason: reason.to_string(),
+        };
+        let (length, rest) = match unsigned_varint::decode::u64(&self.buf[pos..]) {
+            Ok(decoded) => decoded,
+            Err(unsigned_varint::decode::Error::Insufficient) => return Ok(None),
+            Err(err) => return Err(malformed(&err).into()),
+        };
+        let limit = self.policy.max_section_size;
+        if length > limit {
+            return Err(Rejection::SectionTooLarge {
+                offset,
+                length,
+                limit,
+            }
+            .into());
+        }
+        let start = self.buf.len() - rest.len();
+        let end = (start as u64)
+            .checked_add(length)
+            .ok_or_else(|| malformed(&"section length overflows"))?;
+        if let Some(limit) = self.policy.max_size {
+            if self.offset + end > limit {
+                return Err(Rejection::TooLarge { limit }.into());
+            }
+        }
+        if end > self.buf.len() as u64 {
+            return Ok(None);
+        }
+        let end = end as usize;
+        let mut section = &self.buf[start..end];
+
+        let roots = match &self.roots {
+            Some(roots) => roots,
+            None => {
+                let header = DagCborCodec
+                    .decode(section)
+                    .map_err(CarError::from)
+                    .and_then(CarHeaderV1::from_ipld)
+                    .map_err(|err| malformed(&err))?;
+                self.roots = Some(header.roots);
+                return Ok(Some(end));
+            }
+        };
+        let cid = Cid::read_bytes(&mut section).map_err(|err| malformed(&err))?;
+        if self.policy.root_first && self.blocks == 0 && !roots.contains(&cid) {
+            return Err(Rejection::FirstBlockNotRoot { cid }.into());
+        }
+        if let Some(codecs) = self.policy.allowed_codecs {
+            if !codecs.contains(&cid.codec()) {
+                return Err(Rejection::CodecNotAllowed { offset, cid }.into());
+            }
+        }
+        match self.policy.hashes.block(cid, section.to_vec(), offset) {
+            Ok(_) => {}
+            Err(CarError::UnverifiableHash(_)) => {
+                return Err(Rejection::UnverifiableHash { offset, cid }.into())
+            }
+            Err(_) => return Err(Rejection::HashMismatch { offset, cid }.into()),
+        }
+        self.blocks += 1;
+        Ok(Some(end))
+    }
+}
+
+/// Copies the upload in `r` to `w` while checking it, stopping at the first chunk that breaks
+/// the policy before it is written. What was written before a rejection is left for the caller to
+/// discard.
+pub fn validate_upload<R: Read, W: Write>(
+    mut r: R,
+    mut w: W,
+    policy: UploadPolicy,
+) -> CarResult<UploadSummary> {
+    let mut validator = UploadValidator::new(policy);
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let read = r.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        validator.push(&buf[..read])?;
+        w.write_all(&buf[..read])?;
+    }
+    w.flush()?;
+    validator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cids, diamond};
+    use crate::v1::CarV1;
+    use libipld::{Block, DefaultParams};
+
+    fn upload(car: &CarV1) -> Vec<u8> {
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn upload_len(block: &Block<DefaultParams>) -> usize {
+        let mut section = vec![];
+        crate::block::write_car_v1_block(&mut section, block).unwrap();
+        section.len()
+    }
+
+    fn rejection(result: CarResult<UploadSummary>) -> Rejection {
+        match result {
+            Err(CarError::Rejected(rejection)) => rejection,
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_accepts_uploads_as_they_stream_through() {
+        // The root of the diamond first.
+        let mut car = diamond();
+        car.blocks.swap(0, 2);
+        let bytes = upload(&car);
+        let mut stored = vec![];
+        let summary = validate_upload(&bytes[..], &mut stored, UploadPolicy::default()).unwrap();
+        assert_eq!(stored, bytes);
+        assert_eq!(summary.roots, car.header.roots);
+        assert_eq!(summary.blocks, 4);
+        assert_eq!(summary.bytes, bytes.len() as u64);
+
+        let mut validator = UploadValidator::new(UploadPolicy::default());
+        for chunk in bytes.chunks(3) {
+            validator.push(chunk).unwrap();
+        }
+        assert_eq!(validator.received(), bytes.len() as u64);
+        assert_eq!(validator.finish().unwrap(), summary);
+    }
+
+    #[test]
+    fn it_rejects_uploads_with_a_precise_reason() {
+        let car = diamond();
+        let [leaf, right, ..] = cids(&car)[..] else {
+            unreachable!()
+        };
+        let bytes = upload(&car);
+        let check =
+            |bytes: &[u8], policy: UploadPolicy| rejection(validate_upload(bytes, vec![], policy));
+        let default = UploadPolicy::default();
+        assert_eq!(
+            check(&bytes, default),
+            Rejection::FirstBlockNotRoot { cid: leaf }
+        );
+
+        let any_order = UploadPolicy {
+            root_first: false,
+            ..default
+        };
+        validate_upload(&bytes[..], vec![], any_order).unwrap();
+        let small = UploadPolicy {
+            max_size: Some(bytes.len() as u64 - 1),
+            ..any_order
+        };
+        assert_eq!(
+            check(&bytes, small),
+            Rejection::TooLarge {
+                limit: bytes.len() as u64 - 1
+            }
+        );
+        let raw_only = UploadPolicy {
+            allowed_codecs: Some(&[0x55]),
+            ..any_order
+        };
+        let header_len = bytes.len() - car.blocks.iter().map(upload_len).sum::<usize>();
+        let offset = (header_len + upload_len(&car.blocks[0])) as u64;
+        assert_eq!(
+            check(&bytes, raw_only),
+            Rejection::CodecNotAllowed { offset, cid: right }
+        );
+        assert_eq!(
+            check(&bytes[..bytes.len() - 1], any_order),
+            Rejection::Truncated {
+                offset: bytes.len() as u64 - upload_len(&car.blocks[3]) as u64
+            }
+        );
+
+        let mut corrupt = car.clone();
+        corrupt.blocks[0] = Block::new_unchecked(leaf, b"lead".to_vec());
+        assert_eq!(
+            check(&upload(&corrupt), any_order),
+            Rejection::HashMismatch {
+                offset: header_len as u64,
+                cid: leaf
+            }
+        );
+        let empty = CarV1::new(car.header.clone(), vec![]);
+        assert_eq!(check(&upload(&empty), default), Rejection::Empty);
+        assert!(matches!(
+            check(b"\x02\xa0\x00", default),
+            Rejection::Malformed { offset: 0, .. }
+        ));
+        let short_sections = UploadPolicy {
+            max_section_size: header_len as u64 - 2,
+            ..any_order
+        };
+        assert_eq!(
+            check(&bytes, short_sections),
+            Rejection::SectionTooLarge {
+                offset: 0,
+                length: header_len as u64 - 1,
+                limit: header_len as u64 - 2,
+            }
+        );
+        // A length past the limit is rejected before the section is buffered.
+        let mut validator = UploadValidator::new(default);
+        assert!(validator.push(&bytes[..header_len]).is_ok());
+        assert!(matches!(
+            validator.push(&[0xff, 0xff, 0xff, 0xff, 0x0f]),
+            Err(CarError::Rejected(Rejection::SectionTooLarge { .. }))
+        ));
+
+        // Nothing of the rejected chunk reaches storage.
+        let mut stored = vec![];
+        assert!(validate_upload(&bytes[..], &mut stored, default).is_err());
+        assert!(stored.is_empty());
+    }
+}
@@ -1,14 +1,67 @@
-use std::io::{Cursor, Read, Seek};
-use libipld::{Block, DefaultParams, Ipld, cid::Cid, prelude::Codec};
+use crate::block::{write_car_v1_block, write_car_v1_data};
+use crate::index::CarV2Index;
+use crate::params;
+use crate::traversal::links;
+use crate::{
+    read_length_prefixed, read_varint_lenient, write_varint, CarError, CarResult, CountingReader,
+    ReadAnomaly, ReadOptions, ReadReport,
+};
 use libipld::cbor::DagCborCodec;
-use crate::{CarError, CarResult};
+use libipld::multihash::Code;
+use libipld::store::StoreParams;
+use libipld::{cid::Cid, prelude::Codec, Block, DefaultParams, Ipld, IpldCodec};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use unsigned_varint::io::read_u64 as varint_read_u64;
 
 /// An IPLD Content Archive Version 1
+///
+/// Blocks are libipld blocks of the store parameters `S`, so that archives can be read into
+/// and written from blockstores with their own, e.g. [`crate::params::LargeParams`], through
+/// [`CarV1::new_with_params`] and [`CarV1::from_reader_with_params`]. Reading does not enforce
+/// `S::MAX_BLOCK_SIZE`. Traversing the blocks, as most of the crate does, needs the
+/// [`DefaultParams`] codecs.
 #[derive(Debug, Clone)]
-pub struct CarV1 {
+pub struct CarV1<S: StoreParams = DefaultParams> {
     pub header: CarHeaderV1,
-    pub blocks: Vec<Block<DefaultParams>>,
+    pub blocks: Vec<Block<S>>,
+}
+
+impl<S: StoreParams> CarV1<S> {
+    /// Like [`CarV1::new`], for blocks of the store parameters `S`.
+    pub fn new_with_params(header: CarHeaderV1, blocks: Vec<Block<S>>) -> Self {
+        Self { header, blocks }
+    }
+
+    /// Like [`CarV1::from_reader_with_report`], for blocks of the store parameters `S`.
+    pub fn from_reader_with_params<R: Read>(
+        r: R,
+        options: &ReadOptions,
+    ) -> CarResult<(Self, ReadReport)> {
+        let mut r = CountingReader::new(r);
+        let mut report = ReadReport::default();
+        let header = CarHeaderV1::from_reader(&mut r, options, &mut report)?;
+        if let Some(metrics) = options.metrics {
+            metrics.bytes_read(r.position());
+        }
+        let blocks = read_sections(&mut r, options, &mut report)?;
+
+        Ok((Self { header, blocks }, report))
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> CarResult<()> {
+        self.header.write_to(&mut w)?;
+        write_car_v1_data(w, &self.blocks)
+    }
+
+    /// Adds `block` and returns its CID. Its links are not checked, so a DAG can be put in any
+    /// order; see [`CarV1::missing_links`]. A block that was already added is added again, as
+    /// archives may store a block twice; [`crate::builder::CarBuilder`] keeps each once.
+    pub fn put_block(&mut self, block: Block<S>) -> Cid {
+        let cid = *block.cid();
+        self.blocks.push(block);
+        cid
+    }
 }
 
 impl CarV1 {
@@ -16,29 +69,293 @@ impl CarV1 {
         Self { header, blocks }
     }
 
-    pub fn from_reader<R: Read>(mut r: R) -> CarResult<Self> {
-        let header = CarHeaderV1::from_reader(&mut r)?;
+    pub fn from_reader<R: Read>(r: R) -> CarResult<Self> {
+        Self::from_reader_with_options(r, &ReadOptions::default())
+    }
+
+    pub fn from_reader_with_options<R: Read>(r: R, options: &ReadOptions) -> CarResult<Self> {
+        Self::from_reader_with_report(r, options).map(|(car, _)| car)
+    }
+
+    /// Reads an archive along with the anomalies found in it. Offsets in the report are counted
+    /// from the start of `r`.
+    pub fn from_reader_with_report<R: Read>(
+        r: R,
+        options: &ReadOptions,
+    ) -> CarResult<(Self, ReadReport)> {
+        Self::from_reader_with_params(r, options)
+    }
 
-        Ok(Self { header, blocks: read_car_v1_data(r)? })
+    /// Encodes `value` with `codec`, hashes it with sha2-256 and adds the block, returning its
+    /// CID to link to from later blocks.
+    pub fn put_ipld(&mut self, codec: IpldCodec, value: &Ipld) -> CarResult<Cid> {
+        Ok(self.put_block(params::encode(codec, Code::Sha2_256, value)?))
     }
+
+    /// Wraps the archive in a CARv2 with an index of `index`, see
+    /// [`crate::v2::CarV2::from_car_v1_with_index`].
+    #[cfg(feature = "v2")]
+    pub fn into_v2(self, index: crate::v2::IndexKind) -> CarResult<crate::v2::CarV2> {
+        crate::v2::CarV2::from_car_v1_with_index(self, Some(index))
+    }
+
+    /// The links of stored blocks to blocks that are not stored, each once, in the order
+    /// they are found.
+    pub fn missing_links(&self) -> CarResult<Vec<Cid>> {
+        let stored: HashSet<Cid> = self.blocks.iter().map(|block| *block.cid()).collect();
+        let mut missing = vec![];
+        let mut seen = HashSet::new();
+        for block in &self.blocks {
+            for link in links(block)? {
+                if !stored.contains(&link) && seen.insert(link) {
+                    missing.push(link);
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    /// The blocks by CID, for looking many of them up without a scan each.
+    pub fn indexed(&self) -> IndexedCarV1<'_> {
+        let mut index = HashMap::with_capacity(self.blocks.len());
+        for (i, block) in self.blocks.iter().enumerate() {
+            index.entry(*block.cid()).or_insert(i);
+        }
+        IndexedCarV1 { car: self, index }
+    }
+}
+
+/// The blocks of a [`CarV1`] by CID, see [`CarV1::indexed`]. A block stored twice is found at
+/// its first copy.
+#[derive(Debug, Clone)]
+pub struct IndexedCarV1<'a> {
+    car: &'a CarV1,
+    index: HashMap<Cid, usize>,
 }
 
-pub fn read_car_v1_data<R: Read>(mut r: R) -> CarResult<Vec<Block<DefaultParams>>> {
-    let mut data: Vec<Block<DefaultParams>> = vec![];
-    while let Ok(length) = varint_read_u64(&mut r) {
-        let mut data_buf = vec![0u8; length as usize];
-        r.read_exact(&mut data_buf)?;
+impl<'a> IndexedCarV1<'a> {
+    pub fn car(&self) -> &'a CarV1 {
+        self.car
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<&'a Block<DefaultParams>> {
+        self.index.get(cid).map(|&i| &self.car.blocks[i])
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.index.contains_key(cid)
+    }
+}
+
+pub(crate) fn read_sections<R: Read, S: StoreParams>(
+    r: &mut CountingReader<R>,
+    options: &ReadOptions,
+    report: &mut ReadReport,
+) -> CarResult<Vec<Block<S>>> {
+    let mut data: Vec<Block<S>> = vec![];
+    let mut seen = HashSet::new();
+    let mut reported = r.position();
+    let mut report_bytes = |position: u64| {
+        if let Some(metrics) = options.metrics {
+            metrics.bytes_read(position - reported);
+        }
+        reported = position;
+    };
+    loop {
+        let offset = r.position();
+        // The data ends cleanly only where a section would start.
+        let length = match read_varint_lenient(&mut *r) {
+            Ok(Some((length, true))) => length,
+            Ok(Some((length, false))) if options.lenient => {
+                report
+                    .anomalies
+                    .push(ReadAnomaly::NonMinimalVarint { offset });
+                length
+            }
+            Ok(Some((_, false))) => return Err(CarError::NonMinimalVarint { offset }),
+            Ok(None) => break,
+            Err(CarError::Io(err)) if options.lenient && err.kind() == ErrorKind::UnexpectedEof => {
+                report.anomalies.push(ReadAnomaly::TrailingBytes {
+                    offset,
+                    length: r.position() - offset,
+                });
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        options.deadline.check()?;
+        if length == 0 && options.lenient {
+            match report.anomalies.last_mut() {
+                Some(ReadAnomaly::Padding {
+                    offset: start,
+                    length,
+                }) if *start + *length == offset => *length += 1,
+                _ => report
+                    .anomalies
+                    .push(ReadAnomaly::Padding { offset, length: 1 }),
+            }
+            continue;
+        }
+        let data_buf = match read_length_prefixed(&mut *r, length) {
+            Ok(data_buf) => data_buf,
+            Err(err) if options.lenient && err.kind() == ErrorKind::UnexpectedEof => {
+                report.anomalies.push(ReadAnomaly::TrailingBytes {
+                    offset,
+                    length: r.position() - offset,
+                });
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
         let mut data_stream = Cursor::new(data_buf);
 
         let cid = Cid::read_bytes(&mut data_stream)?;
         let pos = data_stream.position() as usize;
         let data_buf = data_stream.into_inner();
-        let block = Block::new(cid, data_buf[pos..].to_vec())?;
+        let block_data = data_buf[pos..].to_vec();
+        let (block, verified) = options.hashes.block_with_params(cid, block_data, offset)?;
+        if !verified {
+            report
+                .anomalies
+                .push(ReadAnomaly::UnverifiedHash { cid, offset });
+        }
+        if cid.codec() == u64::from(IpldCodec::DagCbor) {
+            // Blocks that do not decode are left to the codec to reject.
+            match crate::cbor::item_length(block.data()).map(|length| block.data().len() - length) {
+                None | Some(0) => {}
+                Some(_) if options.reject_block_slack => return Err(CarError::BlockSlack(cid)),
+                Some(slack) => report.anomalies.push(ReadAnomaly::BlockSlack {
+                    cid,
+                    offset,
+                    length: slack as u64,
+                }),
+            }
+        }
+        if !seen.insert(cid) {
+            report
+                .anomalies
+                .push(ReadAnomaly::DuplicateBlock { cid, offset });
+        }
+        if let Some(metrics) = options.metrics {
+            metrics.block_read(&cid, block.data().len());
+        }
+        report_bytes(r.position());
         data.push(block);
     }
+    report_bytes(r.position());
     Ok(data)
 }
 
+/// The length of the section [`write_car_v1_block`] writes for `block`, its varint included.
+pub(crate) fn section_len<S: StoreParams>(block: &Block<S>) -> u64 {
+    let length = (block.cid().encoded_len() + block.data().len()) as u64;
+    let mut varint = unsigned_varint::encode::u64_buffer();
+    unsigned_varint::encode::u64(length, &mut varint).len() as u64 + length
+}
+
+/// A push-based CARv1 writer, the counterpart of [`CarV1Decoder`](crate::raw::CarV1Decoder):
+/// the header is written on creation and every block as soon as it is given, so no more than
+/// one block is held at once.
+#[derive(Debug)]
+pub struct CarWriter<W> {
+    w: W,
+}
+
+impl<W: Write> CarWriter<W> {
+    /// Writes the header of an archive with `roots` to `w`.
+    pub fn new(mut w: W, roots: Vec<Cid>) -> CarResult<Self> {
+        CarHeaderV1 { roots }.write_to(&mut w)?;
+        Ok(Self { w })
+    }
+
+    /// Writes the section of `block`. Blocks are not checked against their CIDs.
+    pub fn write_block<S: StoreParams>(&mut self, block: &Block<S>) -> CarResult<()> {
+        write_car_v1_block(&mut self.w, block)
+    }
+
+    /// Flushes the archive and returns the writer.
+    pub fn finish(mut self) -> CarResult<W> {
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Random access to the blocks of a CARv1 through an index, as [`crate::v2::CarV2Reader`] has
+/// for a CARv2, see [`CarV1Reader::with_external_index`].
+///
+/// Offsets are counted from where the reader was when the archive was opened.
+#[derive(Debug)]
+pub struct CarV1Reader<R> {
+    r: R,
+    start: u64,
+    /// The length of the archive.
+    len: u64,
+    roots: Vec<Cid>,
+    index: Option<CarV2Index>,
+}
+
+impl<R: Read + Seek> CarV1Reader<R> {
+    /// Reads the header of the CARv1 in `r`.
+    pub fn new(mut r: R) -> CarResult<Self> {
+        let start = r.stream_position()?;
+        let len = r.seek(SeekFrom::End(0))? - start;
+        r.seek(SeekFrom::Start(start))?;
+        let header = CarHeaderV1::from_reader(
+            &mut CountingReader::new(&mut r),
+            &ReadOptions::default(),
+            &mut ReadReport::default(),
+        )?;
+        Ok(Self {
+            r,
+            start,
+            len,
+            roots: header.roots,
+            index: None,
+        })
+    }
+
+    /// Looks blocks up in `index`, such as the detached index `car index` writes next to an
+    /// archive, read with [`CarV2Index::from_reader`]. Its offsets are counted from the start
+    /// of the archive, its header included, as those of a CARv2 are from the start of its
+    /// payload.
+    ///
+    /// Fails with [`CarError::IndexOutOfBounds`] if an offset is past the end of the archive,
+    /// and with [`CarError::UnknownIndexCodec`] on an index it cannot look anything up in.
+    pub fn with_external_index(mut self, index: CarV2Index) -> CarResult<Self> {
+        if index.kind().is_none() {
+            return Err(CarError::UnknownIndexCodec(index.codec()));
+        }
+        index.check_offsets(self.len)?;
+        self.index = Some(index);
+        Ok(self)
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// The index blocks are looked up in: the one attached, or the one the first lookup built.
+    pub fn index(&self) -> Option<&CarV2Index> {
+        self.index.as_ref()
+    }
+
+    /// Looks `cid` up in the index and reads its section, as
+    /// [`crate::v2::CarV2Reader::get_block`] does. Without an index attached, the first lookup
+    /// builds one by reading the framing of every section once.
+    pub fn get_block(&mut self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        if self.index.is_none() {
+            self.r.seek(SeekFrom::Start(self.start))?;
+            let index = CarV2Index::generate_from_reader((&mut self.r).take(self.len))?;
+            self.index = Some(index);
+        }
+        let offset = match self.index.as_ref().and_then(|index| index.lookup(cid)) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.r.seek(SeekFrom::Start(self.start + offset))?;
+        crate::read_indexed_block(&mut self.r, cid, offset, self.len - offset)
+    }
+}
 
 /// An IPLD Content Archive Header Version 1
 #[derive(Debug, Clone)]
@@ -47,33 +364,463 @@ pub struct CarHeaderV1 {
 }
 
 impl CarHeaderV1 {
-    fn from_reader<R: Read>(r: &mut R) -> CarResult<Self> {
-        let header_length = varint_read_u64(&mut *r)?;
+    fn from_reader<R: Read>(
+        r: &mut CountingReader<R>,
+        options: &ReadOptions,
+        report: &mut ReadReport,
+    ) -> CarResult<Self> {
+        let header_length = if options.lenient {
+            match read_varint_lenient(&mut *r)? {
+                Some((length, minimal)) => {
+                    if !minimal {
+                        report
+                            .anomalies
+                            .push(ReadAnomaly::NonMinimalVarint { offset: 0 });
+                    }
+                    length
+                }
+                None => return Err(CarError::InvalidFormat),
+            }
+        } else {
+            varint_read_u64(&mut *r)?
+        };
 
-        let mut header_buf = vec![0; header_length as usize];
-        r.read_exact(&mut header_buf)?;
+        let header_buf = read_length_prefixed(&mut *r, header_length)?;
 
         let header_map: Ipld = DagCborCodec.decode(&header_buf)?;
+        if let Ipld::Map(fields) = &header_map {
+            for name in fields
+                .keys()
+                .filter(|name| !["version", "roots"].contains(&name.as_str()))
+            {
+                report
+                    .anomalies
+                    .push(ReadAnomaly::UnknownHeaderField { name: name.clone() });
+            }
+        }
         let header = Self::from_ipld(header_map)?;
 
         Ok(header)
     }
 
-    fn from_ipld(header_map: Ipld) -> CarResult<Self> {
-        let version = header_map.get("version").map_err(|_| CarError::InvalidFormat)?;
-        let roots_list = header_map.get("roots").map_err(|_| CarError::InvalidFormat)?;
+    pub(crate) fn from_ipld(header_map: Ipld) -> CarResult<Self> {
+        let version = header_map
+            .get("version")
+            .map_err(|_| CarError::InvalidFormat)?;
+        let roots_list = header_map
+            .get("roots")
+            .map_err(|_| CarError::InvalidFormat)?;
         match (version, roots_list) {
             (Ipld::Integer(1), Ipld::List(cids)) => {
-                let roots: Result<Vec<_>, _> =
-                    cids.iter().map(|ipld| match ipld {
-                        Ipld::Link(link) => Ok(link.clone()),
+                let roots: Result<Vec<_>, _> = cids
+                    .iter()
+                    .map(|ipld| match ipld {
+                        Ipld::Link(link) => Ok(*link),
                         _ => Err(CarError::InvalidFormat),
-                    }).collect();
+                    })
+                    .collect();
 
                 Ok(CarHeaderV1 { roots: roots? })
             }
             _ => Err(CarError::InvalidFormat),
         }
     }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> CarResult<()> {
+        let header_buf = DagCborCodec.encode(&self.to_ipld())?;
+        write_varint(&mut w, header_buf.len() as u64)?;
+        w.write_all(&header_buf)?;
+        Ok(())
+    }
+
+    fn to_ipld(&self) -> Ipld {
+        let mut header_map = BTreeMap::new();
+        header_map.insert("version".to_string(), Ipld::Integer(1));
+        header_map.insert(
+            "roots".to_string(),
+            Ipld::List(self.roots.iter().copied().map(Ipld::Link).collect()),
+        );
+        Ipld::Map(header_map)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::CarV1Decoder;
+    use crate::test_utils::raw;
+
+    /// A header with an extra field, then `a`, a non-minimal length for `b`, `a` again and padding.
+    fn irregular_car() -> (Vec<u8>, Vec<Block<DefaultParams>>) {
+        let blocks = vec![raw(b"a"), raw(b"b")];
+        let mut header = BTreeMap::new();
+        header.insert("version".to_string(), Ipld::Integer(1));
+        header.insert(
+            "roots".to_string(),
+            Ipld::List(vec![Ipld::Link(*blocks[0].cid())]),
+        );
+        header.insert("note".to_string(), Ipld::String("hi".into()));
+        let header = DagCborCodec.encode(&Ipld::Map(header)).unwrap();
+
+        let mut car = vec![header.len() as u8];
+        car.extend(&header);
+        write_car_v1_block(&mut car, &blocks[0]).unwrap();
+        let mut section = vec![];
+        write_car_v1_block(&mut section, &blocks[1]).unwrap();
+        car.extend([section[0] | 0x80, 0x00]);
+        car.extend(&section[1..]);
+        write_car_v1_block(&mut car, &blocks[0]).unwrap();
+        car.extend([0, 0, 0]);
+        (car, blocks)
+    }
+
+    #[test]
+    fn it_writes_blocks_as_they_come() {
+        let car = crate::test_utils::diamond();
+        let mut expected = vec![];
+        car.write_to(&mut expected).unwrap();
+
+        let mut writer =
+            CarWriter::new(std::io::BufWriter::new(vec![]), car.header.roots.clone()).unwrap();
+        for block in &car.blocks {
+            writer.write_block(block).unwrap();
+        }
+        let bytes = writer.finish().unwrap().into_inner().unwrap();
+        assert_eq!(bytes, expected);
+        assert_eq!(CarV1::from_reader(&bytes[..]).unwrap().blocks, car.blocks);
+    }
+
+    #[test]
+    fn it_reports_anomalies_of_lenient_reads() {
+        let (car, blocks) = irregular_car();
+        let a_len = 1 + blocks[0].cid().to_bytes().len() as u64 + 1;
+        let header_len = car.len() as u64 - 3 * a_len - 1 - 3;
+
+        let options = ReadOptions {
+            lenient: true,
+            ..ReadOptions::default()
+        };
+        let (read, report) = CarV1::from_reader_with_report(&car[..], &options).unwrap();
+        assert_eq!(
+            read.blocks,
+            vec![blocks[0].clone(), blocks[1].clone(), blocks[0].clone()]
+        );
+        assert_eq!(
+            report.anomalies,
+            vec![
+                ReadAnomaly::UnknownHeaderField {
+                    name: "note".into()
+                },
+                ReadAnomaly::NonMinimalVarint {
+                    offset: header_len + a_len
+                },
+                ReadAnomaly::DuplicateBlock {
+                    cid: *blocks[0].cid(),
+                    offset: header_len + 2 * a_len + 1
+                },
+                ReadAnomaly::Padding {
+                    offset: car.len() as u64 - 3,
+                    length: 3
+                },
+            ]
+        );
+
+        let mut truncated = car.clone();
+        truncated.truncate(car.len() - 3 - 2);
+        let (read, report) = CarV1::from_reader_with_report(&truncated[..], &options).unwrap();
+        assert_eq!(read.blocks.len(), 2);
+        assert_eq!(
+            report.anomalies.last(),
+            Some(&ReadAnomaly::TrailingBytes {
+                offset: header_len + 2 * a_len + 1,
+                length: a_len - 2
+            })
+        );
+
+        // Strict reads fail at the non-minimal varint, and on a truncated section.
+        assert!(matches!(
+            CarV1::from_reader_with_report(&car[..], &ReadOptions::default()),
+            Err(CarError::NonMinimalVarint { offset }) if offset == header_len + a_len
+        ));
+        let mut strict = vec![];
+        crate::test_utils::diamond().write_to(&mut strict).unwrap();
+        strict.truncate(strict.len() - 2);
+        assert!(matches!(
+            CarV1::from_reader(&strict[..]),
+            Err(CarError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn it_applies_hash_policies() {
+        use crate::HashPolicy;
+        use libipld::multihash::Multihash;
+
+        // A sha1 block, which has no linked implementation, and a sha2-256 block with bad data.
+        let sha1 = Cid::new_v1(0x55, Multihash::wrap(0x11, &[1; 20]).unwrap());
+        let forged = Block::new_unchecked(*raw(b"a").cid(), b"b".to_vec());
+        let car = CarV1::new(
+            CarHeaderV1 { roots: vec![sha1] },
+            vec![Block::new_unchecked(sha1, b"x".to_vec()), forged.clone()],
+        );
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        match CarV1::from_reader(&bytes[..]) {
+            Err(CarError::UnverifiableHash(cid)) => assert_eq!(cid, sha1),
+            other => panic!("Expected UnverifiableHash, got {:?}", other),
+        }
+        let trusted = [0x11, 0x12];
+        let options = ReadOptions {
+            hashes: HashPolicy {
+                trusted: &trusted,
+                ..HashPolicy::default()
+            },
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            CarV1::from_reader_with_options(&bytes[..], &options)
+                .unwrap()
+                .blocks,
+            car.blocks
+        );
+
+        let options = ReadOptions {
+            hashes: HashPolicy {
+                strict: false,
+                ..HashPolicy::default()
+            },
+            ..ReadOptions::default()
+        };
+        assert!(matches!(
+            CarV1::from_reader_with_options(&bytes[..], &options),
+            Err(CarError::HashMismatch { cid, .. }) if cid == *forged.cid()
+        ));
+        let options = ReadOptions {
+            hashes: HashPolicy {
+                trusted: &[0x12],
+                strict: false,
+                hashers: None,
+            },
+            ..ReadOptions::default()
+        };
+        let (read, report) = CarV1::from_reader_with_report(&bytes[..], &options).unwrap();
+        assert_eq!(read.blocks, car.blocks);
+        assert!(
+            matches!(report.anomalies[..], [ReadAnomaly::UnverifiedHash { cid, .. }] if cid == sha1)
+        );
+    }
+
+    #[test]
+    fn it_reports_or_rejects_block_slack() {
+        use libipld::multihash::MultihashDigest;
+
+        // An empty dag-cbor map followed by two bytes, hashed along with them.
+        let data = b"\xa0\x00\x00".to_vec();
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(&data));
+        let car = CarV1::new(
+            CarHeaderV1 { roots: vec![cid] },
+            vec![Block::new_unchecked(cid, data)],
+        );
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let (read, report) =
+            CarV1::from_reader_with_report(&bytes[..], &ReadOptions::default()).unwrap();
+        assert_eq!(read.blocks, car.blocks);
+        assert!(matches!(
+            report.anomalies[..],
+            [ReadAnomaly::BlockSlack { length: 2, .. }]
+        ));
+        let options = ReadOptions {
+            reject_block_slack: true,
+            ..ReadOptions::default()
+        };
+        assert!(matches!(
+            CarV1::from_reader_with_options(&bytes[..], &options),
+            Err(CarError::BlockSlack(c)) if c == cid
+        ));
+    }
+
+    #[test]
+    fn it_puts_blocks_and_finds_missing_links() {
+        use libipld::ipld;
+
+        let mut car = CarV1::new(CarHeaderV1 { roots: vec![] }, vec![]);
+        let absent = *raw(b"absent").cid();
+        let leaf = car.put_block(raw(b"leaf"));
+        assert_eq!(car.missing_links().unwrap(), vec![]);
+        let root = car
+            .put_ipld(
+                IpldCodec::DagCbor,
+                &ipld!({ "leaf": leaf, "more": [absent, absent] }),
+            )
+            .unwrap();
+        car.header.roots = vec![root];
+
+        assert_eq!(root.codec(), 0x71);
+        assert_eq!(car.blocks.len(), 2);
+        assert_eq!(car.missing_links().unwrap(), vec![absent]);
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        assert_eq!(CarV1::from_reader(&bytes[..]).unwrap().blocks, car.blocks);
+    }
+
+    #[test]
+    fn it_reads_and_writes_blocks_of_other_params() {
+        use crate::params::{self, LargeParams};
+        use libipld::raw::RawCodec;
+
+        let large: Block<LargeParams> =
+            params::encode(RawCodec, Code::Sha2_256, &Ipld::Bytes(vec![7; 2 << 20])).unwrap();
+        let mut car = CarV1::new_with_params(
+            CarHeaderV1 {
+                roots: vec![*large.cid()],
+            },
+            vec![],
+        );
+        car.put_block(large.clone());
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        let (read, _) =
+            CarV1::<LargeParams>::from_reader_with_params(&bytes[..], &ReadOptions::default())
+                .unwrap();
+        assert_eq!(read.blocks, vec![large]);
+        let mut written = vec![];
+        let mut writer = CarWriter::new(&mut written, read.header.roots.clone()).unwrap();
+        writer.write_block(&read.blocks[0]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn it_round_trips_empty_blocks() {
+        use crate::block::{write_car_blocks, CarBlock, CarBlockReader};
+        use libipld::multihash::Multihash;
+
+        let identity = |data: &[u8]| {
+            Block::new_unchecked(
+                Cid::new_v1(0x55, Multihash::wrap(0, data).unwrap()),
+                data.to_vec(),
+            )
+        };
+        let blocks = vec![raw(b""), identity(b""), identity(b"hi")];
+        assert_eq!(blocks[1].cid().to_string(), "bafkqaaa");
+        let car = CarV1::new(
+            CarHeaderV1 {
+                roots: vec![*blocks[1].cid()],
+            },
+            blocks.clone(),
+        );
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+
+        assert_eq!(CarV1::from_reader(&bytes[..]).unwrap().blocks, blocks);
+        let options = ReadOptions {
+            lenient: true,
+            ..ReadOptions::default()
+        };
+        let (read, report) = CarV1::from_reader_with_report(&bytes[..], &options).unwrap();
+        assert_eq!((read.blocks, report.anomalies), (blocks.clone(), vec![]));
+        #[cfg(feature = "v1")]
+        {
+            let archive = crate::ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap();
+            assert_eq!(archive.car_v1().blocks, blocks);
+        }
+
+        let mut decoder = CarV1Decoder::new();
+        decoder.push(&bytes);
+        let mut decoded = vec![];
+        while let Some(block) = decoder.next_block().unwrap() {
+            decoded.push(block);
+        }
+        assert_eq!(decoded, blocks);
+
+        let framed: Vec<CarBlock> = CarBlockReader::new(&bytes[..])
+            .unwrap()
+            .collect::<CarResult<_>>()
+            .unwrap();
+        assert_eq!(framed[0].data, b"");
+        let mut written = vec![];
+        write_car_blocks(&mut written, &car.header.roots, &framed).unwrap();
+        assert_eq!(written, bytes);
+        let converted: Vec<_> = framed
+            .into_iter()
+            .map(|block| block.into_block().unwrap())
+            .collect();
+        assert_eq!(converted, blocks);
+
+        let forged = identity(b"hi").data().to_vec();
+        let forged = Block::new_unchecked(*blocks[1].cid(), forged);
+        let car = CarV1::new(CarHeaderV1 { roots: vec![] }, vec![forged]);
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        match CarV1::from_reader(&bytes[..]) {
+            Err(CarError::HashMismatch { cid, offset }) => {
+                let mut section = vec![];
+                write_car_v1_block(&mut section, &car.blocks[0]).unwrap();
+                assert_eq!(cid, *blocks[1].cid());
+                assert_eq!(offset, (bytes.len() - section.len()) as u64);
+            }
+            other => panic!("Expected HashMismatch, got {:?}", other),
+        }
+
+        // The push-based and indexed readers fail the same way.
+        let mut section = vec![];
+        write_car_v1_block(&mut section, &car.blocks[0]).unwrap();
+        let offset = (bytes.len() - section.len()) as u64;
+        let mismatch = |err: CarError| {
+            matches!(err, CarError::HashMismatch { cid, offset: at }
+                if cid == *blocks[1].cid() && at == offset)
+        };
+        let mut decoder = CarV1Decoder::new();
+        decoder.push(&bytes);
+        assert!(mismatch(decoder.next_block().unwrap_err()));
+        let mut reader = CarV1Reader::new(Cursor::new(&bytes)).unwrap();
+        assert!(mismatch(reader.get_block(blocks[1].cid()).unwrap_err()));
+    }
+
+    #[test]
+    fn it_reads_blocks_through_a_detached_index() {
+        use crate::test_utils::diamond;
+
+        let car = diamond();
+        // Behind a prefix, to check offsets are counted from where the reader started.
+        let mut bytes = b"prefix".to_vec();
+        car.write_to(&mut bytes).unwrap();
+        let mut idx = vec![];
+        CarV2Index::generate_from_reader(&bytes[6..])
+            .unwrap()
+            .write_to(&mut idx)
+            .unwrap();
+        let index = CarV2Index::from_reader(&idx[..]).unwrap();
+
+        let open = || {
+            let mut r = Cursor::new(bytes.clone());
+            r.seek(SeekFrom::Start(6)).unwrap();
+            CarV1Reader::new(r).unwrap()
+        };
+        let mut reader = open().with_external_index(index.clone()).unwrap();
+        assert_eq!(reader.roots(), &car.header.roots[..]);
+        for block in &car.blocks {
+            assert_eq!(reader.get_block(block.cid()).unwrap().as_ref(), Some(block));
+        }
+        assert_eq!(reader.get_block(raw(b"other").cid()).unwrap(), None);
+
+        // Without one, the reader indexes the archive itself.
+        let mut reader = open();
+        assert!(reader.index().is_none());
+        assert_eq!(
+            reader.get_block(car.blocks[3].cid()).unwrap().as_ref(),
+            Some(&car.blocks[3])
+        );
+        assert!(reader.index().is_some());
+
+        let past_end = CarV2Index::sorted([(car.blocks[0].cid(), bytes.len() as u64)]);
+        assert!(matches!(
+            open().with_external_index(past_end),
+            Err(CarError::IndexOutOfBounds(_))
+        ));
+    }
+}
@@ -1,7 +1,14 @@
-use std::io::{Read, Seek};
-use byteorder::{LittleEndian, ByteOrder};
-use libipld::Ipld;
-use crate::{v1, CarError, CarResult, CHARACTERISTICS_LENGTH, HEADER_LENGTH};
+use crate::block::write_car_v1_block;
+use crate::block::CarBlockReader;
+pub use crate::block::CarHeaderV2;
+use crate::block::{parse_v2_header, FULLY_INDEXED, PRAGMA};
+use crate::index::read_v2_index;
+pub use crate::index::{CarV2Index, IndexKind};
+use crate::{cbor, v1};
+use crate::{CarError, CarResult, CHARACTERISTICS_LENGTH, HEADER_LENGTH};
+use libipld::{cid::Cid, Block, DefaultParams};
+use std::io::{self, Read, Write};
+use std::io::{Seek, SeekFrom};
 use unsigned_varint::io::read_u64 as varint_read_u64;
 
 /// An IPLD Content Archive Version 2; wraps a CAR Version 1
@@ -12,18 +19,6 @@ pub struct CarV2 {
     pub index: Option<CarV2Index>,
 }
 
-#[derive(Debug, Clone)]
-pub struct CarV2Index;
-
-/// An IPLD Content Archive Header Version 2
-#[derive(Debug, Clone)]
-pub struct CarHeaderV2 {
-    pub characteristics: [u8; CHARACTERISTICS_LENGTH],
-    pub data_offset: u64,
-    pub data_size: u64,
-    pub index_offset: u64,
-}
-
 impl CarV2 {
     pub fn new(header: CarHeaderV2, car_v1: v1::CarV1, index: Option<CarV2Index>) -> Self {
         Self {
@@ -33,40 +28,747 @@ impl CarV2 {
         }
     }
 
+    /// Wraps `car_v1`, indexing its blocks with [`CarV2Index::multihash_sorted`] and marking
+    /// the archive fully indexed if `indexed` is set. The offsets of the header are filled in
+    /// by [`CarV2::write_to`].
+    pub fn from_car_v1(car_v1: v1::CarV1, indexed: bool) -> CarResult<Self> {
+        Self::from_car_v1_with_index(car_v1, indexed.then_some(IndexKind::MultihashSorted))
+    }
+
+    /// Wraps `car_v1` like [`CarV2::from_car_v1`], with an index of `kind` if any.
+    pub fn from_car_v1_with_index(car_v1: v1::CarV1, kind: Option<IndexKind>) -> CarResult<Self> {
+        let mut characteristics = [0; CHARACTERISTICS_LENGTH];
+        let index = match kind {
+            Some(kind) => {
+                characteristics[0] |= FULLY_INDEXED;
+                Some(index_payload(&car_v1, kind)?)
+            }
+            None => None,
+        };
+        let header = CarHeaderV2 {
+            characteristics,
+            data_offset: 0,
+            data_size: 0,
+            index_offset: 0,
+        };
+        Ok(Self::new(header, car_v1, index))
+    }
+
+    /// The payload, without the header and index.
+    pub fn into_v1(self) -> v1::CarV1 {
+        self.car_v1
+    }
+
     pub fn is_fully_indexed(&self) -> bool {
-        self.header.characteristics[0] & 0b1000_0000 == 1
+        self.header.is_fully_indexed()
+    }
+
+    /// Writes the pragma, the header, the payload right after it and the index, if any, right
+    /// after that. The offsets of the header are computed as the parts are written, then the
+    /// header is written over its placeholder; it is returned as written. Offsets are counted
+    /// from where `w` was, and `w` is left at the end of the archive.
+    ///
+    /// The index is rebuilt from the payload as it is written, of the same kind as `index` or a
+    /// `MultihashIndexSorted` in place of one of an unknown codec, so it matches the payload
+    /// however `car_v1` changed since `index` was built. The archive is marked fully indexed
+    /// if and only if it has an index.
+    pub fn write_to<W: Write + Seek>(&self, w: W) -> CarResult<CarHeaderV2> {
+        self.write_to_with_options(w, &CarV2WriteOptions::default())
+    }
+
+    /// Like [`CarV2::write_to`], with the zero padding of `options` before the payload and the
+    /// index.
+    pub fn write_to_with_options<W: Write + Seek>(
+        &self,
+        w: W,
+        options: &CarV2WriteOptions,
+    ) -> CarResult<CarHeaderV2> {
+        self.write_padded(w, &[], options)
+    }
+
+    /// Like [`CarV2::write_to`], reserving `capacity` bytes of padding between the header and
+    /// the payload for an application region holding `region`, see [`write_region`]. Fails with
+    /// [`CarError::RegionTooLarge`] if it needs more than `capacity`, see [`region_len`].
+    pub fn write_to_with_region<W: Write + Seek>(
+        &self,
+        w: W,
+        region: &[u8],
+        capacity: u64,
+    ) -> CarResult<CarHeaderV2> {
+        let options = CarV2WriteOptions {
+            data_padding: capacity,
+            index_padding: 0,
+        };
+        self.write_padded(w, region, &options)
+    }
+
+    fn write_padded<W: Write + Seek>(
+        &self,
+        mut w: W,
+        region: &[u8],
+        options: &CarV2WriteOptions,
+    ) -> CarResult<CarHeaderV2> {
+        let start = w.stream_position()?;
+        w.write_all(&PRAGMA)?;
+        w.write_all(&[0; HEADER_LENGTH])?;
+        if options.data_padding > 0 || !region.is_empty() {
+            write_framed_region(&mut w, region, options.data_padding)?;
+        }
+        let data_offset = (PRAGMA.len() + HEADER_LENGTH) as u64 + options.data_padding;
+        self.car_v1.write_to(&mut w)?;
+        let data_size = w.stream_position()? - start - data_offset;
+        let mut characteristics = self.header.characteristics;
+        characteristics[0] &= !FULLY_INDEXED;
+        let kind = self
+            .index
+            .as_ref()
+            .map(|index| index.kind().unwrap_or_default());
+        let index_offset = match kind {
+            Some(kind) => {
+                characteristics[0] |= FULLY_INDEXED;
+                io::copy(&mut io::repeat(0).take(options.index_padding), &mut w)?;
+                index_payload(&self.car_v1, kind)?.write_to(&mut w)?;
+                data_offset + data_size + options.index_padding
+            }
+            None => 0,
+        };
+        let end = w.stream_position()?;
+        let header = CarHeaderV2 {
+            characteristics,
+            data_offset,
+            data_size,
+            index_offset,
+        };
+        w.seek(SeekFrom::Start(start + PRAGMA.len() as u64))?;
+        w.write_all(&header.to_bytes())?;
+        w.seek(SeekFrom::Start(end))?;
+        Ok(header)
     }
 }
 
+/// An index of `kind` of the blocks of `car_v1`, by the offsets of their sections from the start
+/// of the payload as [`v1::CarV1::write_to`] writes it.
+fn index_payload(car_v1: &v1::CarV1, kind: IndexKind) -> CarResult<CarV2Index> {
+    let mut header = vec![];
+    car_v1.header.write_to(&mut header)?;
+    let mut offset = header.len() as u64;
+    let mut sections = vec![];
+    for block in &car_v1.blocks {
+        sections.push((block.cid(), offset));
+        offset += v1::section_len(block);
+    }
+    Ok(CarV2Index::build(kind, sections))
+}
 
-pub fn parse_v2_header(header: [u8; HEADER_LENGTH]) -> CarResult<CarHeaderV2> {
-    Ok(CarHeaderV2 {
-        characteristics: header[0..CHARACTERISTICS_LENGTH].try_into()?,
-        data_offset: LittleEndian::read_u64(
-            &header[CHARACTERISTICS_LENGTH..CHARACTERISTICS_LENGTH + 8],
-        ),
-        data_size: LittleEndian::read_u64(
-            &header[CHARACTERISTICS_LENGTH + 8..CHARACTERISTICS_LENGTH + 16],
-        ),
-        index_offset: LittleEndian::read_u64(&header[CHARACTERISTICS_LENGTH + 16..HEADER_LENGTH]),
-    })
+/// Zero padding to write between the parts of a CARv2, which the spec allows and readers skip,
+/// e.g. to start the payload at a page boundary for memory-mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CarV2WriteOptions {
+    /// Bytes between the header and the payload, so `4045` starts the payload at 4 KiB.
+    pub data_padding: u64,
+    /// Bytes between the payload and the index; none are written without an index.
+    pub index_padding: u64,
 }
 
-// TODO: Finish index parsing
-pub fn read_v2_index<R: Read + Seek>(mut r: R, index_offset: u64) -> CarResult<Option<CarV2Index>> {
-    if index_offset == 0 {
-        return Ok(None);
+/// Writes a CARv2 one block at a time, like [`v1::CarWriter`] does a CARv1, optionally building
+/// an index of the blocks as they are written, see [`CarV2Writer::with_index`].
+///
+/// The header is written as a placeholder and filled in by [`CarV2Writer::finish`], so the
+/// writer must be seekable; offsets are counted from where it was.
+#[derive(Debug)]
+pub struct CarV2Writer<W> {
+    w: W,
+    start: u64,
+    /// The offset of the next section from the start of the payload.
+    offset: u64,
+    data_offset: u64,
+    index_padding: u64,
+    index: Option<IndexKind>,
+    sections: Vec<(Cid, u64)>,
+}
+
+impl<W: Write + Seek> CarV2Writer<W> {
+    /// Writes the pragma, a placeholder header and the header of a payload with `roots` to `w`.
+    pub fn new(w: W, roots: Vec<Cid>) -> CarResult<Self> {
+        Self::new_with_options(w, roots, &CarV2WriteOptions::default())
+    }
+
+    /// Like [`CarV2Writer::new`], with the zero padding of `options` before the payload and the
+    /// index.
+    pub fn new_with_options(
+        mut w: W,
+        roots: Vec<Cid>,
+        options: &CarV2WriteOptions,
+    ) -> CarResult<Self> {
+        let start = w.stream_position()?;
+        let mut header = vec![];
+        v1::CarHeaderV1 { roots }.write_to(&mut header)?;
+        w.write_all(&PRAGMA)?;
+        w.write_all(&[0; HEADER_LENGTH])?;
+        io::copy(&mut io::repeat(0).take(options.data_padding), &mut w)?;
+        w.write_all(&header)?;
+        Ok(Self {
+            w,
+            start,
+            offset: header.len() as u64,
+            data_offset: (PRAGMA.len() + HEADER_LENGTH) as u64 + options.data_padding,
+            index_padding: options.index_padding,
+            index: None,
+            sections: vec![],
+        })
+    }
+
+    /// Indexes the blocks written with an index of `kind`, written after the payload and
+    /// marking the archive fully indexed.
+    pub fn with_index(mut self, kind: IndexKind) -> Self {
+        self.index = Some(kind);
+        self
+    }
+
+    /// Writes the section of `block`. Blocks are not checked against their CIDs.
+    pub fn write_block(&mut self, block: &Block<DefaultParams>) -> CarResult<()> {
+        write_car_v1_block(&mut self.w, block)?;
+        if self.index.is_some() {
+            self.sections.push((*block.cid(), self.offset));
+        }
+        self.offset += v1::section_len(block);
+        Ok(())
+    }
+
+    /// Writes the index, if any, fills in the header and flushes the archive, returning the
+    /// header as written and the writer, left at the end of the archive.
+    pub fn finish(mut self) -> CarResult<(CarHeaderV2, W)> {
+        let data_offset = self.data_offset;
+        let mut characteristics = [0; CHARACTERISTICS_LENGTH];
+        let index_offset = match self.index {
+            Some(kind) => {
+                characteristics[0] |= FULLY_INDEXED;
+                io::copy(&mut io::repeat(0).take(self.index_padding), &mut self.w)?;
+                let sections = self.sections.iter().map(|(cid, offset)| (cid, *offset));
+                CarV2Index::build(kind, sections).write_to(&mut self.w)?;
+                data_offset + self.offset + self.index_padding
+            }
+            None => 0,
+        };
+        let end = self.w.stream_position()?;
+        let header = CarHeaderV2 {
+            characteristics,
+            data_offset,
+            data_size: self.offset,
+            index_offset,
+        };
+        self.w
+            .seek(SeekFrom::Start(self.start + PRAGMA.len() as u64))?;
+        self.w.write_all(&header.to_bytes())?;
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        Ok((header, self.w))
+    }
+}
+
+/// Random access to the blocks of a CARv2 through its index, reading only the sections asked
+/// for, see [`CarV2Reader::get_block`].
+///
+/// Offsets are counted from where the reader was when the archive was opened.
+#[derive(Debug)]
+pub struct CarV2Reader<R> {
+    r: R,
+    start: u64,
+    header: CarHeaderV2,
+    roots: Vec<Cid>,
+    index: CarV2Index,
+}
+
+impl<R: Read + Seek> CarV2Reader<R> {
+    /// Reads the pragma, the header, the roots of the payload and the index of the CARv2 in `r`.
+    /// An archive without an index, or with one of an unknown codec, is indexed by reading the
+    /// framing of its payload once.
+    pub fn new(mut r: R) -> CarResult<Self> {
+        let start = r.stream_position()?;
+        let end = r.seek(SeekFrom::End(0))? - start;
+        r.seek(SeekFrom::Start(start))?;
+        let length = varint_read_u64(&mut r)?;
+        if length > end {
+            return Err(CarError::InvalidFormat);
+        }
+        let mut pragma = vec![0; length as usize];
+        r.read_exact(&mut pragma)?;
+        match cbor::decode_header(&pragma)?.version {
+            2 => {}
+            version => {
+                return Err(CarError::UnsupportedVersion(
+                    version.min(u8::MAX.into()) as u8
+                ))
+            }
+        }
+        let mut buf = [0; HEADER_LENGTH];
+        r.read_exact(&mut buf)?;
+        let header = parse_v2_header(buf)?;
+        let data = header.data_range();
+        if data.start < r.stream_position()? - start || data.end > end {
+            return Err(CarError::InvalidFormat);
+        }
+
+        let index = match header.index_range(end) {
+            Some(range) if range.start >= data.end => read_v2_index(&mut r, start + range.start)?,
+            Some(_) => return Err(CarError::IndexOutOfBounds(header.index_offset)),
+            None if header.has_index() => {
+                return Err(CarError::IndexOutOfBounds(header.index_offset))
+            }
+            None => None,
+        };
+        let index = match index {
+            Some(index) if index.kind().is_none() => None,
+            None => None,
+            Some(index) => {
+                index.check_offsets(header.data_size)?;
+                Some(index)
+            }
+        };
+        r.seek(SeekFrom::Start(start + data.start))?;
+        let roots = CarBlockReader::new((&mut r).take(header.data_size))?
+            .roots()
+            .to_vec();
+        let index = match index {
+            Some(index) => index,
+            None => {
+                r.seek(SeekFrom::Start(start + data.start))?;
+                CarV2Index::generate_from_reader((&mut r).take(header.data_size))?
+            }
+        };
+        Ok(Self {
+            r,
+            start,
+            header,
+            roots,
+            index,
+        })
+    }
+
+    pub fn header(&self) -> &CarHeaderV2 {
+        &self.header
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    pub fn index(&self) -> &CarV2Index {
+        &self.index
+    }
+
+    /// Looks `cid` up in the index and reads its section, verifying the data against the CID
+    /// as [`crate::HashPolicy::default`] does. `None` if the index does not have it, or points
+    /// at a section of another multihash; a section with the same multihash under another
+    /// codec holds the same data, so it is returned as the block `cid`.
+    pub fn get_block(&mut self, cid: &Cid) -> CarResult<Option<Block<DefaultParams>>> {
+        let offset = match self.index.lookup(cid) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.r.seek(SeekFrom::Start(
+            self.start + self.header.data_offset + offset,
+        ))?;
+        crate::read_indexed_block(&mut self.r, cid, offset, self.header.data_size - offset)
+    }
+}
+
+/// How many bytes of padding an application region of `length` bytes needs: its length as a
+/// varint, then its bytes. The least capacity to give [`CarV2::write_to_with_region`] for it.
+pub fn region_len(length: u64) -> u64 {
+    let mut varint = unsigned_varint::encode::u64_buffer();
+    unsigned_varint::encode::u64(length, &mut varint).len() as u64 + length
+}
+
+/// Writes `region` as [`write_region`] does, zero filled to `capacity`.
+fn write_framed_region<W: Write>(mut w: W, region: &[u8], capacity: u64) -> CarResult<()> {
+    let length = region_len(region.len() as u64);
+    if length > capacity {
+        return Err(CarError::RegionTooLarge { length, capacity });
     }
-    r.seek(std::io::SeekFrom::Start(index_offset))?;
+    crate::write_varint(&mut w, region.len() as u64)?;
+    w.write_all(region)?;
+    io::copy(&mut io::repeat(0).take(capacity - length), &mut w)?;
+    Ok(())
+}
 
-    let codec = varint_read_u64(&mut r)?;
+/// Reads the pragma and header of the CARv2 at the start of `r`, returning the header and the
+/// size of the padding after it.
+fn read_padded_header<R: Read>(mut r: R) -> CarResult<(CarHeaderV2, u64)> {
+    let mut pragma = [0; PRAGMA.len()];
+    r.read_exact(&mut pragma)?;
+    if pragma != PRAGMA {
+        return Err(CarError::InvalidFormat);
+    }
+    let mut header = [0; HEADER_LENGTH];
+    r.read_exact(&mut header)?;
+    let header = parse_v2_header(header)?;
+    let padding = header
+        .data_offset
+        .checked_sub((PRAGMA.len() + HEADER_LENGTH) as u64)
+        .ok_or(CarError::InvalidFormat)?;
+    Ok((header, padding))
+}
 
-    match codec {
-        0x0400 => (), // TODO: IndexSorted
-        0x0401 => (), // TODO: MultihashIndexSorted
-        _ => (),
+/// Reads the application region kept in the padding between the header and the payload of the
+/// CARv2 at the start of `r`, which other readers skip. `None` if there is no padding or it is
+/// zero filled, as most writers leave it and as an empty region is written.
+///
+/// The region is its length as a varint followed by its bytes; padding that does not hold one
+/// fails with [`CarError::InvalidFormat`].
+pub fn read_region<R: Read>(mut r: R) -> CarResult<Option<Vec<u8>>> {
+    let (_, padding) = read_padded_header(&mut r)?;
+    let mut bytes = vec![];
+    r.take(padding).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != padding {
+        return Err(CarError::InvalidFormat);
     }
+    if bytes.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    let (length, rest) =
+        unsigned_varint::decode::u64(&bytes).map_err(|_| CarError::InvalidFormat)?;
+    if length > rest.len() as u64 {
+        return Err(CarError::InvalidFormat);
+    }
+    Ok(Some(rest[..length as usize].to_vec()))
+}
 
-    Ok(Some(CarV2Index {}))
+/// Replaces the application region of the CARv2 at the start of `w` in place, reading the
+/// header and writing right after it, so `w` must read and write at one position, as files do.
+/// Fails with [`CarError::RegionTooLarge`] if the region does not fit in the padding, which only
+/// rewriting the archive with [`CarV2::write_to_with_region`] can grow.
+pub fn write_region<W: Read + Write>(mut w: W, region: &[u8]) -> CarResult<()> {
+    let (_, padding) = read_padded_header(&mut w)?;
+    write_framed_region(w, region, padding)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexLayout;
+
+    #[test]
+    fn it_writes_car_v2() {
+        use crate::test_utils::{diamond, raw};
+        use crate::ContentArchive;
+        use libipld::multihash::Multihash;
+        use libipld::Block;
+        use std::io::Cursor;
+
+        let mut car_v1 = diamond();
+        car_v1.put_block(raw(b""));
+        let identity = libipld::cid::Cid::new_v1(0x55, Multihash::wrap(0, b"").unwrap());
+        car_v1.put_block(Block::new_unchecked(identity, vec![]));
+        let carv2 = CarV2::from_car_v1(car_v1.clone(), true).unwrap();
+        assert!(carv2.is_fully_indexed());
+
+        // Written after a prefix, to check offsets are counted from where the archive starts.
+        let mut w = Cursor::new(b"prefix".to_vec());
+        w.seek(SeekFrom::End(0)).unwrap();
+        let header = carv2.write_to(&mut w).unwrap();
+        let bytes = w.into_inner().split_off(6);
+        let mut payload = vec![];
+        car_v1.write_to(&mut payload).unwrap();
+        assert_eq!(&bytes[..11], PRAGMA);
+        assert_eq!(header.data_offset, 51);
+        assert_eq!(header.data_size, payload.len() as u64);
+        assert_eq!(header.index_offset, 51 + payload.len() as u64);
+        assert_eq!(
+            parse_v2_header(bytes[11..51].try_into().unwrap()).unwrap(),
+            header
+        );
+        assert_eq!(bytes[51..header.index_offset as usize], payload);
+
+        let read = match ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap() {
+            ContentArchive::V2(read) => read,
+            #[allow(unreachable_patterns)]
+            _ => panic!("Expected V2"),
+        };
+        assert_eq!(read.car_v1.blocks, car_v1.blocks);
+        assert!(read.is_fully_indexed());
+        let index = read.index.unwrap();
+        assert_eq!(Some(&index), carv2.index.as_ref());
+        // Every entry points at the section of its block, including the empty ones.
+        let starts: Vec<u64> = crate::layout::layout(&bytes)
+            .iter()
+            .filter(|region| matches!(region.part, crate::layout::Part::Section { .. }))
+            .map(|region| region.range.start - 51)
+            .collect();
+        let mut offsets: Vec<u64> = index
+            .layout()
+            .entries()
+            .map(|(_, entry)| entry.offset)
+            .collect();
+        offsets.sort();
+        assert_eq!(offsets, starts);
+
+        let mut unindexed = vec![];
+        let unindexed_v2 = CarV2::from_car_v1(car_v1.clone(), false).unwrap();
+        let header = unindexed_v2.write_to(Cursor::new(&mut unindexed)).unwrap();
+        assert!(!header.has_index() && !header.is_fully_indexed());
+        assert_eq!(unindexed.len() as u64, header.data_range().end);
+    }
+
+    #[test]
+    fn it_rebuilds_the_index_of_changed_payloads() {
+        use crate::index::{INDEX_SORTED, MULTIHASH_INDEX_SORTED};
+        use crate::test_utils::{diamond, raw};
+        use crate::ContentArchive;
+        use std::io::Cursor;
+
+        let mut car_v2 = CarV2::from_car_v1_with_index(diamond(), Some(IndexKind::Sorted)).unwrap();
+        // Every offset the index was built with is now off.
+        car_v2.car_v1.blocks.remove(1);
+        car_v2.car_v1.put_block(raw(b"added"));
+        let options = CarV2WriteOptions {
+            data_padding: 7,
+            index_padding: 3,
+        };
+        let unknown = CarV2Index::from(IndexLayout::Unknown {
+            codec: 1,
+            raw_bytes: vec![],
+        });
+        for (index, codec) in [
+            (car_v2.index.clone(), INDEX_SORTED),
+            (Some(unknown), MULTIHASH_INDEX_SORTED),
+        ] {
+            let car_v2 = CarV2 {
+                index,
+                ..car_v2.clone()
+            };
+            let mut bytes = vec![];
+            let header = car_v2
+                .write_to_with_options(Cursor::new(&mut bytes), &options)
+                .unwrap();
+            assert!(header.is_fully_indexed());
+            let mut reader = CarV2Reader::new(Cursor::new(&bytes)).unwrap();
+            assert_eq!(reader.index().codec(), codec);
+            for block in &car_v2.car_v1.blocks {
+                assert_eq!(reader.get_block(block.cid()).unwrap().as_ref(), Some(block));
+            }
+            match ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap() {
+                ContentArchive::V2(read) => assert_eq!(read.car_v1.blocks, car_v2.car_v1.blocks),
+                #[allow(unreachable_patterns)]
+                _ => panic!("Expected V2"),
+            }
+        }
+
+        // Without an index, the archive is not marked fully indexed whatever its header says.
+        let header = CarV2 {
+            index: None,
+            ..car_v2
+        }
+        .write_to(Cursor::new(vec![]))
+        .unwrap();
+        assert!(!header.has_index() && !header.is_fully_indexed());
+    }
+
+    #[test]
+    fn it_keeps_application_regions_in_the_padding() {
+        use crate::test_utils::diamond;
+        use crate::ContentArchive;
+        use std::io::Cursor;
+
+        let car_v2 = CarV2::from_car_v1(diamond(), true).unwrap();
+        let mut bytes = vec![];
+        let header = car_v2
+            .write_to_with_region(Cursor::new(&mut bytes), b"meta", 32)
+            .unwrap();
+        assert_eq!(header.data_offset, 51 + 32);
+        assert_eq!(region_len(4), 5);
+        assert_eq!(read_region(&bytes[..]).unwrap(), Some(b"meta".to_vec()));
+        let read = ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap();
+        assert_eq!(read.car_v1().blocks, car_v2.car_v1.blocks);
+
+        write_region(Cursor::new(&mut bytes), &[7; 31]).unwrap();
+        assert_eq!(read_region(&bytes[..]).unwrap(), Some(vec![7; 31]));
+        let before = bytes.clone();
+        assert!(matches!(
+            write_region(Cursor::new(&mut bytes), &[7; 32]),
+            Err(CarError::RegionTooLarge {
+                length: 33,
+                capacity: 32
+            })
+        ));
+        assert_eq!(bytes, before);
+        // An empty region is all zeros, like padding without one.
+        write_region(Cursor::new(&mut bytes), b"").unwrap();
+        assert_eq!(read_region(&bytes[..]).unwrap(), None);
+
+        let mut unpadded = vec![];
+        car_v2.write_to(Cursor::new(&mut unpadded)).unwrap();
+        assert_eq!(read_region(&unpadded[..]).unwrap(), None);
+        assert!(write_region(Cursor::new(&mut unpadded), b"").is_err());
+        assert!(matches!(
+            car_v2.write_to_with_region(Cursor::new(vec![]), b"meta", 4),
+            Err(CarError::RegionTooLarge {
+                length: 5,
+                capacity: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn it_reads_archives_with_padding() {
+        use crate::block::CarBlockReader;
+        use crate::test_utils::diamond;
+        use crate::ContentArchive;
+        use std::io::Cursor;
+
+        let car_v1 = diamond();
+        let options = CarV2WriteOptions {
+            data_padding: 4045,
+            index_padding: 10,
+        };
+        let mut bytes = vec![];
+        let car_v2 = CarV2::from_car_v1(car_v1.clone(), true).unwrap();
+        let header = car_v2
+            .write_to_with_options(Cursor::new(&mut bytes), &options)
+            .unwrap();
+        assert_eq!(header.data_offset, 4096);
+        assert_eq!(header.index_offset, header.data_range().end + 10);
+        assert!(bytes[51..4096].iter().all(|byte| *byte == 0));
+        assert_eq!(read_region(&bytes[..]).unwrap(), None);
+
+        // The same bytes as written one block at a time.
+        let roots = car_v1.header.roots.clone();
+        let mut writer = CarV2Writer::new_with_options(Cursor::new(vec![]), roots, &options)
+            .unwrap()
+            .with_index(IndexKind::MultihashSorted);
+        for block in &car_v1.blocks {
+            writer.write_block(block).unwrap();
+        }
+        let (written, w) = writer.finish().unwrap();
+        assert_eq!((written, w.into_inner()), (header, bytes.clone()));
+
+        let read = ContentArchive::read_bytes(Cursor::new(&bytes)).unwrap();
+        assert_eq!(read.car_v1().blocks, car_v1.blocks);
+        let mut reader = CarV2Reader::new(Cursor::new(&bytes)).unwrap();
+        for block in &car_v1.blocks {
+            assert_eq!(reader.get_block(block.cid()).unwrap().as_ref(), Some(block));
+        }
+        let streamed = CarBlockReader::new(&bytes[..]).unwrap().map(Result::unwrap);
+        assert_eq!(streamed.count(), car_v1.blocks.len());
+    }
+
+    #[test]
+    fn it_converts_between_versions() {
+        use crate::test_utils::diamond;
+        use std::io::Cursor;
+
+        let car_v1 = diamond();
+        let car_v2 = car_v1.clone().into_v2(IndexKind::Sorted).unwrap();
+        assert!(car_v2.is_fully_indexed());
+        assert_eq!(
+            car_v2.index.as_ref().unwrap().kind(),
+            Some(IndexKind::Sorted)
+        );
+        let mut bytes = vec![];
+        car_v2.write_to(Cursor::new(&mut bytes)).unwrap();
+        let mut reader = CarV2Reader::new(Cursor::new(&bytes)).unwrap();
+        for block in &car_v1.blocks {
+            assert_eq!(reader.get_block(block.cid()).unwrap().as_ref(), Some(block));
+        }
+
+        let unwrapped = car_v2.into_v1();
+        assert_eq!(unwrapped.header.roots, car_v1.header.roots);
+        assert_eq!(unwrapped.blocks, car_v1.blocks);
+    }
+
+    #[test]
+    fn it_indexes_blocks_as_they_are_written() {
+        use crate::test_utils::diamond;
+        use std::io::Cursor;
+
+        let car_v1 = diamond();
+        let write = |kind: Option<IndexKind>| {
+            let roots = car_v1.header.roots.clone();
+            let mut writer = CarV2Writer::new(Cursor::new(vec![]), roots).unwrap();
+            if let Some(kind) = kind {
+                writer = writer.with_index(kind);
+            }
+            for block in &car_v1.blocks {
+                writer.write_block(block).unwrap();
+            }
+            let (header, w) = writer.finish().unwrap();
+            (header, w.into_inner())
+        };
+
+        // The same bytes as the whole archive written at once.
+        let (header, bytes) = write(Some(IndexKind::MultihashSorted));
+        let mut expected = Cursor::new(vec![]);
+        let carv2 = CarV2::from_car_v1(car_v1.clone(), true).unwrap();
+        assert_eq!(header, carv2.write_to(&mut expected).unwrap());
+        assert_eq!(bytes, expected.into_inner());
+
+        let (header, bytes) = write(Some(IndexKind::Sorted));
+        assert!(header.is_fully_indexed());
+        let index = read_v2_index(Cursor::new(&bytes), header.index_offset)
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.codec(), IndexKind::Sorted.codec());
+        let mut payload = vec![];
+        car_v1.write_to(&mut payload).unwrap();
+        assert_eq!(bytes[51..header.index_offset as usize], payload);
+        for block in &car_v1.blocks {
+            // Every section is short enough for a one byte length.
+            let offset = index.lookup(block.cid()).unwrap() as usize + 1;
+            let section = [&block.cid().to_bytes()[..], block.data()].concat();
+            assert_eq!(payload[offset..offset + section.len()], section);
+        }
+
+        let (header, bytes) = write(None);
+        assert!(!header.has_index() && !header.is_fully_indexed());
+        assert_eq!(bytes.len() as u64, header.data_range().end);
+    }
+
+    #[test]
+    fn it_reads_single_blocks_through_the_index() {
+        use crate::index::{INDEX_SORTED, MULTIHASH_INDEX_SORTED};
+        use crate::test_utils::{diamond, raw};
+        use crate::ContentArchive;
+        use std::io::Cursor;
+
+        let bytes = include_bytes!("../tests/fixtures/carv2-basic.car");
+        let car_v1 = match ContentArchive::read_bytes(Cursor::new(bytes)).unwrap() {
+            ContentArchive::V2(car) => car.car_v1,
+            #[allow(unreachable_patterns)]
+            _ => panic!("Expected V2"),
+        };
+        // The index of the fixture lacks its codec, so it is rebuilt from the payload unless
+        // the codec is put back.
+        let fixed = [&bytes[..499], &[0x80, 0x08], &bytes[499..]].concat();
+        for (bytes, codec) in [(&bytes[..], MULTIHASH_INDEX_SORTED), (&fixed, INDEX_SORTED)] {
+            let mut reader = CarV2Reader::new(Cursor::new(bytes)).unwrap();
+            assert_eq!(reader.roots(), &car_v1.header.roots[..]);
+            assert_eq!(reader.index().codec(), codec);
+            for block in car_v1.blocks.iter().rev() {
+                assert_eq!(reader.get_block(block.cid()).unwrap().as_ref(), Some(block));
+            }
+            assert_eq!(reader.get_block(raw(b"absent").cid()).unwrap(), None);
+        }
+
+        // Without an index, after a prefix: the payload is indexed when opened.
+        let car_v1 = diamond();
+        let mut w = Cursor::new(b"prefix".to_vec());
+        w.seek(SeekFrom::End(0)).unwrap();
+        CarV2::from_car_v1(car_v1.clone(), false)
+            .unwrap()
+            .write_to(&mut w)
+            .unwrap();
+        w.seek(SeekFrom::Start(6)).unwrap();
+        let mut reader = CarV2Reader::new(w).unwrap();
+        assert!(!reader.header().has_index());
+        let leaf = &car_v1.blocks[0];
+        assert_eq!(reader.get_block(leaf.cid()).unwrap().as_ref(), Some(leaf));
+
+        // A corrupt section fails to verify.
+        let mut w = reader.r;
+        let end = w.get_ref().len();
+        w.get_mut()[end - 1] ^= 1;
+        w.seek(SeekFrom::Start(6)).unwrap();
+        let last = car_v1.blocks.last().unwrap();
+        assert!(CarV2Reader::new(w).unwrap().get_block(last.cid()).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Verifying many archives at once on a pool of threads, see [`Verifier`].
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use libipld::cid::Cid;
+
+use crate::{CarError, CarResult, ContentArchive, ReadOptions, ReadReport};
+
+/// An archive to verify, read from the start.
+pub trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+enum Job {
+    Path(PathBuf),
+    Reader(String, Box<dyn Source>),
+}
+
+/// What was found verifying an archive, see [`Verifier`].
+#[derive(Debug)]
+pub struct Verification {
+    /// The path of the archive, or the name it was submitted under.
+    pub name: String,
+    /// Fails if the archive cannot be read, or a block does not match its CID.
+    pub result: CarResult<VerifySummary>,
+}
+
+/// An archive that could be read, with every block matching its CID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub roots: Vec<Cid>,
+    pub blocks: usize,
+    /// Blocks reachable from the roots that are not in the archive, see
+    /// [`ContentArchive::verify_complete`].
+    pub missing: Vec<Cid>,
+    pub report: ReadReport,
+}
+
+impl VerifySummary {
+    /// Whether the archive holds its whole DAG, without anomalies.
+    pub fn is_sound(&self) -> bool {
+        self.missing.is_empty() && self.report.is_clean()
+    }
+}
+
+/// Verifies the archives submitted to it on a fixed number of threads, sending a
+/// [`Verification`] for each to the receiver returned by [`Verifier::new`], in the order they
+/// finish.
+///
+/// Each archive is read with [`ReadOptions::default`], checking every block against its CID,
+/// and then checked for completeness.
+#[derive(Debug)]
+pub struct Verifier {
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Verifier {
+    /// Starts `threads` workers, at least one.
+    pub fn new(threads: usize) -> (Self, mpsc::Receiver<Verification>) {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (reports, received) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let reports = reports.clone();
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting for a job, not while running it.
+                    let job = match queue.lock().map(|queue| queue.recv()) {
+                        Ok(Ok(job)) => job,
+                        _ => return,
+                    };
+                    if reports.send(run(job)).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        let verifier = Self {
+            jobs: Some(jobs),
+            workers,
+        };
+        (verifier, received)
+    }
+
+    /// Queues the archive at `path`, reported under its display form.
+    pub fn submit_path(&self, path: impl Into<PathBuf>) {
+        self.submit_job(Job::Path(path.into()));
+    }
+
+    /// Queues the archive read from `r`, reported as `name`.
+    pub fn submit<R: Source + 'static>(&self, name: impl Into<String>, r: R) {
+        self.submit_job(Job::Reader(name.into(), Box::new(r)));
+    }
+
+    fn submit_job(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            // Workers only stop once the queue is closed, so this cannot fail.
+            let _ = jobs.send(job);
+        }
+    }
+
+    /// Waits for the archives submitted to be verified. The receiver ends once their reports
+    /// are taken.
+    pub fn finish(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+fn run(job: Job) -> Verification {
+    match job {
+        Job::Path(path) => Verification {
+            name: path.display().to_string(),
+            result: File::open(&path)
+                .map_err(CarError::from)
+                .and_then(|file| verify(BufReader::new(file))),
+        },
+        Job::Reader(name, r) => Verification {
+            name,
+            result: verify(r),
+        },
+    }
+}
+
+fn verify<R: Read + Seek>(r: R) -> CarResult<VerifySummary> {
+    let (archive, report) = ContentArchive::read_bytes_with_report(r, &ReadOptions::default())?;
+    let missing = match archive.verify_complete() {
+        Ok(()) => vec![],
+        Err(CarError::IncompleteDag(missing)) => missing,
+        Err(err) => return Err(err),
+    };
+    let car = archive.car_v1();
+    Ok(VerifySummary {
+        roots: car.header.roots.clone(),
+        blocks: car.blocks.len(),
+        missing,
+        report,
+    })
+}
+
+#[cfg(all(test, feature = "v1"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{diamond, temp_dir};
+    use libipld::Block;
+    use std::io::Cursor;
+
+    fn bytes(car: &crate::v1::CarV1) -> Vec<u8> {
+        let mut bytes = vec![];
+        car.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_verifies_archives_concurrently() {
+        let car = diamond();
+        let dir = temp_dir("verify");
+        let path = dir.join("diamond.car");
+        std::fs::write(&path, bytes(&car)).unwrap();
+        let mut incomplete = car.clone();
+        incomplete.blocks.remove(1);
+        let mut corrupt = car.clone();
+        corrupt.blocks[0] = Block::new_unchecked(*car.blocks[0].cid(), b"lead".to_vec());
+
+        let (verifier, reports) = Verifier::new(2);
+        verifier.submit_path(&path);
+        verifier.submit("incomplete", Cursor::new(bytes(&incomplete)));
+        verifier.submit("corrupt", Cursor::new(bytes(&corrupt)));
+        verifier.submit_path(dir.join("absent.car"));
+        verifier.finish();
+
+        let reports: Vec<_> = reports.iter().collect();
+        assert_eq!(reports.len(), 4);
+        let path = path.display().to_string();
+        let absent = dir.join("absent.car").display().to_string();
+        let find = |name: &str| {
+            &reports
+                .iter()
+                .find(|report| report.name == name)
+                .unwrap()
+                .result
+        };
+        let sound = find(&path).as_ref().unwrap();
+        assert!(sound.is_sound());
+        assert_eq!(sound.roots, car.header.roots);
+        assert_eq!(sound.blocks, 4);
+        let incomplete = find("incomplete").as_ref().unwrap();
+        assert_eq!(incomplete.missing, vec![*car.blocks[1].cid()]);
+        assert!(!incomplete.is_sound());
+        assert!(matches!(
+            find("corrupt"),
+            Err(CarError::HashMismatch { .. })
+        ));
+        assert!(matches!(find(&absent), Err(CarError::Io(_))));
+    }
+}
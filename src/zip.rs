@@ -0,0 +1,140 @@
+//! Zip files stored as UnixFS content, read in place through a [`UnixFsReader`]. Over an indexed
+//! or remote source of blocks (see [`CarZip::from_reader`]) only the central directory and the
+//! members asked for are fetched.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use ::zip::ZipArchive;
+use libipld::cid::Cid;
+
+use crate::gateway::BlockFetcher;
+use crate::unixfs::{KeyProvider, UnixFsReader};
+use crate::v1::{CarV1, IndexedCarV1};
+use crate::CarResult;
+
+/// An entry of a zip file's central directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipMember {
+    pub name: String,
+    /// The uncompressed size.
+    pub size: u64,
+    pub compressed_size: u64,
+    pub is_dir: bool,
+}
+
+/// The zip file at the UnixFS entity `root` of an archive.
+#[derive(Debug)]
+pub struct CarZip<'a, F = IndexedCarV1<'a>> {
+    zip: ZipArchive<UnixFsReader<'a, F>>,
+}
+
+impl<'a> CarZip<'a> {
+    /// Opens the zip file `root` of `car`, reading its central directory.
+    pub fn open(car: &'a CarV1, root: &Cid) -> CarResult<Self> {
+        Ok(Self {
+            zip: ZipArchive::new(UnixFsReader::new(car, root)?)?,
+        })
+    }
+
+    /// Like [`CarZip::open`] for a zip file whose leaves are encrypted, decrypting them with
+    /// `keys`.
+    pub fn open_with_keys(
+        car: &'a CarV1,
+        root: &Cid,
+        keys: &'a dyn KeyProvider,
+    ) -> CarResult<Self> {
+        Ok(Self {
+            zip: ZipArchive::new(UnixFsReader::new(car, root)?.with_keys(keys)?)?,
+        })
+    }
+}
+
+impl<'a, F: BlockFetcher> CarZip<'a, F> {
+    /// Opens the zip file `reader` reads, such as one made with [`UnixFsReader::from_fetcher`],
+    /// reading its central directory.
+    pub fn from_reader(reader: UnixFsReader<'a, F>) -> CarResult<Self> {
+        Ok(Self {
+            zip: ZipArchive::new(reader)?,
+        })
+    }
+
+    /// The members of the zip file, in central directory order.
+    pub fn members(&mut self) -> CarResult<Vec<ZipMember>> {
+        (0..self.zip.len())
+            .map(|i| {
+                let file = self.zip.by_index_raw(i)?;
+                Ok(ZipMember {
+                    name: file.name().to_string(),
+                    size: file.size(),
+                    compressed_size: file.compressed_size(),
+                    is_dir: file.is_dir(),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the decompressed content of the member `name` to `w`, returning its size.
+    pub fn extract<W: Write>(&mut self, name: &str, mut w: W) -> CarResult<u64> {
+        let mut file = self.zip.by_name(name)?;
+        Ok(io::copy(&mut file, &mut w)?)
+    }
+
+    /// Extracts every member below `dir`, skipping names that would escape it.
+    pub fn extract_all(&mut self, dir: &Path) -> CarResult<()> {
+        Ok(self.zip.extract(dir)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::Packer;
+    use crate::test_utils::temp_dir;
+    use crate::CarError;
+    use ::zip::write::SimpleFileOptions;
+    use ::zip::{CompressionMethod, ZipWriter};
+    use std::io::Cursor;
+
+    #[test]
+    fn it_lists_and_extracts_members() {
+        let dir = temp_dir("zip");
+        let content: Vec<u8> = (0..5000).map(|i| (i * 13 % 256) as u8).collect();
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        writer
+            .start_file("data/a.bin", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(&content).unwrap();
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        writer.start_file("readme.txt", stored).unwrap();
+        writer.write_all(b"hello").unwrap();
+        let path = dir.join("bundle.zip");
+        std::fs::write(&path, writer.finish().unwrap().into_inner()).unwrap();
+        let car = Packer::new().with_chunk_size(512).pack(&path).unwrap();
+
+        let mut zip = CarZip::open(&car, &car.header.roots[0]).unwrap();
+        let members = zip.members().unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "data/a.bin");
+        assert_eq!(members[0].size, 5000);
+        assert_eq!(members[1].compressed_size, 5);
+        let reader = UnixFsReader::from_fetcher(car.indexed(), &car.header.roots[0]).unwrap();
+        assert_eq!(
+            CarZip::from_reader(reader).unwrap().members().unwrap(),
+            members
+        );
+
+        let mut a = vec![];
+        assert_eq!(zip.extract("data/a.bin", &mut a).unwrap(), 5000);
+        assert_eq!(a, content);
+        assert!(matches!(
+            zip.extract("missing", io::sink()),
+            Err(CarError::Zip(_))
+        ));
+
+        let out = dir.join("out");
+        zip.extract_all(&out).unwrap();
+        assert_eq!(std::fs::read(out.join("readme.txt")).unwrap(), b"hello");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! Runs every subcommand of the `racecar` binary with `--format json` and parses what it prints.
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde_json::{json, Value};
+
+const CARV1: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/carv1-basic.car"
+);
+const CARV2: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/carv2-basic.car"
+);
+
+fn racecar(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_racecar"));
+    command.arg("--format").arg("json").args(args);
+    command
+}
+
+/// Runs `racecar --format json <args>`, which must succeed, and parses its stdout.
+fn run(args: &[&str]) -> Value {
+    let output = racecar(args).output().unwrap();
+    assert!(
+        output.status.success(),
+        "racecar {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// An empty directory of its own for the test `name`.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("racecar-cli-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn it_packs_extracts_and_queries_trees() {
+    let dir = temp_dir("pack");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    fs::write(tree.join("a.txt"), "hello").unwrap();
+    fs::write(tree.join("sub/b.txt"), "world").unwrap();
+    let out = dir.join("out");
+
+    let packed = run(&["pack", path(&tree), "-o", path(&out)]);
+    let root = packed["roots"][0]["cid"].as_str().unwrap().to_string();
+    assert_eq!(packed["roots"][0]["path"], path(&tree));
+    assert_eq!(packed["blocks"], 4);
+    let archive = out.join(packed["archive"].as_str().unwrap());
+
+    let extracted = dir.join("extracted");
+    assert_eq!(
+        run(&["extract", path(&archive), "-o", path(&extracted)]),
+        json!({ "roots": [root], "output": path(&extracted) })
+    );
+    assert_eq!(fs::read(extracted.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(extracted.join("sub/b.txt")).unwrap(), b"world");
+
+    let found = run(&["query", path(&archive), "Links/*/Name == 'a.txt'"]);
+    assert_eq!(
+        found,
+        json!([{ "cid": root, "path": "Links/0/Name", "node": "a.txt" }])
+    );
+
+    // Splitting into shards of a block each, every one of them named after its CID.
+    let shards = dir.join("shards");
+    let manifest = run(&[
+        "split",
+        path(&archive),
+        "-o",
+        path(&shards),
+        "--max-size",
+        "1",
+    ]);
+    assert_eq!(manifest["roots"], json!([root]));
+    let shards_written = manifest["shards"].as_array().unwrap();
+    assert_eq!(shards_written.len(), 4);
+    for shard in shards_written {
+        let cid = shard["cid"].as_str().unwrap();
+        assert!(shards.join(format!("{}.car", cid)).is_file());
+        assert_eq!(shard["blocks"].as_array().unwrap().len(), 1);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn it_packs_symlinks_as_asked() {
+    let dir = temp_dir("symlinks");
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("a.txt"), "hello").unwrap();
+    std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+    let root = |flag: &str| {
+        let out = dir.join(flag.trim_start_matches('-'));
+        let packed = run(&["pack", path(&tree), "-o", path(&out), flag]);
+        packed["roots"][0]["cid"].as_str().unwrap().to_string()
+    };
+    let followed = root("--follow-symlinks");
+    let preserved = root("--preserve-symlinks");
+    assert_ne!(followed, preserved);
+    let out = dir.join("default");
+    let packed = run(&["pack", path(&tree), "-o", path(&out)]);
+    assert_eq!(packed["roots"][0]["cid"], followed);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn it_inspects_archives() {
+    let dir = temp_dir("inspect");
+
+    let regions = run(&["debug", CARV2]);
+    let parts: Vec<&str> = regions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|region| region["part"].as_str().unwrap())
+        .collect();
+    assert_eq!(parts.first(), Some(&"pragma"));
+    assert_eq!(parts.last(), Some(&"index"));
+
+    let stats = run(&["stats", CARV1]);
+    let blocks = stats["blocks"].as_u64().unwrap();
+    assert!(blocks > 0);
+
+    let index = dir.join("carv1.idx");
+    assert_eq!(
+        run(&["index", CARV1, "-o", path(&index)]),
+        json!({ "sections": stats["sections"], "output": path(&index) })
+    );
+    assert!(index.is_file());
+
+    let changes = run(&["diff", CARV1, CARV1]);
+    assert_eq!(changes["added"], json!([]));
+    assert_eq!(changes["removed"], json!([]));
+    assert_eq!(changes["unchanged"], blocks);
+
+    for command in ["repair", "truncate-to-valid"] {
+        let recovery = run(&[command, CARV1]);
+        assert_eq!(recovery["blocks"], blocks);
+        assert_eq!(recovery["stopped"], Value::Null);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn it_prints_the_address_it_serves() {
+    let mut server = racecar(&["serve", CARV2, "--port", "0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    let serving: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(serving["file"], CARV2);
+    assert!(serving["url"]
+        .as_str()
+        .unwrap()
+        .starts_with("http://127.0.0.1:"));
+}
+
+#[test]
+fn it_prints_errors_as_json() {
+    let output = racecar(&["stats", "does-not-exist.car"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let err: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert!(err["error"].is_string());
+}